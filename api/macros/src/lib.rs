@@ -1,16 +1,193 @@
 use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
 use quote::quote;
 use syn::{
-    parse_macro_input, AngleBracketedGenericArguments, Attribute, Data, DeriveInput, Expr, ExprLit,
-    Fields, GenericArgument, Ident, Lit, Meta, MetaNameValue, Path, PathArguments, Type, TypePath,
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    AngleBracketedGenericArguments, Attribute, Data, DeriveInput, Expr, ExprLit, Fields,
+    GenericArgument, Ident, Lit, LitInt, LitStr, Meta, MetaNameValue, Path, PathArguments, Token,
+    Type, TypePath,
 };
 
-#[proc_macro_derive(Entity, attributes(collection_name))]
+/// One option inside `#[index(...)]` or `#[ttl(...)]`
+enum IndexArg {
+    Flag(String),
+    Fields(String),
+    Seconds(u64),
+}
+
+impl Parse for IndexArg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        // `2d` and `2dsphere` aren't identifiers, they lex as the number 2
+        // with a suffix
+        if input.peek(LitInt) {
+            let lit: LitInt = input.parse()?;
+            if lit.to_string() == "2d" || lit.to_string() == "2dsphere" {
+                return Ok(IndexArg::Flag(lit.to_string()));
+            }
+            return Err(syn::Error::new(lit.span(), "expected an index option"));
+        }
+        let ident: Ident = input.parse()?;
+        if !input.peek(Token![=]) {
+            return Ok(IndexArg::Flag(ident.to_string()));
+        }
+        input.parse::<Token![=]>()?;
+        match ident.to_string().as_str() {
+            "fields" => Ok(IndexArg::Fields(input.parse::<LitStr>()?.value())),
+            "seconds" => Ok(IndexArg::Seconds(input.parse::<LitInt>()?.base10_parse()?)),
+            _ => Err(syn::Error::new(
+                ident.span(),
+                "expected `fields` or `seconds`",
+            )),
+        }
+    }
+}
+
+/// Turns an `#[index(...)]` or `#[ttl(...)]` attribute into a
+/// `crate::db::IndexSpec`. `field` is the serialized name of the field the
+/// attribute is on, `None` for attributes on the struct, which name their
+/// fields with `fields = "a, -b"`.
+fn index_spec(attr: &Attribute, field: Option<&str>) -> syn::Result<TokenStream2> {
+    let args = match &attr.meta {
+        Meta::Path(_) => Vec::new(),
+        _ => attr
+            .parse_args_with(Punctuated::<IndexArg, Token![,]>::parse_terminated)?
+            .into_iter()
+            .collect(),
+    };
+
+    let mut paths: Vec<String> = field
+        .map(|field| vec![field.to_string()])
+        .unwrap_or_default();
+    let mut kind = quote! { crate::db::IndexKey::Ascending };
+    let mut unique = false;
+    let mut sparse = false;
+    let mut expire_after = quote! { std::option::Option::None };
+    if attr.path().is_ident("ttl") {
+        expire_after = quote! { std::option::Option::Some(0) };
+    }
+    for arg in args {
+        match arg {
+            IndexArg::Flag(flag) => match flag.as_str() {
+                "unique" => unique = true,
+                "sparse" => sparse = true,
+                "desc" => kind = quote! { crate::db::IndexKey::Descending },
+                "2dsphere" => kind = quote! { crate::db::IndexKey::Sphere2d },
+                "2d" => kind = quote! { crate::db::IndexKey::Flat2d },
+                _ => {
+                    return Err(syn::Error::new_spanned(
+                        attr,
+                        format!("unknown index option `{flag}`"),
+                    ))
+                }
+            },
+            IndexArg::Fields(fields) if field.is_none() => {
+                paths = fields
+                    .split(',')
+                    .map(|path| path.trim().to_string())
+                    .collect();
+            }
+            IndexArg::Seconds(seconds) if attr.path().is_ident("ttl") => {
+                expire_after = quote! { std::option::Option::Some(#seconds) };
+            }
+            _ => return Err(syn::Error::new_spanned(attr, "option not allowed here")),
+        }
+    }
+    if paths.is_empty() {
+        return Err(syn::Error::new_spanned(
+            attr,
+            "indexes on the struct need `fields = \"...\"`",
+        ));
+    }
+
+    // `-field` sorts that field descending in compound indexes
+    let keys = paths.iter().map(|path| match path.strip_prefix('-') {
+        Some(path) => quote! { (#path, crate::db::IndexKey::Descending) },
+        None => quote! { (#path, #kind) },
+    });
+    Ok(quote! {
+        crate::db::IndexSpec {
+            keys: &[#(#keys),*],
+            unique: #unique,
+            sparse: #sparse,
+            expire_after: #expire_after,
+        }
+    })
+}
+
+/// What serde calls `field`, going by `rename` and the struct's `rename_all`
+fn serialized_name(field: &syn::Field, rename_all: Option<&str>) -> String {
+    let mut name = None;
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("serde"))
+    {
+        // Other serde options are skipped, and ones we can't parse ignored
+        let _ = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                name = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.input.peek(Token![=]) {
+                meta.value()?.parse::<Expr>()?;
+            }
+            Ok(())
+        });
+    }
+    if let Some(name) = name {
+        return name;
+    }
+    let ident = field.ident.as_ref().unwrap().to_string();
+    match rename_all {
+        Some("camelCase") => {
+            let mut parts = ident.split('_');
+            let mut name = parts.next().unwrap_or_default().to_string();
+            for part in parts {
+                let mut chars = part.chars();
+                if let Some(first) = chars.next() {
+                    name.extend(first.to_uppercase());
+                    name.push_str(chars.as_str());
+                }
+            }
+            name
+        }
+        _ => ident,
+    }
+}
+
+/// Derives `db::Entity` and `repository::Model`. Indexes are declared with
+/// `#[index]`, `#[index(unique)]`, `#[index(desc)]`, `#[index(2d)]`,
+/// `#[index(2dsphere)]` or `#[ttl(seconds = 3600)]` on fields, and compound or
+/// nested ones with `#[index(fields = "a, -b")]` on the struct.
+#[proc_macro_derive(Entity, attributes(collection_name, index, ttl))]
 pub fn insertable(input: TokenStream) -> TokenStream {
     let ast = parse_macro_input!(input as DeriveInput);
 
     let mut collection_name: Option<String> = None;
+    let mut rename_all: Option<String> = None;
+    let mut indexes: Vec<TokenStream2> = Vec::new();
+    for attr in ast.attrs.iter() {
+        if attr.path().is_ident("index") {
+            match index_spec(attr, None) {
+                Ok(spec) => indexes.push(spec),
+                Err(err) => return err.to_compile_error().into(),
+            }
+        }
+        if attr.path().is_ident("serde") {
+            let _ = attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename_all") {
+                    rename_all = Some(meta.value()?.parse::<LitStr>()?.value());
+                } else if meta.input.peek(Token![=]) {
+                    meta.value()?.parse::<Expr>()?;
+                }
+                Ok(())
+            });
+        }
+    }
     for attr in ast.attrs.clone().into_iter() {
+        if !attr.path().is_ident("collection_name") {
+            continue;
+        }
         if let Attribute {
             meta:
                 Meta::NameValue(MetaNameValue {
@@ -44,8 +221,18 @@ pub fn insertable(input: TokenStream) -> TokenStream {
     // This code tests that the struct we are dering for
     // has an id field with a type of Option<u32>
     let mut has_id: bool = false;
+    let mut has_object_id: bool = false;
     let mut id_field_ident = None;
     for field in fields.into_iter() {
+        for attr in field.attrs.iter() {
+            if attr.path().is_ident("index") || attr.path().is_ident("ttl") {
+                let name = serialized_name(&field, rename_all.as_deref());
+                match index_spec(attr, Some(&name)) {
+                    Ok(spec) => indexes.push(spec),
+                    Err(err) => return err.to_compile_error().into(),
+                }
+            }
+        }
         if field.ident.clone().unwrap().to_string() == "_id"
             || field.ident.clone().unwrap().to_string() == "id"
         {
@@ -68,6 +255,7 @@ pub fn insertable(input: TokenStream) -> TokenStream {
                         {
                             let last_segment = segments.last().unwrap().ident.to_string();
                             has_id = last_segment == "ObjectId" || last_segment == "u32";
+                            has_object_id = last_segment == "ObjectId";
                             id_field_ident = field.ident;
                         }
                     }
@@ -94,16 +282,11 @@ pub fn insertable(input: TokenStream) -> TokenStream {
 
     let delete = quote! {
         async fn delete(self, db: &mongodb::Database) -> std::result::Result<mongodb::results::DeleteResult, mongodb::error::Error> {
-            db.collection::<#ident>(#collection_name).delete_one(bson::to_document(&self).unwrap(), None).await
-        }
-    };
-
-    let query = quote! {
-        async fn query(
-            filter: bson::document::Document,
-            db: &mongodb::Database
-        ) -> std::result::Result<mongodb::Cursor<Self>, mongodb::error::Error> {
-            db.collection::<#ident>(#collection_name).find(filter, None).await
+            let filter = match self.#id_field_ident {
+                Some(id) => bson::doc! { "_id": id },
+                None => bson::to_document(&self)?,
+            };
+            db.collection::<#ident>(#collection_name).delete_one(filter, None).await
         }
     };
 
@@ -122,18 +305,6 @@ pub fn insertable(input: TokenStream) -> TokenStream {
         }
     };
 
-    let find_one = quote! {
-        async fn find_one(
-            filter: bson::document::Document,
-            db: &mongodb::Database,
-            options: std::option::Option<mongodb::options::FindOptions>
-        ) -> std::option::Option<std::boxed::Box<Self>> {
-            let mut cursor = db.collection::<#ident>(#collection_name).find(filter, options).await.ok()?;
-            cursor.advance().await.ok()?;
-            cursor.deserialize_current().ok().map(|x| std::boxed::Box::new(x))
-        }
-    };
-
     let update = quote! {
         async fn update(
             &mut self,
@@ -147,16 +318,42 @@ pub fn insertable(input: TokenStream) -> TokenStream {
         }
     };
 
+    // Entities with ObjectId ids also get a typed repository
+    let repository = if has_object_id {
+        quote! {
+            impl crate::repository::Model for #ident {
+                const COLLECTION: &'static str = #collection_name;
+                const INDEXES: &'static [crate::db::IndexSpec] = &[#(#indexes),*];
+
+                fn id(&self) -> std::option::Option<bson::oid::ObjectId> {
+                    self.#id_field_ident
+                }
+
+                fn set_id(&mut self, id: bson::oid::ObjectId) {
+                    self.#id_field_ident = std::option::Option::Some(id);
+                }
+            }
+
+            impl #ident {
+                pub fn repository(db: &mongodb::Database) -> crate::repository::MongoRepository<#ident> {
+                    crate::repository::MongoRepository::new(db)
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
     quote! {
         #[async_trait::async_trait]
-       impl Entity for #ident {
+       impl crate::db::Entity for #ident {
             #insert
             #delete
-            #query
             #update
             #find
-            #find_one
         }
+
+        #repository
     }
     .into()
 }
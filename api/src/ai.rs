@@ -8,15 +8,13 @@ use utoipa::ToSchema;
 
 #[utoipa::path(post, tag = "AI", path = "/api/ai/info", request_body = AiInfoRequest)]
 #[post("/ai/info", format = "application/json", data = "<request>")]
-pub async fn get_ai_info<'a>(request: Json<AiInfoRequest>) -> ApiResponse<'a, AiInfoResponse> {
+pub async fn get_ai_info(request: Json<AiInfoRequest>) -> ApiResponse<AiInfoResponse> {
     let mapbox_api_key = if let Ok(key) = dbg!(env::var("MAPBOX_API_KEY")) {
         key
     } else {
         log::error!("We couldn't get the mapbox api key. The environment variable was not set!");
-        return ApiError::ServerError(
-            "Invalid server configuration, please contact an administrator",
-        )
-        .into();
+        return ApiError::internal("Invalid server configuration, please contact an administrator")
+            .into();
     };
 
     match Command::new("python3")
@@ -43,13 +41,13 @@ pub async fn get_ai_info<'a>(request: Json<AiInfoRequest>) -> ApiResponse<'a, Ai
                         "Python script output: {}",
                         String::from_utf8_lossy(&val.stdout)
                     );
-                    ApiError::ServerError("Unable to fetch AI map data at this time").into()
+                    ApiError::internal("Unable to fetch AI map data at this time").into()
                 }
             }
         }
         Err(err) => {
             log::error!("Couldn't run python script: {err:?}");
-            ApiError::ServerError("Unable to fetch AI map data at this time").into()
+            ApiError::internal("Unable to fetch AI map data at this time").into()
         }
     }
 }
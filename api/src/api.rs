@@ -1,24 +1,176 @@
 #![allow(dead_code)]
 
 use crate::loadshedding::MapDataDefaultResponse;
-use rocket::{http::ContentType, response::Responder};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Outcome;
+use rocket::response::Responder;
+use rocket::{catch, Request, Response};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-#[derive(Debug, Serialize, Deserialize, ToSchema, Clone)]
-#[non_exhaustive]
-pub enum ApiError<'a> {
-    AuthError(&'a str),
-    UserCreationError(&'a str),
-    SavedPlacesError(&'a str),
-    ServerError(&'a str),
-    ScraperUploadError(&'a str),
-    RequestError(&'a str),
+/// Stable, machine readable reason a request failed. Each maps to one HTTP
+/// status, so clients can branch on either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    BadRequest,
+    /// Some fields of the request were invalid, see the error's `details`
+    ValidationFailed,
+    Unauthenticated,
+    Forbidden,
+    NotFound,
+    Conflict,
+    RateLimited,
+    Internal,
+    /// A dependency like the database is down, try again later
+    Unavailable,
 }
 
-pub enum ApiResponse<'a, O: Serialize> {
+impl ErrorCode {
+    pub fn status(self) -> Status {
+        match self {
+            Self::BadRequest | Self::ValidationFailed => Status::BadRequest,
+            Self::Unauthenticated => Status::Unauthorized,
+            Self::Forbidden => Status::Forbidden,
+            Self::NotFound => Status::NotFound,
+            Self::Conflict => Status::Conflict,
+            Self::RateLimited => Status::TooManyRequests,
+            Self::Internal => Status::InternalServerError,
+            Self::Unavailable => Status::ServiceUnavailable,
+        }
+    }
+
+    fn from_status(status: Status) -> Self {
+        match status.code {
+            401 => Self::Unauthenticated,
+            403 => Self::Forbidden,
+            404 => Self::NotFound,
+            409 => Self::Conflict,
+            422 => Self::ValidationFailed,
+            429 => Self::RateLimited,
+            503 => Self::Unavailable,
+            400..=499 => Self::BadRequest,
+            _ => Self::Internal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldError {
+    /// Name of the offending field, as it's spelled in the request
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+#[schema(example = json! {
+    ApiError {
+        code: ErrorCode::Unauthenticated,
+        message: "Incorrect password".to_string(),
+        details: Vec::new(),
+        request_id: Some("5f2b9c1d8e7a4b30".to_string())
+    }
+})]
+pub struct ApiError {
+    pub code: ErrorCode,
+    /// Human readable, not meant to be matched on
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<FieldError>,
+    /// Also sent as the `X-Request-Id` header. Quote it when reporting a problem.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            details: Vec::new(),
+            request_id: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::BadRequest, message)
+    }
+
+    /// A single invalid field
+    pub fn invalid(field: &str, message: impl Into<String>) -> Self {
+        let message = message.into();
+        Self::new(ErrorCode::ValidationFailed, message.clone()).with_detail(field, message)
+    }
+
+    pub fn unauthenticated(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unauthenticated, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Forbidden, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Conflict, message)
+    }
+
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::RateLimited, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Internal, message)
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unavailable, message)
+    }
+
+    pub fn database_unavailable() -> Self {
+        Self::unavailable("Database is unavailable. Please try again later!")
+    }
+
+    pub fn with_detail(mut self, field: &str, message: impl Into<String>) -> Self {
+        self.details.push(FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn status(&self) -> Status {
+        self.code.status()
+    }
+
+    /// Fails a request guard with this error. Rocket doesn't hand guard
+    /// errors to catchers, so it's also kept on the request for
+    /// [`default_catcher`] to send.
+    pub fn fail<S>(self, request: &Request<'_>) -> Outcome<S, ApiError> {
+        let status = self.status();
+        request.local_cache(|| GuardError(Some(self.clone())));
+        Outcome::Failure((status, self))
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+pub enum ApiResponse<O: Serialize> {
     Ok(O),
-    Err(ApiError<'a>),
+    Err(ApiError),
+    /// A result remembered from before the database went down, along with
+    /// when it was (in milliseconds since the epoch)
+    Stale(O, u64),
 }
 
 #[derive(Serialize, Deserialize, ToSchema)]
@@ -26,45 +178,162 @@ pub enum ApiResponse<'a, O: Serialize> {
     UnifiedResponse::<()> {
         success: false,
         result: None,
-        error: Some(ApiError::AuthError("Incorrect password"))
+        error: Some(ApiError::unauthenticated("Incorrect password")),
+        stale_since: None
     }
 })]
 #[aliases(
-    ResponseString = UnifiedResponse<'a, String>,
-    ResponseMapData = UnifiedResponse<'a, MapDataDefaultResponse>
+    ResponseString = UnifiedResponse<String>,
+    ResponseMapData = UnifiedResponse<MapDataDefaultResponse>
 )]
-pub struct UnifiedResponse<'b, O: Serialize> {
+pub struct UnifiedResponse<O: Serialize> {
     pub success: bool,
     pub result: Option<O>,
-    #[serde(borrow)]
-    pub error: Option<ApiError<'b>>,
+    pub error: Option<ApiError>,
+    /// Set when the database is down and `result` is the last one we saw
+    /// for this request, to when that was (in milliseconds since the epoch)
+    #[serde(
+        default,
+        rename = "staleSince",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub stale_since: Option<u64>,
 }
 
-impl<'r, 'a, O: Serialize> Responder<'r, 'static> for ApiResponse<'a, O> {
-    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
-        let response = match self {
-            Self::Ok(result) => UnifiedResponse {
-                result: Some(result),
-                success: true,
-                error: None,
-            },
-            Self::Err(error) => UnifiedResponse {
+fn json_response<'r>(status: Status, body: &impl Serialize) -> rocket::response::Result<'r> {
+    let body = serde_json::to_string(body).unwrap();
+    Response::build()
+        .status(status)
+        .header(ContentType::JSON)
+        .sized_body(body.len(), std::io::Cursor::new(body))
+        .ok()
+}
+
+impl<'r, O: Serialize> Responder<'r, 'static> for ApiResponse<O> {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        match self {
+            Self::Ok(result) => json_response(
+                Status::Ok,
+                &UnifiedResponse {
+                    result: Some(result),
+                    success: true,
+                    error: None,
+                    stale_since: None,
+                },
+            ),
+            Self::Err(error) => error.respond_to(request),
+            Self::Stale(result, since) => {
+                let mut response = json_response(
+                    Status::Ok,
+                    &UnifiedResponse {
+                        result: Some(result),
+                        success: true,
+                        error: None,
+                        stale_since: Some(since),
+                    },
+                )?;
+                response.set_header(Header::new("Warning", "110 - \"Response is Stale\""));
+                Ok(response)
+            }
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(mut self, request: &'r Request<'_>) -> rocket::response::Result<'static> {
+        let status = self.status();
+        let request_id = RequestId::of(request);
+        if status.code >= 500 {
+            log::warn!("Request {request_id} failed: {self}");
+        }
+        self.request_id = Some(request_id.to_string());
+        json_response(
+            status,
+            &UnifiedResponse::<()> {
                 result: None,
                 success: false,
-                error: Some(error),
+                error: Some(self),
+                stale_since: None,
             },
-        };
-
-        let response = serde_json::to_string(&response).unwrap();
-        rocket::Response::build()
-            .header(ContentType::JSON)
-            .sized_body(response.len(), std::io::Cursor::new(response))
-            .ok()
+        )
     }
 }
 
-impl<'a, O: Serialize> From<ApiError<'a>> for ApiResponse<'a, O> {
-    fn from(value: ApiError<'a>) -> Self {
+impl<O: Serialize> From<ApiError> for ApiResponse<O> {
+    fn from(value: ApiError) -> Self {
         Self::Err(value)
     }
 }
+
+impl<O: Serialize> From<Result<O, ApiError>> for ApiResponse<O> {
+    fn from(value: Result<O, ApiError>) -> Self {
+        match value {
+            Ok(result) => Self::Ok(result),
+            Err(err) => Self::Err(err),
+        }
+    }
+}
+
+struct GuardError(Option<ApiError>);
+
+/// Answers every failed request that no handler responded to, with the
+/// error of the request guard that failed if there was one
+#[catch(default)]
+pub fn default_catcher(status: Status, request: &Request<'_>) -> ApiError {
+    if let Some(error) = &request.local_cache(|| GuardError(None)).0 {
+        return error.clone();
+    }
+    let message = match status.code {
+        404 => "There's nothing here",
+        413 => "The request is too large",
+        422 => "The request body is invalid",
+        _ => status.reason().unwrap_or("Something went wrong"),
+    };
+    ApiError::new(ErrorCode::from_status(status), message)
+}
+
+/// Identifies a request in logs and error responses. Taken from the
+/// client's `X-Request-Id` header when it sends a sensible one.
+pub struct RequestId(String);
+
+impl RequestId {
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r str {
+        &request
+            .local_cache(|| {
+                let id = request
+                    .headers()
+                    .get_one("X-Request-Id")
+                    .filter(|id| {
+                        !id.is_empty()
+                            && id.len() <= 64
+                            && id
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                    })
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+                RequestId(id)
+            })
+            .0
+    }
+}
+
+/// Sends every response's request id back in `X-Request-Id`
+pub struct RequestIds;
+
+#[rocket::async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Ids",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(
+            "X-Request-Id",
+            RequestId::of(request).to_string(),
+        ));
+    }
+}
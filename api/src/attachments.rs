@@ -0,0 +1,385 @@
+use crate::{
+    api::{ApiError, ApiResponse},
+    auth::JWTAuthToken,
+    reporting::{find_report, now_millis, UserReport},
+    repository::{MongoStore, Repository, Store},
+    storage::Storage,
+    user::current_user,
+    DB_NAME,
+};
+use bson::oid::ObjectId;
+use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageOutputFormat};
+use mongodb::Client;
+use rocket::form::Form;
+use rocket::fs::TempFile;
+use rocket::http::ContentType;
+use rocket::{delete, get, post, FromForm, State};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use utoipa::ToSchema;
+
+/// Photos larger than this are turned away before we decode them
+pub const MAX_PHOTO_BYTES: u64 = 5 * 1024 * 1024;
+/// Stored photos are scaled down to fit in a square this big
+const MAX_PHOTO_DIMENSION: u32 = 2048;
+const THUMBNAIL_DIMENSION: u32 = 320;
+const DEFAULT_MAX_PHOTOS: usize = 3;
+
+#[utoipa::path(
+    post,
+    path = "/api/reports/{id}/photos",
+    params(("id",)),
+    request_body(content = PhotoUpload, content_type = "multipart/form-data"),
+    responses((status = 200, body = ReportPhotoResponse)),
+    security(("jwt" = []))
+)]
+#[post(
+    "/reports/<id>/photos",
+    format = "multipart/form-data",
+    data = "<upload>"
+)]
+pub async fn upload_report_photo(
+    id: &str,
+    upload: Form<PhotoUpload<'_>>,
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+    storage: &State<Storage>,
+) -> ApiResponse<ReportPhotoResponse> {
+    let (user, store) = match current_user(&token, state).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+    let report = match find_report(&store, id).await {
+        Ok(report) => report,
+        Err(err) => return err.into(),
+    };
+    if report.email != user.email {
+        return ApiError::forbidden("You can only add photos to your own reports").into();
+    }
+    if report.hidden || report.is_expired() {
+        return ApiError::bad_request("This report is no longer active").into();
+    }
+    if report.photos.len() >= max_photos() {
+        return ApiError::bad_request("This report already has as many photos as it can").into();
+    }
+
+    let format = match upload.photo.content_type() {
+        Some(content_type) if content_type.is_jpeg() => ImageFormat::Jpeg,
+        Some(content_type) if content_type.is_png() => ImageFormat::Png,
+        _ => return ApiError::invalid("photo", "Photos have to be JPEG or PNG images").into(),
+    };
+    if upload.photo.len() > MAX_PHOTO_BYTES {
+        return ApiError::invalid("photo", "Photos can be at most 5 MiB").into();
+    }
+    let bytes = match upload.photo.path() {
+        Some(path) => match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                log::error!("Couldn't read uploaded photo: {err:?}");
+                return ApiError::internal("Couldn't read the uploaded photo").into();
+            }
+        },
+        None => return ApiError::invalid("photo", "Upload the photo as a file").into(),
+    };
+
+    let processed = match tokio::task::spawn_blocking(move || process_photo(&bytes, format)).await {
+        Ok(Ok(processed)) => processed,
+        Ok(Err(err)) => {
+            log::info!("Rejected photo for report {id}: {err}");
+            return ApiError::bad_request("Couldn't read the photo, is it a valid image?").into();
+        }
+        Err(err) => {
+            log::error!("Photo processing panicked: {err:?}");
+            return ApiError::internal("Couldn't process the photo").into();
+        }
+    };
+
+    let photo = ReportPhoto {
+        id: hex::encode(rand::random::<[u8; 12]>()),
+        width: processed.width,
+        height: processed.height,
+        uploaded: now_millis(),
+    };
+    let report_id = report.id.unwrap().to_hex();
+    let key = photo.key(&report_id);
+    if let Err(err) = storage.put(&key, "image/jpeg", processed.photo).await {
+        log::error!("{err}");
+        return ApiError::internal("Couldn't store the photo").into();
+    }
+    if let Err(err) = storage
+        .put(
+            &photo.thumbnail_key(&report_id),
+            "image/jpeg",
+            processed.thumbnail,
+        )
+        .await
+    {
+        log::error!("{err}");
+        let _ = storage.delete(&key).await;
+        return ApiError::internal("Couldn't store the photo").into();
+    }
+
+    if let Err(err) = attach_photo(&store, report.id.unwrap(), &photo, max_photos()).await {
+        delete_files(&report_id, &photo, storage).await;
+        return err.into();
+    }
+    ApiResponse::Ok(ReportPhotoResponse::new(&report_id, &photo))
+}
+
+/// Adds a stored photo to a report, unless it already has `max` of them.
+/// The limit is part of the update, so uploads racing each other can't go
+/// over it.
+pub async fn attach_photo<S: Store>(
+    store: &S,
+    report: ObjectId,
+    photo: &ReportPhoto,
+    max: usize,
+) -> Result<(), ApiError> {
+    if max == 0 {
+        return Err(ApiError::conflict(
+            "This report already has as many photos as it can",
+        ));
+    }
+    let filter = bson::doc! {
+        "_id": report,
+        format!("photos.{}", max - 1): { "$exists": false },
+    };
+    match store
+        .repository::<UserReport>()
+        .update_one(
+            filter,
+            bson::doc! { "$push": { "photos": bson::to_bson(photo).unwrap() } },
+        )
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::conflict(
+            "This report already has as many photos as it can",
+        )),
+        Err(err) => {
+            log::error!("Couldn't add photo to report: {err:?}");
+            Err(ApiError::internal("Couldn't store the photo"))
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/api/reports/{id}/photos/{photo}", params(("id",), ("photo",)), responses(
+    (status = 200, description = "The photo as a JPEG", content_type = "image/jpeg")
+))]
+#[get("/reports/<id>/photos/<photo>")]
+pub async fn get_report_photo(
+    id: &str,
+    photo: &str,
+    state: &State<Option<Client>>,
+    storage: &State<Storage>,
+) -> Result<(ContentType, Vec<u8>), ApiError> {
+    fetch_photo(id, photo, false, state, storage).await
+}
+
+#[utoipa::path(get, path = "/api/reports/{id}/photos/{photo}/thumbnail", params(("id",), ("photo",)), responses(
+    (status = 200, description = "A thumbnail of the photo as a JPEG", content_type = "image/jpeg")
+))]
+#[get("/reports/<id>/photos/<photo>/thumbnail")]
+pub async fn get_report_photo_thumbnail(
+    id: &str,
+    photo: &str,
+    state: &State<Option<Client>>,
+    storage: &State<Storage>,
+) -> Result<(ContentType, Vec<u8>), ApiError> {
+    fetch_photo(id, photo, true, state, storage).await
+}
+
+async fn fetch_photo(
+    id: &str,
+    photo_id: &str,
+    thumbnail: bool,
+    state: &State<Option<Client>>,
+    storage: &State<Storage>,
+) -> Result<(ContentType, Vec<u8>), ApiError> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return Err(ApiError::database_unavailable()),
+    };
+    let report = find_report(&store, id).await?;
+    let photo = match report.photos.iter().find(|photo| photo.id == photo_id) {
+        Some(photo) if !report.hidden => photo,
+        _ => return Err(ApiError::not_found("No such photo")),
+    };
+
+    let report_id = report.id.unwrap().to_hex();
+    let key = if thumbnail {
+        photo.thumbnail_key(&report_id)
+    } else {
+        photo.key(&report_id)
+    };
+    match storage.get(&key).await {
+        Ok(Some(bytes)) => Ok((ContentType::JPEG, bytes)),
+        Ok(None) => Err(ApiError::not_found("No such photo")),
+        Err(err) => {
+            log::error!("{err}");
+            Err(ApiError::internal("Couldn't fetch the photo"))
+        }
+    }
+}
+
+#[utoipa::path(delete, path = "/api/reports/{id}/photos/{photo}", params(("id",), ("photo",)), security(("jwt" = [])))]
+#[delete("/reports/<id>/photos/<photo>")]
+pub async fn delete_report_photo(
+    id: &str,
+    photo: &str,
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+    storage: &State<Storage>,
+) -> ApiResponse<&'static str> {
+    let (user, store) = match current_user(&token, state).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+    let report = match find_report(&store, id).await {
+        Ok(report) => report,
+        Err(err) => return err.into(),
+    };
+    if report.email != user.email {
+        return ApiError::forbidden("You can only remove photos from your own reports").into();
+    }
+    let photo = match report.photos.iter().find(|found| found.id == photo) {
+        Some(photo) => photo.clone(),
+        None => return ApiError::not_found("No such photo").into(),
+    };
+
+    if let Err(err) = store
+        .repository::<UserReport>()
+        .update_one(
+            bson::doc! { "_id": report.id },
+            bson::doc! { "$pull": { "photos": { "id": &photo.id } } },
+        )
+        .await
+    {
+        log::error!("Couldn't remove photo from report: {err:?}");
+        return ApiError::internal("Couldn't remove the photo").into();
+    }
+    delete_files(&report.id.unwrap().to_hex(), &photo, storage).await;
+    ApiResponse::Ok("Photo removed")
+}
+
+/// Removes the stored files of every photo on a report that's going away
+pub async fn delete_photos(report: &UserReport, storage: &Storage) {
+    if let Some(id) = report.id {
+        for photo in &report.photos {
+            delete_files(&id.to_hex(), photo, storage).await;
+        }
+    }
+}
+
+async fn delete_files(report_id: &str, photo: &ReportPhoto, storage: &Storage) {
+    for key in [photo.key(report_id), photo.thumbnail_key(report_id)] {
+        if let Err(err) = storage.delete(&key).await {
+            log::error!("{err}");
+        }
+    }
+}
+
+/// How many photos a report can have, `REPORT_MAX_PHOTOS` or 3
+fn max_photos() -> usize {
+    std::env::var("REPORT_MAX_PHOTOS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(DEFAULT_MAX_PHOTOS)
+}
+
+#[derive(FromForm, ToSchema)]
+pub struct PhotoUpload<'r> {
+    /// A JPEG or PNG, at most 5 MiB
+    #[schema(value_type = String, format = Binary)]
+    pub photo: TempFile<'r>,
+}
+
+pub struct ProcessedPhoto {
+    pub photo: Vec<u8>,
+    pub thumbnail: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Decodes an uploaded photo and encodes it again as a JPEG, along with a
+/// thumbnail. Nothing but the pixels survives, which strips EXIF data such
+/// as where the photo was taken.
+pub fn process_photo(bytes: &[u8], format: ImageFormat) -> Result<ProcessedPhoto, String> {
+    if image::guess_format(bytes).ok() != Some(format) {
+        return Err(format!("Contents aren't {format:?}"));
+    }
+    let image = image::load_from_memory_with_format(bytes, format)
+        .map_err(|err| format!("Couldn't decode photo: {err}"))?;
+    let image = if image.width() > MAX_PHOTO_DIMENSION || image.height() > MAX_PHOTO_DIMENSION {
+        image.resize(
+            MAX_PHOTO_DIMENSION,
+            MAX_PHOTO_DIMENSION,
+            FilterType::Lanczos3,
+        )
+    } else {
+        image
+    };
+    let image = DynamicImage::ImageRgb8(image.to_rgb8());
+
+    Ok(ProcessedPhoto {
+        photo: encode_jpeg(&image, 85)?,
+        thumbnail: encode_jpeg(
+            &image.thumbnail(THUMBNAIL_DIMENSION, THUMBNAIL_DIMENSION),
+            75,
+        )?,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    image
+        .write_to(
+            &mut Cursor::new(&mut bytes),
+            ImageOutputFormat::Jpeg(quality),
+        )
+        .map_err(|err| format!("Couldn't encode photo: {err}"))?;
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReportPhoto {
+    pub id: String,
+    pub width: u32,
+    pub height: u32,
+    pub uploaded: u64,
+}
+
+impl ReportPhoto {
+    fn key(&self, report_id: &str) -> String {
+        format!("reports/{report_id}/{}.jpg", self.id)
+    }
+
+    fn thumbnail_key(&self, report_id: &str) -> String {
+        format!("reports/{report_id}/{}_thumbnail.jpg", self.id)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ReportPhotoResponse {
+    pub id: String,
+    pub url: String,
+    pub thumbnail_url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ReportPhotoResponse {
+    pub fn new(report_id: &str, photo: &ReportPhoto) -> Self {
+        let url = format!("/api/reports/{report_id}/photos/{}", photo.id);
+        Self {
+            id: photo.id.clone(),
+            thumbnail_url: format!("{url}/thumbnail"),
+            url,
+            width: photo.width,
+            height: photo.height,
+        }
+    }
+}
@@ -1,191 +1,293 @@
-use crate::api::ApiError;
-use crate::db::Entity;
-use crate::user::User;
+use crate::api::{ApiError, ApiResponse};
+use crate::db::Db;
+use crate::keys::{JwtKeys, TOKEN_LIFETIME_SECS};
+use crate::oidc::OidcProviders;
+use crate::ratelimit::{RateLimiter, TooManyRequests};
+use crate::repository::{MongoStore, PageRequest, RepoResult, Repository, Store};
+use crate::scheduler::{Job, Schedule, Scheduler};
+use crate::user::{token_user, User};
 use crate::DB_NAME;
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use bson::oid::ObjectId;
-use bson::Document;
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use log::warn;
+use jsonwebtoken::{Algorithm, Validation};
 use log::{error, info};
 use macros::Entity;
 use mongodb::Client;
 use rand::{RngCore, SeedableRng};
-use rocket::futures::TryStreamExt;
+use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::{Cookie, CookieJar};
 use rocket::request::{FromRequest, Outcome};
 use rocket::serde::json::Json;
 use rocket::Responder;
-use rocket::{post, State};
+use rocket::{delete, get, post, Orbit, Rocket, State};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokio::io::AsyncReadExt;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use utoipa::ToSchema;
 
 #[utoipa::path(post, tag = "Authenticate", path = "/api/auth", request_body = AuthRequest)]
 #[post("/auth", format = "application/json", data = "<auth_request>")]
 pub async fn authenticate(
     auth_request: Json<AuthRequest>,
-    state: &State<Option<Client>>,
+    db: Option<Db>,
+    oidc_providers: &State<OidcProviders>,
+    keys: &State<JwtKeys>,
+    limiter: &State<RateLimiter>,
     cookies: &CookieJar<'_>,
-) -> Result<AuthResponder, Json<ApiError<'static>>> {
+    client: ClientInfo,
+) -> Result<AuthResponder, AuthFailure> {
+    if let (AuthType::User, Some(email)) = (auth_request.auth_type, &auth_request.email) {
+        if let Some(retry_after) = limiter.login_locked_out(email).await {
+            return Err(AuthFailure::LockedOut(TooManyRequests::new(
+                retry_after,
+                "Too many failed login attempts. Please try again later",
+            )));
+        }
+    }
+
+    // Anonymous logins don't need the database, so the guard is optional
+    let db = db.as_ref().map(|db| &db.0);
+    login(
+        auth_request,
+        db,
+        oidc_providers,
+        keys,
+        limiter,
+        cookies,
+        &client,
+    )
+    .await
+    .map_err(AuthFailure::Rejected)
+}
+
+#[derive(Responder)]
+pub enum AuthFailure {
+    Rejected(ApiError),
+    LockedOut(TooManyRequests),
+}
+
+async fn login(
+    auth_request: Json<AuthRequest>,
+    db: Option<&Client>,
+    oidc_providers: &State<OidcProviders>,
+    keys: &State<JwtKeys>,
+    limiter: &State<RateLimiter>,
+    cookies: &CookieJar<'_>,
+    client: &ClientInfo,
+) -> Result<AuthResponder, ApiError> {
     match auth_request.auth_type {
         AuthType::Anonymous => Ok(AuthResponder {
             inner: Json(
-                JWTAuthToken::new(auth_request.auth_type, None)
+                JWTAuthToken::new(keys, auth_request.auth_type, None, None)
                     .await
                     .unwrap(),
             ),
             header: rocket::http::Header::new("X-Anon-Auth", "yes"),
         }),
         AuthType::User => {
-            let db = state.inner().as_ref().unwrap();
-
-            let email = auth_request.email.clone();
+            let store = match db {
+                Some(client) => MongoStore::new(client, DB_NAME),
+                None => return Err(ApiError::database_unavailable()),
+            };
+            let password = match &auth_request.password {
+                Some(password) => password,
+                None => return Err(ApiError::invalid("password", "Missing password")),
+            };
+            let user = match store
+                .repository::<User>()
+                .find_one(bson::doc! { "email": &auth_request.email }, None)
+                .await?
+            {
+                Some(user) => user,
+                None => return Err(ApiError::unauthenticated("No such user")),
+            };
 
-            if auth_request.password.is_none() {
-                return Err(Json(ApiError::AuthError("Missing password")));
+            if user.password_hash.is_empty() {
+                return Err(ApiError::unauthenticated(
+                    "This account signs in with an identity provider",
+                ));
             }
 
-            let password = auth_request.password.clone().unwrap();
-            let mut doc = Document::new();
-            doc.insert("email", email);
-
-            #[allow(deprecated)]
-            match User::query(doc, &db.database(DB_NAME)).await {
-                Ok(mut result) => match result.try_next().await {
-                    Ok(user) => {
-                        if user.is_none() {
-                            return Err(Json(ApiError::AuthError("No such user")));
-                        }
-                        let user = user.unwrap();
-
-                        let argon = Argon2::default();
-                        let hash = PasswordHash::new(&user.password_hash).unwrap();
-                        match argon.verify_password(password.as_bytes(), &hash) {
-                            Ok(_) => {
-                                let mut rng = rand::rngs::StdRng::from_entropy();
-                                let mut cookie = vec![0u8; 32];
-                                rng.fill_bytes(cookie.as_mut());
-                                let cookie = AuthCookie::new(
-                                    &cookie,
-                                    user.id.expect("Couldn't fetch user id from database"),
-                                );
-
-                                if let Err(err) = cookie.insert(&db.database(DB_NAME)).await {
-                                    log::error!("Couldn't write cookie to database: {err:?}");
-                                    return Err(Json(ApiError::ServerError(
-                                        "Couldn't communicate with the database",
-                                    )));
-                                }
-
-                                Ok(AuthResponder {
-                                    inner: Json(
-                                        JWTAuthToken::new(auth_request.auth_type, Some(&user))
-                                            .await
-                                            .unwrap(),
-                                    ),
-                                    header: rocket::http::Header::new(
-                                        "Set-Cookie",
-                                        format!("cookie={cookie};expires=0;path=/;SameSite=Strict"),
-                                    ),
-                                })
-                            }
-                            Err(err) => {
-                                info!("Password hash incorrect, rejecting user login: {err:?}");
-                                Err(Json(ApiError::AuthError("Incorrect password")))
-                            }
-                        }
-                    }
-                    Err(err) => {
-                        error!("Couldn't fetch user from result: {err:?}");
-                        Err(Json(ApiError::ServerError("Couldn't resolve user")))
-                    }
-                },
+            let hash = PasswordHash::new(&user.password_hash).map_err(|err| {
+                error!("Stored password hash is invalid: {err:?}");
+                ApiError::internal("Couldn't verify password")
+            })?;
+            match Argon2::default().verify_password(password.as_bytes(), &hash) {
+                Ok(_) => {
+                    limiter.login_succeeded(&user.email).await;
+                    start_session(&store, &user, keys, client).await
+                }
                 Err(err) => {
-                    error!("Couldn't query database: {err:?}");
-                    Err(Json(ApiError::ServerError("Couldn't query database")))
+                    info!("Password hash incorrect, rejecting user login: {err:?}");
+                    limiter.login_failed(&user.email).await;
+                    Err(ApiError::unauthenticated("Incorrect password"))
                 }
             }
         }
         AuthType::Cookie => {
-            let db = state.inner().as_ref().unwrap();
-            if let Some(cookie) = cookies.get("cookie").map(Cookie::value) {
-                log::info!("User trying to authenticate with cookie ({cookie})");
-                #[allow(deprecated)]
-                match AuthCookie::query(
-                    bson::doc! {
-                        "cookie": cookie
-                    },
-                    &db.database(DB_NAME),
-                )
-                .await
+            let store = match db {
+                Some(client) => MongoStore::new(client, DB_NAME),
+                None => return Err(ApiError::database_unavailable()),
+            };
+            let cookie = match cookies.get("cookie").map(Cookie::value) {
+                Some(cookie) => cookie,
+                None => {
+                    return Err(ApiError::unauthenticated(
+                        "Cookie auth type selected, but not cookie is present",
+                    ))
+                }
+            };
+            log::info!("User trying to authenticate with cookie");
+            let auth_cookies = store.repository::<AuthCookie>();
+            let db_cookie = match auth_cookies
+                .find_one(bson::doc! { "cookie": AuthCookie::hash(cookie) }, None)
+                .await?
+            {
+                Some(db_cookie) => db_cookie,
+                None => {
+                    return Err(ApiError::unauthenticated(
+                        "Couldn't find the cookie in the database",
+                    ))
+                }
+            };
+            let now = now();
+            if now > db_cookie.exp {
+                return Err(ApiError::unauthenticated("Expired cookie"));
+            }
+
+            let mut last_used = bson::doc! { "last_used": now as i64 };
+            if let Some(ip) = client.ip {
+                last_used.insert("ip", ip.to_string());
+            }
+            if let Some(id) = db_cookie.id {
+                if let Err(err) = auth_cookies
+                    .update_by_id(id, bson::doc! { "$set": last_used })
+                    .await
                 {
-                    Ok(mut cursor) => {
-                        if let Ok(true) = cursor.advance().await {
-                            let db_cookie = cursor.deserialize_current().unwrap();
-                            if SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .unwrap()
-                                .as_secs()
-                                > db_cookie.exp
-                            {
-                                return Err(Json(ApiError::AuthError("Expired cookie")));
-                            }
+                    log::warn!("Couldn't record cookie use: {err:?}");
+                }
+            }
 
-                            let mut cursor = User::query(
-                                bson::doc! {
-                                    "_id": db_cookie.user
-                                },
-                                &db.database(DB_NAME),
-                            )
-                            .await
-                            .expect("Couldn't query db to find user associated with cookie");
-
-                            let user = if let Ok(true) = cursor.advance().await {
-                                cursor.deserialize_current().unwrap()
-                            } else {
-                                return Err(Json(ApiError::AuthError(
-                                    "Couldn't find user associated with cookie",
-                                )));
-                            };
-
-                            return Ok(AuthResponder {
-                                inner: Json(
-                                    JWTAuthToken::new(AuthType::User, Some(&user))
-                                        .await
-                                        .expect("Couldn't generate JWT"),
-                                ),
-                                header: rocket::http::Header::new("X-User-Auth", "yes"),
-                            });
-                        } else {
-                            return Err(Json(ApiError::AuthError(
-                                "Couldn't find the cookie in the database",
-                            )));
-                        }
-                    }
-                    Err(err) => {
-                        log::error!("Couldn't fetch cookie from database: {err:?}");
-                        return Err(Json(ApiError::AuthError(
-                            "Couldn't communicate with database",
-                        )));
-                    }
+            let user = match store
+                .repository::<User>()
+                .find_by_id(db_cookie.user)
+                .await?
+            {
+                Some(user) => user,
+                None => {
+                    return Err(ApiError::unauthenticated(
+                        "Couldn't find user associated with cookie",
+                    ))
+                }
+            };
+            match JWTAuthToken::new(keys, AuthType::User, Some(&user), db_cookie.id).await {
+                Ok(token) => Ok(AuthResponder {
+                    inner: Json(token),
+                    header: rocket::http::Header::new("X-User-Auth", "yes"),
+                }),
+                Err(err) => {
+                    log::error!("Couldn't sign a token: {err:?}");
+                    Err(ApiError::internal("Couldn't sign a token"))
                 }
-            } else {
-                Err(Json(ApiError::AuthError(
-                    "Cookie auth type selected, but not cookie is present",
-                )))
             }
         }
+        AuthType::Oidc => {
+            let store = match db {
+                Some(client) => MongoStore::new(client, DB_NAME),
+                None => return Err(ApiError::database_unavailable()),
+            };
+
+            let (provider, id_token) = match (&auth_request.provider, &auth_request.id_token) {
+                (Some(provider), Some(id_token)) => (provider, id_token),
+                _ => {
+                    return Err(ApiError::unauthenticated(
+                        "OIDC login requires a provider and an ID token",
+                    ))
+                }
+            };
+
+            let provider = match oidc_providers.find(provider) {
+                Some(provider) => provider,
+                None => return Err(ApiError::unauthenticated("Unknown identity provider")),
+            };
+
+            let claims = provider.verify_id_token(id_token).await?;
+            let email = claims.email.clone().unwrap();
+
+            // Accounts are linked by email, so a user who registered with a
+            // password can also sign in through their provider
+            let users = store.repository::<User>();
+            let user = match users.find_one(bson::doc! { "email": &email }, None).await? {
+                Some(user) => user,
+                None => {
+                    info!("Creating user for first OIDC login of {email}");
+                    let mut user = User::from(claims);
+                    if let Err(err) = users.insert(&mut user).await {
+                        error!("Couldn't insert OIDC user: {err:?}");
+                        return Err(ApiError::internal("Couldn't create user"));
+                    }
+                    user
+                }
+            };
+
+            start_session(&store, &user, keys, client).await
+        }
     }
 }
 
+/// Records a new login cookie for `user` and hands back their JWT
+async fn start_session<S: Store>(
+    store: &S,
+    user: &User,
+    keys: &JwtKeys,
+    client: &ClientInfo,
+) -> Result<AuthResponder, ApiError> {
+    let mut rng = rand::rngs::StdRng::from_entropy();
+    let mut cookie = vec![0u8; 32];
+    rng.fill_bytes(cookie.as_mut());
+    let cookie = hex::encode(cookie);
+    let mut db_cookie = AuthCookie::new(&cookie, user.stored_id()?, client);
+
+    let session = match store
+        .repository::<AuthCookie>()
+        .insert(&mut db_cookie)
+        .await
+    {
+        Ok(session) => session,
+        Err(err) => {
+            log::error!("Couldn't write cookie to database: {err:?}");
+            return Err(ApiError::internal("Couldn't communicate with the database"));
+        }
+    };
+
+    let token = match JWTAuthToken::new(keys, AuthType::User, Some(user), Some(session)).await {
+        Ok(token) => token,
+        Err(err) => {
+            log::error!("Couldn't sign a token: {err:?}");
+            return Err(ApiError::internal("Couldn't sign a token"));
+        }
+    };
+    Ok(AuthResponder {
+        inner: Json(token),
+        header: rocket::http::Header::new(
+            "Set-Cookie",
+            format!("cookie={cookie};expires=0;path=/;SameSite=Strict"),
+        ),
+    })
+}
+
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AuthClaims {
     pub auth_type: AuthType,
     pub email: Option<String>,
     pub exp: u64,
+    /// The login session the token was issued for, so revoking the session
+    /// stops the token too. Tokens issued before sessions were tracked have
+    /// none and last until they expire.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, ToSchema)]
@@ -193,6 +295,7 @@ pub enum AuthType {
     User,
     Anonymous,
     Cookie,
+    Oidc,
 }
 
 #[derive(Debug, Deserialize, Serialize, ToSchema)]
@@ -201,13 +304,19 @@ pub enum AuthType {
     AuthRequest {
         auth_type: AuthType::Anonymous,
         email: None,
-        password: None
+        password: None,
+        provider: None,
+        id_token: None
     }
 })]
 pub struct AuthRequest {
     pub auth_type: AuthType,
     pub email: Option<String>,
     pub password: Option<String>,
+    /// Name of a configured identity provider, only used with `AuthType::Oidc`
+    pub provider: Option<String>,
+    /// ID token issued by `provider`, only used with `AuthType::Oidc`
+    pub id_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
@@ -221,45 +330,37 @@ pub struct JWTAuthToken {
 
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for JWTAuthToken {
-    type Error = ApiError<'static>;
+    type Error = ApiError;
 
     async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
-        use rocket::http::Status;
         let auth_header = request.headers().iter().find(|h| h.name == "Authorization");
 
         if auth_header.is_none() {
-            return Outcome::Failure((
-                Status::Unauthorized,
-                ApiError::AuthError("No authorization header"),
-            ));
+            return ApiError::unauthenticated("No authorization header").fail(request);
         }
 
         let auth_header = auth_header.unwrap();
         let auth_header = auth_header.value.trim().split(" ").collect::<Vec<_>>();
 
         if auth_header.len() != 2 {
-            Outcome::Failure((
-                Status::Unauthorized,
-                ApiError::AuthError("Unable to recover JWT from headers"),
-            ))
+            ApiError::unauthenticated("Unable to recover JWT from headers").fail(request)
         } else {
-            let public_key = match tokio::fs::read_to_string("publicKey.pem").await {
-                Ok(pk) => match DecodingKey::from_rsa_pem(pk.as_bytes()) {
-                    Ok(pk) => pk,
-                    Err(err) => {
-                        log::error!("Couldn't decode public key: {err:?}");
-                        return Outcome::Failure((
-                            Status::InternalServerError,
-                            ApiError::ServerError("We couldn't decode your auth token"),
-                        ));
+            let kid = match jsonwebtoken::decode_header(auth_header[1]) {
+                Ok(header) => header.kid,
+                Err(_) => return ApiError::unauthenticated("Invalid token").fail(request),
+            };
+
+            let public_key = match request.rocket().state::<JwtKeys>() {
+                Some(keys) => match keys.decoding_key(kid.as_deref()).await {
+                    Some(key) => key,
+                    None => {
+                        return ApiError::unauthenticated("Token was signed with an unknown key")
+                            .fail(request)
                     }
                 },
-                Err(err) => {
-                    log::error!("Couldn't read public key for JWT decoding: {err:?}");
-                    return Outcome::Failure((
-                        Status::InternalServerError,
-                        ApiError::ServerError("We couldn't decode your auth token"),
-                    ));
+                None => {
+                    log::error!("No JWT keys are being managed by this rocket instance");
+                    return ApiError::internal("We couldn't decode your auth token").fail(request);
                 }
             };
 
@@ -270,49 +371,53 @@ impl<'r> FromRequest<'r> for JWTAuthToken {
                     AuthClaims {
                         auth_type: AuthType::User,
                         email,
+                        sid,
                         ..
                     } => {
                         if email.is_none() {
                             error!("Received a user auth claim with no attached email. Something is wrong!");
-                            return Outcome::Failure((
-                                Status::Unauthorized,
-                                ApiError::AuthError("Invalid token").into(),
-                            ));
+                            return ApiError::unauthenticated("Invalid token").fail(request);
                         }
 
-                        if let Some(client) = request.rocket().state::<Option<mongodb::Client>>() {
-                            let mut doc = Document::new();
-                            doc.insert("email", email.unwrap());
-                            let user = if let Some(user) = User::find_one(
-                                doc,
-                                &client
-                                    .as_ref()
-                                    .expect("Couldn't connect to database")
-                                    .database(DB_NAME),
-                                None,
-                            )
-                            .await
-                            {
-                                user
-                            } else {
-                                return Outcome::Failure((
-                                    Status::Unauthorized,
-                                    ApiError::AuthError("Couldn't find the requested user"),
-                                ));
-                            };
-
-                            Outcome::Success(JWTAuthToken {
-                                token: auth_header[1].to_string(),
-                                email: Some(user.email),
-                                first_name: Some(user.first_name),
-                                last_name: Some(user.last_name),
-                            })
-                        } else {
-                            Outcome::Failure((
-                                Status::InternalServerError,
-                                ApiError::ServerError("Unable to communicate with database"),
-                            ))
+                        let db = match Db::from_request(request).await {
+                            Outcome::Success(db) => db,
+                            Outcome::Failure(failure) => return Outcome::Failure(failure),
+                            Outcome::Forward(forward) => return Outcome::Forward(forward),
+                        };
+                        let store = MongoStore::new(&db.0, DB_NAME);
+                        if let Some(sid) = sid {
+                            match live_session(&store, &sid).await {
+                                Ok(true) => {}
+                                Ok(false) => {
+                                    return ApiError::unauthenticated(
+                                        "This session has been signed out",
+                                    )
+                                    .fail(request)
+                                }
+                                Err(err) => return err.fail(request),
+                            }
                         }
+                        let user = match store
+                            .repository::<User>()
+                            .find_one(bson::doc! { "email": email.unwrap() }, None)
+                            .await
+                        {
+                            Ok(Some(user)) => user,
+                            Ok(None) => {
+                                return ApiError::unauthenticated(
+                                    "Couldn't find the requested user",
+                                )
+                                .fail(request)
+                            }
+                            Err(err) => return ApiError::from(err).fail(request),
+                        };
+
+                        Outcome::Success(JWTAuthToken {
+                            token: auth_header[1].to_string(),
+                            email: Some(user.email),
+                            first_name: Some(user.first_name),
+                            last_name: Some(user.last_name),
+                        })
                     }
                     AuthClaims {
                         auth_type: AuthType::Anonymous,
@@ -323,47 +428,63 @@ impl<'r> FromRequest<'r> for JWTAuthToken {
                         first_name: None,
                         last_name: None,
                     }),
-                    _ => Outcome::Failure((
-                        Status::Unauthorized,
-                        ApiError::AuthError("Invalid token"),
-                    )),
+                    _ => ApiError::unauthenticated("Invalid token").fail(request),
                 },
-                Err(_) => {
-                    Outcome::Failure((Status::Unauthorized, ApiError::AuthError("Invalid token")))
-                }
+                Err(_) => ApiError::unauthenticated("Invalid token").fail(request),
             }
         }
     }
 }
 
+/// A signed in user whose email is listed in the comma separated
+/// `ADMIN_EMAILS` environment variable
+pub struct AdminToken(pub JWTAuthToken);
+
+impl AdminToken {
+    pub fn email(&self) -> &str {
+        self.0.email.as_deref().unwrap()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = match JWTAuthToken::from_request(request).await {
+            Outcome::Success(token) => token,
+            Outcome::Failure(failure) => return Outcome::Failure(failure),
+            Outcome::Forward(forward) => return Outcome::Forward(forward),
+        };
+
+        let admins = std::env::var("ADMIN_EMAILS").unwrap_or_default();
+        let is_admin = token.email.as_ref().is_some_and(|email| {
+            admins
+                .split(',')
+                .any(|admin| admin.trim().eq_ignore_ascii_case(email))
+        });
+
+        if is_admin {
+            Outcome::Success(AdminToken(token))
+        } else {
+            ApiError::forbidden("Admin access required").fail(request)
+        }
+    }
+}
+
 #[derive(Responder)]
 pub struct AuthResponder {
     pub inner: Json<JWTAuthToken>,
     pub header: rocket::http::Header<'static>,
 }
 
-async fn read_private_key(path: &str) -> Result<String, tokio::io::Error> {
-    let mut file = tokio::fs::File::open(path).await?;
-    let mut buff = String::new();
-    file.read_to_string(&mut buff).await?;
-    Ok(buff)
-}
-
 impl JWTAuthToken {
     pub async fn new(
+        keys: &JwtKeys,
         auth_type: AuthType,
         user: Option<&User>,
+        session: Option<ObjectId>,
     ) -> Result<Self, jsonwebtoken::errors::Error> {
-        let header = Header::new(Algorithm::RS256);
-
-        let private_key = match read_private_key("privateKey.pem").await {
-            Ok(key) => key,
-            Err(err) => {
-                warn!("Couldn't read private key file: {err:?}");
-                std::env::var("JWT_PRIVATE_KEY").expect("Couldn't find a private key anywhere")
-            }
-        };
-
         let claims = AuthClaims {
             auth_type,
             email: user.map(|user| user.email.clone()),
@@ -371,7 +492,8 @@ impl JWTAuthToken {
                 .duration_since(UNIX_EPOCH)
                 .expect("Couldn't get system time")
                 .as_secs()
-                + 3600 * 6,
+                + TOKEN_LIFETIME_SECS,
+            sid: session.map(|session| session.to_hex()),
         };
 
         log::info!(
@@ -384,12 +506,7 @@ impl JWTAuthToken {
             claims.exp.clone()
         );
 
-        let token = jsonwebtoken::encode(
-            &header,
-            &claims,
-            &EncodingKey::from_rsa_pem(private_key.as_bytes())
-                .expect("Expected valid encoding key"),
-        )?;
+        let token = keys.sign(&claims).await?;
 
         Ok(Self {
             token,
@@ -400,34 +517,263 @@ impl JWTAuthToken {
     }
 }
 
+/// How long a login cookie stays valid
+pub(crate) const COOKIE_LIFETIME_SECS: u64 = 3600 * 24 * 7;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+
 #[derive(Debug, Clone, Serialize, Deserialize, Entity)]
 #[collection_name = "cookies"]
 pub struct AuthCookie {
     #[serde(rename = "_id")]
     #[serde(skip_serializing_if = "Option::is_none")]
     id: Option<ObjectId>,
+    /// SHA-256 of the cookie we handed out, so a database leak can't be
+    /// used to sign in
+    #[index(unique)]
     cookie: String,
+    #[index]
     pub user: ObjectId,
+    #[index]
     pub exp: u64,
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub last_used: u64,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub user_agent: Option<String>,
 }
 
 impl AuthCookie {
-    pub fn new(cookie: &impl AsRef<[u8]>, user: ObjectId) -> Self {
+    pub fn new(cookie: &str, user: ObjectId, client: &ClientInfo) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
         Self {
             id: None,
-            cookie: hex::encode(cookie.as_ref()),
+            cookie: Self::hash(cookie),
             user,
-            exp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
-                + 3600 * 24 * 7,
+            exp: now + COOKIE_LIFETIME_SECS,
+            created_at: now,
+            last_used: now,
+            ip: client.ip.map(|ip| ip.to_string()),
+            user_agent: client.user_agent.clone(),
+        }
+    }
+
+    pub fn hash(cookie: &str) -> String {
+        hex::encode(Sha256::digest(cookie.as_bytes()))
+    }
+}
+
+/// What we tell users about one of their login cookies, never the cookie itself
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+    pub id: Option<String>,
+    pub created_at: u64,
+    pub last_used: u64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub expires: u64,
+    /// Whether this is the session the request was made from
+    pub current: bool,
+}
+
+impl From<&AuthCookie> for Session {
+    fn from(value: &AuthCookie) -> Self {
+        Self {
+            id: value.id.map(|id| id.to_hex()),
+            created_at: value.created_at,
+            last_used: value.last_used,
+            ip: value.ip.clone(),
+            user_agent: value.user_agent.clone(),
+            expires: value.exp,
+            current: false,
+        }
+    }
+}
+
+/// Where a request came from, recorded against new sessions
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientInfo {
+    type Error = ();
+
+    async fn from_request(request: &'r rocket::Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ClientInfo {
+            ip: request.client_ip(),
+            user_agent: request.headers().get_one("User-Agent").map(str::to_string),
+        })
+    }
+}
+
+#[utoipa::path(get, tag = "Authenticate", path = "/api/user/sessions", security(("jwt" = [])), responses(
+    (status = 200, description = "The signed in user's active login cookies", body = [Session])
+))]
+#[get("/user/sessions")]
+pub async fn get_sessions(
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+    cookies: &CookieJar<'_>,
+) -> ApiResponse<Vec<Session>> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token)
+        .await
+        .and_then(|user| user.stored_id())
+    {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    let current = cookies.get("cookie").map(|cookie| cookie.value());
+    match active_sessions(&store, user, current).await {
+        Ok(sessions) => ApiResponse::Ok(sessions),
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(delete, tag = "Authenticate", path = "/api/user/sessions/{id}", params(("id",)), security(("jwt" = [])))]
+#[delete("/user/sessions/<id>")]
+pub async fn revoke_session(
+    id: &str,
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token)
+        .await
+        .and_then(|user| user.stored_id())
+    {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+    let id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return ApiError::bad_request("Invalid session id").into(),
+    };
+
+    match revoke(&store, user, id).await {
+        Ok(()) => ApiResponse::Ok("Session revoked"),
+        Err(err) => err.into(),
+    }
+}
+
+/// `user`'s unexpired login cookies, marking the one matching `current`
+pub async fn active_sessions<S: Store>(
+    store: &S,
+    user: ObjectId,
+    current: Option<&str>,
+) -> Result<Vec<Session>, ApiError> {
+    let current = current.map(AuthCookie::hash);
+    match store
+        .repository::<AuthCookie>()
+        .find(
+            bson::doc! { "user": user, "exp": { "$gt": now() as i64 } },
+            PageRequest::all(),
+        )
+        .await
+    {
+        Ok(sessions) => Ok(sessions
+            .items
+            .iter()
+            .map(|cookie| Session {
+                current: current.as_ref() == Some(&cookie.cookie),
+                ..Session::from(cookie)
+            })
+            .collect()),
+        Err(err) => {
+            error!("Couldn't fetch sessions: {err:?}");
+            Err(ApiError::internal("Couldn't fetch your sessions"))
         }
     }
 }
 
-impl std::fmt::Display for AuthCookie {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(self.cookie.as_ref())
+/// Deletes the login cookie `id` if it belongs to `user`
+pub async fn revoke<S: Store>(store: &S, user: ObjectId, id: ObjectId) -> Result<(), ApiError> {
+    // Matching on the user too, so nobody can revoke someone else's session
+    match store
+        .repository::<AuthCookie>()
+        .delete_many(bson::doc! { "_id": id, "user": user })
+        .await
+    {
+        Ok(1) => Ok(()),
+        Ok(_) => Err(ApiError::not_found("No such session")),
+        Err(err) => {
+            error!("Couldn't revoke session: {err:?}");
+            Err(ApiError::internal("Couldn't revoke session"))
+        }
+    }
+}
+
+/// Whether the session a token names is still signed in
+pub async fn live_session<S: Store>(store: &S, sid: &str) -> Result<bool, ApiError> {
+    let id = match ObjectId::parse_str(sid) {
+        Ok(id) => id,
+        Err(_) => return Ok(false),
+    };
+    let filter = bson::doc! { "_id": id, "exp": { "$gt": now() as i64 } };
+    Ok(store.repository::<AuthCookie>().count(filter).await? > 0)
+}
+
+/// Deletes login cookies that expired before `now`, returning how many
+pub async fn sweep_cookies<S: Store>(store: &S, now: u64) -> RepoResult<u64> {
+    store
+        .repository::<AuthCookie>()
+        .delete_many(bson::doc! { "exp": { "$lt": now as i64 } })
+        .await
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Deletes expired login cookies every hour
+pub struct SessionSweeper;
+
+#[rocket::async_trait]
+impl Fairing for SessionSweeper {
+    fn info(&self) -> Info {
+        Info {
+            name: "Session Sweeper",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let store = match rocket.state::<Option<Client>>().unwrap() {
+            Some(client) => MongoStore::new(client, DB_NAME),
+            None => return,
+        };
+
+        let scheduler = rocket.state::<Scheduler>().unwrap();
+
+        let job = Job::new("session sweep", Schedule::every(SWEEP_INTERVAL));
+        scheduler.spawn(job, rocket.shutdown(), move || {
+            let store = store.clone();
+            async move {
+                match sweep_cookies(&store, now()).await {
+                    Ok(0) => {}
+                    Ok(swept) => info!("Swept {swept} expired login cookies"),
+                    Err(err) => return Err(format!("Couldn't sweep expired login cookies: {err}")),
+                }
+                Ok(())
+            }
+        });
     }
 }
@@ -0,0 +1,315 @@
+use crate::{
+    api::{ApiError, ApiResponse},
+    auth::AdminToken,
+    loadshedding::{
+        DBFunctions, DBFunctionsTrait, GroupEntity, LoadSheddingStage, SuburbEntity,
+        TimeScheduleEntity,
+    },
+    reporting::now_millis,
+    repository::{MemoryStore, Model, PageRequest, RepoError, RepoResult, Repository, Store},
+};
+use async_trait::async_trait;
+use bson::{doc, Document};
+use mongodb::{options::FindOptions, Database};
+use rocket::{get, State};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use utoipa::ToSchema;
+
+/// How long a cached collection is trusted without being invalidated, in
+/// milliseconds. Other instances write too, and we only hear about our own
+/// writes.
+const SCHEDULE_TTL: u64 = 60 * 60 * 1000;
+const STAGE_LOG_TTL: u64 = 10 * 60 * 1000;
+
+fn ttl(collection: &str) -> u64 {
+    if collection == LoadSheddingStage::COLLECTION {
+        STAGE_LOG_TTL
+    } else {
+        SCHEDULE_TTL
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CacheStats {
+    pub collection: String,
+    pub hits: u64,
+    pub misses: u64,
+    pub invalidations: u64,
+}
+
+struct Inner {
+    source: Box<dyn DBFunctionsTrait + Send>,
+    store: MemoryStore,
+    /// When each collection was last read from the source
+    loaded: Mutex<HashMap<&'static str, u64>>,
+    /// Bumped by every invalidation, so a load that raced one isn't trusted
+    generation: AtomicU64,
+    /// Only one collection is read at a time, so concurrent misses wait for
+    /// the first instead of all going to the source
+    loading: tokio::sync::Mutex<()>,
+    stats: Mutex<HashMap<&'static str, CacheStats>>,
+    /// Woken by invalidations, for whatever is derived from the cached data
+    changed: tokio::sync::Notify,
+}
+
+/// Schedules, groups, suburbs and the stage timeline kept in memory in
+/// front of a [`DBFunctionsTrait`]. Each collection is read whole on first
+/// use and queries are answered from the copy until it is invalidated or
+/// expires.
+#[derive(Clone)]
+pub struct ScheduleCache(Arc<Inner>);
+
+impl Default for ScheduleCache {
+    fn default() -> Self {
+        Self::new(DBFunctions {})
+    }
+}
+
+impl fmt::Debug for ScheduleCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ScheduleCache").field(&self.stats()).finish()
+    }
+}
+
+impl ScheduleCache {
+    pub fn new(source: impl DBFunctionsTrait + Send + 'static) -> Self {
+        Self(Arc::new(Inner {
+            source: Box::new(source),
+            store: MemoryStore::default(),
+            loaded: Mutex::default(),
+            generation: AtomicU64::default(),
+            loading: tokio::sync::Mutex::default(),
+            stats: Mutex::default(),
+            changed: tokio::sync::Notify::new(),
+        }))
+    }
+
+    /// Forgets `collection`, the next query reads it again
+    pub fn invalidate(&self, collection: &'static str) {
+        self.0.generation.fetch_add(1, Ordering::SeqCst);
+        if self.0.loaded.lock().unwrap().remove(collection).is_some() {
+            self.record(collection, |stats| stats.invalidations += 1);
+        }
+        self.0.changed.notify_one();
+    }
+
+    /// Resolves once anything has been invalidated since the last call
+    pub async fn changed(&self) {
+        self.0.changed.notified().await
+    }
+
+    /// Forgets the schedule data, after new municipalities go live
+    pub fn invalidate_schedules(&self) {
+        self.invalidate(TimeScheduleEntity::COLLECTION);
+        self.invalidate(GroupEntity::COLLECTION);
+        self.invalidate(SuburbEntity::COLLECTION);
+    }
+
+    pub fn stats(&self) -> Vec<CacheStats> {
+        let mut stats: Vec<CacheStats> = self.0.stats.lock().unwrap().values().cloned().collect();
+        stats.sort_by(|a, b| a.collection.cmp(&b.collection));
+        stats
+    }
+
+    fn record(&self, collection: &'static str, f: impl FnOnce(&mut CacheStats)) {
+        let mut stats = self.0.stats.lock().unwrap();
+        let entry = stats.entry(collection).or_insert_with(|| CacheStats {
+            collection: collection.to_string(),
+            ..Default::default()
+        });
+        f(entry);
+    }
+
+    fn fresh(&self, collection: &'static str) -> bool {
+        self.0
+            .loaded
+            .lock()
+            .unwrap()
+            .get(collection)
+            .is_some_and(|loaded| now_millis().saturating_sub(*loaded) < ttl(collection))
+    }
+
+    /// Reads all of `T` with `load` unless the copy we have is fresh
+    async fn ensure<T: Model>(
+        &self,
+        load: impl Future<Output = Result<Vec<T>, ApiError>>,
+    ) -> Result<(), ApiError> {
+        if !self.fresh(T::COLLECTION) {
+            let _loading = self.0.loading.lock().await;
+            if !self.fresh(T::COLLECTION) {
+                self.record(T::COLLECTION, |stats| stats.misses += 1);
+                let generation = self.0.generation.load(Ordering::SeqCst);
+                let documents = load
+                    .await?
+                    .iter()
+                    .map(bson::to_document)
+                    .collect::<Result<Vec<Document>, _>>()
+                    .map_err(RepoError::from)?;
+                self.0.store.load(T::COLLECTION, documents);
+                if self.0.generation.load(Ordering::SeqCst) == generation {
+                    self.0
+                        .loaded
+                        .lock()
+                        .unwrap()
+                        .insert(T::COLLECTION, now_millis());
+                }
+                return Ok(());
+            }
+        }
+        self.record(T::COLLECTION, |stats| stats.hits += 1);
+        Ok(())
+    }
+
+    async fn select<T: Model>(
+        &self,
+        query: &Document,
+        options: Option<FindOptions>,
+    ) -> RepoResult<Vec<T>> {
+        let page = options.map(PageRequest::from).unwrap_or_default();
+        Ok(self
+            .0
+            .store
+            .repository::<T>()
+            .find(query.clone(), page)
+            .await?
+            .items)
+    }
+}
+
+/// Queries the cache can't answer go to the source instead
+fn uncached<T>(result: RepoResult<T>) -> Result<Option<T>, ApiError> {
+    match result {
+        Ok(items) => Ok(Some(items)),
+        Err(RepoError::Unsupported(what)) => {
+            log::debug!("Schedule cache can't answer a query, passing it on: {what}");
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Takes just the first of what `options` would find
+fn first(options: &Option<FindOptions>) -> FindOptions {
+    FindOptions::builder()
+        .sort(options.as_ref().and_then(|options| options.sort.clone()))
+        .limit(1)
+        .build()
+}
+
+#[async_trait]
+impl DBFunctionsTrait for ScheduleCache {
+    async fn collect_schedules<'a>(
+        &self,
+        query: Document,
+        connection: Option<&'a Database>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<TimeScheduleEntity>, ApiError> {
+        let source = &self.0.source;
+        self.ensure(source.collect_schedules(doc! {}, connection, None))
+            .await?;
+        match uncached(self.select(&query, options.clone()).await)? {
+            Some(schedules) => Ok(schedules),
+            None => source.collect_schedules(query, connection, options).await,
+        }
+    }
+
+    async fn collect_groups<'a>(
+        &self,
+        query: Document,
+        connection: Option<&'a Database>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<GroupEntity>, ApiError> {
+        let source = &self.0.source;
+        self.ensure(source.collect_groups(doc! {}, connection, None))
+            .await?;
+        match uncached(self.select(&query, options.clone()).await)? {
+            Some(groups) => Ok(groups),
+            None => source.collect_groups(query, connection, options).await,
+        }
+    }
+
+    async fn collect_suburbs<'a>(
+        &self,
+        query: Document,
+        connection: Option<&'a Database>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<SuburbEntity>, ApiError> {
+        let source = &self.0.source;
+        self.ensure(source.collect_suburbs(doc! {}, connection, None))
+            .await?;
+        match uncached(self.select(&query, options.clone()).await)? {
+            Some(suburbs) => Ok(suburbs),
+            None => source.collect_suburbs(query, connection, options).await,
+        }
+    }
+
+    // Nothing found in the cache is checked against the source, which also
+    // reports it missing the usual way
+    async fn collect_one_group<'a>(
+        &self,
+        query: Document,
+        connection: Option<&'a Database>,
+        options: Option<FindOptions>,
+    ) -> Result<GroupEntity, ApiError> {
+        let source = &self.0.source;
+        self.ensure(source.collect_groups(doc! {}, connection, None))
+            .await?;
+        match uncached(self.select(&query, Some(first(&options))).await)? {
+            Some(groups) if !groups.is_empty() => Ok(groups.into_iter().next().unwrap()),
+            _ => source.collect_one_group(query, connection, options).await,
+        }
+    }
+
+    async fn collect_stage_logs<'a>(
+        &self,
+        query: Document,
+        connection: Option<&'a Database>,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<LoadSheddingStage>, ApiError> {
+        let source = &self.0.source;
+        self.ensure(source.collect_stage_logs(doc! {}, connection, None))
+            .await?;
+        match uncached(self.select(&query, options.clone()).await)? {
+            Some(stages) => Ok(stages),
+            None => source.collect_stage_logs(query, connection, options).await,
+        }
+    }
+
+    async fn collect_one_stage_log<'a>(
+        &self,
+        query: Document,
+        connection: Option<&'a Database>,
+        options: Option<FindOptions>,
+    ) -> Result<LoadSheddingStage, ApiError> {
+        let source = &self.0.source;
+        self.ensure(source.collect_stage_logs(doc! {}, connection, None))
+            .await?;
+        match uncached(self.select(&query, Some(first(&options))).await)? {
+            Some(stages) if !stages.is_empty() => Ok(stages.into_iter().next().unwrap()),
+            _ => {
+                source
+                    .collect_one_stage_log(query, connection, options)
+                    .await
+            }
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/api/admin/scheduleCache", security(("jwt" = [])), responses(
+    (status = 200, description = "Hits, misses and invalidations per cached collection", body = [CacheStats])
+))]
+#[get("/admin/scheduleCache")]
+pub async fn get_cache_stats(
+    _admin: AdminToken,
+    cache: &State<ScheduleCache>,
+) -> ApiResponse<Vec<CacheStats>> {
+    ApiResponse::Ok(cache.stats())
+}
@@ -0,0 +1,362 @@
+use crate::{
+    api::{ApiError, ApiResponse},
+    auth::AdminToken,
+    db::Entity,
+    repository::{MongoStore, RepoError, RepoResult, Repository, Store},
+    DB_NAME,
+};
+use bson::{oid::ObjectId, Document};
+use macros::Entity;
+use mongodb::{Client, Database};
+use rocket::{delete, get, patch, post, serde::json::Json, State};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// The category the unscheduled outage detector looks at
+pub const POWER_OUTAGE: &str = "PowerOutage";
+/// A week
+const MAX_EXPIRY_MINUTES: u32 = 60 * 24 * 7;
+
+#[utoipa::path(get, path = "/api/reports/categories", responses(
+    (status = 200, description = "Categories reports can be made in", body = [ReportCategory])
+))]
+#[get("/reports/categories")]
+pub async fn get_categories(state: &State<Option<Client>>) -> ApiResponse<Vec<ReportCategory>> {
+    let db = match state.inner() {
+        Some(client) => client.database(DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    list_categories(bson::doc! { "retired": { "$ne": true } }, &db).await
+}
+
+#[utoipa::path(get, path = "/api/admin/reportCategories", security(("jwt" = [])), responses(
+    (status = 200, description = "Every category, retired ones included", body = [ReportCategory])
+))]
+#[get("/admin/reportCategories")]
+pub async fn admin_get_categories(
+    _admin: AdminToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<Vec<ReportCategory>> {
+    let db = match state.inner() {
+        Some(client) => client.database(DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    list_categories(bson::doc! {}, &db).await
+}
+
+async fn list_categories(filter: Document, db: &Database) -> ApiResponse<Vec<ReportCategory>> {
+    match ReportCategory::find(filter, db, None).await {
+        Ok(categories) => {
+            let mut categories = categories.into_iter().map(|x| *x).collect::<Vec<_>>();
+            sort_categories(&mut categories);
+            ApiResponse::Ok(categories)
+        }
+        Err(err) => {
+            log::error!("Couldn't fetch report categories: {err:?}");
+            ApiError::internal("Couldn't fetch report categories").into()
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/api/admin/reportCategories", request_body = NewReportCategory, security(("jwt" = [])))]
+#[post(
+    "/admin/reportCategories",
+    format = "application/json",
+    data = "<category>"
+)]
+pub async fn create_category(
+    category: Json<NewReportCategory>,
+    admin: AdminToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<ReportCategory> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let category = category.into_inner();
+    if category.key.is_empty()
+        || category.key.len() > 40
+        || !category.key.chars().all(|c| c.is_ascii_alphanumeric())
+    {
+        return ApiError::invalid("key", "Category keys have to be 1 to 40 letters and digits")
+            .into();
+    }
+    if let Err(err) = validate(&category.label, category.expiry_minutes) {
+        return err.into();
+    }
+
+    let mut category = ReportCategory {
+        id: None,
+        key: category.key,
+        label: category.label,
+        icon: category.icon,
+        expiry_minutes: category.expiry_minutes,
+        severity: category.severity,
+        retired: false,
+    };
+    // The unique index on the key turns away duplicates
+    match store
+        .repository::<ReportCategory>()
+        .insert(&mut category)
+        .await
+    {
+        Ok(_) => {
+            log::info!("{} created report category {}", admin.email(), category.key);
+            ApiResponse::Ok(category)
+        }
+        Err(RepoError::Duplicate(_)) => {
+            ApiError::conflict("A category with this key already exists").into()
+        }
+        Err(err) => {
+            log::error!("Couldn't create report category: {err:?}");
+            ApiError::internal("Couldn't create the category").into()
+        }
+    }
+}
+
+#[utoipa::path(patch, path = "/api/admin/reportCategories/{key}", params(("key",)), request_body = UpdateReportCategory, security(("jwt" = [])))]
+#[patch(
+    "/admin/reportCategories/<key>",
+    format = "application/json",
+    data = "<update>"
+)]
+pub async fn update_category(
+    key: &str,
+    update: Json<UpdateReportCategory>,
+    admin: AdminToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<ReportCategory> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    match edit_category(&store, key, update.into_inner()).await {
+        Ok(category) => {
+            log::info!("{} updated report category {key}", admin.email());
+            ApiResponse::Ok(category)
+        }
+        Err(err) => err.into(),
+    }
+}
+
+/// Changes only the fields `update` sets, so admins editing different
+/// fields at the same time don't undo each other's changes
+pub async fn edit_category<S: Store>(
+    store: &S,
+    key: &str,
+    update: UpdateReportCategory,
+) -> Result<ReportCategory, ApiError> {
+    let mut changes = Document::new();
+    if let Some(label) = update.label {
+        validate_label(&label)?;
+        changes.insert("label", label);
+    }
+    if let Some(icon) = update.icon {
+        changes.insert("icon", icon);
+    }
+    if let Some(expiry_minutes) = update.expiry_minutes {
+        validate_expiry(expiry_minutes)?;
+        changes.insert("expiryMinutes", expiry_minutes);
+    }
+    if let Some(severity) = update.severity {
+        changes.insert("severity", bson::to_bson(&severity).unwrap());
+    }
+    if let Some(retired) = update.retired {
+        changes.insert("retired", retired);
+    }
+
+    let categories = store.repository::<ReportCategory>();
+    if !changes.is_empty()
+        && !categories
+            .update_one(bson::doc! { "key": key }, bson::doc! { "$set": changes })
+            .await?
+    {
+        return Err(ApiError::not_found("No such category"));
+    }
+    categories
+        .find_one(bson::doc! { "key": key }, None)
+        .await?
+        .ok_or_else(|| ApiError::not_found("No such category"))
+}
+
+/// Reports keep referring to their category, so categories are retired
+/// rather than deleted. Retired categories can't be reported in anymore.
+#[utoipa::path(delete, path = "/api/admin/reportCategories/{key}", params(("key",)), security(("jwt" = [])))]
+#[delete("/admin/reportCategories/<key>")]
+pub async fn retire_category(
+    key: &str,
+    admin: AdminToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<&'static str> {
+    let db = match state.inner() {
+        Some(client) => client.database(DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    match db
+        .collection::<ReportCategory>("report_categories")
+        .update_one(
+            bson::doc! { "key": key },
+            bson::doc! { "$set": { "retired": true } },
+            None,
+        )
+        .await
+    {
+        Ok(result) if result.matched_count == 0 => ApiError::not_found("No such category").into(),
+        Ok(_) => {
+            log::info!("{} retired report category {key}", admin.email());
+            ApiResponse::Ok("Category retired")
+        }
+        Err(err) => {
+            log::error!("Couldn't retire report category: {err:?}");
+            ApiError::internal("Couldn't retire the category").into()
+        }
+    }
+}
+
+/// Most severe first, then by label. Severities are stored by name, so the
+/// database can't sort them.
+pub(crate) fn sort_categories(categories: &mut [ReportCategory]) {
+    categories.sort_by(|a, b| {
+        b.severity
+            .cmp(&a.severity)
+            .then_with(|| a.label.cmp(&b.label))
+    });
+}
+
+fn validate(label: &str, expiry_minutes: u32) -> Result<(), ApiError> {
+    validate_label(label)?;
+    validate_expiry(expiry_minutes)
+}
+
+fn validate_label(label: &str) -> Result<(), ApiError> {
+    if label.trim().is_empty() {
+        return Err(ApiError::invalid("label", "Categories need a label"));
+    }
+    Ok(())
+}
+
+fn validate_expiry(expiry_minutes: u32) -> Result<(), ApiError> {
+    if expiry_minutes == 0 || expiry_minutes > MAX_EXPIRY_MINUTES {
+        return Err(ApiError::invalid(
+            "expiryMinutes",
+            "Reports have to expire after a minute to a week",
+        ));
+    }
+    Ok(())
+}
+
+/// The category a new report is made in, if it exists and isn't retired
+pub async fn active_category<S: Store>(store: &S, key: &str) -> RepoResult<Option<ReportCategory>> {
+    store
+        .repository::<ReportCategory>()
+        .find_one(bson::doc! { "key": key, "retired": { "$ne": true } }, None)
+        .await
+}
+
+/// Ordered from least to most severe
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ToSchema)]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+/// A kind of thing users can report. Reports store the category's `key`.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema, Entity)]
+#[serde(rename_all = "camelCase")]
+#[collection_name = "report_categories"]
+#[schema(example = json! {
+    ReportCategory {
+        id: None,
+        key: "PowerOutage".to_string(),
+        label: "Power outage".to_string(),
+        icon: "power_off".to_string(),
+        expiry_minutes: 120,
+        severity: Severity::High,
+        retired: false
+    }
+})]
+pub struct ReportCategory {
+    #[serde(skip_serializing_if = "Option::is_none", rename = "_id")]
+    #[schema(value_type = Option<String>)]
+    pub id: Option<ObjectId>,
+    #[index(unique)]
+    pub key: String,
+    pub label: String,
+    /// Name of the icon the apps show for the category
+    pub icon: String,
+    /// How long reports in this category stay up
+    pub expiry_minutes: u32,
+    pub severity: Severity,
+    #[serde(default)]
+    pub retired: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NewReportCategory {
+    pub key: String,
+    pub label: String,
+    pub icon: String,
+    pub expiry_minutes: u32,
+    pub severity: Severity,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateReportCategory {
+    pub label: Option<String>,
+    pub icon: Option<String>,
+    pub expiry_minutes: Option<u32>,
+    pub severity: Option<Severity>,
+    /// Set to false to bring a retired category back
+    pub retired: Option<bool>,
+}
+
+/// The categories reports used to be limited to, minus traffic, which
+/// has nothing to do with power
+pub(crate) fn default_categories() -> Vec<ReportCategory> {
+    [
+        (
+            POWER_OUTAGE,
+            "Power outage",
+            "power_off",
+            120,
+            Severity::High,
+        ),
+        (
+            "SubstationBlew",
+            "Substation blew",
+            "electrical_services",
+            240,
+            Severity::High,
+        ),
+        (
+            "CablesStolen",
+            "Cables stolen",
+            "content_cut",
+            24 * 60,
+            Severity::Medium,
+        ),
+        (
+            "CablesDamaged",
+            "Cables damaged",
+            "warning",
+            24 * 60,
+            Severity::Medium,
+        ),
+    ]
+    .into_iter()
+    .map(
+        |(key, label, icon, expiry_minutes, severity)| ReportCategory {
+            id: None,
+            key: key.to_string(),
+            label: label.to_string(),
+            icon: icon.to_string(),
+            expiry_minutes,
+            severity,
+            retired: false,
+        },
+    )
+    .collect()
+}
@@ -1,11 +1,26 @@
+use crate::api::ApiError;
+use crate::repository::Model;
+use crate::DB_NAME;
 use async_trait::async_trait;
-use bson::Document;
+use bson::{Bson, Document};
 use mongodb::{
-    options::{FindOptions, UpdateModifications},
+    error::{CommandError, ErrorKind},
+    options::{FindOptions, IndexOptions, UpdateModifications},
     results::{DeleteResult, InsertOneResult, UpdateResult},
-    Cursor, Database,
+    Client, Database, IndexModel,
 };
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::futures::TryStreamExt;
+use rocket::request::{FromRequest, Outcome};
+use rocket::{Build, Orbit, Request, Rocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+/// The original CRUD helpers. Handlers that look up or change single
+/// documents have moved to [`crate::repository`], which runs in transactions
+/// and can be tested without a database. The schedule data, the scraper and
+/// some listings still use these.
 #[async_trait]
 pub trait Entity {
     async fn insert(&self, db: &Database) -> Result<InsertOneResult, mongodb::error::Error>;
@@ -17,17 +32,6 @@ pub trait Entity {
         options: Option<FindOptions>,
     ) -> Result<Vec<Box<Self>>, mongodb::error::Error>;
 
-    async fn find_one(
-        filter: Document,
-        db: &Database,
-        options: Option<FindOptions>,
-    ) -> Option<Box<Self>>;
-
-    #[deprecated(note = "Use `Entity::find` instead.")]
-    async fn query(filter: Document, db: &Database) -> Result<Cursor<Self>, mongodb::error::Error>
-    where
-        Self: Sized;
-
     async fn update(
         &mut self,
         update: UpdateModifications,
@@ -46,3 +50,347 @@ pub trait Entity {
     //         filter: impl Into<Option<Document>>,
     //     ) -> Result<Option<Self::Output>, mongodb::error::Error>;
 }
+
+/// How often the database is pinged while it's up
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Whether MongoDB is answering, as last seen by [`DbMonitor`]. Handlers
+/// check it to fail fast or fall back instead of waiting for the driver to
+/// time out.
+#[derive(Clone)]
+pub struct DbHealth(Arc<AtomicBool>);
+
+impl Default for DbHealth {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+impl DbHealth {
+    pub fn is_up(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Records whether the database answered, returning whether that changed
+    pub fn set_up(&self, up: bool) -> bool {
+        self.0.swap(up, Ordering::Relaxed) != up
+    }
+
+    /// The managed client, unless there is none or it can't reach the database
+    pub fn client<'a>(&self, state: &'a Option<Client>) -> Option<&'a Client> {
+        state.as_ref().filter(|_| self.is_up())
+    }
+}
+
+/// Request guard for handlers that can't do anything without the database.
+/// Fails with 503 when there's no client or the database is down.
+pub struct Db(pub Client);
+
+impl Db {
+    pub fn database(&self, name: &str) -> Database {
+        self.0.database(name)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Db {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let client = request
+            .rocket()
+            .state::<Option<Client>>()
+            .and_then(Option::as_ref);
+        let health = request.rocket().state::<DbHealth>();
+        match (client, health) {
+            (Some(client), Some(health)) if health.is_up() => Outcome::Success(Db(client.clone())),
+            (Some(client), None) => Outcome::Success(Db(client.clone())),
+            _ => ApiError::database_unavailable().fail(request),
+        }
+    }
+}
+
+/// Manages [`DbHealth`] and keeps pinging the database, backing off while
+/// it's unreachable
+pub struct DbMonitor;
+
+#[rocket::async_trait]
+impl Fairing for DbMonitor {
+    fn info(&self) -> Info {
+        Info {
+            name: "Database Monitor",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> rocket::fairing::Result {
+        Ok(rocket.manage(DbHealth::default()))
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let client = match rocket.state::<Option<Client>>() {
+            Some(Some(client)) => client.clone(),
+            _ => return,
+        };
+        let health = rocket.state::<DbHealth>().unwrap().clone();
+
+        tokio::spawn(async move {
+            let mut delay = MIN_RECONNECT_DELAY;
+            loop {
+                let ping = client
+                    .database("admin")
+                    .run_command(bson::doc! { "ping": 1 }, None)
+                    .await;
+                match ping {
+                    Ok(_) => {
+                        if health.set_up(true) {
+                            log::info!("Database is reachable again");
+                        }
+                        delay = MIN_RECONNECT_DELAY;
+                        tokio::time::sleep(HEALTH_CHECK_INTERVAL).await;
+                    }
+                    Err(err) => {
+                        if health.set_up(false) {
+                            log::error!(
+                                "Lost the database, serving what we can without it: {err:?}"
+                            );
+                        } else {
+                            log::warn!(
+                                "Database still unreachable, retrying in {delay:?}: {err:?}"
+                            );
+                        }
+                        tokio::time::sleep(delay).await;
+                        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IndexKey {
+    Ascending,
+    Descending,
+    Sphere2d,
+    Flat2d,
+}
+
+impl IndexKey {
+    fn value(self) -> Bson {
+        match self {
+            Self::Ascending => Bson::Int32(1),
+            Self::Descending => Bson::Int32(-1),
+            Self::Sphere2d => Bson::String("2dsphere".to_string()),
+            Self::Flat2d => Bson::String("2d".to_string()),
+        }
+    }
+}
+
+/// An index an entity declares with `#[index]` or `#[ttl]`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexSpec {
+    pub keys: &'static [(&'static str, IndexKey)],
+    pub unique: bool,
+    pub sparse: bool,
+    /// Seconds after the date in the field that a document gets deleted.
+    /// Mongo ignores it for fields that aren't dates.
+    pub expire_after: Option<u64>,
+}
+
+impl IndexSpec {
+    pub fn keys(&self) -> Document {
+        self.keys
+            .iter()
+            .map(|(field, key)| (field.to_string(), key.value()))
+            .collect()
+    }
+
+    fn model(&self) -> IndexModel {
+        let options = IndexOptions::builder()
+            .unique(self.unique.then_some(true))
+            .sparse(self.sparse.then_some(true))
+            .expire_after(self.expire_after.map(Duration::from_secs))
+            .build();
+        IndexModel::builder()
+            .keys(self.keys())
+            .options(options)
+            .build()
+    }
+
+    /// Whether an existing index is on the same keys, in the same order
+    pub fn same_keys(&self, keys: &Document) -> bool {
+        let same_value = |a: &Bson, b: &Bson| match (a.as_str(), b.as_str()) {
+            (None, None) => bson_number(a) == bson_number(b),
+            (a, b) => a == b,
+        };
+        keys.len() == self.keys.len()
+            && keys
+                .iter()
+                .zip(self.keys.iter())
+                .all(|((field, value), (declared, key))| {
+                    field == declared && same_value(value, &key.value())
+                })
+    }
+
+    /// How an existing index on the same keys differs from this
+    pub fn drift(&self, options: &IndexOptions) -> Vec<String> {
+        let mut drift = Vec::new();
+        if options.unique.unwrap_or(false) != self.unique {
+            drift.push(format!(
+                "unique is {:?}, declared {}",
+                options.unique, self.unique
+            ));
+        }
+        if options.sparse.unwrap_or(false) != self.sparse {
+            drift.push(format!(
+                "sparse is {:?}, declared {}",
+                options.sparse, self.sparse
+            ));
+        }
+        let expire_after = options.expire_after.map(|ttl| ttl.as_secs());
+        if expire_after != self.expire_after {
+            drift.push(format!(
+                "expireAfterSeconds is {expire_after:?}, declared {:?}",
+                self.expire_after
+            ));
+        }
+        drift
+    }
+}
+
+fn bson_number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+/// Creates the indexes entities declare when the server starts, and logs
+/// indexes that drifted from their declaration or aren't declared at all.
+/// Drifted TTLs are corrected; anything else is left for a person to fix,
+/// since rebuilding an index can lock up a big collection.
+#[derive(Clone, Default)]
+pub struct IndexManager {
+    /// The app's own database unless set
+    database: Option<&'static str>,
+    collections: Vec<(&'static str, &'static [IndexSpec])>,
+}
+
+impl IndexManager {
+    /// For entities kept outside the app's own database
+    pub fn in_database(database: &'static str) -> Self {
+        Self {
+            database: Some(database),
+            ..Self::default()
+        }
+    }
+
+    pub fn with<T: Model>(mut self) -> Self {
+        self.collections.push((T::COLLECTION, T::INDEXES));
+        self
+    }
+
+    /// Every declared index, grouped by collection
+    fn declared(&self) -> Vec<(&'static str, Vec<IndexSpec>)> {
+        let mut declared: Vec<(&'static str, Vec<IndexSpec>)> = Vec::new();
+        for (collection, specs) in &self.collections {
+            match declared.iter_mut().find(|(name, _)| name == collection) {
+                Some((_, existing)) => existing.extend_from_slice(specs),
+                None => declared.push((collection, specs.to_vec())),
+            }
+        }
+        declared
+    }
+
+    pub async fn ensure(&self, db: &Database) {
+        for (collection, specs) in self.declared() {
+            if let Err(err) = ensure_collection(db, collection, &specs).await {
+                log::error!("Couldn't ensure the indexes on {collection}: {err:?}");
+            }
+        }
+    }
+}
+
+async fn ensure_collection(
+    db: &Database,
+    collection: &str,
+    specs: &[IndexSpec],
+) -> Result<(), mongodb::error::Error> {
+    let indexes = db.collection::<Document>(collection);
+    let existing: Vec<IndexModel> = match indexes.list_indexes(None).await {
+        Ok(cursor) => cursor.try_collect().await?,
+        // The collection doesn't exist yet
+        Err(err) if matches!(*err.kind, ErrorKind::Command(CommandError { code: 26, .. })) => {
+            Vec::new()
+        }
+        Err(err) => return Err(err),
+    };
+
+    for spec in specs {
+        let index = match existing.iter().find(|index| spec.same_keys(&index.keys)) {
+            Some(index) => index,
+            None => {
+                indexes.create_index(spec.model(), None).await?;
+                log::info!("Created index {} on {collection}", spec.keys());
+                continue;
+            }
+        };
+        let options = index.options.clone().unwrap_or_default();
+        let name = options.name.clone().unwrap_or_default();
+        for drift in spec.drift(&options) {
+            log::warn!("Index {name} on {collection} drifted: {drift}");
+        }
+        let expire_after = options.expire_after.map(|ttl| ttl.as_secs());
+        if let Some(seconds) = spec
+            .expire_after
+            .filter(|seconds| Some(*seconds) != expire_after)
+        {
+            db.run_command(
+                bson::doc! {
+                    "collMod": collection,
+                    "index": { "keyPattern": spec.keys(), "expireAfterSeconds": seconds as i64 },
+                },
+                None,
+            )
+            .await?;
+            log::info!("Set the TTL of index {name} on {collection} to {seconds}s");
+        }
+    }
+
+    for index in &existing {
+        let name = index
+            .options
+            .as_ref()
+            .and_then(|options| options.name.as_deref());
+        if name != Some("_id_") && !specs.iter().any(|spec| spec.same_keys(&index.keys)) {
+            log::warn!(
+                "Index {} on {collection} isn't declared by any entity",
+                name.unwrap_or_default()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl Fairing for IndexManager {
+    fn info(&self) -> Info {
+        Info {
+            name: "Index Manager",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let db = match rocket.state::<Option<Client>>() {
+            Some(Some(client)) => client.database(self.database.unwrap_or(DB_NAME)),
+            _ => return,
+        };
+        self.ensure(&db).await;
+    }
+}
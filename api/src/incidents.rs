@@ -0,0 +1,770 @@
+use crate::{
+    api::{ApiError, ApiResponse},
+    categories::POWER_OUTAGE,
+    db::Entity,
+    loadshedding::{self, DBFunctions},
+    reporting::{
+        bbox_geometry, now_millis, GeoPoint, UserReport, UserReportResponse, DEFAULT_PAGE_SIZE,
+        MAX_PAGE_SIZE,
+    },
+    repository::{MongoStore, PageRequest, RepoResult, Repository, Store},
+    scheduler::{Job, Schedule, Scheduler},
+    webhooks, DB_NAME,
+};
+use bson::{oid::ObjectId, Bson};
+use macros::Entity;
+use mongodb::{options::FindOptions, Client, Database};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{get, FromForm, Orbit, Rocket, State};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+/// How close a report has to be to an incident to count towards it
+const CLUSTER_RADIUS_METRES: f64 = 2000.0;
+/// How long after an incident's latest report a new one can still join it
+const CLUSTER_WINDOW_MILLIS: u64 = 1000 * 60 * 60;
+const ANALYSIS_INTERVAL: Duration = Duration::from_secs(60);
+/// Outage reports older than this aren't checked against the schedule
+const ANALYSIS_WINDOW_MILLIS: u64 = 1000 * 60 * 60 * 24;
+const ANALYSIS_BATCH_SIZE: i64 = 100;
+/// How often adding or taking out a report is retried when another writer
+/// changed the incident in the meantime
+const MAX_ATTEMPTS: usize = 5;
+
+#[utoipa::path(get, path = "/api/incidents", params(IncidentQuery), responses(
+    (status = 200, description = "Incidents matching every filter given, most recently reported first", body = [IncidentResponse])
+))]
+#[get("/incidents?<query..>")]
+pub async fn get_incidents(
+    query: IncidentQuery,
+    state: &State<Option<Client>>,
+) -> ApiResponse<Vec<IncidentResponse>> {
+    let db = match state.inner() {
+        Some(client) => client.database(DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+
+    let mut filter = bson::doc! { "reports.0": { "$exists": true } };
+    if !query.include_resolved.unwrap_or(false) {
+        filter.insert("expires", bson::doc! { "$gt": now_millis() as i64 });
+    }
+    if let Some(bbox) = &query.bbox {
+        match bbox_geometry(bbox) {
+            Ok(geometry) => {
+                filter.insert("location", bson::doc! { "$geoWithin": geometry });
+            }
+            Err(err) => return err.into(),
+        }
+    }
+    if let Some(report_type) = &query.report_type {
+        filter.insert("reportType", report_type);
+    }
+
+    let options = FindOptions::builder()
+        .sort(bson::doc! { "lastReport": -1 })
+        .limit(
+            query
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+        )
+        .build();
+    match Incident::find(filter, &db, Some(options)).await {
+        Ok(incidents) => ApiResponse::Ok(
+            incidents
+                .iter()
+                .map(|incident| IncidentResponse::from(incident.as_ref()))
+                .collect(),
+        ),
+        Err(err) => {
+            log::error!("Couldn't fetch incidents: {err:?}");
+            ApiError::internal("Couldn't fetch incidents!").into()
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/api/incidents/{id}", params(("id",)), responses(
+    (status = 200, description = "The incident and the reports it's made up of", body = IncidentDetail)
+))]
+#[get("/incidents/<id>")]
+pub async fn get_incident(id: &str, state: &State<Option<Client>>) -> ApiResponse<IncidentDetail> {
+    let client = match state.inner() {
+        Some(client) => client,
+        None => return ApiError::database_unavailable().into(),
+    };
+    let db = client.database(DB_NAME);
+    let id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return ApiError::bad_request("Invalid incident id").into(),
+    };
+    let store = MongoStore::new(client, DB_NAME);
+    let incident = match store.repository::<Incident>().find_by_id(id).await {
+        Ok(Some(incident)) => incident,
+        Ok(None) => return ApiError::not_found("No such incident").into(),
+        Err(err) => return ApiError::from(err).into(),
+    };
+
+    let options = FindOptions::builder()
+        .sort(bson::doc! { "created": -1 })
+        .build();
+    match UserReport::find(
+        bson::doc! { "_id": { "$in": &incident.reports }, "hidden": { "$ne": true } },
+        &db,
+        Some(options),
+    )
+    .await
+    {
+        Ok(reports) => ApiResponse::Ok(IncidentDetail {
+            incident: IncidentResponse::from(&incident),
+            reports: reports
+                .iter()
+                .map(|report| UserReportResponse::from(report.as_ref()))
+                .collect(),
+        }),
+        Err(err) => {
+            log::error!("Couldn't fetch the reports of incident {id}: {err:?}");
+            ApiError::internal("Couldn't fetch incident!").into()
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/api/outages/unscheduled", params(OutageQuery), responses(
+    (status = 200, description = "Incidents that look like faults rather than loadshedding, by suburb", body = [SuburbOutages])
+))]
+#[get("/outages/unscheduled?<query..>")]
+pub async fn get_unscheduled_outages(
+    query: OutageQuery,
+    state: &State<Option<Client>>,
+) -> ApiResponse<Vec<SuburbOutages>> {
+    let db = match state.inner() {
+        Some(client) => client.database(DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+
+    let mut filter = bson::doc! {
+        "reports.0": { "$exists": true },
+        "$expr": { "$gt": ["$unscheduledReports", "$scheduledReports"] },
+    };
+    if !query.include_resolved.unwrap_or(false) {
+        filter.insert("expires", bson::doc! { "$gt": now_millis() as i64 });
+    }
+    if let Some(suburb) = &query.suburb {
+        filter.insert("suburbs", suburb);
+    }
+
+    let options = FindOptions::builder()
+        .sort(bson::doc! { "lastReport": -1 })
+        .limit(MAX_PAGE_SIZE)
+        .build();
+    let incidents = match Incident::find(filter, &db, Some(options)).await {
+        Ok(incidents) => incidents,
+        Err(err) => {
+            log::error!("Couldn't fetch unscheduled outages: {err:?}");
+            return ApiError::internal("Couldn't fetch unscheduled outages!").into();
+        }
+    };
+
+    let mut suburbs = BTreeMap::<String, Vec<IncidentResponse>>::new();
+    for incident in incidents {
+        let response = IncidentResponse::from(incident.as_ref());
+        for suburb in &incident.suburbs {
+            if query.suburb.as_ref().is_some_and(|wanted| wanted != suburb) {
+                continue;
+            }
+            suburbs
+                .entry(suburb.clone())
+                .or_default()
+                .push(response.clone());
+        }
+    }
+    ApiResponse::Ok(
+        suburbs
+            .into_iter()
+            .map(|(suburb, incidents)| SuburbOutages { suburb, incidents })
+            .collect(),
+    )
+}
+
+#[derive(Debug, Default, FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OutageQuery {
+    /// Only outages in this suburb
+    pub suburb: Option<String>,
+    /// Include outages whose reports have all expired
+    #[field(name = "includeResolved")]
+    #[param(rename = "includeResolved")]
+    pub include_resolved: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SuburbOutages {
+    pub suburb: String,
+    pub incidents: Vec<IncidentResponse>,
+}
+
+#[derive(Debug, Default, FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IncidentQuery {
+    /// Bounding box as `west,south,east,north`
+    pub bbox: Option<String>,
+    #[field(name = "reportType")]
+    #[param(rename = "reportType")]
+    pub report_type: Option<String>,
+    /// Include incidents whose reports have all expired
+    #[field(name = "includeResolved")]
+    #[param(rename = "includeResolved")]
+    pub include_resolved: Option<bool>,
+    /// At most 500, defaults to 100
+    pub limit: Option<i64>,
+}
+
+/// Reports of the same type made close together, in place and in time,
+/// which most likely describe the same event.
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[serde(rename_all = "camelCase")]
+#[collection_name = "incidents"]
+#[index(fields = "expires, -lastReport")]
+pub struct Incident {
+    #[serde(skip_serializing_if = "Option::is_none", rename = "_id")]
+    pub id: Option<ObjectId>,
+    pub report_type: String,
+    /// Centroid of the reports
+    pub latitude: f64,
+    pub longitude: f64,
+    #[index(2dsphere)]
+    pub location: GeoPoint,
+    /// Names of the suburbs the reports were made in
+    pub suburbs: Vec<String>,
+    #[index]
+    pub reports: Vec<ObjectId>,
+    /// When the first report was made, in milliseconds
+    pub started: u64,
+    pub last_report: u64,
+    /// When the last of the reports expires
+    pub expires: u64,
+    /// Outage reports the schedule accounts for
+    #[serde(default)]
+    pub scheduled_reports: u32,
+    /// Outage reports made while the schedule had power on
+    #[serde(default)]
+    pub unscheduled_reports: u32,
+    /// Reports already counted in `scheduled_reports` or
+    /// `unscheduled_reports`, so none is counted twice
+    #[serde(default)]
+    pub analysed: Vec<ObjectId>,
+    /// Bumped whenever `reports` changes, so a centroid worked out from a
+    /// stale copy is never written
+    #[serde(default)]
+    pub version: u64,
+}
+
+impl Incident {
+    pub fn from_report(report: &UserReport, suburb: Option<String>) -> Self {
+        Self {
+            id: None,
+            report_type: report.report_type.clone(),
+            latitude: report.latitude,
+            longitude: report.longitude,
+            location: GeoPoint::new(report.longitude, report.latitude),
+            suburbs: suburb.into_iter().collect(),
+            reports: report.id.into_iter().collect(),
+            started: report.created,
+            last_report: report.created,
+            expires: report.expires,
+            scheduled_reports: (report.unscheduled == Some(false)) as u32,
+            unscheduled_reports: (report.unscheduled == Some(true)) as u32,
+            analysed: report
+                .id
+                .filter(|_| report.unscheduled.is_some())
+                .into_iter()
+                .collect(),
+            version: 0,
+        }
+    }
+
+    /// Adds a report to the incident, moving the centroid towards it
+    pub fn absorb(&mut self, report: &UserReport, suburb: Option<String>) {
+        let count = self.reports.len() as f64;
+        self.latitude = (self.latitude * count + report.latitude) / (count + 1.0);
+        self.longitude = (self.longitude * count + report.longitude) / (count + 1.0);
+        self.location = GeoPoint::new(self.longitude, self.latitude);
+        if let Some(id) = report.id {
+            self.reports.push(id);
+        }
+        if let Some(suburb) = suburb {
+            if !self.suburbs.contains(&suburb) {
+                self.suburbs.push(suburb);
+            }
+        }
+        self.started = self.started.min(report.created);
+        self.last_report = self.last_report.max(report.created);
+        self.expires = self.expires.max(report.expires);
+        match report.unscheduled {
+            Some(true) => self.unscheduled_reports += 1,
+            Some(false) => self.scheduled_reports += 1,
+            None => return,
+        }
+        if let Some(id) = report.id {
+            self.analysed.push(id);
+        }
+    }
+
+    /// Works the centroid and times out again from `reports`, the ones that
+    /// still count, and drops every other report. The outage counters are
+    /// left alone.
+    pub fn recentre(&mut self, reports: &[UserReport]) {
+        self.reports
+            .retain(|id| reports.iter().any(|report| report.id == Some(*id)));
+        if reports.is_empty() {
+            return;
+        }
+        let count = reports.len() as f64;
+        self.latitude = reports.iter().map(|report| report.latitude).sum::<f64>() / count;
+        self.longitude = reports.iter().map(|report| report.longitude).sum::<f64>() / count;
+        self.location = GeoPoint::new(self.longitude, self.latitude);
+        self.started = reports
+            .iter()
+            .map(|report| report.created)
+            .min()
+            .unwrap_or(self.started);
+        self.last_report = reports
+            .iter()
+            .map(|report| report.created)
+            .max()
+            .unwrap_or(self.last_report);
+        self.expires = reports
+            .iter()
+            .map(|report| report.expires)
+            .max()
+            .unwrap_or(self.expires);
+    }
+
+    /// Most likely a fault rather than loadshedding
+    pub fn is_unscheduled(&self) -> bool {
+        self.unscheduled_reports > self.scheduled_reports
+    }
+
+    pub fn status(&self, now: u64) -> IncidentStatus {
+        if self.expires > now {
+            IncidentStatus::Active
+        } else {
+            IncidentStatus::Resolved
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum IncidentStatus {
+    Active,
+    Resolved,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct IncidentResponse {
+    pub id: String,
+    pub report_type: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub suburbs: Vec<String>,
+    pub report_count: usize,
+    pub started: u64,
+    pub last_report: u64,
+    pub expires: u64,
+    pub status: IncidentStatus,
+    /// Most of the outage reports were made while the schedule had power
+    /// on, so this is likely a fault rather than loadshedding
+    pub unscheduled: bool,
+}
+
+impl From<&Incident> for IncidentResponse {
+    fn from(value: &Incident) -> Self {
+        Self {
+            id: value.id.map(|id| id.to_hex()).unwrap_or_default(),
+            report_type: value.report_type.clone(),
+            latitude: value.latitude,
+            longitude: value.longitude,
+            suburbs: value.suburbs.clone(),
+            report_count: value.reports.len(),
+            started: value.started,
+            last_report: value.last_report,
+            expires: value.expires,
+            status: value.status(now_millis()),
+            unscheduled: value.is_unscheduled(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct IncidentDetail {
+    #[serde(flatten)]
+    pub incident: IncidentResponse,
+    pub reports: Vec<UserReportResponse>,
+}
+
+/// Adds a newly stored report to the nearest active incident of its type,
+/// starting a new incident if there isn't one close enough.
+pub async fn cluster_report(report: &UserReport, client: &Client) {
+    let store = MongoStore::new(client, DB_NAME);
+    let suburb = match loadshedding::suburb_at(
+        report.longitude,
+        report.latitude,
+        &client.database("production"),
+        &DBFunctions {},
+    )
+    .await
+    {
+        Ok(suburb) => suburb.map(|suburb| suburb.name),
+        Err(err) => {
+            log::warn!(
+                "Couldn't find the suburb of report {:?}: {err:?}",
+                report.id
+            );
+            None
+        }
+    };
+
+    // $nearSphere sorts by distance, so this is the closest candidate
+    let filter = bson::doc! {
+        "reportType": &report.report_type,
+        "lastReport": { "$gte": report.created.saturating_sub(CLUSTER_WINDOW_MILLIS) as i64 },
+        "location": { "$nearSphere": {
+            "$geometry": bson::to_bson(&GeoPoint::new(report.longitude, report.latitude)).unwrap(),
+            "$maxDistance": CLUSTER_RADIUS_METRES,
+        } },
+    };
+    let incidents = store.repository::<Incident>();
+    for _ in 0..MAX_ATTEMPTS {
+        let result = match incidents.find_one(filter.clone(), None).await {
+            Ok(Some(incident)) => join_incident(&store, &incident, report, suburb.clone()).await,
+            Ok(None) => {
+                let mut incident = Incident::from_report(report, suburb.clone());
+                match incidents.insert(&mut incident).await {
+                    Ok(_) => {
+                        webhooks::incident_created(&incident, &client.database(DB_NAME)).await;
+                        Ok(true)
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(true) => return count_stored_analysis(&store, report).await,
+            Ok(false) => continue,
+            Err(err) => {
+                log::error!(
+                    "Couldn't add report {:?} to an incident: {err:?}",
+                    report.id
+                );
+                return;
+            }
+        }
+    }
+    log::error!(
+        "Couldn't add report {:?} to an incident, it kept changing underneath",
+        report.id
+    );
+}
+
+/// Counts the analysis of a report the outage analysis got to while it was
+/// being clustered, which couldn't find the incident to count it in yet
+async fn count_stored_analysis<S: Store>(store: &S, report: &UserReport) {
+    let id = match report.id {
+        Some(id) => id,
+        None => return,
+    };
+    let result = match store.repository::<UserReport>().find_by_id(id).await {
+        Ok(Some(UserReport {
+            unscheduled: Some(unscheduled),
+            ..
+        })) => count_analysis(store, id, unscheduled).await,
+        Ok(_) => Ok(()),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        log::error!("Couldn't count the analysis of report {id:?}: {err:?}");
+    }
+}
+
+/// Counts an analysed report in the incidents it belongs to, unless they
+/// already count it. Both the outage analysis and clustering call this, as
+/// either can happen first.
+pub async fn count_analysis<S: Store>(
+    store: &S,
+    report: ObjectId,
+    unscheduled: bool,
+) -> RepoResult<()> {
+    store
+        .repository::<Incident>()
+        .update_many(
+            bson::doc! { "reports": report, "analysed": { "$ne": report } },
+            bson::doc! {
+                "$addToSet": { "analysed": report },
+                "$inc": { counter_field(unscheduled): 1 },
+            },
+        )
+        .await?;
+    Ok(())
+}
+
+/// Adds a report to `incident` unless its reports changed since it was
+/// read, in which case it has to be read and tried again. The counters are
+/// only ever incremented, so the outage analysis running at the same time
+/// doesn't lose any.
+pub async fn join_incident<S: Store>(
+    store: &S,
+    incident: &Incident,
+    report: &UserReport,
+    suburb: Option<String>,
+) -> RepoResult<bool> {
+    let mut joined = incident.clone();
+    joined.absorb(report, suburb.clone());
+
+    let mut added = bson::doc! { "reports": report.id };
+    if let Some(suburb) = suburb {
+        added.insert("suburbs", suburb);
+    }
+    let mut counters = bson::doc! { "version": 1 };
+    if let (Some(unscheduled), Some(id)) = (report.unscheduled, report.id) {
+        counters.insert(counter_field(unscheduled), 1);
+        added.insert("analysed", id);
+    }
+    store
+        .repository::<Incident>()
+        .update_one(
+            bson::doc! { "_id": incident.id, "version": version_guard(incident.version) },
+            bson::doc! {
+                "$set": {
+                    "latitude": joined.latitude,
+                    "longitude": joined.longitude,
+                    "location": bson::to_bson(&joined.location)?,
+                },
+                "$addToSet": added,
+                "$min": { "started": joined.started as i64 },
+                "$max": {
+                    "lastReport": joined.last_report as i64,
+                    "expires": joined.expires as i64,
+                },
+                "$inc": counters,
+            },
+        )
+        .await
+}
+
+/// Matches `version`, which incidents stored before it was added don't have
+fn version_guard(version: u64) -> Bson {
+    if version == 0 {
+        bson::bson!({ "$in": [0, null] })
+    } else {
+        Bson::Int64(version as i64)
+    }
+}
+
+/// Keeps an incident active for as long as any of its reports is
+pub async fn extend_incident(report: &UserReport, db: &Database) {
+    if let Err(err) = db
+        .collection::<Incident>("incidents")
+        .update_many(
+            bson::doc! { "reports": report.id },
+            bson::doc! { "$max": { "expires": report.expires as i64 } },
+            None,
+        )
+        .await
+    {
+        log::error!(
+            "Couldn't extend the incident of report {:?}: {err:?}",
+            report.id
+        );
+    }
+}
+
+/// Takes a hidden or deleted report out of its incident. Incidents left
+/// without reports aren't listed anymore.
+pub async fn detach_report<S: Store>(report: &UserReport, store: &S) {
+    match remove_from_incident(store, report).await {
+        Ok(true) => {}
+        Ok(false) => log::error!(
+            "Couldn't take report {:?} out of its incident, it kept changing underneath",
+            report.id
+        ),
+        Err(err) => {
+            log::error!(
+                "Couldn't take report {:?} out of its incident: {err:?}",
+                report.id
+            )
+        }
+    }
+}
+
+/// Takes a report out of every incident it's in, working each centroid out
+/// again from the reports that are left. Returns false when an incident kept
+/// being changed by someone else.
+pub async fn remove_from_incident<S: Store>(store: &S, report: &UserReport) -> RepoResult<bool> {
+    let incidents = store.repository::<Incident>();
+    for _ in 0..MAX_ATTEMPTS {
+        let mut incident = match incidents
+            .find_one(bson::doc! { "reports": report.id }, None)
+            .await?
+        {
+            Some(incident) => incident,
+            None => return Ok(true),
+        };
+        let remaining = incident
+            .reports
+            .iter()
+            .filter(|id| Some(**id) != report.id)
+            .copied()
+            .collect::<Vec<_>>();
+        let reports = store
+            .repository::<UserReport>()
+            .find(
+                bson::doc! { "_id": { "$in": remaining }, "hidden": { "$ne": true } },
+                PageRequest::all(),
+            )
+            .await?
+            .items;
+        incident.recentre(&reports);
+
+        let mut counters = bson::doc! { "version": 1 };
+        if let Some(unscheduled) = report.unscheduled {
+            if incident.analysed.iter().any(|id| Some(*id) == report.id) {
+                counters.insert(counter_field(unscheduled), -1);
+            }
+        }
+        incident.analysed.retain(|id| Some(*id) != report.id);
+        incidents
+            .update_one(
+                bson::doc! { "_id": incident.id, "version": version_guard(incident.version) },
+                bson::doc! {
+                    "$set": {
+                        "latitude": incident.latitude,
+                        "longitude": incident.longitude,
+                        "location": bson::to_bson(&incident.location)?,
+                        "reports": &incident.reports,
+                        "analysed": &incident.analysed,
+                        "started": incident.started as i64,
+                        "lastReport": incident.last_report as i64,
+                        "expires": incident.expires as i64,
+                    },
+                    "$inc": counters,
+                },
+            )
+            .await?;
+    }
+    Ok(false)
+}
+
+fn counter_field(unscheduled: bool) -> &'static str {
+    if unscheduled {
+        "unscheduledReports"
+    } else {
+        "scheduledReports"
+    }
+}
+
+/// Checks outage reports that haven't been looked at yet against the
+/// schedule. An outage where the schedule had power on is most likely a
+/// fault, and counts towards its incident being flagged as unscheduled.
+pub async fn analyse_outage_reports(client: &Client) {
+    let db = client.database(DB_NAME);
+    let production = client.database("production");
+    let filter = bson::doc! {
+        "reportType": POWER_OUTAGE,
+        "unscheduled": { "$exists": false },
+        "hidden": { "$ne": true },
+        "created": { "$gte": now_millis().saturating_sub(ANALYSIS_WINDOW_MILLIS) as i64 },
+    };
+    let options = FindOptions::builder()
+        .sort(bson::doc! { "created": 1 })
+        .limit(ANALYSIS_BATCH_SIZE)
+        .build();
+    let reports = match UserReport::find(filter, &db, Some(options)).await {
+        Ok(reports) => reports,
+        Err(err) => {
+            log::error!("Couldn't fetch outage reports to analyse: {err:?}");
+            return;
+        }
+    };
+
+    let store = MongoStore::new(client, DB_NAME);
+    for report in reports {
+        let id = match report.id {
+            Some(id) => id,
+            None => continue,
+        };
+        // Power being on according to the schedule is what makes it unscheduled
+        let unscheduled = match loadshedding::scheduled_power_on(
+            report.longitude,
+            report.latitude,
+            (report.created / 1000) as i64,
+            &production,
+            &DBFunctions {},
+        )
+        .await
+        {
+            Ok(unscheduled) => unscheduled,
+            Err(err) => {
+                log::warn!(
+                    "Couldn't check report {:?} against the schedule: {err:?}",
+                    report.id
+                );
+                continue;
+            }
+        };
+
+        if let Err(err) = store
+            .repository::<UserReport>()
+            .update_by_id(id, bson::doc! { "$set": { "unscheduled": unscheduled } })
+            .await
+        {
+            log::error!("Couldn't store the analysis of report {id:?}: {err:?}");
+            continue;
+        }
+        let unscheduled = match unscheduled {
+            Some(unscheduled) => unscheduled,
+            None => continue,
+        };
+        if unscheduled {
+            log::info!("Report {:?} looks like an unscheduled outage", report.id);
+        }
+        if let Err(err) = count_analysis(&store, id, unscheduled).await {
+            log::error!(
+                "Couldn't update the incident of report {:?}: {err:?}",
+                report.id
+            );
+        }
+    }
+}
+
+/// Runs `analyse_outage_reports` every minute
+pub struct OutageDetector;
+
+#[rocket::async_trait]
+impl Fairing for OutageDetector {
+    fn info(&self) -> Info {
+        Info {
+            name: "Unscheduled Outage Detector",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let client = match rocket.state::<Option<Client>>().unwrap() {
+            Some(client) => client.clone(),
+            None => return,
+        };
+        let scheduler = rocket.state::<Scheduler>().unwrap();
+
+        let job = Job::new("outage analysis", Schedule::every(ANALYSIS_INTERVAL));
+        scheduler.spawn(job, rocket.shutdown(), move || {
+            let client = client.clone();
+            async move {
+                analyse_outage_reports(&client).await;
+                Ok(())
+            }
+        });
+    }
+}
@@ -0,0 +1,536 @@
+use crate::db::Entity;
+use crate::scheduler::{Job, Schedule, Scheduler};
+use crate::DB_NAME;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use async_trait::async_trait;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use bson::oid::ObjectId;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, Jwk, JwkSet, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Validation};
+use log::{error, info, warn};
+use macros::Entity;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::UpdateOptions;
+use mongodb::{Client, Database};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::serde::json::Json;
+use rocket::{get, Orbit, Rocket, State};
+use rsa::pkcs1::{DecodeRsaPrivateKey, EncodeRsaPrivateKey};
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::traits::PublicKeyParts;
+use rsa::RsaPrivateKey;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+
+/// How long a JWT we hand out stays valid
+pub const TOKEN_LIFETIME_SECS: u64 = 3600 * 6;
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+const GENERATED_KEY_BITS: usize = 2048;
+/// How long an instance may take to generate and store a new key before
+/// another one can take over
+const ROTATION_LEASE: Duration = Duration::from_secs(5 * 60);
+const ROTATION_LOCK_ID: &str = "jwt_rotation";
+/// AES-GCM nonces are 96 bits
+const NONCE_BYTES: usize = 12;
+
+#[utoipa::path(get, tag = "Authenticate", path = "/.well-known/jwks.json")]
+#[get("/.well-known/jwks.json")]
+pub async fn get_jwks(keys: &State<JwtKeys>) -> Json<JwkSet> {
+    Json(keys.jwks().await)
+}
+
+/// A private key we sign JWTs with. Keys generated by rotation are stored in
+/// the database, encrypted, so every instance can verify tokens signed by
+/// the others.
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[serde(rename_all = "camelCase")]
+#[collection_name = "jwt_keys"]
+pub struct StoredSigningKey {
+    #[serde(rename = "_id")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<ObjectId>,
+    pub kid: String,
+    /// The PEM sealed by [`KeyCipher`]
+    #[serde(default)]
+    encrypted_private_key: String,
+    /// Keys stored before they were encrypted. Encrypted on the next sync.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    private_key_pem: Option<String>,
+    pub created_at: u64,
+    pub retired_at: Option<u64>,
+}
+
+/// Encrypts stored private keys with the 32 byte key, base64 encoded, in
+/// `JWT_KEY_ENCRYPTION_KEY`, so reading the database isn't enough to forge
+/// tokens
+#[derive(Clone)]
+pub struct KeyCipher(Aes256Gcm);
+
+impl KeyCipher {
+    pub fn new(key: &str) -> Result<Self, String> {
+        let key = STANDARD
+            .decode(key.trim())
+            .map_err(|err| format!("JWT_KEY_ENCRYPTION_KEY isn't base64: {err}"))?;
+        Aes256Gcm::new_from_slice(&key)
+            .map(Self)
+            .map_err(|_| "JWT_KEY_ENCRYPTION_KEY has to be 32 bytes".to_string())
+    }
+
+    fn from_env() -> Result<Self, String> {
+        match std::env::var("JWT_KEY_ENCRYPTION_KEY") {
+            Ok(key) => Self::new(&key),
+            Err(_) => Err("JWT_KEY_ENCRYPTION_KEY isn't set".to_string()),
+        }
+    }
+
+    /// Seals `pem`, bound to `kid` so ciphertexts can't be swapped between
+    /// keys
+    pub fn encrypt(&self, kid: &str, pem: &str) -> Result<String, String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: pem.as_bytes(),
+            aad: kid.as_bytes(),
+        };
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.0
+                .encrypt(&nonce, payload)
+                .map_err(|_| "Couldn't encrypt the key".to_string())?,
+        );
+        Ok(STANDARD.encode(sealed))
+    }
+
+    pub fn decrypt(&self, kid: &str, sealed: &str) -> Result<String, String> {
+        let sealed = STANDARD
+            .decode(sealed)
+            .map_err(|err| format!("Stored key isn't base64: {err}"))?;
+        if sealed.len() <= NONCE_BYTES {
+            return Err("Stored key is too short".to_string());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_BYTES);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: kid.as_bytes(),
+        };
+        let pem = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| {
+                "Couldn't decrypt the key, is JWT_KEY_ENCRYPTION_KEY right?".to_string()
+            })?;
+        String::from_utf8(pem).map_err(|_| "Decrypted key isn't UTF-8".to_string())
+    }
+}
+
+struct SigningKey {
+    kid: String,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    jwk: Jwk,
+    created_at: u64,
+    retired_at: Option<u64>,
+    /// The key from privateKey.pem / JWT_PRIVATE_KEY, never rotated out
+    configured: bool,
+}
+
+impl SigningKey {
+    fn from_pem(pem: &str, kid: Option<String>, created_at: u64) -> Result<Self, String> {
+        let private_key = RsaPrivateKey::from_pkcs8_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+            .map_err(|err| format!("Couldn't parse RSA private key: {err:?}"))?;
+
+        let n = URL_SAFE_NO_PAD.encode(private_key.n().to_bytes_be());
+        let e = URL_SAFE_NO_PAD.encode(private_key.e().to_bytes_be());
+        // RFC 7638 thumbprint, so instances sharing a key agree on its kid
+        let kid = kid.unwrap_or_else(|| {
+            let thumbprint = format!("{{\"e\":\"{e}\",\"kty\":\"RSA\",\"n\":\"{n}\"}}");
+            URL_SAFE_NO_PAD.encode(Sha256::digest(thumbprint.as_bytes()))
+        });
+
+        Ok(Self {
+            encoding_key: EncodingKey::from_rsa_pem(pem.as_bytes())
+                .map_err(|err| format!("Invalid encoding key: {err:?}"))?,
+            decoding_key: DecodingKey::from_rsa_components(&n, &e)
+                .map_err(|err| format!("Invalid decoding key: {err:?}"))?,
+            jwk: Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    algorithm: Some(Algorithm::RS256),
+                    key_id: Some(kid.clone()),
+                    ..Default::default()
+                },
+                algorithm: AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n,
+                    e,
+                }),
+            },
+            kid,
+            created_at,
+            retired_at: None,
+            configured: false,
+        })
+    }
+}
+
+/// Every key we currently accept JWTs from. Tokens are signed with the
+/// newest key that hasn't been retired; retired keys keep verifying until
+/// the tokens they signed have expired.
+#[derive(Clone)]
+pub struct JwtKeys {
+    keys: Arc<RwLock<Vec<SigningKey>>>,
+    rotation_interval: Option<Duration>,
+    cipher: Option<KeyCipher>,
+    /// Tells this instance's rotation lease apart from the others'
+    owner: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Couldn't get system time")
+        .as_secs()
+}
+
+impl JwtKeys {
+    /// Reads the configured key from `privateKey.pem`, falling back to the
+    /// `JWT_PRIVATE_KEY` environment variable. `JWT_KEY_ROTATION_HOURS`
+    /// turns on scheduled rotation, which also needs
+    /// `JWT_KEY_ENCRYPTION_KEY`.
+    pub async fn load() -> Self {
+        let private_key = match tokio::fs::read_to_string("privateKey.pem").await {
+            Ok(key) => key,
+            Err(err) => {
+                warn!("Couldn't read private key file: {err:?}");
+                std::env::var("JWT_PRIVATE_KEY").expect("Couldn't find a private key anywhere")
+            }
+        };
+
+        let mut key = SigningKey::from_pem(&private_key, None, 0).expect("Invalid JWT private key");
+        key.configured = true;
+        info!("Loaded JWT signing key {}", key.kid);
+
+        let mut rotation_interval = std::env::var("JWT_KEY_ROTATION_HOURS")
+            .ok()
+            .and_then(|hours| hours.parse::<u64>().ok())
+            .map(|hours| Duration::from_secs(hours * 3600));
+        let cipher = match KeyCipher::from_env() {
+            Ok(cipher) => Some(cipher),
+            Err(err) => {
+                if rotation_interval.take().is_some() {
+                    error!("Not rotating JWT keys, they can't be stored safely: {err}");
+                }
+                None
+            }
+        };
+
+        Self {
+            keys: Arc::new(RwLock::new(vec![key])),
+            rotation_interval,
+            cipher,
+            owner: ObjectId::new().to_hex(),
+        }
+    }
+
+    pub async fn sign<T: Serialize>(
+        &self,
+        claims: &T,
+    ) -> Result<String, jsonwebtoken::errors::Error> {
+        let keys = self.keys.read().await;
+        let key = keys
+            .iter()
+            .filter(|key| key.retired_at.is_none())
+            .max_by_key(|key| key.created_at)
+            .expect("No active JWT signing key");
+
+        let mut header = jsonwebtoken::Header::new(Algorithm::RS256);
+        header.kid = Some(key.kid.clone());
+        jsonwebtoken::encode(&header, claims, &key.encoding_key)
+    }
+
+    /// Finds the key a token was signed with. Tokens without a `kid` predate
+    /// key rotation and were signed with the configured key.
+    pub async fn decoding_key(&self, kid: Option<&str>) -> Option<DecodingKey> {
+        let keys = self.keys.read().await;
+        match kid {
+            Some(kid) => keys.iter().find(|key| key.kid == kid),
+            None => keys.iter().find(|key| key.configured),
+        }
+        .map(|key| key.decoding_key.clone())
+    }
+
+    /// The claims in `token` if one of our keys signed it and it hasn't
+    /// expired
+    pub async fn verify<T: DeserializeOwned>(&self, token: &str) -> Option<T> {
+        let kid = jsonwebtoken::decode_header(token).ok()?.kid;
+        let key = self.decoding_key(kid.as_deref()).await?;
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.leeway = 10;
+        jsonwebtoken::decode::<T>(token, &key, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+
+    pub async fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .keys
+                .read()
+                .await
+                .iter()
+                .map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+
+    /// Syncs with the keys in the database, generating a new one if the
+    /// current signing key is older than the rotation interval.
+    pub async fn rotate(&self, db: &Database) {
+        let (interval, cipher) = match (self.rotation_interval, &self.cipher) {
+            (Some(interval), Some(cipher)) => (interval.as_secs(), cipher),
+            _ => return,
+        };
+
+        let mut stored = match StoredSigningKey::find(bson::doc! {}, db, None).await {
+            Ok(stored) => stored,
+            Err(err) => {
+                error!("Couldn't load JWT keys from the database: {err:?}");
+                return;
+            }
+        };
+
+        let due = |stored: &[Box<StoredSigningKey>]| {
+            let newest = stored
+                .iter()
+                .filter(|key| key.retired_at.is_none())
+                .map(|key| key.created_at)
+                .max()
+                .unwrap_or(0);
+            now().saturating_sub(newest) >= interval
+        };
+
+        // Only one instance generates the next key, the rest pick it up on
+        // their next sync
+        if due(&stored) && self.lease(db).await {
+            // Another instance may have rotated between our read and the lease
+            match StoredSigningKey::find(bson::doc! {}, db, None).await {
+                Ok(reloaded) => stored = reloaded,
+                Err(err) => {
+                    error!("Couldn't reload JWT keys from the database: {err:?}");
+                    self.release(db).await;
+                    return;
+                }
+            }
+            if due(&stored) {
+                match tokio::task::spawn_blocking(generate_key).await {
+                    Ok(Ok((mut key, pem))) => match self.store(db, cipher, &mut key, &pem).await {
+                        Ok(()) => {
+                            info!("Rotated JWT signing key, new key is {}", key.kid);
+                            for old in stored.iter_mut().filter(|key| key.retired_at.is_none()) {
+                                old.retired_at = Some(now());
+                            }
+                            stored.push(Box::new(key));
+                        }
+                        Err(err) => error!("{err}"),
+                    },
+                    Ok(Err(err)) => error!("Couldn't generate JWT key: {err}"),
+                    Err(err) => error!("JWT key generation panicked: {err:?}"),
+                }
+            }
+            self.release(db).await;
+        }
+
+        let expired_before = now().saturating_sub(TOKEN_LIFETIME_SECS + 60);
+        let mut keys = self.keys.write().await;
+        keys.retain(|key| key.configured);
+        for stored in stored {
+            if stored.retired_at.is_some_and(|at| at < expired_before) {
+                let _ = stored.delete(db).await;
+                continue;
+            }
+            let pem = match &stored.private_key_pem {
+                Some(pem) => {
+                    encrypt_legacy(db, cipher, &stored, pem).await;
+                    Ok(pem.clone())
+                }
+                None => cipher.decrypt(&stored.kid, &stored.encrypted_private_key),
+            };
+            match pem.and_then(|pem| {
+                SigningKey::from_pem(&pem, Some(stored.kid.clone()), stored.created_at)
+            }) {
+                Ok(mut key) => {
+                    key.retired_at = stored.retired_at;
+                    keys.push(key);
+                }
+                Err(err) => error!("Skipping stored JWT key {}: {err}", stored.kid),
+            }
+        }
+
+        // The configured key only signs while nothing newer is around
+        let rotated = keys
+            .iter()
+            .any(|key| !key.configured && key.retired_at.is_none());
+        for key in keys.iter_mut().filter(|key| key.configured) {
+            key.retired_at = if rotated { Some(now()) } else { None };
+        }
+    }
+
+    /// Stores a new key, then retires the rest, so there's always one to
+    /// sign with
+    async fn store(
+        &self,
+        db: &Database,
+        cipher: &KeyCipher,
+        key: &mut StoredSigningKey,
+        pem: &str,
+    ) -> Result<(), String> {
+        key.encrypted_private_key = cipher.encrypt(&key.kid, pem)?;
+        key.insert(db)
+            .await
+            .map_err(|err| format!("Couldn't store new JWT key: {err:?}"))?;
+        db.collection::<StoredSigningKey>("jwt_keys")
+            .update_many(
+                bson::doc! { "retiredAt": null, "kid": { "$ne": &key.kid } },
+                bson::doc! { "$set": { "retiredAt": now() as i64 } },
+                None,
+            )
+            .await
+            .map(|_| ())
+            .map_err(|err| format!("Couldn't retire old JWT keys: {err:?}"))
+    }
+
+    /// Takes the rotation lease, unless another instance holds it
+    async fn lease(&self, db: &Database) -> bool {
+        let now = now() as i64;
+        let result = db
+            .collection::<bson::Document>("jwt_rotation_lock")
+            .update_one(
+                bson::doc! {
+                    "_id": ROTATION_LOCK_ID,
+                    "$or": [{ "owner": &self.owner }, { "expiresAt": { "$lt": now } }],
+                },
+                bson::doc! { "$set": {
+                    "owner": &self.owner,
+                    "expiresAt": now + ROTATION_LEASE.as_secs() as i64,
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await;
+        match result {
+            Ok(_) => true,
+            Err(err) => {
+                if !matches!(
+                    err.kind.as_ref(),
+                    ErrorKind::Write(WriteFailure::WriteError(write)) if write.code == 11000
+                ) {
+                    error!("Couldn't take the JWT rotation lease: {err:?}");
+                }
+                false
+            }
+        }
+    }
+
+    async fn release(&self, db: &Database) {
+        if let Err(err) = db
+            .collection::<bson::Document>("jwt_rotation_lock")
+            .delete_one(
+                bson::doc! { "_id": ROTATION_LOCK_ID, "owner": &self.owner },
+                None,
+            )
+            .await
+        {
+            warn!("Couldn't release the JWT rotation lease: {err:?}");
+        }
+    }
+}
+
+/// Replaces a plaintext key stored before keys were encrypted
+async fn encrypt_legacy(db: &Database, cipher: &KeyCipher, stored: &StoredSigningKey, pem: &str) {
+    let sealed = match cipher.encrypt(&stored.kid, pem) {
+        Ok(sealed) => sealed,
+        Err(err) => return error!("Couldn't encrypt JWT key {}: {err}", stored.kid),
+    };
+    let result = db
+        .collection::<StoredSigningKey>("jwt_keys")
+        .update_one(
+            bson::doc! { "kid": &stored.kid },
+            bson::doc! {
+                "$set": { "encryptedPrivateKey": sealed },
+                "$unset": { "privateKeyPem": "" },
+            },
+            None,
+        )
+        .await;
+    match result {
+        Ok(_) => info!("Encrypted stored JWT key {}", stored.kid),
+        Err(err) => error!("Couldn't encrypt stored JWT key {}: {err:?}", stored.kid),
+    }
+}
+
+/// A new key, to be encrypted before it's stored, and its PEM
+fn generate_key() -> Result<(StoredSigningKey, String), String> {
+    let mut rng = rand::thread_rng();
+    let private_key =
+        RsaPrivateKey::new(&mut rng, GENERATED_KEY_BITS).map_err(|err| format!("{err:?}"))?;
+    let pem = private_key
+        .to_pkcs1_pem(rsa::pkcs8::LineEnding::LF)
+        .map_err(|err| format!("{err:?}"))?;
+    let created_at = now();
+    let kid = SigningKey::from_pem(&pem, None, created_at)?.kid;
+
+    let key = StoredSigningKey {
+        id: None,
+        kid,
+        encrypted_private_key: String::new(),
+        private_key_pem: None,
+        created_at,
+        retired_at: None,
+    };
+    Ok((key, pem.to_string()))
+}
+
+pub struct KeyRotator;
+
+#[async_trait]
+impl Fairing for KeyRotator {
+    fn info(&self) -> Info {
+        Info {
+            name: "JWT Key Rotator",
+            kind: Kind::Ignite | Kind::Liftoff,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<rocket::Build>) -> fairing::Result {
+        Ok(rocket.manage(JwtKeys::load().await))
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let keys = rocket.state::<JwtKeys>().unwrap().clone();
+        let db = match rocket.state::<Option<Client>>().unwrap() {
+            Some(client) => client.database(DB_NAME),
+            None => return,
+        };
+        if keys.rotation_interval.is_none() {
+            return;
+        }
+
+        let scheduler = rocket.state::<Scheduler>().unwrap();
+
+        let job = Job::new("key rotation", Schedule::every(ROTATION_CHECK_INTERVAL));
+        scheduler.spawn(job, rocket.shutdown(), move || {
+            let (keys, db) = (keys.clone(), db.clone());
+            async move {
+                keys.rotate(&db).await;
+                Ok(())
+            }
+        });
+    }
+}
@@ -1,13 +1,20 @@
-use std::{collections::HashMap, sync::Arc, thread};
+use std::{collections::HashMap, sync::Arc, time::Duration as StdDuration};
 
 use crate::{
     api::{ApiError, ApiResponse},
-    db::Entity,
+    cache::ScheduleCache,
+    db::{DbHealth, Entity},
+    outages,
+    reporting::now_millis,
+    repository::{Model, PageRequest, Repository},
+    scheduler::{Job, Schedule, Scheduler},
+    snapshot::Snapshots,
+    stages::{Provenance, ESKOM_SOURCE},
+    webhooks,
 };
 use async_trait::async_trait;
 use bson::{doc, oid::ObjectId, Document};
 use chrono::{DateTime, Datelike, Duration, FixedOffset, Local, NaiveDateTime, Timelike, Utc};
-use lazy_static::__Deref;
 use log::warn;
 use macros::Entity;
 use mockall::automock;
@@ -21,19 +28,30 @@ use rocket::{
     Orbit, Rocket, State,
 };
 use serde::{Deserialize, Deserializer, Serialize};
-use tokio::{runtime::Runtime, sync::RwLock};
+use tokio::sync::RwLock;
 use utoipa::ToSchema;
 pub struct StageUpdater;
 
 // Rocket endpoints
 #[utoipa::path(post, tag = "Map Data", path = "/api/fetchMapData", request_body = MapDataRequest)]
 #[post("/fetchMapData", format = "application/json", data = "<request>")]
-pub async fn fetch_map_data<'a>(
+pub async fn fetch_map_data(
     db: &State<Option<Client>>,
+    health: &State<DbHealth>,
+    snapshots: &State<Snapshots>,
+    cache: &State<ScheduleCache>,
     loadshedding_stage: &State<Option<Arc<RwLock<LoadSheddingStage>>>>,
     request: Json<MapDataRequest>,
-) -> ApiResponse<'a, MapDataDefaultResponse> {
-    let connection = &db.inner().as_ref().unwrap().database("production");
+) -> ApiResponse<MapDataDefaultResponse> {
+    let key = Snapshots::key("fetchMapData", &*request);
+    let connection = &match health.client(db) {
+        Some(client) => client.database("production"),
+        None => {
+            return snapshots
+                .fallback(&key, ApiError::database_unavailable())
+                .await
+        }
+    };
     let south_west: Vec<f64> = request.bottom_left.iter().cloned().map(|x| x).collect();
     let north_east: Vec<f64> = request.top_right.iter().cloned().map(|x| x).collect();
     // query start
@@ -53,17 +71,17 @@ pub async fn fetch_map_data<'a>(
         Ok(cursor) => cursor,
         Err(err) => {
             log::error!("Database error occured when handling geo query: {err}");
-            return ApiError::ServerError(
-                "Database error occured when handling request. Check logs.",
-            )
-            .into();
+            let err =
+                ApiError::internal("Database error occured when handling request. Check logs.");
+            return snapshots.fallback(&key, err).await;
         }
     };
     let municipalities: Vec<MunicipalityEntity> = match cursor.try_collect().await {
         Ok(item) => item,
         Err(err) => {
             log::error!("Unable to Collect suburbs from cursor {err}");
-            return ApiError::ServerError("Error occured on the server, sorry :<").into();
+            let err = ApiError::internal("Error occured on the server, sorry :<");
+            return snapshots.fallback(&key, err).await;
         }
     };
     // query end
@@ -75,79 +93,112 @@ pub async fn fetch_map_data<'a>(
         .read()
         .await
         .stage;
-    let db_functions = DBFunctions {};
+    let db_functions = cache.inner();
+    // Only municipalities that haven't been materialized yet are worked out here
+    let time = request.time;
+    let mut materialized =
+        outages::maps_at(connection, &municipalities, get_date_time(time).timestamp()).await;
     let future_data = municipalities.iter().map(|municipality| {
-        municipality.get_regions_at_time(
-            stage.to_owned(),
-            request.time,
-            Some(connection),
-            &db_functions,
-        )
+        let found = municipality.id.and_then(|id| materialized.remove(&id));
+        async move {
+            match found {
+                Some(map) => Ok(map),
+                None => {
+                    municipality
+                        .get_regions_at_time(stage.to_owned(), time, Some(connection), db_functions)
+                        .await
+                }
+            }
+        }
     });
     let response = try_join_all(future_data).await;
     if let Ok(data) = response {
-        return ApiResponse::Ok(data.into_iter().fold(
+        let data = data.into_iter().fold(
             MapDataDefaultResponse {
                 map_polygons: vec![],
             },
             |acc, obj| acc + obj,
-        ));
+        );
+        snapshots.store(key, &data).await;
+        return ApiResponse::Ok(data);
     } else {
         log::error!("Unable to fold MapDataResponse");
-        return ApiError::ServerError("Error occured on the server, sorry :<").into();
+        let err = ApiError::internal("Error occured on the server, sorry :<");
+        return snapshots.fallback(&key, err).await;
     }
 }
 
 #[utoipa::path(post, tag = "Suburb Statistics", path = "/api/fetchSuburbStats", request_body = SuburbStatsRequest)]
 #[post("/fetchSuburbStats", format = "application/json", data = "<request>")]
-pub async fn fetch_suburb_stats<'a>(
+pub async fn fetch_suburb_stats(
     db: &State<Option<Client>>,
+    health: &State<DbHealth>,
+    snapshots: &State<Snapshots>,
+    cache: &State<ScheduleCache>,
     request: Json<SuburbStatsRequest>,
-) -> ApiResponse<'a, SuburbStatsResponse> {
-    let oid = &request.suburb_id;
-    let connection = db.as_ref().unwrap().database("production");
-    let query = doc! {"geometry" : {"$in" : [oid]}};
-    let suburb: SuburbEntity = match connection
-        .collection("suburbs")
-        .find_one(query, None)
-        .await
-        .unwrap()
-    {
-        Some(result) => result,
-        None => return ApiError::ServerError("Document not found").into(),
+) -> ApiResponse<SuburbStatsResponse> {
+    let key = Snapshots::key("fetchSuburbStats", &*request);
+    let connection = match health.client(db) {
+        Some(client) => client.database("production"),
+        None => {
+            return snapshots
+                .fallback(&key, ApiError::database_unavailable())
+                .await
+        }
     };
-    let db_functions = DBFunctions {};
+    let suburb = match find_suburb(request.suburb_id, &connection).await {
+        Ok(suburb) => suburb,
+        Err(err) => return snapshots.fallback(&key, err).await,
+    };
+    let db_functions = cache.inner();
     match suburb
-        .get_total_time_down_stats(Some(&connection), &db_functions, None)
+        .get_total_time_down_stats(Some(&connection), db_functions, None)
         .await
     {
-        Ok(data) => return ApiResponse::Ok(data),
-        Err(err) => return err.into(),
+        Ok(data) => {
+            snapshots.store(key, &data).await;
+            ApiResponse::Ok(data)
+        }
+        Err(err) => snapshots.fallback(&key, err).await,
     }
 }
 
 #[utoipa::path(post, tag = "Schedule Data", path = "/api/fetchScheduleData", request_body = SuburbStatsRequest)]
 #[post("/fetchScheduleData", format = "application/json", data = "<request>")]
-pub async fn fetch_schedule<'a>(
+pub async fn fetch_schedule(
     db: &State<Option<Client>>,
+    health: &State<DbHealth>,
+    snapshots: &State<Snapshots>,
+    cache: &State<ScheduleCache>,
     request: Json<SuburbStatsRequest>,
-) -> ApiResponse<'a, PredictiveSuburbStatsResponse> {
-    let oid = &request.suburb_id;
-    let connection = db.as_ref().unwrap().database("production");
-    let query = doc! {"geometry" : {"$in" : [oid]}};
-    let suburb: SuburbEntity = match connection
-        .collection("suburbs")
-        .find_one(query, None)
+) -> ApiResponse<PredictiveSuburbStatsResponse> {
+    let key = Snapshots::key("fetchScheduleData", &*request);
+    let connection = match health.client(db) {
+        Some(client) => client.database("production"),
+        None => {
+            return snapshots
+                .fallback(&key, ApiError::database_unavailable())
+                .await
+        }
+    };
+    let suburb = match find_suburb(request.suburb_id, &connection).await {
+        Ok(suburb) => suburb,
+        Err(err) => return snapshots.fallback(&key, err).await,
+    };
+    if let Some(data) = outages::schedule(&connection, &suburb, None).await {
+        snapshots.store(key, &data).await;
+        return ApiResponse::Ok(data);
+    }
+    let db_functions = cache.inner();
+    match suburb
+        .build_schedule(Some(&connection), db_functions, None)
         .await
-        .unwrap()
     {
-        Some(result) => result,
-        None => return ApiError::ServerError("Document not found").into(),
-    };
-    let db_functions = DBFunctions {};
-    match suburb.build_schedule(Some(&connection), &db_functions, None).await {
-        Ok(data) => return ApiResponse::Ok(data),
-        Err(err) => return err.into(),
+        Ok(data) => {
+            snapshots.store(key, &data).await;
+            ApiResponse::Ok(data)
+        }
+        Err(err) => snapshots.fallback(&key, err).await,
     }
 }
 
@@ -157,48 +208,58 @@ pub async fn fetch_schedule<'a>(
     format = "application/json",
     data = "<request>"
 )]
-pub async fn fetch_time_for_polygon<'a>(
+pub async fn fetch_time_for_polygon(
     db: &State<Option<Client>>,
+    health: &State<DbHealth>,
+    snapshots: &State<Snapshots>,
+    cache: &State<ScheduleCache>,
     request: Json<SuburbStatsRequest>,
-) -> ApiResponse<'a, PredictiveSuburbStatsResponse> {
-    let oid = &request.suburb_id;
-    let connection = db.as_ref().unwrap().database("production");
-    let query = doc! {"geometry" : {"$in" : [oid]}};
-    let suburb: SuburbEntity = match connection
-        .collection("suburbs")
-        .find_one(query, None)
-        .await
-        .unwrap()
-    {
-        Some(result) => result,
-        None => return ApiError::ServerError("Document not found").into(),
+) -> ApiResponse<PredictiveSuburbStatsResponse> {
+    let key = Snapshots::key("fetchTimeForPolygon", &*request);
+    let connection = match health.client(db) {
+        Some(client) => client.database("production"),
+        None => {
+            return snapshots
+                .fallback(&key, ApiError::database_unavailable())
+                .await
+        }
     };
-    let db_functions = DBFunctions {};
+    let suburb = match find_suburb(request.suburb_id, &connection).await {
+        Ok(suburb) => suburb,
+        Err(err) => return snapshots.fallback(&key, err).await,
+    };
+    let db_functions = cache.inner();
     let time_now = get_date_time(None);
-    match suburb.build_schedule(Some(&connection), &db_functions, None).await {
+    let schedule = match outages::schedule(&connection, &suburb, None).await {
+        Some(data) => Ok(data),
+        None => {
+            suburb
+                .build_schedule(Some(&connection), db_functions, None)
+                .await
+        }
+    };
+    match schedule {
         Ok(data) => {
             let relevant: Vec<TimeSlot> = data
                 .times_off
                 .into_iter()
                 .filter(|time| time.time_slot_bound_validation(&time_now))
                 .collect();
-            if let Some(to_return) = relevant.get(0) {
-                return ApiResponse::Ok(PredictiveSuburbStatsResponse {
-                    times_off: vec![to_return.clone()],
-                });
-            } else {
-                return ApiResponse::Ok(PredictiveSuburbStatsResponse { times_off: vec![] });
-            }
+            let data = PredictiveSuburbStatsResponse {
+                times_off: relevant.into_iter().take(1).collect(),
+            };
+            snapshots.store(key, &data).await;
+            ApiResponse::Ok(data)
         }
-        Err(err) => err.into(),
+        Err(err) => snapshots.fallback(&key, err).await,
     }
 }
 
 #[utoipa::path(get, path = "/api/fetchCurrentStage")]
 #[get("/fetchCurrentStage")]
-pub async fn get_current_stage<'a>(
+pub async fn get_current_stage(
     loadshedding_stage: &State<Option<Arc<RwLock<LoadSheddingStage>>>>,
-) -> ApiResponse<'a, i32> {
+) -> ApiResponse<i32> {
     // query end
     ApiResponse::Ok(loadshedding_stage
         .inner()
@@ -210,6 +271,22 @@ pub async fn get_current_stage<'a>(
         .stage)
 }
 
+/// The suburb whose geometry includes the polygon with this id
+async fn find_suburb(suburb_id: u32, connection: &Database) -> Result<SuburbEntity, ApiError> {
+    match connection
+        .collection::<SuburbEntity>("suburbs")
+        .find_one(doc! {"geometry" : {"$in" : [suburb_id]}}, None)
+        .await
+    {
+        Ok(Some(suburb)) => Ok(suburb),
+        Ok(None) => Err(ApiError::not_found("Document not found")),
+        Err(err) => {
+            log::error!("Couldn't look up suburb {suburb_id}: {err:?}");
+            Err(ApiError::internal("Error occured on the server, sorry :<"))
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Entity)]
 #[serde(rename_all = "camelCase")]
 #[collection_name = "stage_log"]
@@ -217,11 +294,12 @@ pub struct LoadSheddingStage {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "_id")]
     pub id: Option<ObjectId>,
+    #[index]
     pub start_time: i64,
     pub end_time: i64,
-    #[serde(skip_serializing, skip_deserializing)]
-    db: Option<Client>,
-    stage: i32,
+    pub(crate) stage: i32,
+    /// Whether automated updates may change the window, anything but
+    /// `Some(true)` locks it
     pub update: Option<bool>
 }
 
@@ -367,6 +445,7 @@ pub struct GroupEntity {
     #[serde(rename = "_id")]
     pub id: Option<ObjectId>,
     pub number: i32,
+    #[index]
     pub suburbs: Vec<ObjectId>,
     // consider a small refactor to add groups associated municipalities for better efficiency
 }
@@ -374,12 +453,15 @@ pub struct GroupEntity {
 #[derive(Debug, Serialize, Deserialize, Clone, Entity,PartialEq)]
 #[serde(rename_all = "camelCase")]
 #[collection_name = "suburbs"]
+#[index(fields = "municipality, geometry")]
 pub struct SuburbEntity {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "_id")]
     pub id: Option<ObjectId>,
     pub municipality: ObjectId,
+    #[index]
     pub name: String,
+    #[index]
     pub geometry: Vec<i32>,
 }
 
@@ -395,12 +477,14 @@ pub struct TimeScheduleEntity {
     pub stop_hour: i32,
     pub stop_minute: i32,
     pub stages: Vec<StageTimes>,
+    #[index]
     pub municipality: ObjectId,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Entity)]
 #[serde(rename_all = "camelCase")]
 #[collection_name = "municipality"]
+#[index(fields = "geometry.bounds", 2d)]
 pub struct MunicipalityEntity {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "_id")]
@@ -460,13 +544,23 @@ pub struct SuburbStatsResponse {
 #[derive(Serialize, Deserialize, Debug, ToSchema, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub struct PredictiveSuburbStatsResponse {
-    times_off: Vec<TimeSlot>,
+    pub(crate) times_off: Vec<TimeSlot>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct TimeSlot {
-    start: i64,
-    end: i64,
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+}
+
+/// Where a point is and whether it has power, for the app's home screen
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PowerStatus {
+    pub suburb: String,
+    pub power_on: bool,
+    /// The outage happening right now, or the next one within a day
+    pub next_outage: Option<TimeSlot>,
 }
 
 #[derive(Serialize, Deserialize, Debug,PartialEq)]
@@ -498,7 +592,7 @@ impl TotalTime {
 }
 
 // entity implimentations:
-fn get_date_time(time: Option<i64>) -> DateTime<FixedOffset> {
+pub(crate) fn get_date_time(time: Option<i64>) -> DateTime<FixedOffset> {
     // South African Standard Time Offset
     let sast = FixedOffset::east_opt(2 * 3600).unwrap();
     // get search time
@@ -520,37 +614,37 @@ pub trait DBFunctionsTrait: Sync {
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<Vec<TimeScheduleEntity>, ApiError<'static>>;
+    ) -> Result<Vec<TimeScheduleEntity>, ApiError>;
     async fn collect_groups<'a>(
         &self,
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<Vec<GroupEntity>, ApiError<'static>>;
+    ) -> Result<Vec<GroupEntity>, ApiError>;
     async fn collect_suburbs<'a>(
         &self,
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<Vec<SuburbEntity>, ApiError<'static>>;
+    ) -> Result<Vec<SuburbEntity>, ApiError>;
     async fn collect_one_group<'a>(
         &self,
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<GroupEntity, ApiError<'static>>;
+    ) -> Result<GroupEntity, ApiError>;
     async fn collect_stage_logs<'a>(
         &self,
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<Vec<LoadSheddingStage>, ApiError<'static>>;
+    ) -> Result<Vec<LoadSheddingStage>, ApiError>;
     async fn collect_one_stage_log<'a>(
         &self,
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<LoadSheddingStage, ApiError<'static>>;
+    ) -> Result<LoadSheddingStage, ApiError>;
 }
 
 pub struct DBFunctions {}
@@ -562,17 +656,12 @@ impl DBFunctionsTrait for DBFunctions {
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<Vec<TimeScheduleEntity>, ApiError<'static>> {
-        let result = match TimeScheduleEntity::find(query, connection.unwrap(), options).await {
-            Ok(groups) => groups.into_iter().map(|b| *b).collect(),
-            Err(err) => {
-                log::error!("Unable to Collect suburbs from cursor {err}");
-                return Err(ApiError::ServerError(
-                    "Error occured on the server, sorry :<",
-                ));
-            }
-        };
-        Ok(result)
+    ) -> Result<Vec<TimeScheduleEntity>, ApiError> {
+        let page = options.map(PageRequest::from).unwrap_or_default();
+        Ok(TimeScheduleEntity::repository(connection.unwrap())
+            .find(query, page)
+            .await?
+            .items)
     }
 
     async fn collect_suburbs<'a>(
@@ -580,17 +669,12 @@ impl DBFunctionsTrait for DBFunctions {
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<Vec<SuburbEntity>, ApiError<'static>> {
-        let result = match SuburbEntity::find(query, connection.unwrap(), options).await {
-            Ok(groups) => groups.into_iter().map(|b| *b).collect(),
-            Err(err) => {
-                log::error!("Unable to Collect suburbs from cursor {err}");
-                return Err(ApiError::ServerError(
-                    "Error occured on the server, sorry :<",
-                ));
-            }
-        };
-        Ok(result)
+    ) -> Result<Vec<SuburbEntity>, ApiError> {
+        let page = options.map(PageRequest::from).unwrap_or_default();
+        Ok(SuburbEntity::repository(connection.unwrap())
+            .find(query, page)
+            .await?
+            .items)
     }
 
     async fn collect_groups<'a>(
@@ -598,68 +682,62 @@ impl DBFunctionsTrait for DBFunctions {
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<Vec<GroupEntity>, ApiError<'static>> {
-        let result = match GroupEntity::find(query, connection.unwrap(), options).await {
-            Ok(groups) => groups.into_iter().map(|b| *b).collect(),
-            Err(err) => {
-                log::error!("Unable to Collect suburbs from cursor {err}");
-                return Err(ApiError::ServerError(
-                    "Error occured on the server, sorry :<",
-                ));
-            }
-        };
-        Ok(result)
+    ) -> Result<Vec<GroupEntity>, ApiError> {
+        let page = options.map(PageRequest::from).unwrap_or_default();
+        Ok(GroupEntity::repository(connection.unwrap())
+            .find(query, page)
+            .await?
+            .items)
     }
     async fn collect_one_group<'a>(
         &self,
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<GroupEntity, ApiError<'static>> {
-        let result = match GroupEntity::find_one(query, connection.unwrap(), options).await {
-            Some(group) => group,
+    ) -> Result<GroupEntity, ApiError> {
+        let sort = options.and_then(|options| options.sort);
+        match GroupEntity::repository(connection.unwrap())
+            .find_one(query, sort)
+            .await?
+        {
+            Some(group) => Ok(group),
             None => {
                 warn!("Error, a suburb is not associated with a group");
-                return Err(ApiError::ServerError(
+                Err(ApiError::internal(
                     "Group cannot be identified for specified suburb",
-                ));
+                ))
             }
-        };
-        Ok(result.deref().clone())
+        }
     }
     async fn collect_stage_logs<'a>(
         &self,
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<Vec<LoadSheddingStage>, ApiError<'static>> {
-        let result = match LoadSheddingStage::find(query, connection.unwrap(), options).await {
-            Ok(groups) => groups.into_iter().map(|b| *b).collect(),
-            Err(err) => {
-                log::error!("Unable to Collect suburbs from cursor {err}");
-                return Err(ApiError::ServerError(
-                    "Error occured on the server, sorry :<",
-                ));
-            }
-        };
-        Ok(result)
+    ) -> Result<Vec<LoadSheddingStage>, ApiError> {
+        let page = options.map(PageRequest::from).unwrap_or_default();
+        Ok(LoadSheddingStage::repository(connection.unwrap())
+            .find(query, page)
+            .await?
+            .items)
     }
     async fn collect_one_stage_log<'a>(
         &self,
         query: Document,
         connection: Option<&'a Database>,
         options: Option<FindOptions>,
-    ) -> Result<LoadSheddingStage, ApiError<'static>> {
-        let result = match LoadSheddingStage::find_one(query, connection.unwrap(), options).await {
-            Some(group) => group,
+    ) -> Result<LoadSheddingStage, ApiError> {
+        let sort = options.and_then(|options| options.sort);
+        match LoadSheddingStage::repository(connection.unwrap())
+            .find_one(query, sort)
+            .await?
+        {
+            Some(stage) => Ok(stage),
             None => {
-                warn!("Error, a suburb is not associated with a group");
-                return Err(ApiError::ServerError(
-                    "Group cannot be identified for specified suburb",
-                ));
+                warn!("Error, no stage change was logged before the requested time");
+                Err(ApiError::internal("Stage history cannot be identified"))
             }
-        };
-        Ok(result.deref().clone())
+        }
     }
 }
 // db functions end
@@ -669,7 +747,6 @@ impl LoadsheddingData {
             id: None,
             start_time: self.start.0.timestamp(),
             end_time: self.end.0.timestamp(),
-            db: None,
             stage: self.stage,
             update: Some(true)
         }
@@ -736,6 +813,38 @@ impl TimeScheduleEntity {
         }
         return maybe;
     }
+
+    /// Like `is_within_timeslot`, but also matches slots that run past
+    /// midnight
+    fn covers(&self, time_to_search: &DateTime<FixedOffset>) -> bool {
+        let (start, stop) = self.minutes_of_day();
+        let now = (time_to_search.hour() * 60 + time_to_search.minute()) as i32;
+        if start <= stop {
+            start <= now && now < stop
+        } else {
+            // runs past midnight
+            now >= start || now < stop
+        }
+    }
+
+    /// Day of the month the slot containing `time_to_search` started on,
+    /// which picks its groups even once it runs past midnight
+    fn starting_day(&self, time_to_search: &DateTime<FixedOffset>) -> u32 {
+        let (start, stop) = self.minutes_of_day();
+        let now = (time_to_search.hour() * 60 + time_to_search.minute()) as i32;
+        if start > stop && now < stop {
+            (*time_to_search - chrono::Duration::days(1)).day()
+        } else {
+            time_to_search.day()
+        }
+    }
+
+    fn minutes_of_day(&self) -> (i32, i32) {
+        (
+            self.start_hour * 60 + self.start_minute,
+            self.stop_hour * 60 + self.stop_minute,
+        )
+    }
 }
 
 impl MunicipalityEntity {
@@ -745,7 +854,7 @@ impl MunicipalityEntity {
         time: Option<i64>,
         connection: Option<&Database>,
         db_functions: &dyn DBFunctionsTrait,
-    ) -> Result<MapDataDefaultResponse, ApiError<'static>> {
+    ) -> Result<MapDataDefaultResponse, ApiError> {
         let mut suburbs_off = Vec::<SuburbEntity>::new();
         let time_to_search: DateTime<FixedOffset> = get_date_time(time);
         let mut geography = self.geometry.clone();
@@ -764,7 +873,7 @@ impl MunicipalityEntity {
         let mut schedules: Vec<TimeScheduleEntity> = Vec::new();
         // filter schedules to relevant ones
         for schedule in unfiltered_schedules {
-            let keep = schedule.is_within_timeslot(&time_to_search);
+            let keep = schedule.covers(&time_to_search);
             //println!("{:?}", time_to_search.hour());
             if keep {
                 schedules.push(schedule);
@@ -792,6 +901,7 @@ impl MunicipalityEntity {
 
         // go through schedules
         for doc in schedules {
+            let day = doc.starting_day(&time_to_search);
             // All the groups that could be affected by the current stage
             let times: Vec<StageTimes> = doc
                 .stages
@@ -803,12 +913,7 @@ impl MunicipalityEntity {
             // All the filtered groups affected on this day at this time
             let groups: Vec<ObjectId> = times
                 .iter()
-                .map(|schedule| {
-                    schedule
-                        .groups
-                        .get((time_to_search.day() - 1) as usize)
-                        .unwrap()
-                })
+                .map(|schedule| schedule.groups.get((day - 1) as usize).unwrap())
                 .cloned()
                 .collect();
 
@@ -835,46 +940,267 @@ impl MunicipalityEntity {
             }
         }
         // now we must mark all of our map stuff
-        for feature in &mut geography.features {
-            for suburb in &suburbs_off {
-                if suburb.geometry.contains(&feature.id) {
-                    feature.properties.power_status = Some("off".to_string());
-                    break;
-                }
+        let off: Vec<&[i32]> = suburbs_off
+            .iter()
+            .map(|suburb| &suburb.geometry[..])
+            .collect();
+        let on: Vec<&[i32]> = suburbs
+            .values()
+            .map(|suburb| &suburb.geometry[..])
+            .collect();
+        geography.mark_power_status(&off, &on);
+        Ok(MapDataDefaultResponse {
+            map_polygons: vec![geography],
+        })
+    }
+}
+
+impl GeoJson {
+    /// Marks each feature off if it's part of an `off` suburb, on if it's
+    /// part of an `on` one, and undefined otherwise
+    pub(crate) fn mark_power_status(&mut self, off: &[&[i32]], on: &[&[i32]]) {
+        for feature in &mut self.features {
+            if off.iter().any(|geometry| geometry.contains(&feature.id)) {
+                feature.properties.power_status = Some("off".to_string());
+            }
+            if feature.properties.power_status.is_none()
+                && on.iter().any(|geometry| geometry.contains(&feature.id))
+            {
+                feature.properties.power_status = Some("on".to_string());
             }
+            if feature.properties.power_status.is_none() {
+                feature.properties.power_status = Some("undefined".to_string());
+            }
+        }
+    }
+}
 
-            if let None = feature.properties.power_status {
-                for (_, suburb) in &suburbs {
-                    if suburb.geometry.contains(&feature.id) {
-                        feature.properties.power_status = Some("on".to_string());
-                        break;
-                    }
+impl MunicipalityEntity {
+    /// The id of the map feature containing the point, if any
+    pub fn feature_at(&self, longitude: f64, latitude: f64) -> Option<i32> {
+        self.geometry
+            .features
+            .iter()
+            .find(|feature| feature.geometry.contains(longitude, latitude))
+            .map(|feature| feature.id)
+    }
+}
+
+impl Geometry {
+    /// Even-odd test over every ring, so holes are left out
+    pub fn contains(&self, longitude: f64, latitude: f64) -> bool {
+        let polygons = match &self.coordinates {
+            Coordinates::Polygon(polygon) => vec![polygon],
+            Coordinates::MultiPolygon(polygons) => polygons.iter().collect(),
+        };
+
+        let mut inside = false;
+        for ring in polygons.into_iter().flatten() {
+            for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+                if a.len() < 2 || b.len() < 2 {
+                    continue;
+                }
+                let (x1, y1, x2, y2) = (a[0], a[1], b[0], b[1]);
+                if (y1 > latitude) != (y2 > latitude)
+                    && longitude < (x2 - x1) * (latitude - y1) / (y2 - y1) + x1
+                {
+                    inside = !inside;
                 }
             }
-            if let None = feature.properties.power_status {
-                feature.properties.power_status = Some("undefined".to_string());
+        }
+        inside
+    }
+}
+
+/// Finds the municipality a point falls in, along with the id of the map
+/// feature containing it
+async fn locate(
+    longitude: f64,
+    latitude: f64,
+    connection: &Database,
+) -> Result<Option<(MunicipalityEntity, i32)>, ApiError> {
+    // Bounds are stored as [[west, south], [east, north]]
+    let query = doc! {
+        "geometry.bounds.0.0": { "$lte": longitude },
+        "geometry.bounds.1.0": { "$gte": longitude },
+        "geometry.bounds.0.1": { "$lte": latitude },
+        "geometry.bounds.1.1": { "$gte": latitude },
+    };
+    let municipalities: Vec<MunicipalityEntity> = match connection
+        .collection("municipality")
+        .find(query, None)
+        .await
+    {
+        Ok(cursor) => match cursor.try_collect().await {
+            Ok(municipalities) => municipalities,
+            Err(err) => {
+                log::error!("Unable to collect municipalities from cursor {err}");
+                return Err(ApiError::internal("Error occured on the server, sorry :<"));
             }
+        },
+        Err(err) => {
+            log::error!("Database error occured when locating point: {err}");
+            return Err(ApiError::internal(
+                "Database error occured when handling request. Check logs.",
+            ));
+        }
+    };
+
+    Ok(municipalities.into_iter().find_map(|municipality| {
+        municipality
+            .feature_at(longitude, latitude)
+            .map(|feature| (municipality, feature))
+    }))
+}
+
+/// Resolves the suburb a point falls in, `None` if it isn't in any suburb we
+/// have data for.
+pub async fn suburb_at(
+    longitude: f64,
+    latitude: f64,
+    connection: &Database,
+    db_functions: &dyn DBFunctionsTrait,
+) -> Result<Option<SuburbEntity>, ApiError> {
+    let (municipality, feature) = match locate(longitude, latitude, connection).await? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    let suburbs = db_functions
+        .collect_suburbs(
+            doc! { "municipality": municipality.id, "geometry": { "$in": [feature] } },
+            Some(connection),
+            None,
+        )
+        .await?;
+    Ok(suburbs.into_iter().next())
+}
+
+/// The stage that was in effect at `time`, in seconds
+pub async fn stage_at(time: i64, connection: &Database) -> Result<i32, ApiError> {
+    let options = FindOneOptions::builder()
+        .sort(doc! { "startTime": -1 })
+        .build();
+    match connection
+        .collection::<LoadSheddingStage>("stage_log")
+        .find_one(doc! { "startTime": { "$lte": time } }, options)
+        .await
+    {
+        Ok(Some(stage)) if stage.end_time > time => Ok(stage.stage),
+        Ok(_) => Ok(0),
+        Err(err) => {
+            log::error!("Couldn't look up the stage at {time}: {err}");
+            Err(ApiError::internal(
+                "Database error occured when handling request. Check logs.",
+            ))
         }
-        Ok(MapDataDefaultResponse {
-            map_polygons: vec![geography],
-        })
+    }
+}
+
+/// Whether the schedule had power on at a point at `time`, in seconds, going
+/// by the same map `fetch_map_data` draws. `None` if we have no schedule for
+/// the point.
+pub async fn scheduled_power_on(
+    longitude: f64,
+    latitude: f64,
+    time: i64,
+    connection: &Database,
+    db_functions: &dyn DBFunctionsTrait,
+) -> Result<Option<bool>, ApiError> {
+    let (municipality, feature) = match locate(longitude, latitude, connection).await? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let stage = stage_at(time, connection).await?;
+    municipality
+        .power_on_at(feature, stage, time, Some(connection), db_functions)
+        .await
+}
+
+impl MunicipalityEntity {
+    /// Whether the schedule had power on in one of the municipality's map
+    /// features at `time` during `stage`
+    pub(crate) async fn power_on_at(
+        &self,
+        feature: i32,
+        stage: i32,
+        time: i64,
+        connection: Option<&Database>,
+        db_functions: &dyn DBFunctionsTrait,
+    ) -> Result<Option<bool>, ApiError> {
+        let map = self
+            .get_regions_at_time(stage, Some(time), connection, db_functions)
+            .await?;
+
+        Ok(map
+            .map_polygons
+            .iter()
+            .flat_map(|polygons| polygons.features.iter())
+            .find(|found| found.id == feature)
+            .and_then(|found| match found.properties.power_status.as_deref() {
+                Some("on") => Some(true),
+                Some("off") => Some(false),
+                _ => None,
+            }))
+    }
+}
+
+/// Resolves the suburb a point falls in and works out its power status from
+/// the schedule. `None` if the point isn't in any suburb we have data for.
+pub async fn power_status_at(
+    longitude: f64,
+    latitude: f64,
+    connection: &Database,
+    db_functions: &dyn DBFunctionsTrait,
+    time: Option<i64>,
+) -> Result<Option<PowerStatus>, ApiError> {
+    match suburb_at(longitude, latitude, connection, db_functions).await? {
+        Some(suburb) => Ok(Some(
+            suburb.power_status(connection, db_functions, time).await?,
+        )),
+        None => Ok(None),
     }
 }
 
 impl SuburbEntity {
+    /// Works out whether the suburb has power at `time` from its schedule
+    pub async fn power_status(
+        self,
+        connection: &Database,
+        db_functions: &dyn DBFunctionsTrait,
+        time: Option<i64>,
+    ) -> Result<PowerStatus, ApiError> {
+        let name = self.name.clone();
+        let now = get_date_time(time).timestamp();
+        let schedule = self
+            .build_schedule(Some(connection), db_functions, time)
+            .await?;
+        let next_outage = schedule.times_off.into_iter().find(|slot| slot.end > now);
+
+        let power_on = match &next_outage {
+            Some(slot) => slot.start > now,
+            None => true,
+        };
+
+        Ok(PowerStatus {
+            suburb: name,
+            power_on,
+            next_outage,
+        })
+    }
+
     pub async fn build_schedule(
         self,
         connection: Option<&Database>,
         db_functions: &dyn DBFunctionsTrait,
         time: Option<i64>,
-    ) -> Result<PredictiveSuburbStatsResponse, ApiError<'static>> {
+    ) -> Result<PredictiveSuburbStatsResponse, ApiError> {
         let time_now = get_date_time(time)
             .with_second(0)
             .unwrap()
             .with_minute(0)
             .unwrap();
         //println!("{:?}", time_now.timestamp());
-        let mut response: Vec<TimeSlot> = Vec::new();
         let day_in_future =
             get_date_time(Some((get_date_time(time) + chrono::Duration::days(1)).timestamp()));
 
@@ -886,58 +1212,67 @@ impl SuburbEntity {
             Err(err) => return Err(err),
         };
         all_stages.reverse();
+        let response = merge_slots(self.outages_between(
+            &group,
+            all_stages,
+            &schedule,
+            time_now,
+            day_in_future,
+        ));
+        return Ok(PredictiveSuburbStatsResponse {
+            times_off: response,
+        });
+    }
 
-        let mut time_to_search = time_now;
+    /// Every scheduled outage between `from` and `until`, going by
+    /// `all_stages` in the order they started, the first being the one in
+    /// effect at `from`
+    pub(crate) fn outages_between(
+        &self,
+        group: &GroupEntity,
+        mut all_stages: Vec<LoadSheddingStage>,
+        schedule: &[TimeScheduleEntity],
+        from: DateTime<FixedOffset>,
+        until: DateTime<FixedOffset>,
+    ) -> Vec<TimeSlot> {
+        let mut response: Vec<TimeSlot> = Vec::new();
+        let mut time_to_search = from;
         //println!("{:?}", time_to_search.timestamp());
-        while time_to_search < day_in_future {
+        while time_to_search < until {
             let day = time_to_search.day() as i32;
             let time_slots: Vec<TimeScheduleEntity> = schedule
-                .clone()
-                .into_iter()
+                .iter()
                 .filter(|time| {
                     // check what time it falls under
                     time.is_within_timeslot(&time_to_search)
                 })
+                .cloned()
                 .collect();
             if all_stages.len() >= 2 {
                 if all_stages[1].start_time <= time_to_search.timestamp() {
                     all_stages.remove(0);
                 }
             }
-            if let Some(slot) = self.add_time_checker(&all_stages[0], &time_slots, &group, &day) {
+            if let Some(slot) = self.add_time_checker(&all_stages[0], &time_slots, group, &day) {
                 let end_time = slot.timestamp_from_slot_times(time_to_search, false);
                 time_to_search = slot.timestamp_from_slot_times(time_to_search, true);
-                match response.last_mut() {
-                    Some(time) => {
-                        if time.end >= time_to_search.timestamp() {
-                            time.end = end_time.timestamp();
-                        } else {
-                            response.push(TimeSlot {
-                                start: time_to_search.timestamp(),
-                                end: end_time.timestamp(),
-                            });
-                        }
-                    }
-                    None => response.push(TimeSlot {
-                        start: time_to_search.timestamp(),
-                        end: end_time.timestamp(),
-                    }),
-                }
+                response.push(TimeSlot {
+                    start: time_to_search.timestamp(),
+                    end: end_time.timestamp(),
+                });
                 time_to_search = end_time;
             } else {
                 time_to_search += chrono::Duration::minutes(30);
             }
         }
-        return Ok(PredictiveSuburbStatsResponse {
-            times_off: response,
-        });
+        response
     }
     pub async fn get_total_time_down_stats(
         self,
         connection: Option<&Database>,
         db_functions: &dyn DBFunctionsTrait,
         time: Option<i64>
-    ) -> Result<SuburbStatsResponse, ApiError<'static>> {
+    ) -> Result<SuburbStatsResponse, ApiError> {
         // Time
         let mut down_time = 0;
         let mut daily_stats: HashMap<String, TotalTime> = HashMap::new();
@@ -1045,8 +1380,7 @@ impl SuburbEntity {
         from_time: &i64,
         connection: Option<&Database>,
         db_functions: &dyn DBFunctionsTrait,
-    ) -> Result<(GroupEntity, Vec<LoadSheddingStage>, Vec<TimeScheduleEntity>), ApiError<'static>>
-    {
+    ) -> Result<(GroupEntity, Vec<LoadSheddingStage>, Vec<TimeScheduleEntity>), ApiError> {
         let query = doc! {
             "suburbs" : {
                 "$in" : [self.id.unwrap()]
@@ -1062,44 +1396,7 @@ impl SuburbEntity {
             }
         };
 
-        // get all the stage changes from the past week
-        let query = doc! {
-            "startTime": {
-                "$gt": from_time
-            }
-        };
-        let find_options = FindOptions::builder().sort(doc! { "startTime": 1 }).build();
-        let mut all_stages = match db_functions
-            .collect_stage_logs(query, connection, Some(find_options))
-            .await
-        {
-            Ok(item) => item,
-            Err(err) => {
-                return Err(err);
-            }
-        };
-        all_stages.reverse();
-
-        // find first timestamp after one week ago
-        let query = doc! {
-            "startTime": {
-                "$lte": from_time
-            }
-        };
-        let find_options = FindOptions::builder()
-            .sort(doc! { "startTime": -1 })
-            .limit(1)
-            .build();
-        let first_stage_change = match db_functions
-            .collect_one_stage_log(query, connection, Some(find_options))
-            .await
-        {
-            Ok(cursor) => cursor,
-            Err(err) => {
-                return Err(err);
-            }
-        };
-        all_stages.push(first_stage_change);
+        let all_stages = stages_since(from_time, connection, db_functions).await?;
 
         // get the timeschedules
         let query = doc! {
@@ -1118,163 +1415,291 @@ impl SuburbEntity {
     }
 }
 
+/// Joins outages that run into each other
+pub(crate) fn merge_slots(slots: impl IntoIterator<Item = TimeSlot>) -> Vec<TimeSlot> {
+    let mut merged: Vec<TimeSlot> = Vec::new();
+    for slot in slots {
+        match merged.last_mut() {
+            Some(time) if time.end >= slot.start => time.end = slot.end,
+            _ => merged.push(slot),
+        }
+    }
+    merged
+}
+
+/// Every stage change after `from_time`, newest first, followed by the one
+/// that was in effect at `from_time`
+pub(crate) async fn stages_since(
+    from_time: &i64,
+    connection: Option<&Database>,
+    db_functions: &dyn DBFunctionsTrait,
+) -> Result<Vec<LoadSheddingStage>, ApiError> {
+    // get all the stage changes after from_time
+    let query = doc! {
+        "startTime": {
+            "$gt": from_time
+        }
+    };
+    let find_options = FindOptions::builder().sort(doc! { "startTime": 1 }).build();
+    let mut all_stages = match db_functions
+        .collect_stage_logs(query, connection, Some(find_options))
+        .await
+    {
+        Ok(item) => item,
+        Err(err) => {
+            return Err(err);
+        }
+    };
+    all_stages.reverse();
+
+    // find the stage in effect at from_time
+    let query = doc! {
+        "startTime": {
+            "$lte": from_time
+        }
+    };
+    let find_options = FindOptions::builder()
+        .sort(doc! { "startTime": -1 })
+        .limit(1)
+        .build();
+    let first_stage_change = match db_functions
+        .collect_one_stage_log(query, connection, Some(find_options))
+        .await
+    {
+        Ok(cursor) => cursor,
+        Err(err) => {
+            return Err(err);
+        }
+    };
+    all_stages.push(first_stage_change);
+    Ok(all_stages)
+}
+
 // Rocket State Loop Objects
+const STAGE_DATA_URL: &str =
+    "https://d42sspn7yra3u.cloudfront.net/eskom-load-shedding-extended-status.json";
+
 impl LoadSheddingStage {
-    pub async fn set_stage(&mut self) {
-        // get the next thing from db
-        if let Some(client) = &self.db.as_ref() {
-            let con = client.database("production");
-            let now = get_date_time(None).timestamp();
-            let query = doc! {
-                "startTime" : {
-                    "$lte" : now
-                }
-            };
-            let filter = doc! {
-                "startTime" : -1
-            };
-            let find_options = FindOneOptions::builder().sort(filter).build();
-            let new_status: LoadSheddingStage = con
-                .collection("stage_log")
-                .find_one(query, find_options)
-                .await
-                .unwrap()
-                .unwrap();
-            println!("self is: {:?}", self);
-            println!("new is: {:?}", new_status);
-            self.end_time = new_status.end_time;
-            self.start_time = new_status.start_time;
-            self.stage = new_status.stage;
-            println!("self is after operation: {:?}", self);
-            //println!("{:?}", self);
+    /// Catches `stage` up with the stage log, only holding the lock to swap
+    /// in what was read
+    pub async fn refresh(stage: &RwLock<Self>, client: &Client) -> Result<(), String> {
+        let con = client.database("production");
+        let now = get_date_time(None).timestamp();
+        let query = doc! {
+            "startTime" : {
+                "$lte" : now
+            }
+        };
+        let filter = doc! {
+            "startTime" : -1
+        };
+        let find_options = FindOneOptions::builder().sort(filter).build();
+        let new_status: LoadSheddingStage = con
+            .collection("stage_log")
+            .find_one(query, find_options)
+            .await
+            .map_err(|err| err.to_string())?
+            .ok_or("Nothing in the stage log has started yet")?;
+        let previous = {
+            let mut current = stage.write().await;
+            let previous = (current.start_time, current.stage);
+            current.end_time = new_status.end_time;
+            current.start_time = new_status.start_time;
+            current.stage = new_status.stage;
+            previous
+        };
+        // The first lookup after starting up isn't a change
+        if previous.0 != 0 && previous.1 != new_status.stage {
+            webhooks::stage_changed(previous.1, &new_status, client).await;
         }
+        Ok(())
     }
+}
 
-    pub async fn request_stage_data_update(&mut self) -> Result<i32, reqwest::Error> {
-        loop {
-            let stage = reqwest::get(
-                "https://d42sspn7yra3u.cloudfront.net/eskom-load-shedding-extended-status.json",
-            )
-            .await?;
-            if stage.status().is_success() {
-                let text = stage.text().await?;
-                let times: Vec<LoadsheddingData> = serde_json::from_str(&text).unwrap();
-                self.log_stage_data(times).await;
-                break;
+/// Fetches Eskom's published stages and merges them into the stage log
+pub async fn request_stage_data_update(
+    client: &Client,
+    cache: Option<&ScheduleCache>,
+) -> Result<(), String> {
+    let response = reqwest::get(STAGE_DATA_URL)
+        .await
+        .map_err(|err| err.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!(
+            "{STAGE_DATA_URL} answered {}, check that it's still up",
+            response.status()
+        ));
+    }
+    let provenance = Provenance::new(ESKOM_SOURCE, now_millis());
+    let times: Vec<LoadsheddingData> = response.json().await.map_err(|err| err.to_string())?;
+    let result = log_stage_data(times, &client.database("production"), &provenance).await;
+    // Some of it may have been logged even if something failed
+    if let Some(cache) = cache {
+        cache.invalidate(LoadSheddingStage::COLLECTION);
+    }
+    result
+}
+
+/// Merges `times` into the stage log, recording a revision for each change
+/// once it's made. Windows that overlap locked ones are clipped around
+/// them, and recorded as suppressed as they were sent.
+async fn log_stage_data(
+    mut times: Vec<LoadsheddingData>,
+    db_con: &Database,
+    provenance: &Provenance,
+) -> Result<(), String> {
+    let query = doc! {
+        "startTime" : -1
+    };
+    let find_options = FindOneOptions::builder().sort(query).build();
+
+    // Execute the query to find the latest item
+    let latest_in_db = db_con
+        .collection::<LoadSheddingStage>("stage_log")
+        .find_one(None, find_options)
+        .await
+        .map_err(|err| err.to_string())?
+        .map_or(0, |latest| latest.start_time);
+    while let Some(new_data) = times.pop() {
+        let window = new_data.convert_to_loadsheddingstage();
+        let locked = locked_overlaps(&window, db_con).await?;
+        let parts = if locked.is_empty() {
+            vec![window]
+        } else {
+            log::info!(
+                "Leaving the locked parts of the stage log from {} to {} alone",
+                new_data.start.0,
+                new_data.end.0
+            );
+            provenance.suppressed(&window).record_once(db_con).await?;
+            unlocked_parts(&window, &locked)
+        };
+        for part in parts {
+            if latest_in_db >= part.start_time {
+                update_db_with_changes(part, db_con, provenance).await?;
             } else {
-                warn!("Connection to https://d42sspn7yra3u.cloudfront.net/eskom-load-shedding-extended-status.json Dropped before any operations could take place. Check that url is still up {:?}", stage);
+                announce(part, db_con, provenance).await?;
             }
-            thread::sleep(std::time::Duration::from_secs(10));
         }
-        Ok(self.stage)
     }
+    Ok(())
+}
 
-    async fn log_stage_data(&mut self, mut times: Vec<LoadsheddingData>) {
-        if let Some(client) = &self.db.as_ref() {
-            let db_con = &client.database("production");
-            let query = doc! {
-                "startTime" : -1
-            };
-            let find_options = FindOneOptions::builder().sort(query).build();
+/// The windows `window` overlaps that have been locked against automated
+/// updates, which is any without `update` set to true
+async fn locked_overlaps(
+    window: &LoadSheddingStage,
+    db_con: &Database,
+) -> Result<Vec<LoadSheddingStage>, String> {
+    let filter = doc! {
+        "startTime" : {"$lt" : window.end_time},
+        "endTime" : {"$gt" : window.start_time},
+        "update" : {"$ne" : true}
+    };
+    LoadSheddingStage::repository(db_con)
+        .find(filter, PageRequest::all())
+        .await
+        .map(|page| page.items)
+        .map_err(|err| err.to_string())
+}
 
-            // Execute the query to find the latest item
-            let result: LoadSheddingStage = match db_con
-                .collection("stage_log")
-                .find_one(None, find_options)
-                .await
-                .unwrap()
-            {
-                Some(data) => data,
-                None => LoadSheddingStage {
-                    id: None,
-                    stage: -1,
-                    start_time: 0,
-                    end_time: 0,
-                    db: None,
-                    update: Some(true)
-                },
-            };
-            let latest_in_db = result.start_time;
-            loop {
-                let next = times.pop();
-                if let Some(new_data) = next {
-                    if latest_in_db >= new_data.start.0.timestamp() {
-                        let _ = self.update_db_with_changes(new_data, db_con).await;
-                    } else {
-                        let _ = new_data.convert_to_loadsheddingstage().insert(db_con).await;
-                    }
-                } else {
-                    break;
-                }
-            }
-            self.set_stage().await;
-        } else {
-            return ();
+/// What's left of `window` once the `locked` windows are cut out of it, in
+/// order
+pub(crate) fn unlocked_parts(
+    window: &LoadSheddingStage,
+    locked: &[LoadSheddingStage],
+) -> Vec<LoadSheddingStage> {
+    let mut locked: Vec<(i64, i64)> = locked
+        .iter()
+        .map(|locked| (locked.start_time, locked.end_time))
+        .collect();
+    locked.sort();
+    let part = |start_time: i64, end_time: i64| LoadSheddingStage {
+        start_time,
+        end_time,
+        ..window.clone()
+    };
+
+    let mut parts = Vec::new();
+    let mut from = window.start_time;
+    for (start, end) in locked {
+        if from >= window.end_time {
+            break;
+        }
+        if start > from {
+            parts.push(part(from, start.min(window.end_time)));
         }
+        from = from.max(end);
     }
+    if from < window.end_time {
+        parts.push(part(from, window.end_time));
+    }
+    parts
+}
 
-    async fn update_db_with_changes(&self, new_data:LoadsheddingData, db_con:&Database) {
-        // findone that matches our times.
-        let query = doc! {
-            "startTime" : new_data.start.0.timestamp(),
-            "endTime" : new_data.end.0.timestamp()
-        };
-        //match LoadSheddingStage::find_one(query, db_con, None).await {
-        match db_con
+async fn announce(
+    window: LoadSheddingStage,
+    db_con: &Database,
+    provenance: &Provenance,
+) -> Result<(), String> {
+    let revision = provenance.announced(&window);
+    window.insert(db_con).await.map_err(|err| err.to_string())?;
+    revision.record(db_con).await.map(|_| ())
+}
+
+async fn update_db_with_changes(
+    new_data: LoadSheddingStage,
+    db_con: &Database,
+    provenance: &Provenance,
+) -> Result<(), String> {
+    // findone that matches our times.
+    let query = doc! {
+        "startTime" : new_data.start_time,
+        "endTime" : new_data.end_time
+    };
+    match db_con
         .collection::<LoadSheddingStage>("stage_log")
         .find_one(query, None)
         .await
-        .unwrap() {
-            // if match
-            // check similarities
-            // if stage change: update
-            Some(mut db_data) => {
-                if db_data.stage != new_data.stage {
-                    let update =
-                        mongodb::options::UpdateModifications::Document(doc! {
-                            "$set" : {"stage" : new_data.stage}
-                        });
-                    if let Some(update_value) = db_data.update {
-                        if update_value {
-                            let _ = db_data.update(update, db_con).await;
-                        }
-                    }
-                }
+        .map_err(|err| err.to_string())?
+    {
+        // if match
+        // check similarities
+        // if stage change: update
+        Some(mut db_data) => {
+            if db_data.stage != new_data.stage && db_data.update == Some(true) {
+                let revision = provenance.changed(&db_data, new_data.stage);
+                let update = mongodb::options::UpdateModifications::Document(doc! {
+                    "$set" : {"stage" : new_data.stage}
+                });
+                db_data
+                    .update(update, db_con)
+                    .await
+                    .map_err(|err| err.to_string())?;
+                revision.record(db_con).await?;
             }
-            // else if no match
-            // find all that encapsulate this new time.
-            // delete all of them
-            // insert
-            None => {
-                let filter = doc! {
-                    "startTime" : {"$lt" : new_data.end.0.timestamp()},
-                    "endTime" : {"$gt" : new_data.start.0.timestamp()}
-                };
-                // Can be optimized into a delete many
-                match LoadSheddingStage::find(filter, db_con, None).await {
-                    Ok(data) => {
-                        for stage in data {
-                            let _ = stage.delete(db_con).await;
-                        }
-                        let _ = new_data
-                            .convert_to_loadsheddingstage()
-                            .insert(db_con)
-                            .await;
-                    }
-                    Err(_) => {
-                        let _ = new_data
-                            .convert_to_loadsheddingstage()
-                            .insert(db_con)
-                            .await;
-                    }
-                }
+        }
+        // else if no match
+        // find all that encapsulate this new time.
+        // withdraw all of them
+        // insert
+        None => {
+            let filter = doc! {
+                "startTime" : {"$lt" : new_data.end_time},
+                "endTime" : {"$gt" : new_data.start_time}
+            };
+            let overlapping = LoadSheddingStage::find(filter, db_con, None)
+                .await
+                .map_err(|err| err.to_string())?;
+            for stage in overlapping {
+                let revision = provenance.withdrawn(&stage);
+                stage.delete(db_con).await.map_err(|err| err.to_string())?;
+                revision.record(db_con).await?;
             }
-        };
-    }
-
-    pub fn set_db(&mut self, db: &Client) {
-        self.db = Some(db.to_owned());
-    }
+            announce(new_data, db_con, provenance).await?;
+        }
+    };
+    Ok(())
 }
 
 #[async_trait]
@@ -1292,53 +1717,57 @@ impl Fairing for StageUpdater {
             stage: 0,
             start_time: 0,
             end_time: 0,
-            db: None,
             update: Some(true)
         }));
         let rocket = rocket.manage(Some(stage_info));
         Ok(rocket)
     }
+
     async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
-        let db = rocket.state::<Option<Client>>().unwrap();
-        let stage_updater = rocket
+        let client = match rocket.state::<Option<Client>>().unwrap() {
+            Some(client) => client.clone(),
+            None => return,
+        };
+        let stage = match rocket
             .state::<Option<Arc<RwLock<LoadSheddingStage>>>>()
-            .unwrap();
-        if let Some(stage) = stage_updater {
-            {
-                let mut stage_ref = stage.as_ref().clone().write().await;
-                if let Some(db) = db {
-                    stage_ref.set_db(&db.clone());
-                }
+            .unwrap()
+        {
+            Some(stage) => stage.clone(),
+            None => return,
+        };
+        let cache = rocket.state::<ScheduleCache>().cloned();
+        let scheduler = rocket.state::<Scheduler>().unwrap();
+
+        let job = Job::new(
+            "stage refresh",
+            Schedule::from_env(
+                "STAGE_REFRESH_SCHEDULE",
+                Schedule::every(StdDuration::from_secs(20 * 60)),
+            ),
+        )
+        .jitter(StdDuration::from_secs(30));
+        let (refresh_stage, refresh_client) = (stage.clone(), client.clone());
+        scheduler.spawn(job, rocket.shutdown(), move || {
+            let (stage, client) = (refresh_stage.clone(), refresh_client.clone());
+            async move { LoadSheddingStage::refresh(&stage, &client).await }
+        });
+
+        let job = Job::new(
+            "eskom stage fetch",
+            Schedule::from_env(
+                "STAGE_FETCH_SCHEDULE",
+                Schedule::every(StdDuration::from_secs(5 * 60 * 60)),
+            ),
+        )
+        .jitter(StdDuration::from_secs(5 * 60))
+        .backoff(StdDuration::from_secs(10), StdDuration::from_secs(30 * 60));
+        scheduler.spawn(job, rocket.shutdown(), move || {
+            let (stage, client, cache) = (stage.clone(), client.clone(), cache.clone());
+            async move {
+                request_stage_data_update(&client, cache.as_ref()).await?;
+                LoadSheddingStage::refresh(&stage, &client).await
             }
-            let stage_info_ref = stage.clone();
-            thread::spawn(move || {
-                loop {
-                    {
-                        let stage_info = stage_info_ref.write();
-                        let runtime = Runtime::new().unwrap();
-                        let mut info = runtime.block_on(stage_info);
-                        let stage = info.set_stage();
-                        let _ = runtime.block_on(stage);
-                    }
-                    // Perform any other necessary processing on stage info
-                    thread::sleep(std::time::Duration::from_secs(1600)); // Sleep for 20 mins
-                }
-            });
-            let stage_info_ref = stage.clone();
-            thread::spawn(move || {
-                loop {
-                    {
-                        let stage_info = stage_info_ref.write();
-                        let runtime = Runtime::new().unwrap();
-                        let mut info = runtime.block_on(stage_info);
-                        let stage = info.request_stage_data_update();
-                        let _ = runtime.block_on(stage);
-                    }
-                    // Perform any other necessary processing on stage info
-                    thread::sleep(std::time::Duration::from_secs(18000)); // Sleep for 5 hours
-                }
-            });
-        }
+        });
     }
 }
 
@@ -0,0 +1,46 @@
+use log::{info, warn};
+use serde::Serialize;
+use std::env;
+
+#[derive(Debug, Serialize)]
+struct MailRequest<'a> {
+    to: &'a str,
+    subject: &'a str,
+    text: &'a str,
+}
+
+/// Sends an email through the HTTP mail API at `MAIL_API_URL`, authenticating
+/// with `MAIL_API_KEY`. Without a mail API the message is only logged, which
+/// is enough for local development.
+pub async fn send_mail(to: &str, subject: &str, text: &str) -> Result<(), reqwest::Error> {
+    let url = match env::var("MAIL_API_URL") {
+        Ok(url) => url,
+        Err(_) => {
+            warn!("MAIL_API_URL not set, not sending \"{subject}\" to {to}:\n{text}");
+            return Ok(());
+        }
+    };
+
+    let mut request = reqwest::Client::new()
+        .post(url)
+        .json(&MailRequest { to, subject, text });
+    if let Ok(key) = env::var("MAIL_API_KEY") {
+        request = request.bearer_auth(key);
+    }
+    request.send().await?.error_for_status()?;
+
+    info!("Sent \"{subject}\" to {to}");
+    Ok(())
+}
+
+/// Appends `code` to `message`, along with a link to the page at `path`
+/// in the web app when `APP_URL` is set.
+pub fn with_link(message: &str, path: &str, code: &str) -> String {
+    match env::var("APP_URL") {
+        Ok(app_url) => format!(
+            "{message}: {code}\n\nOr follow this link: {}/{path}?token={code}",
+            app_url.trim_end_matches('/')
+        ),
+        Err(_) => format!("{message}: {code}"),
+    }
+}
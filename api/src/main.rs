@@ -1,31 +1,61 @@
 mod ai;
 mod api;
+mod attachments;
 mod auth;
+mod cache;
+mod categories;
 mod db;
 mod dns;
+mod incidents;
+mod keys;
 mod loadshedding;
+mod mail;
+mod migrations;
+mod oidc;
+mod outages;
+mod ratelimit;
 mod reporting;
+mod repository;
+mod scheduler;
 mod scraper;
+mod snapshot;
+mod stages;
+mod storage;
 #[cfg(test)]
 mod tests;
 mod user;
+mod webhooks;
 
 use crate::scraper::UploadRequest;
-use api::ApiError;
+use api::{ApiError, ApiResponse, RequestIds};
 
+use auth::SessionSweeper;
 use bson::doc;
+use cache::ScheduleCache;
+use db::{DbMonitor, IndexManager};
+use incidents::OutageDetector;
+use keys::KeyRotator;
 use loadshedding::StageUpdater;
 use log::{info, warn, LevelFilter};
+use migrations::Migrations;
 use mongodb::options::ClientOptions;
 use mongodb::Client;
+use oidc::OidcProviders;
+use outages::OutageMaterializer;
+use ratelimit::RateLimiter;
+use repository::MongoStore;
 use rocket::config::TlsConfig;
 use rocket::data::{Limits, ToByteUnit};
 use rocket::figment::Figment;
 use rocket::fs::FileServer;
+use scheduler::Scheduler;
+use snapshot::Snapshots;
+use storage::Storage;
+use webhooks::WebhookDispatcher;
 
 use rocket::http::Method;
 use rocket::serde::json::Json;
-use rocket::{post, routes, Build, Rocket, State};
+use rocket::{catchers, post, routes, Build, Rocket, State};
 use rocket_cors::{AllowedHeaders, CorsOptions};
 use std::env;
 use std::net::IpAddr;
@@ -40,34 +70,120 @@ const DB_NAME: &'static str = "wip";
 #[openapi(
     paths(
         user::create_user,
+        user::get_user,
+        user::update_user,
+        user::delete_user,
+        user::change_password,
+        user::change_email,
+        user::verify_email_change,
+        user::export_user_data,
+        user::admin_export_user_data,
         loadshedding::get_current_stage,
         loadshedding::fetch_map_data,
         loadshedding::fetch_schedule,
         loadshedding::fetch_suburb_stats,
         loadshedding::fetch_time_for_polygon,
+        stages::get_stages,
+        stages::get_stage_revisions,
+        stages::preview_stage_override,
+        stages::override_stage,
+        stages::lock_stage,
+        cache::get_cache_stats,
+        scheduler::get_jobs,
         auth::authenticate,
+        keys::get_jwks,
+        auth::get_sessions,
+        auth::revoke_session,
         ai::get_ai_info,
         user::get_saved_places,
         user::add_saved_place,
         user::delete_saved_place,
+        user::update_saved_place,
+        user::reorder_saved_places,
+        user::share_saved_places,
+        user::unshare_saved_places,
+        user::get_shared_places,
         reporting::create_report,
-        reporting::get_reports
+        reporting::get_reports,
+        reporting::confirm_report,
+        reporting::dispute_report,
+        reporting::hide_report,
+        reporting::delete_report,
+        categories::get_categories,
+        categories::admin_get_categories,
+        categories::create_category,
+        categories::update_category,
+        categories::retire_category,
+        attachments::upload_report_photo,
+        attachments::get_report_photo,
+        attachments::get_report_photo_thumbnail,
+        attachments::delete_report_photo,
+        incidents::get_incidents,
+        incidents::get_incident,
+        incidents::get_unscheduled_outages,
+        webhooks::create_webhook,
+        webhooks::get_webhooks,
+        webhooks::delete_webhook,
+        webhooks::get_deliveries
     ),
     components(schemas(
         auth::AuthRequest,
         auth::AuthType,
         user::NewUser,
         user::UserLocation,
+        user::UserProfile,
+        user::UpdateUser,
+        user::ChangePassword,
+        user::ChangeEmail,
+        user::VerifyEmailChange,
+        user::UserDataExport,
+        user::ExportedReport,
+        user::ExportedVote,
+        user::ExportedWebhook,
+        auth::Session,
         loadshedding::MapDataRequest,
         loadshedding::MapDataDefaultResponse,
         loadshedding::PredictiveSuburbStatsResponse,
         loadshedding::SuburbStatsRequest,
+        stages::StageWindow,
+        stages::StageRevisionResponse,
+        stages::RevisionKind,
+        stages::StageOverride,
+        stages::StageLock,
+        stages::StageOverrideResponse,
+        outages::AffectedSuburb,
+        cache::CacheStats,
+        scheduler::JobStatus,
         api::ResponseString,
         api::ApiError,
+        api::ErrorCode,
+        api::FieldError,
         ai::AiInfoRequest,
         user::SavedPlace,
+        user::SavedPlaceStatus,
+        user::UpdateSavedPlace,
+        user::PlaceShare,
+        user::SharedPlaces,
+        loadshedding::PowerStatus,
         reporting::NewUserReport,
-        reporting::ReportType
+        categories::ReportCategory,
+        categories::NewReportCategory,
+        categories::UpdateReportCategory,
+        categories::Severity,
+        reporting::UserReportResponse,
+        reporting::HideReport,
+        attachments::PhotoUpload,
+        attachments::ReportPhotoResponse,
+        incidents::IncidentStatus,
+        incidents::IncidentResponse,
+        incidents::IncidentDetail,
+        incidents::SuburbOutages,
+        webhooks::WebhookEvent,
+        webhooks::NewWebhook,
+        webhooks::WebhookResponse,
+        webhooks::DeliveryStatus,
+        webhooks::DeliveryAttempt,
+        webhooks::DeliveryResponse
     )),
     info(title = "Where Is The Power API Specification"),
     modifiers(&SecurityAddon)
@@ -91,24 +207,49 @@ async fn upload_data(
     state: &State<Option<Client>>,
     upload_data: Json<UploadRequest>,
     ip: IpAddr,
-) -> Result<&'static str, Json<ApiError<'static>>> {
+) -> ApiResponse<&'static str> {
     if !ip.is_loopback() {
-        return Ok("304 you do not have access to this resource");
+        return ApiError::forbidden("You don't have access to this resource").into();
     }
-    if state.is_none() {
-        return Err(Json(ApiError::ServerError(
-            "Database is unavailable. Please try again later!",
-        )));
+    let client = match state.inner() {
+        Some(client) => client,
+        None => return ApiError::database_unavailable().into(),
+    };
+    match upload_data.into_inner().add_data(client, "staging").await {
+        Ok(()) => {
+            ApiResponse::Ok("Data Successfully added to staging database and ready for review")
+        }
+        Err(err) => err.into(),
     }
-    let data = upload_data.into_inner();
-    // Process the data and return an appropriate response
-    // validate
-    let add_data = data
-        .add_data(&state.inner().as_ref().unwrap(), "staging")
-        .await;
-    match add_data {
-        Ok(()) => return Ok("Data Successfully added to staging database and ready for review"),
-        Err(e) => return Err(e),
+}
+
+/// Puts everything reviewed in staging live, and drops the cached schedules
+/// so the new data is served straight away
+#[post("/promote")]
+async fn promote_data(
+    state: &State<Option<Client>>,
+    cache: &State<ScheduleCache>,
+    ip: IpAddr,
+) -> ApiResponse<String> {
+    if !ip.is_loopback() {
+        return ApiError::forbidden("You don't have access to this resource").into();
+    }
+    let client = match state.inner() {
+        Some(client) => client,
+        None => return ApiError::database_unavailable().into(),
+    };
+    let result = scraper::promote_staging(
+        &MongoStore::new(client, "staging"),
+        &MongoStore::new(client, "production"),
+    )
+    .await;
+    // Part of it may have moved even if something failed
+    cache.invalidate_schedules();
+    match result {
+        Ok(moved) => ApiResponse::Ok(format!(
+            "Promoted {moved} documents from staging to production"
+        )),
+        Err(err) => err.into(),
     }
 }
 
@@ -135,8 +276,14 @@ fn setup_logger() -> Result<(), fern::InitError> {
 }
 
 async fn get_config() -> Figment {
-    let mut figment =
-        rocket::Config::figment().merge(("limits", Limits::new().limit("json", 7.megabytes())));
+    let limits = Limits::new()
+        .limit("json", 7.megabytes())
+        .limit(
+            "data-form",
+            (attachments::MAX_PHOTO_BYTES + 64 * 1024).bytes(),
+        )
+        .limit("file", attachments::MAX_PHOTO_BYTES.bytes());
+    let mut figment = rocket::Config::figment().merge(("limits", limits));
 
     let ssl_cert = if !tokio::fs::try_exists("ssl/ssl_cert.pem")
         .await
@@ -210,8 +357,10 @@ async fn build_rocket() -> Rocket<Build> {
     }
     .to_cors()
     .unwrap();
+    let oidc_providers = OidcProviders::from_env();
 
     let rocket_no_state = || {
+        let limiter = RateLimiter::from_env(None);
         rocket::custom(figment.clone())
             .mount(
                 "/",
@@ -222,60 +371,185 @@ async fn build_rocket() -> Rocket<Build> {
                 "/api",
                 routes!(
                     auth::authenticate,
+                    auth::get_sessions,
+                    auth::revoke_session,
                     user::create_user,
+                    user::get_user,
+                    user::update_user,
+                    user::delete_user,
+                    user::change_password,
+                    user::change_email,
+                    user::verify_email_change,
+                    user::export_user_data,
+                    user::admin_export_user_data,
                     loadshedding::get_current_stage,
                     loadshedding::fetch_map_data,
                     loadshedding::fetch_suburb_stats,
                     loadshedding::fetch_schedule,
                     loadshedding::fetch_time_for_polygon,
+                    stages::get_stages,
+                    stages::get_stage_revisions,
+                    stages::preview_stage_override,
+                    stages::override_stage,
+                    stages::lock_stage,
+                    cache::get_cache_stats,
+                    scheduler::get_jobs,
                     user::add_saved_place,
                     user::get_saved_places,
                     ai::get_ai_info,
                     user::delete_saved_place,
+                    user::update_saved_place,
+                    user::reorder_saved_places,
+                    user::share_saved_places,
+                    user::unshare_saved_places,
+                    user::get_shared_places,
                     reporting::create_report,
-                    reporting::get_reports
+                    reporting::get_reports,
+                    reporting::confirm_report,
+                    reporting::dispute_report,
+                    reporting::hide_report,
+                    reporting::delete_report,
+                    categories::get_categories,
+                    categories::admin_get_categories,
+                    categories::create_category,
+                    categories::update_category,
+                    categories::retire_category,
+                    attachments::upload_report_photo,
+                    attachments::get_report_photo,
+                    attachments::get_report_photo_thumbnail,
+                    attachments::delete_report_photo,
+                    incidents::get_incidents,
+                    incidents::get_incident,
+                    incidents::get_unscheduled_outages,
+                    webhooks::create_webhook,
+                    webhooks::get_webhooks,
+                    webhooks::delete_webhook,
+                    webhooks::get_deliveries
                 ),
             )
-            .mount("/upload", routes![upload_data])
+            .mount("/upload", routes![upload_data, promote_data])
+            .mount("/", routes![keys::get_jwks, ratelimit::rate_limited])
             .mount(
                 "/api-docs",
                 FileServer::new("api-docs", rocket::fs::Options::IndexFile),
             )
+            .attach(DbMonitor)
+            .attach(Migrations)
+            .attach(declared_indexes())
+            .attach(production_indexes())
             .attach(StageUpdater)
+            .attach(KeyRotator)
+            .attach(SessionSweeper)
+            .attach(OutageDetector)
+            .attach(OutageMaterializer)
+            .attach(WebhookDispatcher)
+            .register("/", catchers![api::default_catcher])
+            .attach(RequestIds)
             .attach(cors.clone())
+            .attach(limiter.clone())
+            .manage(limiter)
+            .manage(Storage::from_env())
+            .manage(Snapshots::default())
+            .manage(ScheduleCache::default())
+            .manage(Scheduler::default())
+            .manage(oidc_providers.clone())
             .manage::<Option<Client>>(None)
     };
 
-    match ClientOptions::parse(&db_uri).await {
+    match connect_options(&db_uri).await {
         Ok(client_options) => match Client::with_options(client_options) {
-            Ok(client) => rocket::custom(figment.clone())
-                .mount(
-                    "/",
-                    SwaggerUi::new("/swagger-ui/<_..>")
-                        .url("/api-docs/openapi.json", ApiDoc::openapi()),
-                )
-                .mount(
-                    "/api",
-                    routes!(
-                        auth::authenticate,
-                        user::create_user,
-                        loadshedding::get_current_stage,
-                        loadshedding::fetch_map_data,
-                        loadshedding::fetch_suburb_stats,
-                        loadshedding::fetch_schedule,
-                        loadshedding::fetch_time_for_polygon,
-                        user::add_saved_place,
-                        user::get_saved_places,
-                        ai::get_ai_info,
-                        user::delete_saved_place,
-                        reporting::create_report,
-                        reporting::get_reports
-                    ),
-                )
-                .mount("/upload", routes![upload_data])
-                .attach(StageUpdater)
-                .attach(cors)
-                .manage(Some(client)),
+            Ok(client) => {
+                let limiter = RateLimiter::from_env(Some(&client));
+                rocket::custom(figment.clone())
+                    .mount(
+                        "/",
+                        SwaggerUi::new("/swagger-ui/<_..>")
+                            .url("/api-docs/openapi.json", ApiDoc::openapi()),
+                    )
+                    .mount(
+                        "/api",
+                        routes!(
+                            auth::authenticate,
+                            auth::get_sessions,
+                            auth::revoke_session,
+                            user::create_user,
+                            user::get_user,
+                            user::update_user,
+                            user::delete_user,
+                            user::change_password,
+                            user::change_email,
+                            user::verify_email_change,
+                            user::export_user_data,
+                            user::admin_export_user_data,
+                            loadshedding::get_current_stage,
+                            loadshedding::fetch_map_data,
+                            loadshedding::fetch_suburb_stats,
+                            loadshedding::fetch_schedule,
+                            loadshedding::fetch_time_for_polygon,
+                            stages::get_stages,
+                            stages::get_stage_revisions,
+                            stages::preview_stage_override,
+                            stages::override_stage,
+                            stages::lock_stage,
+                            cache::get_cache_stats,
+                            scheduler::get_jobs,
+                            user::add_saved_place,
+                            user::get_saved_places,
+                            ai::get_ai_info,
+                            user::delete_saved_place,
+                            user::update_saved_place,
+                            user::reorder_saved_places,
+                            user::share_saved_places,
+                            user::unshare_saved_places,
+                            user::get_shared_places,
+                            reporting::create_report,
+                            reporting::get_reports,
+                            reporting::confirm_report,
+                            reporting::dispute_report,
+                            reporting::hide_report,
+                            reporting::delete_report,
+                            categories::get_categories,
+                            categories::admin_get_categories,
+                            categories::create_category,
+                            categories::update_category,
+                            categories::retire_category,
+                            attachments::upload_report_photo,
+                            attachments::get_report_photo,
+                            attachments::get_report_photo_thumbnail,
+                            attachments::delete_report_photo,
+                            incidents::get_incidents,
+                            incidents::get_incident,
+                            incidents::get_unscheduled_outages,
+                            webhooks::create_webhook,
+                            webhooks::get_webhooks,
+                            webhooks::delete_webhook,
+                            webhooks::get_deliveries
+                        ),
+                    )
+                    .mount("/upload", routes![upload_data, promote_data])
+                    .mount("/", routes![keys::get_jwks, ratelimit::rate_limited])
+                    .attach(DbMonitor)
+                    .attach(Migrations)
+                    .attach(declared_indexes())
+                    .attach(production_indexes())
+                    .attach(StageUpdater)
+                    .attach(KeyRotator)
+                    .attach(SessionSweeper)
+                    .attach(OutageDetector)
+                    .attach(OutageMaterializer)
+                    .attach(WebhookDispatcher)
+                    .register("/", catchers![api::default_catcher])
+                    .attach(RequestIds)
+                    .attach(cors)
+                    .attach(limiter.clone())
+                    .manage(limiter)
+                    .manage(Storage::from_env())
+                    .manage(Snapshots::default())
+                    .manage(ScheduleCache::default())
+                    .manage(Scheduler::default())
+                    .manage(oidc_providers)
+                    .manage(Some(client))
+            }
             Err(err) => {
                 warn!("Couldn't create database client! {err:?}");
                 rocket_no_state()
@@ -288,10 +562,95 @@ async fn build_rocket() -> Rocket<Build> {
     }
 }
 
+/// Every entity whose indexes are created on liftoff
+fn declared_indexes() -> IndexManager {
+    IndexManager::default()
+        .with::<user::User>()
+        .with::<auth::AuthCookie>()
+        .with::<reporting::UserReport>()
+        .with::<incidents::Incident>()
+        .with::<categories::ReportCategory>()
+        .with::<webhooks::WebhookSubscription>()
+        .with::<webhooks::WebhookDelivery>()
+        .with::<migrations::AppliedMigration>()
+}
+
+/// The schedule data lives in its own database
+fn production_indexes() -> IndexManager {
+    IndexManager::in_database("production")
+        .with::<loadshedding::LoadSheddingStage>()
+        .with::<loadshedding::GroupEntity>()
+        .with::<loadshedding::SuburbEntity>()
+        .with::<loadshedding::TimeScheduleEntity>()
+        .with::<loadshedding::MunicipalityEntity>()
+        .with::<outages::SuburbOutages>()
+        .with::<stages::StageRevision>()
+}
+
+/// Attempts at resolving the database's address before starting without it
+const CONNECT_ATTEMPTS: u32 = 5;
+
+/// Parses the database URI, retrying with backoff when the address can't be
+/// resolved yet (`mongodb+srv` URIs need DNS), as happens when the database
+/// or network comes up after the API
+async fn connect_options(db_uri: &str) -> mongodb::error::Result<ClientOptions> {
+    let mut delay = std::time::Duration::from_secs(1);
+    let mut attempt = 1;
+    loop {
+        match ClientOptions::parse(db_uri).await {
+            Err(err)
+                if attempt < CONNECT_ATTEMPTS
+                    && matches!(
+                        *err.kind,
+                        mongodb::error::ErrorKind::DnsResolve { .. }
+                            | mongodb::error::ErrorKind::Io(_)
+                    ) =>
+            {
+                warn!("Couldn't resolve the database, retrying in {delay:?}: {err:?}");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// `api migrate ...`, run against `DATABASE_URI` without starting the
+/// server. Returns the exit code.
+async fn migrate(args: &[String]) -> i32 {
+    if let Err(err) = dotenvy::dotenv() {
+        warn!("Couldn't read .env file! {err:?}");
+    }
+    let db_uri = env::var("DATABASE_URI").unwrap_or_default();
+    let client = match connect_options(&db_uri)
+        .await
+        .and_then(Client::with_options)
+    {
+        Ok(client) => client,
+        Err(err) => {
+            log::error!("Couldn't connect to the database: {err:?}");
+            return 1;
+        }
+    };
+    match migrations::cli(&client, args).await {
+        Ok(()) => 0,
+        Err(err) => {
+            log::error!("{err}");
+            1
+        }
+    }
+}
+
 #[rocket::main]
 async fn main() -> Result<(), rocket::Error> {
     setup_logger().expect("Couldn't setup logger!");
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("migrate") {
+        std::process::exit(migrate(&args[1..]).await);
+    }
+
     if let Err(err) = dns::update_dns().await {
         warn!("Couldn't setup DNS: {err:?}");
     }
@@ -0,0 +1,522 @@
+use crate::{
+    auth::{AuthCookie, COOKIE_LIFETIME_SECS},
+    categories::{self, ReportCategory},
+    db::Entity,
+    reporting::{now_millis, UserReport},
+    DB_NAME,
+};
+use async_trait::async_trait;
+use bson::{doc, oid::ObjectId, Bson, Document};
+use macros::Entity;
+use mongodb::{
+    error::{ErrorKind, WriteFailure},
+    options::UpdateOptions,
+    Client, Database,
+};
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::futures::TryStreamExt;
+use rocket::{Build, Rocket};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::time::Duration;
+
+/// How long a lock is held without being renewed before another instance
+/// may take it over, in case its holder died mid-migration
+const LOCK_LEASE: Duration = Duration::from_secs(10 * 60);
+/// How long to wait for another instance to finish migrating
+const LOCK_WAIT: Duration = Duration::from_secs(5 * 60);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const LOCK_ID: &str = "migrations";
+
+/// One versioned change to the documents in the database. Versions are
+/// applied in ascending order and must never be reused.
+#[async_trait]
+pub trait Migration: Send + Sync {
+    fn version(&self) -> u32;
+    fn name(&self) -> &'static str;
+    async fn up(&self, db: &Database) -> Result<(), MigrationError>;
+
+    /// Undoes `up`. Migrations that can't be undone keep the default.
+    async fn down(&self, _db: &Database) -> Result<(), MigrationError> {
+        Err(MigrationError::Irreversible(self.version()))
+    }
+}
+
+/// Every migration, oldest first
+pub fn all() -> Vec<Box<dyn Migration>> {
+    vec![
+        Box::new(ReportLocations),
+        Box::new(DefaultCategories),
+        Box::new(CamelCaseReports),
+        Box::new(HashLoginCookies),
+    ]
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Database(mongodb::error::Error),
+    /// Another instance held the lock for longer than we were willing to wait
+    Locked,
+    Irreversible(u32),
+    /// The database has a migration applied that this build doesn't know,
+    /// so it was migrated by a newer build
+    Unknown(u32),
+    /// A step failed, leaving the database at the previous version
+    Failed(u32, Box<MigrationError>),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Database(err) => write!(f, "database error: {err}"),
+            Self::Locked => write!(f, "another instance is running migrations"),
+            Self::Irreversible(version) => write!(f, "migration {version} can't be undone"),
+            Self::Unknown(version) => write!(f, "migration {version} is applied but unknown"),
+            Self::Failed(version, err) => write!(f, "migration {version} failed: {err}"),
+        }
+    }
+}
+
+impl From<mongodb::error::Error> for MigrationError {
+    fn from(err: mongodb::error::Error) -> Self {
+        Self::Database(err)
+    }
+}
+
+/// A migration that has been applied, kept in the `migrations` collection
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[serde(rename_all = "camelCase")]
+#[collection_name = "migrations"]
+pub struct AppliedMigration {
+    #[serde(skip_serializing_if = "Option::is_none", rename = "_id")]
+    pub id: Option<ObjectId>,
+    #[index(unique)]
+    pub version: u32,
+    pub name: String,
+    pub applied_at: u64,
+}
+
+/// The versions `up` applies to reach `target`, or the latest version
+pub fn plan_up(known: &[u32], applied: &[u32], target: Option<u32>) -> Vec<u32> {
+    let mut plan: Vec<u32> = known
+        .iter()
+        .copied()
+        .filter(|version| !applied.contains(version))
+        .filter(|version| *version <= target.unwrap_or(u32::MAX))
+        .collect();
+    plan.sort_unstable();
+    plan
+}
+
+/// The versions `down` undoes to get back to `target`, newest first
+pub fn plan_down(applied: &[u32], target: u32) -> Vec<u32> {
+    let mut plan: Vec<u32> = applied
+        .iter()
+        .copied()
+        .filter(|version| *version > target)
+        .collect();
+    plan.sort_unstable_by(|a, b| b.cmp(a));
+    plan
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: u32,
+    pub name: &'static str,
+    /// When it was applied, in milliseconds
+    pub applied_at: Option<u64>,
+}
+
+/// Applies and undoes migrations, holding the lock in `migration_lock`
+/// while it does
+pub struct Migrator {
+    db: Database,
+    migrations: Vec<Box<dyn Migration>>,
+    /// Identifies this instance as the lock holder
+    owner: String,
+}
+
+impl Migrator {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db,
+            migrations: all(),
+            owner: ObjectId::new().to_hex(),
+        }
+    }
+
+    async fn applied(&self) -> Result<Vec<AppliedMigration>, MigrationError> {
+        let applied = AppliedMigration::find(doc! {}, &self.db, None).await?;
+        Ok(applied.into_iter().map(|applied| *applied).collect())
+    }
+
+    fn migration(&self, version: u32) -> Result<&dyn Migration, MigrationError> {
+        self.migrations
+            .iter()
+            .find(|migration| migration.version() == version)
+            .map(|migration| migration.as_ref())
+            .ok_or(MigrationError::Unknown(version))
+    }
+
+    pub async fn status(&self) -> Result<Vec<MigrationStatus>, MigrationError> {
+        let applied = self.applied().await?;
+        if let Some(unknown) = applied
+            .iter()
+            .find(|applied| self.migration(applied.version).is_err())
+        {
+            return Err(MigrationError::Unknown(unknown.version));
+        }
+        let mut status: Vec<MigrationStatus> = self
+            .migrations
+            .iter()
+            .map(|migration| MigrationStatus {
+                version: migration.version(),
+                name: migration.name(),
+                applied_at: applied
+                    .iter()
+                    .find(|applied| applied.version == migration.version())
+                    .map(|applied| applied.applied_at),
+            })
+            .collect();
+        status.sort_by_key(|status| status.version);
+        Ok(status)
+    }
+
+    /// Applies every pending migration up to `target`, returning the
+    /// versions applied
+    pub async fn up(&self, target: Option<u32>) -> Result<Vec<u32>, MigrationError> {
+        self.locked(|| async {
+            let known: Vec<u32> = self.status().await?.iter().map(|s| s.version).collect();
+            let applied: Vec<u32> = self.applied().await?.iter().map(|a| a.version).collect();
+            let plan = plan_up(&known, &applied, target);
+            for version in &plan {
+                let migration = self.migration(*version)?;
+                self.lock().await?;
+                log::info!("Applying migration {version} {}", migration.name());
+                migration
+                    .up(&self.db)
+                    .await
+                    .map_err(|err| MigrationError::Failed(*version, Box::new(err)))?;
+                AppliedMigration {
+                    id: None,
+                    version: *version,
+                    name: migration.name().to_string(),
+                    applied_at: now_millis(),
+                }
+                .insert(&self.db)
+                .await?;
+            }
+            Ok(plan)
+        })
+        .await
+    }
+
+    /// Undoes every migration after `target`, newest first, returning the
+    /// versions undone
+    pub async fn down(&self, target: u32) -> Result<Vec<u32>, MigrationError> {
+        self.locked(|| async {
+            let applied: Vec<u32> = self.applied().await?.iter().map(|a| a.version).collect();
+            let plan = plan_down(&applied, target);
+            for version in &plan {
+                let migration = self.migration(*version)?;
+                self.lock().await?;
+                log::info!("Undoing migration {version} {}", migration.name());
+                migration
+                    .down(&self.db)
+                    .await
+                    .map_err(|err| MigrationError::Failed(*version, Box::new(err)))?;
+                self.db
+                    .collection::<AppliedMigration>("migrations")
+                    .delete_one(doc! { "version": *version }, None)
+                    .await?;
+            }
+            Ok(plan)
+        })
+        .await
+    }
+
+    /// Runs `f` while holding the lock, waiting for another instance to
+    /// release it first if needed
+    async fn locked<T, F, Fut>(&self, f: F) -> Result<T, MigrationError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = Result<T, MigrationError>>,
+    {
+        let mut waited = Duration::ZERO;
+        loop {
+            match self.lock().await {
+                Ok(()) => break,
+                Err(MigrationError::Locked) if waited < LOCK_WAIT => {
+                    if waited.is_zero() {
+                        log::info!("Waiting for another instance to finish migrating");
+                    }
+                    tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+                    waited += LOCK_POLL_INTERVAL;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let result = f().await;
+        if let Err(err) = self
+            .db
+            .collection::<bson::Document>("migration_lock")
+            .delete_one(doc! { "_id": LOCK_ID, "owner": &self.owner }, None)
+            .await
+        {
+            log::warn!("Couldn't release the migration lock: {err:?}");
+        }
+        result
+    }
+
+    /// Takes or renews the lock. Taking a lock someone else holds fails on
+    /// the upsert's duplicate `_id`.
+    async fn lock(&self) -> Result<(), MigrationError> {
+        let now = now_millis() as i64;
+        let result = self
+            .db
+            .collection::<bson::Document>("migration_lock")
+            .update_one(
+                doc! {
+                    "_id": LOCK_ID,
+                    "$or": [{ "owner": &self.owner }, { "expiresAt": { "$lt": now } }],
+                },
+                doc! { "$set": {
+                    "owner": &self.owner,
+                    "expiresAt": now + LOCK_LEASE.as_millis() as i64,
+                } },
+                UpdateOptions::builder().upsert(true).build(),
+            )
+            .await;
+        match result {
+            Ok(_) => Ok(()),
+            Err(err) => match err.kind.as_ref() {
+                ErrorKind::Write(WriteFailure::WriteError(write)) if write.code == 11000 => {
+                    Err(MigrationError::Locked)
+                }
+                _ => Err(err.into()),
+            },
+        }
+    }
+}
+
+/// Applies pending migrations before the server starts. Set
+/// `MIGRATE_ON_STARTUP=false` to leave that to `api migrate up` instead.
+pub struct Migrations;
+
+#[rocket::async_trait]
+impl Fairing for Migrations {
+    fn info(&self) -> Info {
+        Info {
+            name: "Migrations",
+            kind: Kind::Ignite,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        if std::env::var("MIGRATE_ON_STARTUP").as_deref() == Ok("false") {
+            return Ok(rocket);
+        }
+        let client = match rocket.state::<Option<Client>>() {
+            Some(Some(client)) => client.clone(),
+            _ => return Ok(rocket),
+        };
+
+        match Migrator::new(client.database(DB_NAME)).up(None).await {
+            Ok(applied) if applied.is_empty() => Ok(rocket),
+            Ok(applied) => {
+                log::info!("Applied migrations {applied:?}");
+                Ok(rocket)
+            }
+            // Serving with half the documents migrated would be worse than
+            // not serving
+            Err(err @ MigrationError::Failed(..)) => {
+                log::error!("Not starting: {err}");
+                Err(rocket)
+            }
+            Err(err) => {
+                log::error!("Couldn't run migrations, starting anyway: {err}");
+                Ok(rocket)
+            }
+        }
+    }
+}
+
+/// `api migrate status`, `api migrate up [version]` and
+/// `api migrate down <version>`
+pub async fn cli(client: &Client, args: &[String]) -> Result<(), String> {
+    let migrator = Migrator::new(client.database(DB_NAME));
+    let version = |arg: Option<&String>| -> Result<Option<u32>, String> {
+        arg.map(|arg| arg.parse().map_err(|_| format!("{arg} isn't a version")))
+            .transpose()
+    };
+
+    match args.first().map(String::as_str) {
+        Some("status") | None => {
+            for status in migrator.status().await.map_err(|err| err.to_string())? {
+                let applied = match status.applied_at {
+                    Some(at) => format!("applied at {at}"),
+                    None => "pending".to_string(),
+                };
+                println!("{:>4} {:<32} {applied}", status.version, status.name);
+            }
+        }
+        Some("up") => {
+            let applied = migrator
+                .up(version(args.get(1))?)
+                .await
+                .map_err(|err| err.to_string())?;
+            println!("Applied {applied:?}");
+        }
+        Some("down") => {
+            let target = version(args.get(1))?.ok_or("down needs a version to go back to")?;
+            let undone = migrator.down(target).await.map_err(|err| err.to_string())?;
+            println!("Undid {undone:?}");
+        }
+        Some(other) => return Err(format!("Unknown migrate command {other}")),
+    }
+    Ok(())
+}
+
+/// Reports made before reports had a `location` get one, and a `created`
+/// half an hour before they expire
+struct ReportLocations;
+
+#[async_trait]
+impl Migration for ReportLocations {
+    fn version(&self) -> u32 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "report_locations"
+    }
+
+    async fn up(&self, db: &Database) -> Result<(), MigrationError> {
+        let result = db
+            .collection::<UserReport>("reports")
+            .update_many(
+                doc! { "location": { "$exists": false } },
+                vec![doc! { "$set": {
+                    "location": { "type": "Point", "coordinates": ["$longitude", "$latitude"] },
+                    "created": { "$ifNull": ["$created", { "$subtract": ["$expires", 1000 * 60 * 30] }] },
+                } }],
+                None,
+            )
+            .await?;
+        log::info!("Added locations to {} old reports", result.modified_count);
+        Ok(())
+    }
+}
+
+/// Adds the default categories to an empty collection
+struct DefaultCategories;
+
+#[async_trait]
+impl Migration for DefaultCategories {
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn name(&self) -> &'static str {
+        "default_report_categories"
+    }
+
+    async fn up(&self, db: &Database) -> Result<(), MigrationError> {
+        let categories = db.collection::<ReportCategory>("report_categories");
+        if categories.count_documents(None, None).await? == 0 {
+            categories
+                .insert_many(categories::default_categories(), None)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// `UserReport` was the only entity serialized with snake_case fields
+struct CamelCaseReports;
+
+const REPORT_FIELDS: [(&str, &str); 2] = [
+    ("report_type", "reportType"),
+    ("reporter_weight", "reporterWeight"),
+];
+
+#[async_trait]
+impl Migration for CamelCaseReports {
+    fn version(&self) -> u32 {
+        3
+    }
+
+    fn name(&self) -> &'static str {
+        "camel_case_report_fields"
+    }
+
+    async fn up(&self, db: &Database) -> Result<(), MigrationError> {
+        let renames: bson::Document = REPORT_FIELDS
+            .iter()
+            .map(|(old, new)| (old.to_string(), (*new).into()))
+            .collect();
+        db.collection::<UserReport>("reports")
+            .update_many(doc! {}, doc! { "$rename": renames }, None)
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, db: &Database) -> Result<(), MigrationError> {
+        let renames: bson::Document = REPORT_FIELDS
+            .iter()
+            .map(|(old, new)| (new.to_string(), (*old).into()))
+            .collect();
+        db.collection::<UserReport>("reports")
+            .update_many(doc! {}, doc! { "$rename": renames }, None)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Login cookies used to be stored as they were handed out. Cookies stored
+/// since they're hashed all have a `created_at`, so the ones without are
+/// the plaintext ones.
+struct HashLoginCookies;
+
+#[async_trait]
+impl Migration for HashLoginCookies {
+    fn version(&self) -> u32 {
+        4
+    }
+
+    fn name(&self) -> &'static str {
+        "hash_login_cookies"
+    }
+
+    async fn up(&self, db: &Database) -> Result<(), MigrationError> {
+        let cookies = db.collection::<Document>("cookies");
+        let plaintext = doc! { "created_at": { "$exists": false } };
+        let mut legacy = cookies.find(plaintext.clone(), None).await?;
+        let mut hashed = 0;
+        while let Some(cookie) = legacy.try_next().await? {
+            let (id, value) = match (cookie.get("_id"), cookie.get_str("cookie")) {
+                (Some(id), Ok(value)) => (id.clone(), value),
+                _ => continue,
+            };
+            let expires = cookie.get("exp").and_then(Bson::as_i64).unwrap_or_default();
+            let created_at = expires.saturating_sub(COOKIE_LIFETIME_SECS as i64).max(0);
+            let mut filter = plaintext.clone();
+            filter.insert("_id", id);
+            cookies
+                .update_one(
+                    filter,
+                    doc! { "$set": {
+                        "cookie": AuthCookie::hash(value),
+                        "created_at": created_at,
+                        "last_used": created_at,
+                    } },
+                    None,
+                )
+                .await?;
+            hashed += 1;
+        }
+        log::info!("Hashed {hashed} old login cookies");
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use crate::api::ApiError;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// The identity providers we accept ID tokens from, read from the
+/// `OIDC_PROVIDERS` environment variable as a JSON array of [`OidcProvider`]s.
+#[derive(Debug, Clone, Default)]
+pub struct OidcProviders(pub Vec<OidcProvider>);
+
+impl OidcProviders {
+    pub fn from_env() -> Self {
+        match env::var("OIDC_PROVIDERS") {
+            Ok(providers) => match serde_json::from_str::<Vec<OidcProvider>>(&providers) {
+                Ok(providers) => Self(providers),
+                Err(err) => {
+                    error!("Couldn't parse OIDC_PROVIDERS env var: {err:?}");
+                    Self::default()
+                }
+            },
+            Err(_) => {
+                warn!("OIDC_PROVIDERS environment variable not set. OIDC logins are disabled");
+                Self::default()
+            }
+        }
+    }
+
+    pub fn find(&self, name: &str) -> Option<&OidcProvider> {
+        self.0.iter().find(|provider| provider.name == name)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OidcProvider {
+    /// The name clients use to select this provider, e.g. "google"
+    pub name: String,
+    /// Must match the `iss` claim of every ID token exactly
+    pub issuer: String,
+    /// Our client id at the provider, checked against the `aud` claim
+    pub client_id: String,
+    /// Skips discovery when set
+    #[serde(default)]
+    pub jwks_uri: Option<String>,
+    /// Accept the `email` claim even if the provider doesn't send `email_verified`.
+    /// Only enable this for providers that never hand out unverified addresses.
+    #[serde(default)]
+    pub trust_email: bool,
+    #[serde(skip)]
+    jwks: Arc<RwLock<Option<JwkSet>>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcClaims {
+    pub iss: String,
+    pub sub: String,
+    pub exp: u64,
+    pub email: Option<String>,
+    pub email_verified: Option<bool>,
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscoveryDocument {
+    jwks_uri: String,
+}
+
+impl OidcProvider {
+    #[cfg(test)]
+    pub fn new(name: &str, issuer: &str, client_id: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            issuer: issuer.to_string(),
+            client_id: client_id.to_string(),
+            jwks_uri: None,
+            trust_email: false,
+            jwks: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    async fn resolve_jwks_uri(&self) -> Result<String, reqwest::Error> {
+        if let Some(jwks_uri) = &self.jwks_uri {
+            return Ok(jwks_uri.clone());
+        }
+
+        let discovery = reqwest::get(format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer.trim_end_matches('/')
+        ))
+        .await?
+        .error_for_status()?
+        .json::<DiscoveryDocument>()
+        .await?;
+        Ok(discovery.jwks_uri)
+    }
+
+    async fn refresh_jwks(&self) -> Result<JwkSet, reqwest::Error> {
+        let jwks = reqwest::get(self.resolve_jwks_uri().await?)
+            .await?
+            .error_for_status()?
+            .json::<JwkSet>()
+            .await?;
+        *self.jwks.write().await = Some(jwks.clone());
+        Ok(jwks)
+    }
+
+    /// Looks up the signing key for `kid`, refetching the provider's JWKS
+    /// if the key isn't cached (providers rotate their keys regularly).
+    async fn decoding_key(&self, kid: &str) -> Result<DecodingKey, ApiError> {
+        let cached = self
+            .jwks
+            .read()
+            .await
+            .as_ref()
+            .and_then(|jwks| jwks.find(kid).cloned());
+
+        let jwk = match cached {
+            Some(jwk) => jwk,
+            None => match self.refresh_jwks().await {
+                Ok(jwks) => match jwks.find(kid) {
+                    Some(jwk) => jwk.clone(),
+                    None => return Err(ApiError::unauthenticated("Unknown ID token signing key")),
+                },
+                Err(err) => {
+                    error!("Couldn't fetch JWKS for {}: {err:?}", self.issuer);
+                    return Err(ApiError::internal("Couldn't reach the identity provider"));
+                }
+            },
+        };
+
+        DecodingKey::from_jwk(&jwk).map_err(|err| {
+            error!("Invalid JWK from {}: {err:?}", self.issuer);
+            ApiError::internal("The identity provider sent an invalid signing key")
+        })
+    }
+
+    pub async fn verify_id_token(&self, id_token: &str) -> Result<OidcClaims, ApiError> {
+        let header = match jsonwebtoken::decode_header(id_token) {
+            Ok(header) => header,
+            Err(_) => return Err(ApiError::unauthenticated("Malformed ID token")),
+        };
+
+        // Never let the token pick a symmetric algorithm, that would let
+        // anyone sign tokens with the (public) JWK as the secret
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(ApiError::unauthenticated("Unsupported ID token algorithm"));
+        }
+
+        let kid = match header.kid {
+            Some(kid) => kid,
+            None => return Err(ApiError::unauthenticated("ID token has no key id")),
+        };
+        let key = self.decoding_key(&kid).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.leeway = 10;
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.client_id]);
+
+        let claims = match jsonwebtoken::decode::<OidcClaims>(id_token, &key, &validation) {
+            Ok(data) => data.claims,
+            Err(err) => {
+                log::info!("Rejecting ID token from {}: {err:?}", self.issuer);
+                return Err(ApiError::unauthenticated("Invalid ID token"));
+            }
+        };
+
+        if claims.email.is_none() {
+            return Err(ApiError::unauthenticated("ID token has no email address"));
+        }
+
+        if claims.email_verified != Some(true) && !self.trust_email {
+            return Err(ApiError::unauthenticated(
+                "The identity provider hasn't verified this email address",
+            ));
+        }
+
+        Ok(claims)
+    }
+}
@@ -0,0 +1,422 @@
+use crate::{
+    api::ApiError,
+    cache::ScheduleCache,
+    loadshedding::{
+        get_date_time, merge_slots, stages_since, DBFunctionsTrait, GroupEntity, LoadSheddingStage,
+        MapDataDefaultResponse, MunicipalityEntity, PredictiveSuburbStatsResponse, SuburbEntity,
+        TimeScheduleEntity, TimeSlot,
+    },
+    reporting::now_millis,
+    repository::{Model, PageRequest, RepoResult, Repository},
+    scheduler::{Job, Schedule, Scheduler},
+    stages::StageRevision,
+};
+use bson::{doc, oid::ObjectId};
+use chrono::{DateTime, Duration, FixedOffset, Timelike};
+use macros::Entity;
+use mongodb::{Client, Database};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    futures::FutureExt,
+    Orbit, Rocket,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use utoipa::ToSchema;
+
+/// How many days ahead outages are worked out, unless `OUTAGE_DAYS` says
+const DEFAULT_DAYS: i64 = 7;
+/// Outages are worked out again at least this often, so the window keeps
+/// moving with the clock
+const REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+/// How often the materializer checks whether the outages are out of date
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+fn days_ahead() -> i64 {
+    std::env::var("OUTAGE_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .filter(|days| *days > 0)
+        .unwrap_or(DEFAULT_DAYS)
+}
+
+/// A suburb's scheduled outages from `from` to `until`, worked out ahead of
+/// time so reads don't have to walk the schedules. Shares its id with the
+/// suburb.
+#[derive(Debug, Serialize, Deserialize, Clone, Entity)]
+#[serde(rename_all = "camelCase")]
+#[collection_name = "suburb_outages"]
+#[index(fields = "municipality, until")]
+pub struct SuburbOutages {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+    pub municipality: ObjectId,
+    /// The map features the suburb covers
+    pub geometry: Vec<i32>,
+    /// One per schedule slot, not joined up
+    pub outages: Vec<TimeSlot>,
+    pub from: i64,
+    pub until: i64,
+    /// The [`stage_version`] the outages were worked out from
+    #[serde(default)]
+    pub stage_version: u64,
+}
+
+impl SuburbOutages {
+    fn off_at(&self, time: i64) -> bool {
+        self.outages
+            .iter()
+            .any(|slot| slot.start <= time && time < slot.end)
+    }
+
+    /// The outages over the day after `time`, as `build_schedule` would work
+    /// them out, if they've been materialized that far
+    pub fn schedule_at(&self, time: Option<i64>) -> Option<PredictiveSuburbStatsResponse> {
+        let (from, until) = schedule_window(time);
+        if self.from > from.timestamp() || self.until < until.timestamp() {
+            return None;
+        }
+        let times_off = merge_slots(
+            self.outages
+                .iter()
+                .filter(|slot| slot.end > from.timestamp() && slot.start < until.timestamp())
+                .cloned(),
+        );
+        Some(PredictiveSuburbStatsResponse { times_off })
+    }
+}
+
+/// How many revisions the stage log has had. Every change to it records a
+/// revision first, on any instance, and revisions are never removed, so
+/// outages worked out from a lower version are out of date.
+pub async fn stage_version<R: Repository<StageRevision>>(revisions: &R) -> RepoResult<u64> {
+    revisions.count(doc! {}).await
+}
+
+/// A municipality's schedule and the suburbs in it that are in a group
+struct GroupedSuburbs {
+    municipality: ObjectId,
+    schedule: Vec<TimeScheduleEntity>,
+    suburbs: Vec<(SuburbEntity, GroupEntity)>,
+}
+
+/// Every suburb with its group, by municipality
+async fn grouped_suburbs(
+    db: &Database,
+    db_functions: &dyn DBFunctionsTrait,
+) -> Result<Vec<GroupedSuburbs>, ApiError> {
+    let suburbs = db_functions
+        .collect_suburbs(doc! {}, Some(db), None)
+        .await?;
+    let mut municipalities: HashMap<ObjectId, Vec<SuburbEntity>> = HashMap::new();
+    for suburb in suburbs {
+        municipalities
+            .entry(suburb.municipality)
+            .or_default()
+            .push(suburb);
+    }
+
+    let mut grouped = Vec::new();
+    for (municipality, suburbs) in municipalities {
+        let schedule = db_functions
+            .collect_schedules(doc! { "municipality": municipality }, Some(db), None)
+            .await?;
+        let ids: Vec<ObjectId> = suburbs.iter().filter_map(|suburb| suburb.id).collect();
+        let groups = db_functions
+            .collect_groups(doc! { "suburbs": { "$in": &ids } }, Some(db), None)
+            .await?;
+        let group_of: HashMap<ObjectId, &GroupEntity> = groups
+            .iter()
+            .flat_map(|group| group.suburbs.iter().map(move |suburb| (*suburb, group)))
+            .collect();
+        let suburbs = suburbs
+            .into_iter()
+            .filter_map(|suburb| match suburb.id.and_then(|id| group_of.get(&id)) {
+                Some(group) => Some((suburb, (*group).clone())),
+                None => {
+                    log::debug!("Suburb {} isn't in any group, skipping it", suburb.name);
+                    None
+                }
+            })
+            .collect();
+        grouped.push(GroupedSuburbs {
+            municipality,
+            schedule,
+            suburbs,
+        });
+    }
+    Ok(grouped)
+}
+
+/// Works out every suburb's outages for the next `days` from the stage log
+/// at `stage_version` and stores them. Returns how many suburbs were
+/// covered.
+pub async fn materialize(
+    db: &Database,
+    db_functions: &dyn DBFunctionsTrait,
+    days: i64,
+    stage_version: u64,
+) -> Result<usize, ApiError> {
+    let from = get_date_time(None)
+        .with_second(0)
+        .unwrap()
+        .with_minute(0)
+        .unwrap();
+    let until = from + Duration::days(days);
+    let mut stages = stages_since(&from.timestamp(), Some(db), db_functions).await?;
+    stages.reverse();
+
+    let repository = SuburbOutages::repository(db);
+    let mut covered = 0;
+    for municipality in grouped_suburbs(db, db_functions).await? {
+        for (suburb, group) in municipality.suburbs {
+            let mut outages = SuburbOutages {
+                id: suburb.id,
+                municipality: municipality.municipality,
+                geometry: suburb.geometry.clone(),
+                outages: suburb.outages_between(
+                    &group,
+                    stages.clone(),
+                    &municipality.schedule,
+                    from,
+                    until,
+                ),
+                from: from.timestamp(),
+                until: until.timestamp(),
+                stage_version,
+            };
+            repository
+                .upsert(doc! { "_id": suburb.id }, &mut outages)
+                .await?;
+            covered += 1;
+        }
+    }
+    Ok(covered)
+}
+
+/// A suburb whose outages would change
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AffectedSuburb {
+    pub name: String,
+    pub municipality: String,
+    pub before: Vec<TimeSlot>,
+    pub after: Vec<TimeSlot>,
+}
+
+/// The suburbs whose outages during `window` would change if it were set
+/// to its stage, compared with the stage log as it is
+pub async fn affected_by(
+    db: &Database,
+    db_functions: &dyn DBFunctionsTrait,
+    window: &LoadSheddingStage,
+) -> Result<Vec<AffectedSuburb>, ApiError> {
+    let from = get_date_time(Some(window.start_time));
+    let until = get_date_time(Some(window.end_time));
+    let mut stages = stages_since(&window.start_time, Some(db), db_functions).await?;
+    stages.reverse();
+
+    let mut affected = Vec::new();
+    for municipality in grouped_suburbs(db, db_functions).await? {
+        for (suburb, group) in municipality.suburbs {
+            let schedule = &municipality.schedule;
+            let before =
+                merge_slots(suburb.outages_between(&group, stages.clone(), schedule, from, until));
+            let after = merge_slots(suburb.outages_between(
+                &group,
+                vec![window.clone()],
+                schedule,
+                from,
+                until,
+            ));
+            if before != after {
+                affected.push(AffectedSuburb {
+                    name: suburb.name,
+                    municipality: municipality.municipality.to_hex(),
+                    before,
+                    after,
+                });
+            }
+        }
+    }
+    affected.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(affected)
+}
+
+/// The window `build_schedule` looks at
+pub(crate) fn schedule_window(time: Option<i64>) -> (DateTime<FixedOffset>, DateTime<FixedOffset>) {
+    let from = get_date_time(time)
+        .with_second(0)
+        .unwrap()
+        .with_minute(0)
+        .unwrap();
+    (from, get_date_time(time) + Duration::days(1))
+}
+
+/// The suburb's materialized outages over the day after `time`, if there
+/// are any that go that far and the stage log hasn't changed since
+pub async fn schedule(
+    db: &Database,
+    suburb: &SuburbEntity,
+    time: Option<i64>,
+) -> Option<PredictiveSuburbStatsResponse> {
+    let id = suburb.id?;
+    let found = async {
+        let version = stage_version(&StageRevision::repository(db)).await?;
+        let found = SuburbOutages::repository(db).find_by_id(id).await?;
+        RepoResult::Ok(found.filter(|found| found.stage_version == version))
+    };
+    match found.await {
+        Ok(found) => found?.schedule_at(time),
+        Err(err) => {
+            log::warn!("Couldn't read the outages of {}: {err}", suburb.name);
+            None
+        }
+    }
+}
+
+/// Draws the municipalities' maps at `time` from materialized outages that
+/// are up to date with the stage log. Municipalities without any are left
+/// out, for the caller to work out the slow way.
+pub async fn maps_at(
+    db: &Database,
+    municipalities: &[MunicipalityEntity],
+    time: i64,
+) -> HashMap<ObjectId, MapDataDefaultResponse> {
+    let ids: Vec<ObjectId> = municipalities
+        .iter()
+        .filter_map(|municipality| municipality.id)
+        .collect();
+    let version = match stage_version(&StageRevision::repository(db)).await {
+        Ok(version) => version,
+        Err(err) => {
+            log::warn!("Couldn't read the stage version: {err}");
+            return HashMap::new();
+        }
+    };
+    let filter = doc! {
+        "municipality": { "$in": &ids },
+        "stageVersion": version as i64,
+        "until": { "$gt": time },
+        "from": { "$lte": time },
+    };
+    let found = match SuburbOutages::repository(db)
+        .find(filter, PageRequest::all())
+        .await
+    {
+        Ok(page) => page.items,
+        Err(err) => {
+            log::warn!("Couldn't read materialized outages: {err}");
+            return HashMap::new();
+        }
+    };
+
+    let mut by_municipality: HashMap<ObjectId, Vec<SuburbOutages>> = HashMap::new();
+    for outages in found {
+        by_municipality
+            .entry(outages.municipality)
+            .or_default()
+            .push(outages);
+    }
+    municipalities
+        .iter()
+        .filter_map(|municipality| {
+            let suburbs = by_municipality.get(&municipality.id?)?;
+            let (off, on): (Vec<&SuburbOutages>, Vec<&SuburbOutages>) =
+                suburbs.iter().partition(|suburb| suburb.off_at(time));
+            let off: Vec<&[i32]> = off.iter().map(|suburb| &suburb.geometry[..]).collect();
+            let on: Vec<&[i32]> = on.iter().map(|suburb| &suburb.geometry[..]).collect();
+            let mut geography = municipality.geometry.clone();
+            geography.mark_power_status(&off, &on);
+            Some((
+                municipality.id?,
+                MapDataDefaultResponse {
+                    map_polygons: vec![geography],
+                },
+            ))
+        })
+        .collect()
+}
+
+/// What the outages were last worked out from
+#[derive(Debug, Clone, Copy)]
+struct Materialized {
+    stage_version: u64,
+    /// In milliseconds
+    at: u64,
+}
+
+/// Works the outages out again if the schedule cache heard of new stages or
+/// schedules, the stage version moved on, or they're an hour old
+async fn refresh(
+    db: &Database,
+    cache: &ScheduleCache,
+    days: i64,
+    last: &Mutex<Option<Materialized>>,
+) -> Result<(), String> {
+    // Read before the stage log. Writers change the log before recording
+    // the revision, so everything this version counts is in the log, and a
+    // change made while this runs shows up as a newer version next time.
+    let version = stage_version(&StageRevision::repository(db))
+        .await
+        .map_err(|err| format!("Couldn't read the stage version: {err}"))?;
+    let previous = *last.lock().unwrap();
+    if previous.is_some_and(|previous| previous.stage_version != version) {
+        // Maybe another instance changed it, so the cached copy is stale too
+        cache.invalidate(LoadSheddingStage::COLLECTION);
+    }
+    let changed = cache.changed().now_or_never().is_some();
+    let now = now_millis();
+    let due = match previous {
+        Some(previous) => {
+            changed || now.saturating_sub(previous.at) >= REFRESH_INTERVAL.as_millis() as u64
+        }
+        None => true,
+    };
+    if !due {
+        return Ok(());
+    }
+
+    let covered = materialize(db, cache, days, version)
+        .await
+        .map_err(|err| format!("Couldn't materialize outages: {err}"))?;
+    log::info!("Materialized outages for {covered} suburbs");
+    *last.lock().unwrap() = Some(Materialized {
+        stage_version: version,
+        at: now,
+    });
+    Ok(())
+}
+
+/// Keeps `suburb_outages` up to date, checking every few seconds whether
+/// they need working out again
+pub struct OutageMaterializer;
+
+#[rocket::async_trait]
+impl Fairing for OutageMaterializer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Outage Materializer",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let client = match rocket.state::<Option<Client>>().unwrap() {
+            Some(client) => client.clone(),
+            None => return,
+        };
+        let cache = rocket.state::<ScheduleCache>().unwrap().clone();
+        let scheduler = rocket.state::<Scheduler>().unwrap();
+        let days = days_ahead();
+        let last = Arc::new(Mutex::new(None));
+
+        let job = Job::new("outage materialization", Schedule::every(CHECK_INTERVAL));
+        scheduler.spawn(job, rocket.shutdown(), move || {
+            let (db, cache, last) = (client.database("production"), cache.clone(), last.clone());
+            async move { refresh(&db, &cache, days, &last).await }
+        });
+    }
+}
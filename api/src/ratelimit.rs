@@ -0,0 +1,561 @@
+use crate::api::ApiError;
+use crate::auth::{AuthClaims, AuthType};
+use crate::keys::JwtKeys;
+use async_trait::async_trait;
+use bson::{doc, Document};
+use log::{error, info, warn};
+use mongodb::options::{
+    FindOneAndUpdateOptions, IndexOptions, ReturnDocument, UpdateModifications,
+};
+use mongodb::{Client, Collection, IndexModel};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method};
+use rocket::request::{FromRequest, Outcome};
+use rocket::{get, Data, Orbit, Request, Responder, Rocket};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where rate limited requests get rerouted to, so their handlers never run
+const RATE_LIMITED_PATH: &str = "/rateLimited";
+
+#[get("/rateLimited")]
+pub fn rate_limited(retry_after: RetryAfter) -> TooManyRequests {
+    TooManyRequests::new(retry_after.0, "Too many requests, slow down")
+}
+
+#[derive(Responder)]
+#[response(status = 429)]
+pub struct TooManyRequests {
+    pub inner: ApiError,
+    pub retry_after: Header<'static>,
+}
+
+impl TooManyRequests {
+    pub fn new(retry_after: Duration, message: &'static str) -> Self {
+        Self {
+            inner: ApiError::rate_limited(message),
+            // Round up so clients never retry a moment too early
+            retry_after: Header::new(
+                "Retry-After",
+                (retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)).to_string(),
+            ),
+        }
+    }
+}
+
+/// How long the fairing told this request to back off for
+pub struct RetryAfter(Duration);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RetryAfter {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.local_cache(|| RateLimitDecision(None)).0 {
+            Some(retry_after) => Outcome::Success(RetryAfter(retry_after)),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+struct RateLimitDecision(Option<Duration>);
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BucketConfig {
+    /// Most requests that can be made in a burst
+    pub capacity: f64,
+    /// Tokens regained per second
+    pub refill_per_second: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitRule {
+    /// Requests whose path starts with this are limited by this rule.
+    /// The longest matching prefix wins.
+    pub path: String,
+    pub per_ip: Option<BucketConfig>,
+    /// Keyed on the signed in user, so every session they hold shares a
+    /// bucket. Anonymous callers are only limited per IP, or by this keyed on
+    /// their IP when there's no `per_ip`.
+    pub per_account: Option<BucketConfig>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LockoutConfig {
+    /// Failed password checks allowed within `window_secs` before locking
+    pub max_failures: u32,
+    pub window_secs: u64,
+    pub lockout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimitConfig {
+    pub rules: Vec<RateLimitRule>,
+    pub lockout: LockoutConfig,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            rules: vec![
+                RateLimitRule {
+                    path: "/".to_string(),
+                    per_ip: Some(BucketConfig {
+                        capacity: 120.0,
+                        refill_per_second: 2.0,
+                    }),
+                    per_account: Some(BucketConfig {
+                        capacity: 60.0,
+                        refill_per_second: 1.0,
+                    }),
+                },
+                RateLimitRule {
+                    path: "/api/auth".to_string(),
+                    per_ip: Some(BucketConfig {
+                        capacity: 10.0,
+                        refill_per_second: 0.1,
+                    }),
+                    per_account: None,
+                },
+            ],
+            lockout: LockoutConfig {
+                max_failures: 5,
+                window_secs: 15 * 60,
+                lockout_secs: 15 * 60,
+            },
+        }
+    }
+}
+
+impl RateLimitConfig {
+    /// Reads the rules from the `RATE_LIMITS` environment variable, using
+    /// the defaults above when it isn't set.
+    pub fn from_env() -> Self {
+        match std::env::var("RATE_LIMITS") {
+            Ok(config) => match serde_json::from_str(&config) {
+                Ok(config) => config,
+                Err(err) => {
+                    error!("Couldn't parse RATE_LIMITS env var, using defaults: {err:?}");
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn rule_for(&self, path: &str) -> Option<&RateLimitRule> {
+        self.rules
+            .iter()
+            .filter(|rule| path.starts_with(&rule.path))
+            .max_by_key(|rule| rule.path.len())
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
+
+/// Keeps the token buckets and failed login counters. All methods return
+/// how long the caller has to wait, if they have to wait at all.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Called once the server has launched
+    async fn prepare(&self) {}
+    async fn take(&self, key: &str, bucket: BucketConfig) -> Option<Duration>;
+    async fn locked_out(&self, key: &str) -> Option<Duration>;
+    async fn record_failure(&self, key: &str, lockout: LockoutConfig) -> Option<Duration>;
+    async fn clear_failures(&self, key: &str);
+}
+
+/// Memory stores sweep out stale entries once they hold this many
+const MAX_IDLE_BUCKETS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: f64,
+    config: BucketConfig,
+}
+
+impl Bucket {
+    fn tokens_at(&self, now: f64) -> f64 {
+        (self.tokens + (now - self.updated_at) * self.config.refill_per_second)
+            .min(self.config.capacity)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    window_start: f64,
+    locked_until: f64,
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn take(&self, key: &str, config: BucketConfig) -> Option<Duration> {
+        let now = now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_IDLE_BUCKETS {
+            // A full bucket is the same as no bucket at all
+            buckets.retain(|_, bucket| bucket.tokens_at(now) < bucket.config.capacity);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: config.capacity,
+            updated_at: now,
+            config,
+        });
+        bucket.config = config;
+        bucket.tokens = bucket.tokens_at(now);
+        bucket.updated_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / config.refill_per_second,
+            ))
+        }
+    }
+
+    async fn locked_out(&self, key: &str) -> Option<Duration> {
+        let now = now();
+        self.failures
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|failures| failures.locked_until > now)
+            .map(|failures| Duration::from_secs_f64(failures.locked_until - now))
+    }
+
+    async fn record_failure(&self, key: &str, lockout: LockoutConfig) -> Option<Duration> {
+        let now = now();
+        let mut all_failures = self.failures.lock().unwrap();
+        if all_failures.len() >= MAX_IDLE_BUCKETS {
+            all_failures.retain(|_, failures| {
+                failures.locked_until > now
+                    || now - failures.window_start <= lockout.window_secs as f64
+            });
+        }
+        let failures = all_failures.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            window_start: now,
+            locked_until: 0.0,
+        });
+
+        if now - failures.window_start > lockout.window_secs as f64 {
+            failures.count = 0;
+            failures.window_start = now;
+        }
+        failures.count += 1;
+
+        if failures.count >= lockout.max_failures {
+            failures.count = 0;
+            failures.window_start = now;
+            failures.locked_until = now + lockout.lockout_secs as f64;
+            Some(Duration::from_secs(lockout.lockout_secs))
+        } else {
+            None
+        }
+    }
+
+    async fn clear_failures(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+}
+
+/// Shares limits between instances through the `rate_limits` collection.
+/// Every check is a single atomic update, so instances can't race each other.
+pub struct MongoStore {
+    collection: Collection<Document>,
+}
+
+impl MongoStore {
+    pub fn new(client: &Client) -> Self {
+        Self {
+            collection: client.database(crate::DB_NAME).collection("rate_limits"),
+        }
+    }
+
+    async fn update(&self, key: &str, pipeline: Vec<Document>) -> Option<Document> {
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+        match self
+            .collection
+            .find_one_and_update(
+                doc! { "_id": key },
+                UpdateModifications::Pipeline(pipeline),
+                options,
+            )
+            .await
+        {
+            Ok(document) => document,
+            Err(err) => {
+                // Fail open, an unreachable database shouldn't lock everyone out
+                error!("Couldn't update rate limit for {key}: {err:?}");
+                None
+            }
+        }
+    }
+}
+
+fn expires_at(seconds: f64) -> bson::DateTime {
+    bson::DateTime::from_millis(((now() + seconds) * 1000.0) as i64)
+}
+
+#[async_trait]
+impl RateLimitStore for MongoStore {
+    async fn prepare(&self) {
+        let index = IndexModel::builder()
+            .keys(doc! { "expiresAt": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Duration::from_secs(0))
+                    .build(),
+            )
+            .build();
+        if let Err(err) = self.collection.create_index(index, None).await {
+            warn!("Couldn't create TTL index on rate_limits: {err:?}");
+        }
+    }
+
+    async fn take(&self, key: &str, config: BucketConfig) -> Option<Duration> {
+        let now = now();
+        let refilled = doc! {
+            "$min": [
+                config.capacity,
+                { "$add": [
+                    { "$ifNull": ["$tokens", config.capacity] },
+                    { "$multiply": [
+                        { "$subtract": [now, { "$ifNull": ["$updatedAt", now] }] },
+                        config.refill_per_second
+                    ] }
+                ] }
+            ]
+        };
+        let document = self
+            .update(
+                key,
+                vec![
+                    doc! { "$set": { "tokens": refilled, "updatedAt": now } },
+                    doc! { "$set": { "allowed": { "$gte": ["$tokens", 1.0] } } },
+                    doc! { "$set": {
+                        "tokens": { "$cond": ["$allowed", { "$subtract": ["$tokens", 1.0] }, "$tokens"] },
+                        "expiresAt": expires_at(config.capacity / config.refill_per_second),
+                    } },
+                ],
+            )
+            .await?;
+
+        if document.get_bool("allowed").unwrap_or(true) {
+            None
+        } else {
+            let tokens = document.get_f64("tokens").unwrap_or(0.0);
+            Some(Duration::from_secs_f64(
+                (1.0 - tokens) / config.refill_per_second,
+            ))
+        }
+    }
+
+    async fn locked_out(&self, key: &str) -> Option<Duration> {
+        let document = self
+            .collection
+            .find_one(doc! { "_id": key }, None)
+            .await
+            .ok()??;
+        let remaining = document.get_f64("lockedUntil").unwrap_or(0.0) - now();
+        (remaining > 0.0).then(|| Duration::from_secs_f64(remaining))
+    }
+
+    async fn record_failure(&self, key: &str, lockout: LockoutConfig) -> Option<Duration> {
+        let now = now();
+        let window_expired = doc! {
+            "$lt": [{ "$ifNull": ["$windowStart", 0.0] }, now - lockout.window_secs as f64]
+        };
+        let document = self
+            .update(
+                key,
+                vec![
+                    doc! { "$set": {
+                        "count": { "$cond": [window_expired.clone(), 1, { "$add": ["$count", 1] }] },
+                        "windowStart": { "$cond": [window_expired, now, "$windowStart"] },
+                    } },
+                    doc! { "$set": {
+                        "lockedUntil": { "$cond": [
+                            { "$gte": ["$count", lockout.max_failures as i64] },
+                            now + lockout.lockout_secs as f64,
+                            { "$ifNull": ["$lockedUntil", 0.0] }
+                        ] },
+                        "count": { "$cond": [{ "$gte": ["$count", lockout.max_failures as i64] }, 0, "$count"] },
+                        "expiresAt": expires_at((lockout.window_secs + lockout.lockout_secs) as f64),
+                    } },
+                ],
+            )
+            .await?;
+
+        let remaining = document.get_f64("lockedUntil").unwrap_or(0.0) - now;
+        (remaining > 0.0).then(|| Duration::from_secs_f64(remaining))
+    }
+
+    async fn clear_failures(&self, key: &str) {
+        if let Err(err) = self.collection.delete_one(doc! { "_id": key }, None).await {
+            error!("Couldn't clear failed logins for {key}: {err:?}");
+        }
+    }
+}
+
+/// Managed state shared by the fairing and `authenticate`
+#[derive(Clone)]
+pub struct RateLimiter {
+    pub config: Arc<RateLimitConfig>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig, store: Arc<dyn RateLimitStore>) -> Self {
+        Self {
+            config: Arc::new(config),
+            store,
+        }
+    }
+
+    /// Uses MongoDB when `RATE_LIMIT_BACKEND=mongodb` and a database is
+    /// available, otherwise keeps everything in this instance's memory.
+    pub fn from_env(client: Option<&Client>) -> Self {
+        let config = RateLimitConfig::from_env();
+        match (std::env::var("RATE_LIMIT_BACKEND").as_deref(), client) {
+            (Ok("mongodb"), Some(client)) => {
+                info!("Keeping rate limits in MongoDB");
+                Self::new(config, Arc::new(MongoStore::new(client)))
+            }
+            (Ok("mongodb"), None) => {
+                warn!("No database for the MongoDB rate limit backend, keeping limits in memory");
+                Self::new(config, Arc::new(MemoryStore::default()))
+            }
+            _ => Self::new(config, Arc::new(MemoryStore::default())),
+        }
+    }
+
+    async fn check(&self, request: &Request<'_>) -> Option<Duration> {
+        let rule = self.config.rule_for(request.uri().path().as_str())?;
+        let mut retry_after = None;
+
+        if let (Some(bucket), Some(ip)) = (rule.per_ip, request.client_ip()) {
+            retry_after = self
+                .store
+                .take(&format!("ip:{}:{ip}", rule.path), bucket)
+                .await;
+        }
+
+        if let (Some(bucket), None) = (rule.per_account, retry_after) {
+            retry_after = match (account(request).await, request.client_ip()) {
+                (Some(account), _) => {
+                    let account = hex::encode(Sha256::digest(account.as_bytes()));
+                    self.store
+                        .take(&format!("account:{}:{account}", rule.path), bucket)
+                        .await
+                }
+                (None, Some(ip)) if rule.per_ip.is_none() => {
+                    self.store
+                        .take(&format!("ip:{}:{ip}", rule.path), bucket)
+                        .await
+                }
+                _ => None,
+            };
+        }
+
+        retry_after
+    }
+
+    /// How much longer logins to `email` are blocked for, if at all
+    pub async fn login_locked_out(&self, email: &str) -> Option<Duration> {
+        self.store.locked_out(&login_key(email)).await
+    }
+
+    /// Counts a failed password check, returning the lockout it triggered
+    pub async fn login_failed(&self, email: &str) -> Option<Duration> {
+        let lockout = self
+            .store
+            .record_failure(&login_key(email), self.config.lockout)
+            .await;
+        if lockout.is_some() {
+            warn!("Locking out logins for {email} after repeated failures");
+        }
+        lockout
+    }
+
+    pub async fn login_succeeded(&self, email: &str) {
+        self.store.clear_failures(&login_key(email)).await
+    }
+}
+
+/// Who signed the bearer token, if it's a valid token for a user. Anything
+/// else can be forged or minted freely, so it doesn't get a bucket of its own.
+async fn account(request: &Request<'_>) -> Option<String> {
+    let token = request
+        .headers()
+        .get_one("Authorization")?
+        .trim()
+        .split_once(' ')?
+        .1;
+    let keys = request.rocket().state::<JwtKeys>()?;
+    match keys.verify::<AuthClaims>(token.trim()).await? {
+        AuthClaims {
+            auth_type: AuthType::User,
+            email: Some(email),
+            ..
+        } => Some(email.trim().to_lowercase()),
+        _ => None,
+    }
+}
+
+fn login_key(email: &str) -> String {
+    format!("login:{}", email.trim().to_lowercase())
+}
+
+#[rocket::async_trait]
+impl Fairing for RateLimiter {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate Limiter",
+            kind: Kind::Liftoff | Kind::Request,
+        }
+    }
+
+    async fn on_liftoff(&self, _: &Rocket<Orbit>) {
+        self.store.prepare().await;
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if let Some(retry_after) = self.check(request).await {
+            info!(
+                "Rate limiting {:?} on {}",
+                request.client_ip(),
+                request.uri().path()
+            );
+            request.local_cache(|| RateLimitDecision(Some(retry_after)));
+            request.set_method(Method::Get);
+            request.set_uri(rocket::http::uri::Origin::parse(RATE_LIMITED_PATH).unwrap());
+        }
+    }
+}
@@ -1,133 +1,614 @@
 use crate::{
     api::{ApiError, ApiResponse},
-    auth::JWTAuthToken,
-    db::Entity,
-    user::User,
+    attachments::{self, ReportPhoto, ReportPhotoResponse},
+    auth::{AdminToken, JWTAuthToken},
+    categories::{self, ReportCategory},
+    db::{Db, Entity},
+    incidents,
+    repository::{MongoStore, RepoResult, Repository, Store},
+    storage::Storage,
+    user::{token_user, User},
     DB_NAME,
 };
-use bson::oid::ObjectId;
+use bson::{oid::ObjectId, Document};
 use macros::Entity;
-use mongodb::Client;
-use rocket::{get, post, serde::json::Json, State};
+use mongodb::{options::FindOptions, Client, Database};
+use rocket::{delete, get, post, put, serde::json::Json, FromForm, State};
 use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
 #[utoipa::path(post, path = "/api/reports", request_body = NewUserReport, security(("jwt" = [])))]
 #[post("/reports", format = "application/json", data = "<new_report>")]
 pub async fn create_report<'a>(
     token: JWTAuthToken,
     new_report: Json<NewUserReport>,
-    state: &State<Option<Client>>,
-) -> ApiResponse<'a, &'a str> {
+    client: Db,
+) -> ApiResponse<&'a str> {
     if token.email.is_none() {
-        return ApiError::AuthError("Registered user required to submit report").into();
+        return ApiError::forbidden("Registered user required to submit report").into();
     }
-
-    let db = state
+    if new_report
+        .description
         .as_ref()
-        .expect("This rocket instance has no valid database!")
-        .database(DB_NAME);
-
-    let report = if let Some(user) = User::find_one(
-        bson::doc! {
-            "email": &token.email
-        },
-        &db,
-        None,
-    )
-    .await
+        .is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_CHARS)
     {
-        new_report.into_inner().into_entity(user.email.clone())
-    } else {
-        return ApiError::ServerError("Couldn't find the user associated with this token").into();
+        return ApiError::invalid("description", "Descriptions can be at most 280 characters")
+            .into();
+    }
+
+    let store = MongoStore::new(&client.0, DB_NAME);
+    let category = match categories::active_category(&store, &new_report.report_type).await {
+        Ok(Some(category)) => category,
+        Ok(None) => return ApiError::invalid("reportType", "Unknown report type").into(),
+        Err(err) => return ApiError::from(err).into(),
+    };
+
+    let user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
     };
+    let mut report = new_report
+        .into_inner()
+        .into_entity(user.email.clone(), &category);
+    report.reporter_weight = user.weight();
 
-    match report.insert(&db).await {
-        Ok(_) => ApiResponse::Ok("Report created"),
+    match store.repository::<UserReport>().insert(&mut report).await {
+        Ok(_) => {
+            incidents::cluster_report(&report, &client.0).await;
+            ApiResponse::Ok("Report created")
+        }
         Err(err) => {
             log::error!("Couldn't insert report: {err:?}");
-            ApiError::ServerError("Couldn't create report").into()
+            ApiError::internal("Couldn't create report").into()
         }
     }
 }
 
-#[utoipa::path(get, path = "/api/reports")]
-#[get("/reports")]
-pub async fn get_reports(state: &State<Option<Client>>) -> ApiResponse<Vec<UserReportResponse>> {
-    let db = state
-        .as_ref()
-        .expect("No attached mongodb client")
-        .database(DB_NAME);
+#[utoipa::path(get, path = "/api/reports", params(ReportQuery), responses(
+    (status = 200, description = "Active reports matching every filter given, newest first", body = [UserReportResponse])
+))]
+#[get("/reports?<query..>")]
+pub async fn get_reports(query: ReportQuery, db: Db) -> ApiResponse<Vec<UserReportResponse>> {
+    let db = db.database(DB_NAME);
+
+    let filter = match query.to_filter(now_millis()) {
+        Ok(filter) => filter,
+        Err(err) => return err.into(),
+    };
+    let options = FindOptions::builder()
+        .sort(bson::doc! { "created": -1 })
+        .skip(query.offset)
+        .limit(
+            query
+                .limit
+                .unwrap_or(DEFAULT_PAGE_SIZE)
+                .clamp(1, MAX_PAGE_SIZE),
+        )
+        .build();
 
-    match UserReport::find(bson::doc! {}, &db, None).await {
+    match UserReport::find(filter, &db, Some(options)).await {
         Ok(reports) => ApiResponse::Ok(
             reports
                 .into_iter()
                 .map(|x| UserReportResponse::from(x.as_ref()))
-                .filter(|x| x.expired == false)
                 .collect(),
         ),
         Err(err) => {
             log::error!("Couldn't fetch user reports: {err:?}");
-            ApiError::ServerError("Couldn't fetch user reports!").into()
+            ApiError::internal("Couldn't fetch user reports!").into()
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
-#[non_exhaustive]
-pub enum ReportType {
-    CablesStolen,
-    SubstationBlew,
-    PowerOutage,
-    CablesDamaged,
-    Traffic,
+pub(crate) const DEFAULT_PAGE_SIZE: i64 = 100;
+pub(crate) const MAX_PAGE_SIZE: i64 = 500;
+const EARTH_RADIUS_METRES: f64 = 6_378_100.0;
+
+/// Filters for `get_reports`. Every filter given has to match.
+#[derive(Debug, Default, FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReportQuery {
+    /// Bounding box as `west,south,east,north`
+    pub bbox: Option<String>,
+    /// Centre of a radius search, needs `lng` and `radius` too
+    pub lat: Option<f64>,
+    pub lng: Option<f64>,
+    /// Radius in metres
+    pub radius: Option<f64>,
+    #[field(name = "reportType")]
+    #[param(rename = "reportType")]
+    pub report_type: Option<String>,
+    /// Only reports made at or after this time, in milliseconds
+    pub since: Option<u64>,
+    /// Only reports made before this time, in milliseconds
+    pub until: Option<u64>,
+    /// At most 500, defaults to 100
+    pub limit: Option<i64>,
+    pub offset: Option<u64>,
+}
+
+impl ReportQuery {
+    pub fn to_filter(&self, now: u64) -> Result<Document, ApiError> {
+        let mut filter = bson::doc! {
+            "hidden": { "$ne": true },
+            "expires": { "$gt": now as i64 },
+        };
+        let mut location = Vec::new();
+
+        if let Some(bbox) = &self.bbox {
+            location.push(bson::doc! { "location": { "$geoWithin": bbox_geometry(bbox)? } });
+        }
+
+        match (self.lat, self.lng, self.radius) {
+            (Some(lat), Some(lng), Some(radius)) if radius > 0.0 => {
+                location.push(bson::doc! { "location": { "$geoWithin": {
+                    "$centerSphere": [[lng, lat], radius / EARTH_RADIUS_METRES]
+                } } });
+            }
+            (None, None, None) => {}
+            _ => {
+                return Err(ApiError::bad_request(
+                    "A radius search needs lat, lng and a positive radius",
+                ))
+            }
+        }
+
+        if !location.is_empty() {
+            filter.insert("$and", location);
+        }
+
+        if let Some(report_type) = &self.report_type {
+            filter.insert("reportType", report_type);
+        }
+
+        let mut created = Document::new();
+        if let Some(since) = self.since {
+            created.insert("$gte", since as i64);
+        }
+        if let Some(until) = self.until {
+            created.insert("$lt", until as i64);
+        }
+        if !created.is_empty() {
+            filter.insert("created", created);
+        }
+
+        Ok(filter)
+    }
+}
+
+/// Turns a `west,south,east,north` bounding box into a `$geometry` for
+/// `$geoWithin`
+pub(crate) fn bbox_geometry(bbox: &str) -> Result<Document, ApiError> {
+    let bbox = bbox
+        .split(',')
+        .map(|x| x.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>();
+    let (west, south, east, north) = match bbox.as_deref() {
+        Ok([west, south, east, north]) if west < east && south < north => {
+            (*west, *south, *east, *north)
+        }
+        _ => {
+            return Err(ApiError::invalid(
+                "bbox",
+                "bbox must be west,south,east,north",
+            ))
+        }
+    };
+    Ok(bson::doc! { "$geometry": {
+        "type": "Polygon",
+        "coordinates": [[[west, south], [east, south], [east, north], [west, north], [west, south]]]
+    } })
+}
+
+#[utoipa::path(post, path = "/api/reports/{id}/confirm", params(("id",)), security(("jwt" = [])))]
+#[post("/reports/<id>/confirm")]
+pub async fn confirm_report(
+    id: &str,
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<UserReportResponse> {
+    vote_on_report(id, true, &token, state).await
+}
+
+#[utoipa::path(post, path = "/api/reports/{id}/dispute", params(("id",)), security(("jwt" = [])))]
+#[post("/reports/<id>/dispute")]
+pub async fn dispute_report(
+    id: &str,
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<UserReportResponse> {
+    vote_on_report(id, false, &token, state).await
+}
+
+async fn vote_on_report(
+    id: &str,
+    confirm: bool,
+    token: &JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<UserReportResponse> {
+    let client = match state.inner() {
+        Some(client) => client,
+        None => return ApiError::database_unavailable().into(),
+    };
+    let (store, db) = (MongoStore::new(client, DB_NAME), client.database(DB_NAME));
+    let user = match token_user(&store, token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+    let report = match find_report(&store, id).await {
+        Ok(report) => report,
+        Err(err) => return err.into(),
+    };
+
+    if report.email == user.email {
+        return ApiError::bad_request("You can't vote on your own report").into();
+    }
+    if report.hidden || report.is_expired() {
+        return ApiError::bad_request("This report is no longer active").into();
+    }
+
+    // Changing your mind takes back the reputation the earlier vote gave
+    let mut reputation_change = if confirm {
+        REPUTATION_STEP
+    } else {
+        -REPUTATION_STEP
+    };
+    if let Some(previous) = report.votes.iter().find(|vote| vote.email == user.email) {
+        if previous.confirm == confirm {
+            return ApiResponse::Ok(UserReportResponse::from(&report));
+        }
+        reputation_change *= 2.0;
+    }
+
+    let vote = ReportVote {
+        email: user.email.clone(),
+        confirm,
+        weight: user.weight(),
+        at: now_millis(),
+    };
+    let extend_to = confirm.then(|| now_millis() + CONFIRMATION_EXTENSION_MILLIS);
+    let report_id = match report.id {
+        Some(id) => id,
+        None => return ApiError::not_found("No such report").into(),
+    };
+    let recorded = match cast_vote(&store, report_id, &vote, extend_to).await {
+        Ok(recorded) => recorded,
+        Err(err) => {
+            log::error!("Couldn't record vote on report: {err:?}");
+            return ApiError::internal("Couldn't record your vote").into();
+        }
+    };
+    let report = match find_report(&store, id).await {
+        Ok(report) => report,
+        Err(err) => return err.into(),
+    };
+
+    // Another vote of theirs got there first and did all this
+    if recorded {
+        if confirm {
+            incidents::extend_incident(&report, &db).await;
+        }
+        adjust_reputation(&report.email, reputation_change, &db).await;
+    }
+    ApiResponse::Ok(UserReportResponse::from(&report))
 }
 
-#[derive(Debug, Clone, Serialize, ToSchema)]
+/// Replaces any earlier vote by the same voter with `vote`, pushing the
+/// report's expiry out to at least `extend_to`. Returns false when another
+/// vote by them landed in between, leaving that one.
+pub async fn cast_vote<S: Store>(
+    store: &S,
+    report: ObjectId,
+    vote: &ReportVote,
+    extend_to: Option<u64>,
+) -> RepoResult<bool> {
+    let reports = store.repository::<UserReport>();
+    reports
+        .update_by_id(
+            report,
+            bson::doc! { "$pull": { "votes": { "email": &vote.email } } },
+        )
+        .await?;
+
+    let mut update = bson::doc! { "$push": { "votes": bson::to_bson(vote)? } };
+    if let Some(extend_to) = extend_to {
+        update.insert("$max", bson::doc! { "expires": extend_to as i64 });
+    }
+    reports
+        .update_one(
+            bson::doc! { "_id": report, "votes.email": { "$ne": &vote.email } },
+            update,
+        )
+        .await
+}
+
+#[utoipa::path(put, path = "/api/reports/{id}/hidden", params(("id",)), request_body = HideReport, security(("jwt" = [])))]
+#[put("/reports/<id>/hidden", format = "application/json", data = "<hide>")]
+pub async fn hide_report(
+    id: &str,
+    hide: Json<HideReport>,
+    admin: AdminToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<&'static str> {
+    let client = match state.inner() {
+        Some(client) => client,
+        None => return ApiError::database_unavailable().into(),
+    };
+    let (store, db) = (MongoStore::new(client, DB_NAME), client.database(DB_NAME));
+    let report = match find_report(&store, id).await {
+        Ok(report) => report,
+        Err(err) => return err.into(),
+    };
+    if report.hidden == hide.hidden {
+        return ApiResponse::Ok("Report unchanged");
+    }
+
+    if let Err(err) = store
+        .repository::<UserReport>()
+        .update_one(
+            bson::doc! { "_id": report.id },
+            bson::doc! { "$set": { "hidden": hide.hidden } },
+        )
+        .await
+    {
+        log::error!("Couldn't hide report: {err:?}");
+        return ApiError::internal("Couldn't update report").into();
+    }
+
+    // Hidden reports don't count towards incidents
+    if hide.hidden {
+        incidents::detach_report(&report, &store).await;
+    } else {
+        incidents::cluster_report(&report, client).await;
+    }
+
+    let penalty = if hide.hidden {
+        -MODERATION_PENALTY
+    } else {
+        MODERATION_PENALTY
+    };
+    adjust_reputation(&report.email, penalty, &db).await;
+    log::info!(
+        "{} set report {id} hidden={}: {}",
+        admin.email(),
+        hide.hidden,
+        hide.reason.as_deref().unwrap_or("no reason given")
+    );
+    ApiResponse::Ok("Report updated")
+}
+
+#[utoipa::path(delete, path = "/api/reports/{id}", params(("id",)), security(("jwt" = [])))]
+#[delete("/reports/<id>")]
+pub async fn delete_report(
+    id: &str,
+    admin: AdminToken,
+    state: &State<Option<Client>>,
+    storage: &State<Storage>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let report = match find_report(&store, id).await {
+        Ok(report) => report,
+        Err(err) => return err.into(),
+    };
+    incidents::detach_report(&report, &store).await;
+
+    match store
+        .repository::<UserReport>()
+        .delete_many(bson::doc! { "_id": report.id })
+        .await
+    {
+        Ok(_) => {
+            attachments::delete_photos(&report, storage).await;
+            log::info!("{} deleted report {id}", admin.email());
+            ApiResponse::Ok("Report deleted")
+        }
+        Err(err) => {
+            log::error!("Couldn't delete report: {err:?}");
+            ApiError::internal("Couldn't delete report").into()
+        }
+    }
+}
+
+pub(crate) async fn find_report<S: Store>(store: &S, id: &str) -> Result<UserReport, ApiError> {
+    let id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return Err(ApiError::bad_request("Invalid report id")),
+    };
+    match store.repository::<UserReport>().find_by_id(id).await? {
+        Some(report) => Ok(report),
+        None => Err(ApiError::not_found("No such report")),
+    }
+}
+
+async fn adjust_reputation(email: &str, change: f64, db: &Database) {
+    if let Err(err) = db
+        .collection::<User>("users")
+        .update_one(
+            bson::doc! { "email": email },
+            vec![bson::doc! { "$set": { "reputation": {
+                "$add": [{ "$ifNull": ["$reputation", 1.0] }, change]
+            } } }],
+            None,
+        )
+        .await
+    {
+        log::error!("Couldn't update reputation of {email}: {err:?}");
+    }
+}
+
+pub(crate) fn now_millis() -> u64 {
+    u64::try_from(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis(),
+    )
+    .unwrap()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
 #[schema(example = json! {
     UserReportResponse {
-        report_type: ReportType::CablesStolen,
-        email: "joe".to_string(),
+        id: "650c4ba5d4fe1b1e3fd0b4d1".to_string(),
+        report_type: "CablesStolen".to_string(),
         latitude: 0.0,
         longitude: 0.0,
-        expired: false
+        expired: false,
+        expires: 1695304800000,
+        confirmations: 2,
+        disputes: 0,
+        confidence: 1.0,
+        description: Some("The whole street is dark".to_string()),
+        photos: vec![]
     }
 })]
 pub struct UserReportResponse {
-    pub report_type: ReportType,
-    pub email: String,
+    pub id: String,
+    /// Key of the report's category
+    pub report_type: String,
     pub latitude: f64,
     pub longitude: f64,
     pub expired: bool,
+    pub expires: u64,
+    pub confirmations: usize,
+    pub disputes: usize,
+    /// Share of reputation-weighted votes, the reporter's included, that
+    /// back the report up. Between 0 and 1
+    pub confidence: f64,
+    pub description: Option<String>,
+    pub photos: Vec<ReportPhotoResponse>,
 }
 
 impl From<&UserReport> for UserReportResponse {
     fn from(value: &UserReport) -> Self {
         Self {
+            id: value.id.map(|id| id.to_hex()).unwrap_or_default(),
             expired: value.is_expired(),
+            expires: value.expires,
             latitude: value.latitude,
             longitude: value.longitude,
             report_type: value.report_type.clone(),
-            email: value.email.clone(),
+            confirmations: value.votes.iter().filter(|vote| vote.confirm).count(),
+            disputes: value.votes.iter().filter(|vote| !vote.confirm).count(),
+            confidence: value.confidence(),
+            description: value.description.clone(),
+            photos: value
+                .photos
+                .iter()
+                .map(|photo| {
+                    ReportPhotoResponse::new(&value.id.unwrap_or_default().to_hex(), photo)
+                })
+                .collect(),
         }
     }
 }
 
+const MAX_DESCRIPTION_CHARS: usize = 280;
+/// How long a report stays up after someone confirms it
+const CONFIRMATION_EXTENSION_MILLIS: u64 = 1000 * 60 * 30;
+const REPUTATION_STEP: f64 = 0.1;
+const MODERATION_PENALTY: f64 = 0.5;
+
 #[derive(Debug, Clone, Deserialize, Serialize, Entity)]
+#[serde(rename_all = "camelCase")]
 #[collection_name = "reports"]
+#[index(fields = "expires, -created")]
 pub struct UserReport {
     #[serde(skip_serializing_if = "Option::is_none", rename = "_id")]
     pub id: Option<ObjectId>,
-    pub report_type: ReportType,
+    /// Key of the report's category. The alias reads reports written before
+    /// migration 3.
+    #[serde(alias = "report_type")]
+    pub report_type: String,
     pub latitude: f64,
     pub longitude: f64,
     pub email: String,
     pub expires: u64,
+    /// When the report was made, in milliseconds
+    #[serde(default)]
+    pub created: u64,
+    /// The same place as `latitude` and `longitude`, for 2dsphere queries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[index(2dsphere)]
+    pub location: Option<GeoPoint>,
+    /// The reporter's reputation weight when they made the report
+    #[serde(default = "default_weight", alias = "reporter_weight")]
+    pub reporter_weight: f64,
+    #[serde(default)]
+    pub votes: Vec<ReportVote>,
+    /// Hidden by a moderator
+    #[serde(default)]
+    pub hidden: bool,
+    /// Set on outage reports once they've been checked against the schedule,
+    /// true when the schedule had power on. Null when we have no schedule
+    /// for where the report was made
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unscheduled: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub photos: Vec<ReportPhoto>,
+}
+
+/// A GeoJSON point, longitude first
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GeoPoint {
+    pub r#type: String,
+    pub coordinates: [f64; 2],
+}
+
+impl GeoPoint {
+    pub fn new(longitude: f64, latitude: f64) -> Self {
+        Self {
+            r#type: "Point".to_string(),
+            coordinates: [longitude, latitude],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReportVote {
+    pub email: String,
+    /// A confirmation when true, a dispute otherwise
+    pub confirm: bool,
+    /// The voter's reputation weight when they voted
+    pub weight: f64,
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct HideReport {
+    pub hidden: bool,
+    pub reason: Option<String>,
+}
+
+fn default_weight() -> f64 {
+    1.0
 }
 
 impl UserReport {
+    pub fn confidence(&self) -> f64 {
+        let (support, against) =
+            self.votes
+                .iter()
+                .fold((self.reporter_weight, 0.0), |(support, against), vote| {
+                    if vote.confirm {
+                        (support + vote.weight, against)
+                    } else {
+                        (support, against + vote.weight)
+                    }
+                });
+        if support + against <= 0.0 {
+            return 0.0;
+        }
+        support / (support + against)
+    }
+
     pub fn is_expired(&self) -> bool {
         u64::try_from(
             SystemTime::now()
@@ -143,28 +624,46 @@ impl UserReport {
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 #[schema(example = json! {
     NewUserReport {
-        report_type: ReportType::CablesStolen,
+        report_type: "CablesStolen".to_string(),
         latitude: 0.0,
         longitude: 0.0,
-        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+        description: Some("The whole street is dark".to_string())
     }
 })]
 pub struct NewUserReport {
-    pub report_type: ReportType,
+    /// Key of the report's category
+    pub report_type: String,
     pub latitude: f64,
     pub longitude: f64,
     pub timestamp: u128,
+    /// What the reporter saw, at most 280 characters. Photos are added
+    /// afterwards through `/api/reports/{id}/photos`
+    #[serde(default)]
+    pub description: Option<String>,
 }
 
 impl NewUserReport {
-    pub fn into_entity(self, email: String) -> UserReport {
+    pub fn into_entity(self, email: String, category: &ReportCategory) -> UserReport {
         UserReport {
             id: None,
             report_type: self.report_type,
             latitude: self.latitude,
             longitude: self.longitude,
-            expires: u64::try_from(self.timestamp).unwrap() + 1000 * 60 * 30,
+            expires: u64::try_from(self.timestamp).unwrap()
+                + u64::from(category.expiry_minutes) * 1000 * 60,
+            created: u64::try_from(self.timestamp).unwrap(),
+            location: Some(GeoPoint::new(self.longitude, self.latitude)),
             email,
+            reporter_weight: default_weight(),
+            votes: Vec::new(),
+            hidden: false,
+            unscheduled: None,
+            description: self
+                .description
+                .map(|description| description.trim().to_string())
+                .filter(|description| !description.is_empty()),
+            photos: Vec::new(),
         }
     }
 }
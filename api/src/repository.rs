@@ -0,0 +1,1229 @@
+#![allow(dead_code)]
+use crate::api::ApiError;
+use crate::db::IndexSpec;
+use async_trait::async_trait;
+use bson::{doc, oid::ObjectId, Bson, Document};
+use mongodb::{
+    error::{ErrorKind, WriteFailure},
+    options::{CountOptions, FindOptions, ReplaceOptions, UpdateOptions},
+    Client, ClientSession, Collection, Database,
+};
+use rocket::futures::TryStreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+
+/// A document type stored in its own collection. Derived alongside
+/// `Entity` by `macros::Entity`.
+pub trait Model: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static {
+    const COLLECTION: &'static str;
+    /// Declared with `#[index]` and `#[ttl]` on the entity
+    const INDEXES: &'static [IndexSpec] = &[];
+
+    fn id(&self) -> Option<ObjectId>;
+    fn set_id(&mut self, id: ObjectId);
+}
+
+#[derive(Debug)]
+pub enum RepoError {
+    Database(mongodb::error::Error),
+    Bson(String),
+    /// Another document already has this unique key
+    Duplicate(String),
+    /// The in-memory store doesn't understand part of a filter or update
+    Unsupported(String),
+}
+
+pub type RepoResult<T> = Result<T, RepoError>;
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Database(err) => write!(f, "database error: {err}"),
+            Self::Bson(err) => write!(f, "couldn't convert document: {err}"),
+            Self::Duplicate(key) => write!(f, "duplicate key: {key}"),
+            Self::Unsupported(what) => write!(f, "unsupported by the in-memory store: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+impl From<mongodb::error::Error> for RepoError {
+    fn from(err: mongodb::error::Error) -> Self {
+        if let ErrorKind::Write(WriteFailure::WriteError(write)) = err.kind.as_ref() {
+            if write.code == 11000 {
+                return Self::Duplicate(write.message.clone());
+            }
+        }
+        Self::Database(err)
+    }
+}
+
+impl From<bson::ser::Error> for RepoError {
+    fn from(err: bson::ser::Error) -> Self {
+        Self::Bson(err.to_string())
+    }
+}
+
+impl From<bson::de::Error> for RepoError {
+    fn from(err: bson::de::Error) -> Self {
+        Self::Bson(err.to_string())
+    }
+}
+
+impl From<RepoError> for ApiError {
+    fn from(err: RepoError) -> Self {
+        match err {
+            RepoError::Duplicate(_) => ApiError::conflict("That already exists"),
+            err => {
+                log::error!("Repository error: {err}");
+                ApiError::internal("Error occured on the server, sorry :<")
+            }
+        }
+    }
+}
+
+/// Which slice of the matching documents to return, and in what order
+#[derive(Debug, Clone, Default)]
+pub struct PageRequest {
+    pub offset: u64,
+    /// `None` returns everything after `offset`
+    pub limit: Option<i64>,
+    pub sort: Option<Document>,
+}
+
+impl PageRequest {
+    pub fn new(offset: u64, limit: i64) -> Self {
+        Self {
+            offset,
+            limit: Some(limit),
+            sort: None,
+        }
+    }
+
+    /// Every matching document, in natural order
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn sorted_by(mut self, sort: Document) -> Self {
+        self.sort = Some(sort);
+        self
+    }
+}
+
+impl From<FindOptions> for PageRequest {
+    fn from(options: FindOptions) -> Self {
+        Self {
+            offset: options.skip.unwrap_or(0),
+            limit: options.limit.filter(|limit| *limit != 0).map(i64::abs),
+            sort: options.sort,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// How many documents matched, ignoring `offset` and `limit`
+    pub total: u64,
+    pub offset: u64,
+}
+
+impl<T> Page<T> {
+    pub fn has_more(&self) -> bool {
+        self.offset + (self.items.len() as u64) < self.total
+    }
+}
+
+/// Typed access to one collection. Updates take the usual MongoDB update
+/// documents (`$set`, `$inc`, ...).
+#[async_trait]
+pub trait Repository<T: Model>: Send + Sync {
+    async fn find_by_id(&self, id: ObjectId) -> RepoResult<Option<T>>;
+    async fn find_one(&self, filter: Document, sort: Option<Document>) -> RepoResult<Option<T>>;
+    async fn find(&self, filter: Document, page: PageRequest) -> RepoResult<Page<T>>;
+    async fn count(&self, filter: Document) -> RepoResult<u64>;
+    /// Inserts `model`, giving it an id first if it has none
+    async fn insert(&self, model: &mut T) -> RepoResult<ObjectId>;
+    /// Returns whether there was a document with that id
+    async fn update_by_id(&self, id: ObjectId, update: Document) -> RepoResult<bool>;
+    /// Updates the first document matching `filter`. A `$` in an updated
+    /// path stands for the first array element the filter matched. Returns
+    /// whether anything matched.
+    async fn update_one(&self, filter: Document, update: Document) -> RepoResult<bool>;
+    /// Returns how many documents matched `filter`
+    async fn update_many(&self, filter: Document, update: Document) -> RepoResult<u64>;
+    /// `update_many` where a `$[name]` in an updated path stands for every
+    /// element of the array that the filter in `array_filters` on `name`
+    /// matches
+    async fn update_elements(
+        &self,
+        filter: Document,
+        update: Document,
+        array_filters: Vec<Document>,
+    ) -> RepoResult<u64>;
+    /// Replaces the first document matching `filter` with `model`, inserting
+    /// it if nothing matches. Returns whether it was inserted.
+    async fn upsert(&self, filter: Document, model: &mut T) -> RepoResult<bool>;
+    /// Returns whether there was a document with that id
+    async fn delete_by_id(&self, id: ObjectId) -> RepoResult<bool>;
+    /// Returns how many documents were deleted
+    async fn delete_many(&self, filter: Document) -> RepoResult<u64>;
+}
+
+/// Hands out repositories and transactions over them
+#[async_trait]
+pub trait Store: Send + Sync {
+    type Repo<T: Model>: Repository<T>;
+    type Transaction: Transaction;
+
+    fn repository<T: Model>(&self) -> Self::Repo<T>;
+    async fn begin(&self) -> RepoResult<Self::Transaction>;
+}
+
+/// Nothing written through a transaction's repositories sticks until
+/// `commit`. Dropping it without committing aborts it.
+#[async_trait]
+pub trait Transaction: Send + Sync + Sized {
+    type Repo<T: Model>: Repository<T>;
+
+    fn repository<T: Model>(&self) -> Self::Repo<T>;
+    async fn commit(self) -> RepoResult<()>;
+    async fn abort(self) -> RepoResult<()>;
+}
+
+fn to_document<T: Model>(model: &mut T) -> RepoResult<Document> {
+    let mut document = bson::to_document(model)?;
+    let id = match document.get("_id") {
+        Some(Bson::ObjectId(id)) => *id,
+        Some(Bson::Null) | None => {
+            let id = ObjectId::new();
+            document.insert("_id", id);
+            id
+        }
+        Some(other) => return Err(RepoError::Bson(format!("unexpected _id {other}"))),
+    };
+    model.set_id(id);
+    Ok(document)
+}
+
+/// Serializes `model` for a replacement, leaving out its id if it has none
+fn to_replacement<T: Model>(model: &T) -> RepoResult<Document> {
+    let mut document = bson::to_document(model)?;
+    if matches!(document.get("_id"), Some(Bson::Null)) {
+        document.remove("_id");
+    }
+    Ok(document)
+}
+
+fn from_document<T: Model>(document: Document) -> RepoResult<T> {
+    Ok(bson::from_document(document)?)
+}
+
+pub struct MongoRepository<T> {
+    collection: Collection<Document>,
+    session: Option<Arc<tokio::sync::Mutex<ClientSession>>>,
+    model: PhantomData<fn() -> T>,
+}
+
+impl<T: Model> MongoRepository<T> {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            collection: db.collection(T::COLLECTION),
+            session: None,
+            model: PhantomData,
+        }
+    }
+
+    fn in_session(db: &Database, session: Arc<tokio::sync::Mutex<ClientSession>>) -> Self {
+        Self {
+            session: Some(session),
+            ..Self::new(db)
+        }
+    }
+
+    async fn collect(&self, filter: Document, options: FindOptions) -> RepoResult<Vec<Document>> {
+        match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                let mut cursor = self
+                    .collection
+                    .find_with_session(filter, options, &mut session)
+                    .await?;
+                let mut documents = Vec::new();
+                while let Some(document) = cursor.next(&mut session).await {
+                    documents.push(document?);
+                }
+                Ok(documents)
+            }
+            None => Ok(self
+                .collection
+                .find(filter, options)
+                .await?
+                .try_collect()
+                .await?),
+        }
+    }
+}
+
+#[async_trait]
+impl<T: Model> Repository<T> for MongoRepository<T> {
+    async fn find_by_id(&self, id: ObjectId) -> RepoResult<Option<T>> {
+        self.find_one(doc! { "_id": id }, None).await
+    }
+
+    async fn find_one(&self, filter: Document, sort: Option<Document>) -> RepoResult<Option<T>> {
+        let options = FindOptions::builder().sort(sort).limit(1).build();
+        match self.collect(filter, options).await?.into_iter().next() {
+            Some(document) => Ok(Some(from_document(document)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find(&self, filter: Document, page: PageRequest) -> RepoResult<Page<T>> {
+        let options = FindOptions::builder()
+            .skip(page.offset)
+            .limit(page.limit)
+            .sort(page.sort)
+            .build();
+        let items = self
+            .collect(filter.clone(), options)
+            .await?
+            .into_iter()
+            .map(from_document)
+            .collect::<RepoResult<Vec<T>>>()?;
+        let total = match page.limit {
+            Some(_) => self.count(filter).await?,
+            None => page.offset + items.len() as u64,
+        };
+        Ok(Page {
+            items,
+            total,
+            offset: page.offset,
+        })
+    }
+
+    async fn count(&self, filter: Document) -> RepoResult<u64> {
+        let options: Option<CountOptions> = None;
+        Ok(match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                self.collection
+                    .count_documents_with_session(filter, options, &mut session)
+                    .await?
+            }
+            None => self.collection.count_documents(filter, options).await?,
+        })
+    }
+
+    async fn insert(&self, model: &mut T) -> RepoResult<ObjectId> {
+        let document = to_document(model)?;
+        match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                self.collection
+                    .insert_one_with_session(document, None, &mut session)
+                    .await?
+            }
+            None => self.collection.insert_one(document, None).await?,
+        };
+        Ok(model.id().expect("to_document sets the id"))
+    }
+
+    async fn update_by_id(&self, id: ObjectId, update: Document) -> RepoResult<bool> {
+        self.update_one(doc! { "_id": id }, update).await
+    }
+
+    async fn update_one(&self, filter: Document, update: Document) -> RepoResult<bool> {
+        let result = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                self.collection
+                    .update_one_with_session(filter, update, None, &mut session)
+                    .await?
+            }
+            None => self.collection.update_one(filter, update, None).await?,
+        };
+        Ok(result.matched_count > 0)
+    }
+
+    async fn update_many(&self, filter: Document, update: Document) -> RepoResult<u64> {
+        let result = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                self.collection
+                    .update_many_with_session(filter, update, None, &mut session)
+                    .await?
+            }
+            None => self.collection.update_many(filter, update, None).await?,
+        };
+        Ok(result.matched_count)
+    }
+
+    async fn update_elements(
+        &self,
+        filter: Document,
+        update: Document,
+        array_filters: Vec<Document>,
+    ) -> RepoResult<u64> {
+        let options = UpdateOptions::builder()
+            .array_filters(array_filters)
+            .build();
+        let result = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                self.collection
+                    .update_many_with_session(filter, update, options, &mut session)
+                    .await?
+            }
+            None => self.collection.update_many(filter, update, options).await?,
+        };
+        Ok(result.matched_count)
+    }
+
+    async fn upsert(&self, filter: Document, model: &mut T) -> RepoResult<bool> {
+        let replacement = to_replacement(model)?;
+        let options = ReplaceOptions::builder().upsert(true).build();
+        let result = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                self.collection
+                    .replace_one_with_session(filter, replacement, options, &mut session)
+                    .await?
+            }
+            None => {
+                self.collection
+                    .replace_one(filter, replacement, options)
+                    .await?
+            }
+        };
+        match result.upserted_id {
+            Some(Bson::ObjectId(id)) => {
+                model.set_id(id);
+                Ok(true)
+            }
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
+
+    async fn delete_by_id(&self, id: ObjectId) -> RepoResult<bool> {
+        let filter = doc! { "_id": id };
+        let result = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                self.collection
+                    .delete_one_with_session(filter, None, &mut session)
+                    .await?
+            }
+            None => self.collection.delete_one(filter, None).await?,
+        };
+        Ok(result.deleted_count > 0)
+    }
+
+    async fn delete_many(&self, filter: Document) -> RepoResult<u64> {
+        let result = match &self.session {
+            Some(session) => {
+                let mut session = session.lock().await;
+                self.collection
+                    .delete_many_with_session(filter, None, &mut session)
+                    .await?
+            }
+            None => self.collection.delete_many(filter, None).await?,
+        };
+        Ok(result.deleted_count)
+    }
+}
+
+#[derive(Clone)]
+pub struct MongoStore {
+    client: Client,
+    db: Database,
+}
+
+impl MongoStore {
+    pub fn new(client: &Client, db: &str) -> Self {
+        Self {
+            client: client.clone(),
+            db: client.database(db),
+        }
+    }
+}
+
+#[async_trait]
+impl Store for MongoStore {
+    type Repo<T: Model> = MongoRepository<T>;
+    type Transaction = MongoTransaction;
+
+    fn repository<T: Model>(&self) -> MongoRepository<T> {
+        MongoRepository::new(&self.db)
+    }
+
+    /// Transactions need the deployment to be a replica set
+    async fn begin(&self) -> RepoResult<MongoTransaction> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        Ok(MongoTransaction {
+            db: self.db.clone(),
+            session: Arc::new(tokio::sync::Mutex::new(session)),
+        })
+    }
+}
+
+/// Mongo aborts transactions whose session is dropped without committing
+pub struct MongoTransaction {
+    db: Database,
+    session: Arc<tokio::sync::Mutex<ClientSession>>,
+}
+
+#[async_trait]
+impl Transaction for MongoTransaction {
+    type Repo<T: Model> = MongoRepository<T>;
+
+    fn repository<T: Model>(&self) -> MongoRepository<T> {
+        MongoRepository::in_session(&self.db, self.session.clone())
+    }
+
+    async fn commit(self) -> RepoResult<()> {
+        Ok(self.session.lock().await.commit_transaction().await?)
+    }
+
+    async fn abort(self) -> RepoResult<()> {
+        Ok(self.session.lock().await.abort_transaction().await?)
+    }
+}
+
+type Collections = HashMap<&'static str, Vec<Document>>;
+
+/// Keeps every collection in memory, for tests and read caches. Filters and
+/// updates support the common operators; anything else fails with
+/// [`RepoError::Unsupported`] rather than being silently ignored.
+#[derive(Clone, Default)]
+pub struct MemoryStore(Arc<Mutex<Collections>>);
+
+impl MemoryStore {
+    /// Replaces everything in `collection` with `documents`
+    pub fn load(&self, collection: &'static str, documents: Vec<Document>) {
+        self.0.lock().unwrap().insert(collection, documents);
+    }
+}
+
+pub struct MemoryRepository<T> {
+    store: MemoryStore,
+    model: PhantomData<fn() -> T>,
+}
+
+impl<T: Model> MemoryRepository<T> {
+    fn with<R>(&self, f: impl FnOnce(&mut Vec<Document>) -> RepoResult<R>) -> RepoResult<R> {
+        let mut collections = self.store.0.lock().unwrap();
+        f(collections.entry(T::COLLECTION).or_default())
+    }
+
+    fn matching(&self, filter: &Document, sort: Option<&Document>) -> RepoResult<Vec<Document>> {
+        let mut documents = self.with(|documents| {
+            let mut matching = Vec::new();
+            for document in documents.iter() {
+                if matches_filter(document, filter)? {
+                    matching.push(document.clone());
+                }
+            }
+            Ok(matching)
+        })?;
+        if let Some(sort) = sort {
+            sort_documents(&mut documents, sort)?;
+        }
+        Ok(documents)
+    }
+}
+
+#[async_trait]
+impl<T: Model> Repository<T> for MemoryRepository<T> {
+    async fn find_by_id(&self, id: ObjectId) -> RepoResult<Option<T>> {
+        self.find_one(doc! { "_id": id }, None).await
+    }
+
+    async fn find_one(&self, filter: Document, sort: Option<Document>) -> RepoResult<Option<T>> {
+        match self.matching(&filter, sort.as_ref())?.into_iter().next() {
+            Some(document) => Ok(Some(from_document(document)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find(&self, filter: Document, page: PageRequest) -> RepoResult<Page<T>> {
+        let documents = self.matching(&filter, page.sort.as_ref())?;
+        let total = documents.len() as u64;
+        let limit = page.limit.map_or(usize::MAX, |limit| limit.max(0) as usize);
+        let items = documents
+            .into_iter()
+            .skip(page.offset as usize)
+            .take(limit)
+            .map(from_document)
+            .collect::<RepoResult<Vec<T>>>()?;
+        Ok(Page {
+            items,
+            total,
+            offset: page.offset,
+        })
+    }
+
+    async fn count(&self, filter: Document) -> RepoResult<u64> {
+        Ok(self.matching(&filter, None)?.len() as u64)
+    }
+
+    async fn insert(&self, model: &mut T) -> RepoResult<ObjectId> {
+        let document = to_document(model)?;
+        let id = model.id().expect("to_document sets the id");
+        self.with(|documents| {
+            if documents
+                .iter()
+                .any(|existing| existing.get("_id") == Some(&Bson::ObjectId(id)))
+            {
+                return Err(RepoError::Duplicate(format!("_id {id}")));
+            }
+            documents.push(document);
+            Ok(id)
+        })
+    }
+
+    async fn update_by_id(&self, id: ObjectId, update: Document) -> RepoResult<bool> {
+        self.update_one(doc! { "_id": id }, update).await
+    }
+
+    async fn update_one(&self, filter: Document, update: Document) -> RepoResult<bool> {
+        self.with(|documents| {
+            for document in documents.iter_mut() {
+                if matches_filter(document, &filter)? {
+                    let mut updated = document.clone();
+                    apply_update(&mut updated, &update, &filter, &[])?;
+                    *document = updated;
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })
+    }
+
+    async fn update_many(&self, filter: Document, update: Document) -> RepoResult<u64> {
+        self.update_elements(filter, update, Vec::new()).await
+    }
+
+    async fn update_elements(
+        &self,
+        filter: Document,
+        update: Document,
+        array_filters: Vec<Document>,
+    ) -> RepoResult<u64> {
+        self.with(|documents| {
+            // Nothing changes if any document fails to update
+            let mut updated = documents.clone();
+            let mut matched = 0;
+            for document in updated.iter_mut() {
+                if matches_filter(document, &filter)? {
+                    apply_update(document, &update, &filter, &array_filters)?;
+                    matched += 1;
+                }
+            }
+            *documents = updated;
+            Ok(matched)
+        })
+    }
+
+    async fn upsert(&self, filter: Document, model: &mut T) -> RepoResult<bool> {
+        let mut replacement = to_replacement(model)?;
+        self.with(|documents| {
+            for document in documents.iter_mut() {
+                if matches_filter(document, &filter)? {
+                    if let Some(id) = document.get("_id") {
+                        replacement.insert("_id", id.clone());
+                    }
+                    *document = replacement;
+                    return Ok(false);
+                }
+            }
+            let id = match replacement.get("_id") {
+                Some(Bson::ObjectId(id)) => *id,
+                _ => ObjectId::new(),
+            };
+            replacement.insert("_id", id);
+            documents.push(replacement);
+            model.set_id(id);
+            Ok(true)
+        })
+    }
+
+    async fn delete_by_id(&self, id: ObjectId) -> RepoResult<bool> {
+        self.with(|documents| {
+            let id = Bson::ObjectId(id);
+            let before = documents.len();
+            documents.retain(|document| document.get("_id") != Some(&id));
+            Ok(documents.len() != before)
+        })
+    }
+
+    async fn delete_many(&self, filter: Document) -> RepoResult<u64> {
+        self.with(|documents| {
+            let mut kept = Vec::new();
+            for document in documents.iter() {
+                if !matches_filter(document, &filter)? {
+                    kept.push(document.clone());
+                }
+            }
+            let deleted = (documents.len() - kept.len()) as u64;
+            *documents = kept;
+            Ok(deleted)
+        })
+    }
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    type Repo<T: Model> = MemoryRepository<T>;
+    type Transaction = MemoryTransaction;
+
+    fn repository<T: Model>(&self) -> MemoryRepository<T> {
+        MemoryRepository {
+            store: self.clone(),
+            model: PhantomData,
+        }
+    }
+
+    async fn begin(&self) -> RepoResult<MemoryTransaction> {
+        let snapshot = self.0.lock().unwrap().clone();
+        Ok(MemoryTransaction {
+            store: self.clone(),
+            snapshot: Some(snapshot),
+        })
+    }
+}
+
+/// Writes go straight to the store and are rolled back on abort. There's no
+/// isolation from other writers, which is fine for tests.
+pub struct MemoryTransaction {
+    store: MemoryStore,
+    snapshot: Option<Collections>,
+}
+
+#[async_trait]
+impl Transaction for MemoryTransaction {
+    type Repo<T: Model> = MemoryRepository<T>;
+
+    fn repository<T: Model>(&self) -> MemoryRepository<T> {
+        self.store.repository()
+    }
+
+    async fn commit(mut self) -> RepoResult<()> {
+        self.snapshot = None;
+        Ok(())
+    }
+
+    async fn abort(self) -> RepoResult<()> {
+        Ok(())
+    }
+}
+
+impl Drop for MemoryTransaction {
+    fn drop(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            *self.store.0.lock().unwrap() = snapshot;
+        }
+    }
+}
+
+/// Every value at a dotted `path`, looking inside arrays along the way
+fn values_at<'a>(value: &'a Bson, path: &str) -> Vec<&'a Bson> {
+    let (key, rest) = match path.split_once('.') {
+        Some((key, rest)) => (key, Some(rest)),
+        None => (path, None),
+    };
+    let children: Vec<&Bson> = match value {
+        Bson::Document(document) => document.get(key).into_iter().collect(),
+        Bson::Array(items) => match key.parse::<usize>() {
+            Ok(index) => items.get(index).into_iter().collect(),
+            Err(_) => items
+                .iter()
+                .filter_map(|item| item.as_document()?.get(key))
+                .collect(),
+        },
+        _ => Vec::new(),
+    };
+    match rest {
+        Some(rest) => children
+            .into_iter()
+            .flat_map(|child| values_at(child, rest))
+            .collect(),
+        None => children,
+    }
+}
+
+/// The values at `path` plus the elements of any arrays among them, which is
+/// what comparisons match against
+fn candidates<'a>(values: &[&'a Bson]) -> Vec<&'a Bson> {
+    let mut candidates = Vec::new();
+    for value in values {
+        candidates.push(*value);
+        if let Bson::Array(items) = value {
+            candidates.extend(items.iter());
+        }
+    }
+    candidates
+}
+
+fn as_number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(n) => Some(*n as f64),
+        Bson::Int64(n) => Some(*n as f64),
+        Bson::Double(n) => Some(*n),
+        _ => None,
+    }
+}
+
+fn type_rank(value: &Bson) -> u8 {
+    match value {
+        Bson::Null | Bson::Undefined => 1,
+        Bson::Int32(_) | Bson::Int64(_) | Bson::Double(_) | Bson::Decimal128(_) => 2,
+        Bson::String(_) | Bson::Symbol(_) => 3,
+        Bson::Document(_) => 4,
+        Bson::Array(_) => 5,
+        Bson::Binary(_) => 6,
+        Bson::ObjectId(_) => 7,
+        Bson::Boolean(_) => 8,
+        Bson::DateTime(_) => 9,
+        Bson::Timestamp(_) => 10,
+        _ => 11,
+    }
+}
+
+/// Orders values the way MongoDB sorts them, as far as tests care
+fn compare(a: &Bson, b: &Bson) -> Ordering {
+    if let (Some(a), Some(b)) = (as_number(a), as_number(b)) {
+        return a.partial_cmp(&b).unwrap_or(Ordering::Equal);
+    }
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => a.cmp(b),
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => a.bytes().cmp(&b.bytes()),
+        (Bson::Boolean(a), Bson::Boolean(b)) => a.cmp(b),
+        (Bson::DateTime(a), Bson::DateTime(b)) => a.cmp(b),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => {
+            (a.time, a.increment).cmp(&(b.time, b.increment))
+        }
+        _ => type_rank(a).cmp(&type_rank(b)),
+    }
+}
+
+fn equals(a: &Bson, b: &Bson) -> bool {
+    match (as_number(a), as_number(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Comparison operators only match values of the same kind
+fn comparable(a: &Bson, b: &Bson) -> bool {
+    type_rank(a) == type_rank(b)
+}
+
+fn operand_array<'a>(operator: &str, operand: &'a Bson) -> RepoResult<&'a Vec<Bson>> {
+    operand
+        .as_array()
+        .ok_or_else(|| RepoError::Bson(format!("{operator} needs an array")))
+}
+
+fn matches_filter(document: &Document, filter: &Document) -> RepoResult<bool> {
+    let root = Bson::Document(document.clone());
+    matches_root(&root, filter)
+}
+
+fn matches_root(root: &Bson, filter: &Document) -> RepoResult<bool> {
+    for (key, condition) in filter {
+        let matched = match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let mut results = Vec::new();
+                for clause in operand_array(key, condition)? {
+                    let clause = clause.as_document().ok_or_else(|| {
+                        RepoError::Bson(format!("{key} clauses must be documents"))
+                    })?;
+                    results.push(matches_root(root, clause)?);
+                }
+                match key.as_str() {
+                    "$and" => results.iter().all(|result| *result),
+                    "$or" => results.iter().any(|result| *result),
+                    _ => !results.iter().any(|result| *result),
+                }
+            }
+            key if key.starts_with('$') => return Err(RepoError::Unsupported(key.to_string())),
+            path => matches_condition(&values_at(root, path), condition)?,
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn is_operator_document(condition: &Bson) -> Option<&Document> {
+    condition.as_document().filter(|document| {
+        document
+            .keys()
+            .next()
+            .is_some_and(|key| key.starts_with('$'))
+    })
+}
+
+fn matches_condition(values: &[&Bson], condition: &Bson) -> RepoResult<bool> {
+    let operators = match is_operator_document(condition) {
+        Some(operators) => operators,
+        None => return Ok(matches_equal(values, condition)),
+    };
+    let candidates = candidates(values);
+    for (operator, operand) in operators {
+        let matched = match operator.as_str() {
+            "$eq" => matches_equal(values, operand),
+            "$ne" => !matches_equal(values, operand),
+            "$gt" | "$gte" | "$lt" | "$lte" => candidates.iter().any(|value| {
+                if !comparable(value, operand) {
+                    return false;
+                }
+                let ordering = compare(value, operand);
+                match operator.as_str() {
+                    "$gt" => ordering == Ordering::Greater,
+                    "$gte" => ordering != Ordering::Less,
+                    "$lt" => ordering == Ordering::Less,
+                    _ => ordering != Ordering::Greater,
+                }
+            }),
+            "$in" => operand_array(operator, operand)?
+                .iter()
+                .any(|option| matches_equal(values, option)),
+            "$nin" => !operand_array(operator, operand)?
+                .iter()
+                .any(|option| matches_equal(values, option)),
+            "$exists" => values.is_empty() != operand.as_bool().unwrap_or(true),
+            "$size" => {
+                let size = as_number(operand)
+                    .ok_or_else(|| RepoError::Bson("$size needs a number".to_string()))?;
+                values.iter().any(|value| match value {
+                    Bson::Array(items) => items.len() as f64 == size,
+                    _ => false,
+                })
+            }
+            "$not" => !matches_condition(values, operand)?,
+            "$elemMatch" => {
+                let mut matched = false;
+                for value in values {
+                    if let Bson::Array(items) = value {
+                        for item in items {
+                            if matches_element(item, operand)? {
+                                matched = true;
+                            }
+                        }
+                    }
+                }
+                matched
+            }
+            other => return Err(RepoError::Unsupported(other.to_string())),
+        };
+        if !matched {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn matches_equal(values: &[&Bson], expected: &Bson) -> bool {
+    if values.is_empty() {
+        return expected == &Bson::Null;
+    }
+    candidates(values)
+        .into_iter()
+        .any(|value| equals(value, expected))
+}
+
+/// Matches one array element against an `$elemMatch` or `$pull` condition
+fn matches_element(item: &Bson, condition: &Bson) -> RepoResult<bool> {
+    match (is_operator_document(condition), condition, item) {
+        (Some(_), _, _) => matches_condition(&[item], condition),
+        (None, Bson::Document(filter), Bson::Document(_)) => matches_root(item, filter),
+        (None, expected, _) => Ok(equals(item, expected)),
+    }
+}
+
+/// Walks to the document holding the last segment of `path`, creating
+/// documents along the way when `create` is set
+fn parent_mut<'a>(
+    document: &'a mut Document,
+    path: &'a str,
+    create: bool,
+) -> RepoResult<Option<(&'a mut Document, &'a str)>> {
+    let (head, rest) = match path.split_once('.') {
+        Some(split) => split,
+        None => return Ok(Some((document, path))),
+    };
+    if !document.contains_key(head) {
+        if !create {
+            return Ok(None);
+        }
+        document.insert(head, Document::new());
+    }
+    match document.get_mut(head) {
+        Some(Bson::Document(child)) => parent_mut(child, rest, create),
+        // Fields of array elements, which positional paths resolve to
+        Some(Bson::Array(items)) => {
+            let element = rest.split_once('.').and_then(|(index, rest)| {
+                Some((items.get_mut(index.parse::<usize>().ok()?)?, rest))
+            });
+            match element {
+                Some((Bson::Document(child), rest)) => parent_mut(child, rest, create),
+                _ if create => Err(RepoError::Unsupported(format!("updating inside {head}"))),
+                _ => Ok(None),
+            }
+        }
+        _ if create => Err(RepoError::Unsupported(format!("updating inside {head}"))),
+        _ => Ok(None),
+    }
+}
+
+fn add(a: &Bson, b: &Bson) -> RepoResult<Bson> {
+    Ok(match (a, b) {
+        (Bson::Int32(a), Bson::Int32(b)) => Bson::Int32(a + b),
+        (Bson::Int32(_) | Bson::Int64(_), Bson::Int32(_) | Bson::Int64(_)) => {
+            let a = as_number(a).unwrap() as i64;
+            let b = as_number(b).unwrap() as i64;
+            Bson::Int64(a + b)
+        }
+        _ => match (as_number(a), as_number(b)) {
+            (Some(a), Some(b)) => Bson::Double(a + b),
+            _ => return Err(RepoError::Bson("$inc needs numbers".to_string())),
+        },
+    })
+}
+
+/// The values an `$push` or `$addToSet` operand adds, unwrapping `$each`
+fn pushed_values(operand: &Bson) -> RepoResult<Vec<Bson>> {
+    match operand
+        .as_document()
+        .and_then(|document| document.get("$each"))
+    {
+        Some(each) => Ok(operand_array("$each", each)?.clone()),
+        None => Ok(vec![operand.clone()]),
+    }
+}
+
+/// Replaces the `$` in `path` with the index of the first element of the
+/// array before it that `filter` matched
+fn positional(document: &Document, path: &str, filter: &Document) -> RepoResult<String> {
+    // `$[name]` is left to the array filters
+    let (array, rest) = match path.split_once(".$") {
+        Some((_, rest)) if rest.starts_with('[') => return Ok(path.to_string()),
+        Some(split) => split,
+        None => return Ok(path.to_string()),
+    };
+    let root = Bson::Document(document.clone());
+    let items = match values_at(&root, array).first() {
+        Some(Bson::Array(items)) => items.clone(),
+        _ => return Err(RepoError::Bson(format!("{array} isn't an array"))),
+    };
+    let prefix = format!("{array}.");
+    for (index, item) in items.iter().enumerate() {
+        let mut matched = false;
+        for (key, condition) in filter {
+            if let Some(field) = key.strip_prefix(&prefix) {
+                if !matches_condition(&values_at(item, field), condition)? {
+                    matched = false;
+                    break;
+                }
+                matched = true;
+            }
+        }
+        if matched {
+            return Ok(format!("{array}.{index}{rest}"));
+        }
+    }
+    Err(RepoError::Bson(format!(
+        "The filter didn't match an element of {array}"
+    )))
+}
+
+/// Expands each `$[name]` in `path` to the indexes of the elements that the
+/// array filter on `name` matches
+fn filtered_paths(
+    document: &Document,
+    path: &str,
+    array_filters: &[Document],
+) -> RepoResult<Vec<String>> {
+    let (array, after) = match path.split_once(".$[") {
+        Some(split) => split,
+        None => return Ok(vec![path.to_string()]),
+    };
+    let (name, rest) = after
+        .split_once(']')
+        .ok_or_else(|| RepoError::Bson(format!("{path} isn't a valid path")))?;
+    let prefix = format!("{name}.");
+    let condition = array_filters
+        .iter()
+        .find(|filter| {
+            filter
+                .keys()
+                .any(|key| key == name || key.starts_with(&prefix))
+        })
+        .ok_or_else(|| RepoError::Bson(format!("No array filter names {name}")))?;
+    let root = Bson::Document(document.clone());
+    let items = match values_at(&root, array).first() {
+        Some(Bson::Array(items)) => items.clone(),
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut paths = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        let mut matched = true;
+        for (key, value) in condition {
+            let values = match key.strip_prefix(&prefix) {
+                Some(field) => values_at(item, field),
+                None => vec![item],
+            };
+            if !matches_condition(&values, value)? {
+                matched = false;
+                break;
+            }
+        }
+        if matched {
+            let path = format!("{array}.{index}{rest}");
+            paths.extend(filtered_paths(document, &path, array_filters)?);
+        }
+    }
+    Ok(paths)
+}
+
+fn apply_update(
+    document: &mut Document,
+    update: &Document,
+    filter: &Document,
+    array_filters: &[Document],
+) -> RepoResult<()> {
+    if !update.keys().any(|key| key.starts_with('$')) {
+        let id = document.get("_id").cloned();
+        *document = update.clone();
+        if let Some(id) = id {
+            document.insert("_id", id);
+        }
+        return Ok(());
+    }
+
+    for (operator, fields) in update {
+        let fields = fields
+            .as_document()
+            .ok_or_else(|| RepoError::Bson(format!("{operator} needs a document")))?;
+        for (path, operand) in fields {
+            let path = positional(document, path, filter)?;
+            for path in filtered_paths(document, &path, array_filters)? {
+                let create = !matches!(operator.as_str(), "$unset" | "$pull");
+                let (parent, key) = match parent_mut(document, &path, create)? {
+                    Some(parent) => parent,
+                    None => continue,
+                };
+                match operator.as_str() {
+                    "$set" => {
+                        parent.insert(key, operand.clone());
+                    }
+                    "$unset" => {
+                        parent.remove(key);
+                    }
+                    "$inc" => {
+                        let value = match parent.get(key) {
+                            Some(current) => add(current, operand)?,
+                            None => operand.clone(),
+                        };
+                        parent.insert(key, value);
+                    }
+                    "$max" | "$min" => {
+                        let wanted = if operator == "$max" {
+                            Ordering::Greater
+                        } else {
+                            Ordering::Less
+                        };
+                        let replace = match parent.get(key) {
+                            Some(current) => compare(operand, current) == wanted,
+                            None => true,
+                        };
+                        if replace {
+                            parent.insert(key, operand.clone());
+                        }
+                    }
+                    "$push" | "$addToSet" => {
+                        let values = pushed_values(operand)?;
+                        let items = match parent.get_mut(key) {
+                            Some(Bson::Array(items)) => items,
+                            Some(_) => {
+                                return Err(RepoError::Bson(format!("{path} isn't an array")))
+                            }
+                            None => {
+                                parent.insert(key, Bson::Array(Vec::new()));
+                                parent.get_array_mut(key).unwrap()
+                            }
+                        };
+                        for value in values {
+                            if operator == "$push" || !items.iter().any(|item| equals(item, &value))
+                            {
+                                items.push(value);
+                            }
+                        }
+                    }
+                    "$pull" => {
+                        if let Some(Bson::Array(items)) = parent.get_mut(key) {
+                            let mut kept = Vec::new();
+                            for item in items.drain(..) {
+                                if !matches_element(&item, operand)? {
+                                    kept.push(item);
+                                }
+                            }
+                            *items = kept;
+                        }
+                    }
+                    other => return Err(RepoError::Unsupported(other.to_string())),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn sort_documents(documents: &mut [Document], sort: &Document) -> RepoResult<()> {
+    let mut keys = Vec::new();
+    for (path, direction) in sort {
+        let descending = match as_number(direction) {
+            Some(direction) if direction < 0.0 => true,
+            Some(_) => false,
+            None => {
+                return Err(RepoError::Unsupported(format!(
+                    "sorting {path} by {direction}"
+                )))
+            }
+        };
+        keys.push((path.as_str(), descending));
+    }
+    documents.sort_by(|a, b| {
+        let (a, b) = (Bson::Document(a.clone()), Bson::Document(b.clone()));
+        for (path, descending) in &keys {
+            let ordering = match (values_at(&a, path).first(), values_at(&b, path).first()) {
+                (Some(a), Some(b)) => compare(a, b),
+                (Some(_), None) => Ordering::Greater,
+                (None, Some(_)) => Ordering::Less,
+                (None, None) => Ordering::Equal,
+            };
+            let ordering = if *descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    });
+    Ok(())
+}
@@ -0,0 +1,350 @@
+use crate::{api::ApiResponse, auth::AdminToken, reporting::now_millis};
+use chrono::{
+    Datelike, Duration as ChronoDuration, FixedOffset, NaiveDateTime, TimeZone, Timelike, Utc,
+};
+use rand::Rng;
+use rocket::{get, Shutdown, State};
+use serde::Serialize;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+/// How far ahead a cron expression is searched for a matching minute
+const CRON_HORIZON_DAYS: i64 = 4 * 366;
+
+/// When a job runs
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    /// Straight away, then this long after each run finishes
+    Every(Duration),
+    /// Whenever the expression matches, in South African time
+    Cron(Cron),
+}
+
+impl Schedule {
+    pub fn every(interval: Duration) -> Self {
+        Self::Every(interval)
+    }
+
+    pub fn cron(expression: &str) -> Result<Self, String> {
+        Ok(Self::Cron(expression.parse()?))
+    }
+
+    /// The cron expression in `var` if it's set, otherwise `default`
+    pub fn from_env(var: &str, default: Self) -> Self {
+        match std::env::var(var) {
+            Ok(expression) => Self::cron(&expression).unwrap_or_else(|err| {
+                log::warn!("Ignoring {var}: {err}");
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// The first run after starting up at `now`, in milliseconds
+    fn first(&self, now: u64) -> Option<u64> {
+        match self {
+            Self::Every(_) => Some(now),
+            Self::Cron(cron) => cron.next_after(now),
+        }
+    }
+
+    /// The next run after one finished at `now`, in milliseconds
+    fn next_after(&self, now: u64) -> Option<u64> {
+        match self {
+            Self::Every(interval) => Some(now + interval.as_millis() as u64),
+            Self::Cron(cron) => cron.next_after(now),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Every(interval) => write!(f, "every {}", humantime::format_duration(*interval)),
+            Self::Cron(cron) => write!(f, "cron {}", cron.expression),
+        }
+    }
+}
+
+/// The usual five fields, minute hour day-of-month month day-of-week, each
+/// `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`, or a list of those
+#[derive(Debug, Clone)]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the days of the month and week were left as `*`. When neither
+    /// was, a day matching either will do, as cron has it.
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("Bad step in {part}")),
+            },
+            None => (part, None),
+        };
+        let number = |value: &str| match value.parse::<u32>() {
+            Ok(value) if (min..=max).contains(&value) => Ok(value),
+            _ => Err(format!("{value} isn't between {min} and {max}")),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // A step from a single value runs to the end of the range
+            None if step.is_some() => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if start > end {
+            return Err(format!("{range} runs backwards"));
+        }
+        for value in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl std::str::FromStr for Cron {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(format!("Expected 5 fields in {expression:?}"));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // Sunday is both 0 and 7
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            expression: fields.join(" "),
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days: parse_field(fields[2], 1, 31)?,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, time: &NaiveDateTime) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+
+    /// The first matching minute after `now`, in milliseconds
+    pub fn next_after(&self, now: u64) -> Option<u64> {
+        let sast = FixedOffset::east_opt(2 * 3600).unwrap();
+        let start = Utc
+            .timestamp_millis_opt(now as i64)
+            .single()?
+            .with_timezone(&sast)
+            .naive_local();
+        let horizon = start + ChronoDuration::days(CRON_HORIZON_DAYS);
+        // The minute after now
+        let mut time = start.with_second(0)?.with_nanosecond(0)? + ChronoDuration::minutes(1);
+        while time < horizon {
+            if self.months & (1 << time.month()) == 0 || !self.matches_day(&time) {
+                time = time.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if self.hours & (1 << time.hour()) == 0 {
+                time = time.with_minute(0)? + ChronoDuration::hours(1);
+            } else if self.minutes & (1 << time.minute()) == 0 {
+                time += ChronoDuration::minutes(1);
+            } else {
+                return Some(sast.from_local_datetime(&time).single()?.timestamp_millis() as u64);
+            }
+        }
+        None
+    }
+}
+
+/// A task to run on a schedule. Failed runs are retried sooner, backing off
+/// exponentially, but never later than the next scheduled run.
+#[derive(Debug, Clone)]
+pub struct Job {
+    name: &'static str,
+    schedule: Schedule,
+    jitter: Duration,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Job {
+    pub fn new(name: &'static str, schedule: Schedule) -> Self {
+        Self {
+            name,
+            schedule,
+            jitter: Duration::ZERO,
+            backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(30 * 60),
+        }
+    }
+
+    /// Delays each scheduled run by up to this much, so instances started
+    /// together don't all hit the same service at once
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// The wait before retrying after the first failure, doubling with each
+    /// one after, up to `max`
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    fn jittered(&self, at: Option<u64>) -> Option<u64> {
+        let jitter = self.jitter.as_millis() as u64;
+        if jitter == 0 {
+            return at;
+        }
+        at.map(|at| at + rand::thread_rng().gen_range(0..=jitter))
+    }
+
+    /// How long to wait after `failures` failed runs in a row
+    pub fn retry_delay(&self, failures: u32) -> Duration {
+        let factor = 1u32 << failures.saturating_sub(1).min(16);
+        self.backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+
+    fn next_after(&self, now: u64, failures: u32) -> Option<u64> {
+        let scheduled = self.jittered(self.schedule.next_after(now));
+        if failures == 0 {
+            return scheduled;
+        }
+        let retry = now + self.retry_delay(failures).as_millis() as u64;
+        Some(scheduled.map_or(retry, |scheduled| scheduled.min(retry)))
+    }
+}
+
+/// How a job is getting on, times in milliseconds
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: String,
+    pub running: bool,
+    pub last_run: Option<u64>,
+    pub last_success: Option<u64>,
+    pub next_run: Option<u64>,
+    pub last_error: Option<String>,
+    /// Failed runs since the last one that worked
+    pub failures: u32,
+}
+
+/// Runs background jobs on Rocket's runtime, until it shuts down
+#[derive(Clone, Default)]
+pub struct Scheduler(Arc<Mutex<Vec<JobStatus>>>);
+
+impl Scheduler {
+    pub fn statuses(&self) -> Vec<JobStatus> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn update(&self, index: usize, f: impl FnOnce(&mut JobStatus)) {
+        f(&mut self.0.lock().unwrap()[index]);
+    }
+
+    /// Runs `task` on `job`'s schedule. A run still going when Rocket shuts
+    /// down is dropped where it is.
+    pub fn spawn<F, Fut>(&self, job: Job, shutdown: Shutdown, task: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send,
+    {
+        let index = {
+            let mut statuses = self.0.lock().unwrap();
+            statuses.push(JobStatus {
+                name: job.name.to_string(),
+                schedule: job.schedule.to_string(),
+                running: false,
+                last_run: None,
+                last_success: None,
+                next_run: None,
+                last_error: None,
+                failures: 0,
+            });
+            statuses.len() - 1
+        };
+        let scheduler = self.clone();
+
+        tokio::spawn(async move {
+            let mut failures = 0;
+            let mut next = job.jittered(job.schedule.first(now_millis()));
+            while let Some(at) = next {
+                scheduler.update(index, |status| status.next_run = Some(at));
+                let wait = Duration::from_millis(at.saturating_sub(now_millis()));
+                tokio::select! {
+                    _ = shutdown.clone() => break,
+                    _ = tokio::time::sleep(wait) => {}
+                }
+
+                let started = now_millis();
+                scheduler.update(index, |status| {
+                    status.running = true;
+                    status.last_run = Some(started);
+                    status.next_run = None;
+                });
+                let result = tokio::select! {
+                    _ = shutdown.clone() => break,
+                    result = task() => result,
+                };
+                let finished = now_millis();
+                match result {
+                    Ok(()) => {
+                        failures = 0;
+                        scheduler.update(index, |status| status.last_success = Some(finished));
+                    }
+                    Err(err) => {
+                        failures += 1;
+                        log::warn!("Job {} failed ({failures} in a row): {err}", job.name);
+                        scheduler.update(index, |status| status.last_error = Some(err));
+                    }
+                }
+                scheduler.update(index, |status| {
+                    status.running = false;
+                    status.failures = failures;
+                });
+                next = job.next_after(finished, failures);
+            }
+            scheduler.update(index, |status| {
+                status.running = false;
+                status.next_run = None;
+            });
+            log::info!("Stopped job {}", job.name);
+        });
+    }
+}
+
+#[utoipa::path(get, path = "/api/admin/jobs", security(("jwt" = [])), responses(
+    (status = 200, description = "Every background job and how it's getting on", body = [JobStatus])
+))]
+#[get("/admin/jobs")]
+pub async fn get_jobs(
+    _admin: AdminToken,
+    scheduler: &State<Scheduler>,
+) -> ApiResponse<Vec<JobStatus>> {
+    ApiResponse::Ok(scheduler.statuses())
+}
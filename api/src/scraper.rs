@@ -4,9 +4,11 @@ use crate::{
     api::ApiError,
     db::Entity,
     loadshedding::{GroupEntity, MunicipalityEntity, StageTimes, SuburbEntity, TimeScheduleEntity, GeoJson},
+    repository::{Model, PageRequest, RepoResult, Repository, Store},
 };
+use bson::doc;
 use mongodb::Client;
-use rocket::{serde::json::Json, Responder};
+use rocket::Responder;
 use serde::{Deserialize, Serialize};
 
 #[derive(Responder)]
@@ -30,13 +32,11 @@ pub struct Times {
     pub end_minute:i32
 }
 
-pub fn convert_to_ints(time_range:&str) -> Result<Times,Json<ApiError<'static>>> {
+pub fn convert_to_ints(time_range: &str) -> Result<Times, ApiError> {
     let stripped: String = time_range.chars().filter(|c| !c.is_whitespace()).collect();
     let times: Vec<&str> = stripped.split("-").collect();
     if times.len() != 2 {
-        return Err(Json(ApiError::ScraperUploadError(
-            "Unexpected time range, your time ranges are not in the format: HH:MM-HH:MM. You potentially have an aditional \"-\"",
-        )));
+        return Err(ApiError::bad_request("Unexpected time range, your time ranges are not in the format: HH:MM-HH:MM. You potentially have an aditional \"-\""));
     }
     let mut integer_times = Vec::new();
     for timestring in times {
@@ -45,42 +45,38 @@ pub fn convert_to_ints(time_range:&str) -> Result<Times,Json<ApiError<'static>>>
         for part in parts {
             let integer_value:i32 = match part.parse() {
                 Ok(hour) => hour,
-                Err(_e) =>  return Err(Json(ApiError::ScraperUploadError(
-                    "Error in time range, unable to convert to an integer. Please check that you are sending in the format \"HH:MM-HH:MM\"",
-                )))
+                Err(_e) =>  return Err(ApiError::bad_request("Error in time range, unable to convert to an integer. Please check that you are sending in the format \"HH:MM-HH:MM\""))
             };
             integer_times.push(integer_value);
         }
     }
     if integer_times.len() != 4 {
-        return Err(Json(ApiError::ScraperUploadError(
-            "Unexpected time range, your time ranges are not in the format: \"HH:MM-HH:MM\". You potentially have an additional : lingering somewhere.",
-        )));
+        return Err(ApiError::bad_request("Unexpected time range, your time ranges are not in the format: \"HH:MM-HH:MM\". You potentially have an additional : lingering somewhere."));
     } else {
         let potential_times = Times {start_hour:integer_times[0],start_minute:integer_times[1],end_hour:integer_times[2],end_minute:integer_times[3]};
         if potential_times.start_hour >= 24 {
-            return Err(Json(ApiError::ScraperUploadError(
+            return Err(ApiError::bad_request(
                 "You have a malformed starting hour, please fix this, HH <= 23",
-            )));
+            ));
         } else if potential_times.end_hour > 24 || potential_times.end_hour == 0 {
-            return Err(Json(ApiError::ScraperUploadError(
+            return Err(ApiError::bad_request(
                 "You have a malformed end hour, please fix this, 0 < HH <= 24",
-            )));
+            ));
         } else if potential_times.start_minute >= 60 {
-            return Err(Json(ApiError::ScraperUploadError(
+            return Err(ApiError::bad_request(
                 "You have a malformed start minute, please fix this, MM <= 59",
-            )));
+            ));
         } else if potential_times.end_minute >= 60 {
-            return Err(Json(ApiError::ScraperUploadError(
+            return Err(ApiError::bad_request(
                 "You have a malformed end minute, please fix this, MM <= 59",
-            )));
+            ));
         }
         Ok(potential_times)
     }
 }
 
 impl UploadRequest {
-    pub async fn add_data(self, db: &Client, database: &str) -> Result<(), Json<ApiError<'static>>> {
+    pub async fn add_data(self, db: &Client, database: &str) -> Result<(), ApiError> {
         // we need refactoring, and we need it immediately
         let municipality = MunicipalityEntity {
             id: None,
@@ -155,10 +151,36 @@ impl UploadRequest {
                 let _ = schedule.insert(&db.database(database)).await;
             } // end of times loop
         } else {
-            return Err(Json(ApiError::ServerError(
-                "For some reason the municpality was not added to the database, this could have happened elsewhere aswell.",
-            )));
+            return Err(ApiError::internal("For some reason the municpality was not added to the database, this could have happened elsewhere aswell."));
         }
     Ok(())
     }
 }
+
+/// Moves everything uploaded to staging into production, the suburbs,
+/// groups and schedules before the municipalities that refer to them.
+/// Returns how many documents moved.
+pub async fn promote_staging<S: Store>(staging: &S, production: &S) -> Result<u64, ApiError> {
+    Ok(promote::<SuburbEntity, S>(staging, production).await?
+        + promote::<GroupEntity, S>(staging, production).await?
+        + promote::<TimeScheduleEntity, S>(staging, production).await?
+        + promote::<MunicipalityEntity, S>(staging, production).await?)
+}
+
+/// Copies each `T` over production by `_id` and only then drops it from
+/// staging, so running it again after a failure finishes the job instead
+/// of duplicating what already moved
+async fn promote<T: Model, S: Store>(staging: &S, production: &S) -> RepoResult<u64> {
+    let from = staging.repository::<T>();
+    let to = production.repository::<T>();
+    let mut moved = 0;
+    for mut document in from.find(doc! {}, PageRequest::all()).await?.items {
+        let Some(id) = document.id() else {
+            continue;
+        };
+        to.upsert(doc! { "_id": id }, &mut document).await?;
+        from.delete_by_id(id).await?;
+        moved += 1;
+    }
+    Ok(moved)
+}
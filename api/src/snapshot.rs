@@ -0,0 +1,81 @@
+use crate::api::{ApiError, ApiResponse, ErrorCode};
+use crate::reporting::now_millis;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use tokio::sync::RwLock;
+
+/// Most responses we remember. The oldest is dropped to make room.
+const MAX_SNAPSHOTS: usize = 5000;
+
+struct Snapshot {
+    body: serde_json::Value,
+    taken: u64,
+}
+
+/// The last successful response of schedule and map reads, keyed by
+/// endpoint and request, so they can still be answered while the
+/// database is down
+#[derive(Default)]
+pub struct Snapshots(RwLock<HashMap<String, Snapshot>>);
+
+impl Snapshots {
+    pub fn key(endpoint: &str, request: &impl Serialize) -> String {
+        format!(
+            "{endpoint}:{}",
+            serde_json::to_string(request).unwrap_or_default()
+        )
+    }
+
+    pub async fn store<T: Serialize>(&self, key: String, result: &T) {
+        let body = match serde_json::to_value(result) {
+            Ok(body) => body,
+            Err(err) => {
+                log::warn!("Couldn't snapshot {key}: {err:?}");
+                return;
+            }
+        };
+        let mut snapshots = self.0.write().await;
+        if snapshots.len() >= MAX_SNAPSHOTS && !snapshots.contains_key(&key) {
+            let oldest = snapshots
+                .iter()
+                .min_by_key(|(_, snapshot)| snapshot.taken)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                snapshots.remove(&oldest);
+            }
+        }
+        snapshots.insert(
+            key,
+            Snapshot {
+                body,
+                taken: now_millis(),
+            },
+        );
+    }
+
+    pub async fn load<T: DeserializeOwned>(&self, key: &str) -> Option<(T, u64)> {
+        let snapshots = self.0.read().await;
+        let snapshot = snapshots.get(key)?;
+        let result = serde_json::from_value(snapshot.body.clone()).ok()?;
+        Some((result, snapshot.taken))
+    }
+
+    /// Answers with the snapshot for `key` if `err` looks like the database
+    /// letting us down, and there is one
+    pub async fn fallback<T: Serialize + DeserializeOwned>(
+        &self,
+        key: &str,
+        err: ApiError,
+    ) -> ApiResponse<T> {
+        if !matches!(err.code, ErrorCode::Internal | ErrorCode::Unavailable) {
+            return err.into();
+        }
+        match self.load(key).await {
+            Some((result, taken)) => {
+                log::warn!("Serving a snapshot of {key} from {taken} instead of failing: {err}");
+                ApiResponse::Stale(result, taken)
+            }
+            None => err.into(),
+        }
+    }
+}
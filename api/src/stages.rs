@@ -0,0 +1,688 @@
+use crate::{
+    api::{ApiError, ApiResponse},
+    auth::AdminToken,
+    cache::ScheduleCache,
+    loadshedding::{DBFunctionsTrait, LoadSheddingStage},
+    outages::{self, AffectedSuburb},
+    reporting::now_millis,
+    repository::{Model, MongoStore, PageRequest, Repository, Store, Transaction},
+};
+use bson::{doc, oid::ObjectId};
+use macros::Entity;
+use mongodb::{options::FindOptions, Client, Database};
+use rocket::{get, post, put, serde::json::Json, FromForm, State};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use utoipa::{IntoParams, ToSchema};
+
+/// Where stage windows fetched from Eskom's status feed come from
+pub const ESKOM_SOURCE: &str = "eskom";
+/// Where windows set by hand through the admin endpoints come from
+pub const ADMIN_SOURCE: &str = "admin";
+/// The highest stage Eskom has defined
+const MAX_STAGE: i32 = 8;
+const MAX_REASON_LENGTH: usize = 500;
+/// How far either endpoint looks without being told, in seconds
+const DEFAULT_LOOKBACK: i64 = 24 * 60 * 60;
+const DEFAULT_LOOKAHEAD: i64 = 7 * 24 * 60 * 60;
+/// The widest range of time the endpoints take, in seconds
+const MAX_RANGE: i64 = 31 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum RevisionKind {
+    /// A window we hadn't heard of before
+    Announced,
+    /// A known window whose stage was revised
+    Changed,
+    /// A window dropped to make way for ones that overlap it
+    Withdrawn,
+    /// A window the Eskom sync was stopped from changing
+    Locked,
+    /// A window handed back to the Eskom sync
+    Unlocked,
+    /// A window Eskom sent that a lock kept out of the stage log, wholly or
+    /// in part
+    Suppressed,
+}
+
+/// One change to the stage log, kept as it was fetched. Revisions are only
+/// ever added, so they still tell what was announced after the stage log
+/// has been rewritten.
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[serde(rename_all = "camelCase")]
+#[collection_name = "stage_revisions"]
+#[index(fields = "startTime, endTime")]
+#[index(fields = "fetchedAt")]
+pub struct StageRevision {
+    #[serde(skip_serializing_if = "Option::is_none", rename = "_id")]
+    pub id: Option<ObjectId>,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub kind: RevisionKind,
+    /// What the window is now, `None` once withdrawn
+    pub stage: Option<i32>,
+    /// What the window was before, `None` when it was announced
+    pub previous: Option<i32>,
+    pub source: String,
+    /// When the source was read, in milliseconds
+    pub fetched_at: u64,
+    /// The admin who made the change by hand
+    pub actor: Option<String>,
+    pub reason: Option<String>,
+}
+
+/// Where and when a batch of stage windows was read, for the revisions they
+/// lead to
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    pub source: String,
+    pub fetched_at: u64,
+    pub actor: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl Provenance {
+    pub fn new(source: impl Into<String>, fetched_at: u64) -> Self {
+        Self {
+            source: source.into(),
+            fetched_at,
+            actor: None,
+            reason: None,
+        }
+    }
+
+    /// A change an admin is making now
+    pub fn admin(admin: &AdminToken, reason: &str) -> Self {
+        Self {
+            actor: Some(admin.email().to_string()),
+            reason: Some(reason.trim().to_string()),
+            ..Self::new(ADMIN_SOURCE, now_millis())
+        }
+    }
+
+    fn revision(
+        &self,
+        window: &LoadSheddingStage,
+        kind: RevisionKind,
+        stage: Option<i32>,
+        previous: Option<i32>,
+    ) -> StageRevision {
+        StageRevision {
+            id: None,
+            start_time: window.start_time,
+            end_time: window.end_time,
+            kind,
+            stage,
+            previous,
+            source: self.source.clone(),
+            fetched_at: self.fetched_at,
+            actor: self.actor.clone(),
+            reason: self.reason.clone(),
+        }
+    }
+
+    pub fn announced(&self, window: &LoadSheddingStage) -> StageRevision {
+        self.revision(window, RevisionKind::Announced, Some(window.stage), None)
+    }
+
+    pub fn changed(&self, window: &LoadSheddingStage, stage: i32) -> StageRevision {
+        self.revision(
+            window,
+            RevisionKind::Changed,
+            Some(stage),
+            Some(window.stage),
+        )
+    }
+
+    pub fn suppressed(&self, window: &LoadSheddingStage) -> StageRevision {
+        self.revision(window, RevisionKind::Suppressed, Some(window.stage), None)
+    }
+
+    pub fn withdrawn(&self, window: &LoadSheddingStage) -> StageRevision {
+        self.revision(window, RevisionKind::Withdrawn, None, Some(window.stage))
+    }
+
+    pub fn locked(&self, window: &LoadSheddingStage, locked: bool) -> StageRevision {
+        let kind = if locked {
+            RevisionKind::Locked
+        } else {
+            RevisionKind::Unlocked
+        };
+        self.revision(window, kind, Some(window.stage), Some(window.stage))
+    }
+}
+
+impl StageRevision {
+    /// Stores the revision. Callers outside a transaction do this right
+    /// after changing the stage log, so by the time a revision counts
+    /// towards the stage version its change is in the log.
+    pub async fn record(self, db: &Database) -> Result<Self, String> {
+        self.record_to(&StageRevision::repository(db)).await
+    }
+
+    /// `record` through any repository, such as a transaction's
+    pub async fn record_to<R: Repository<StageRevision>>(
+        mut self,
+        revisions: &R,
+    ) -> Result<Self, String> {
+        revisions
+            .insert(&mut self)
+            .await
+            .map(|_| self)
+            .map_err(|err| format!("Couldn't record a stage revision: {err}"))
+    }
+
+    /// Stores the revision unless the same change to the same window is
+    /// already stored, in which case only when it was fetched is updated.
+    /// For what the source keeps sending, so it doesn't count towards the
+    /// stage version over and over.
+    pub async fn record_once(self, db: &Database) -> Result<Self, String> {
+        self.record_once_to(&StageRevision::repository(db)).await
+    }
+
+    /// `record_once` through any repository
+    pub async fn record_once_to<R: Repository<StageRevision>>(
+        mut self,
+        revisions: &R,
+    ) -> Result<Self, String> {
+        let filter = doc! {
+            "startTime": self.start_time,
+            "endTime": self.end_time,
+            "kind": bson::to_bson(&self.kind).unwrap(),
+            "stage": self.stage,
+            "previous": self.previous,
+            "source": &self.source,
+        };
+        revisions
+            .upsert(filter, &mut self)
+            .await
+            .map(|_| self)
+            .map_err(|err| format!("Couldn't record a stage revision: {err}"))
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StageWindow {
+    pub start_time: i64,
+    pub end_time: i64,
+    pub stage: i32,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StageRevisionResponse {
+    pub start_time: i64,
+    pub end_time: i64,
+    pub kind: RevisionKind,
+    pub stage: Option<i32>,
+    pub previous: Option<i32>,
+    pub source: String,
+    pub fetched_at: u64,
+    pub actor: Option<String>,
+    pub reason: Option<String>,
+}
+
+impl From<StageRevision> for StageRevisionResponse {
+    fn from(revision: StageRevision) -> Self {
+        Self {
+            start_time: revision.start_time,
+            end_time: revision.end_time,
+            kind: revision.kind,
+            stage: revision.stage,
+            previous: revision.previous,
+            source: revision.source,
+            fetched_at: revision.fetched_at,
+            actor: revision.actor,
+            reason: revision.reason,
+        }
+    }
+}
+
+#[derive(Debug, Default, FromForm, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StageRange {
+    /// Unix seconds, defaults to a day ago
+    pub from: Option<i64>,
+    /// Unix seconds, defaults to a week after `from`. At most 31 days after it.
+    pub to: Option<i64>,
+}
+
+impl StageRange {
+    fn resolve(&self) -> Result<(i64, i64), ApiError> {
+        let from = self
+            .from
+            .unwrap_or_else(|| chrono::Utc::now().timestamp() - DEFAULT_LOOKBACK);
+        let to = self.to.unwrap_or(from + DEFAULT_LOOKAHEAD);
+        if to <= from {
+            return Err(ApiError::invalid("to", "Must be after from"));
+        }
+        if to - from > MAX_RANGE {
+            return Err(ApiError::invalid("to", "Can be at most 31 days after from"));
+        }
+        Ok((from, to))
+    }
+
+    /// Windows overlapping the range
+    fn filter(from: i64, to: i64) -> bson::Document {
+        doc! {
+            "startTime": { "$lt": to },
+            "endTime": { "$gt": from },
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/api/stages", params(StageRange), responses(
+    (status = 200, description = "The stage windows overlapping the range, earliest first", body = [StageWindow])
+))]
+#[get("/stages?<range..>")]
+pub async fn get_stages(
+    range: StageRange,
+    state: &State<Option<Client>>,
+    cache: &State<ScheduleCache>,
+) -> ApiResponse<Vec<StageWindow>> {
+    let (from, to) = match range.resolve() {
+        Ok(range) => range,
+        Err(err) => return err.into(),
+    };
+    let db = match state.inner() {
+        Some(client) => client.database("production"),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let options = FindOptions::builder().sort(doc! { "startTime": 1 }).build();
+    match cache
+        .collect_stage_logs(StageRange::filter(from, to), Some(&db), Some(options))
+        .await
+    {
+        Ok(stages) => ApiResponse::Ok(
+            stages
+                .into_iter()
+                .map(|stage| StageWindow {
+                    start_time: stage.start_time,
+                    end_time: stage.end_time,
+                    stage: stage.stage,
+                })
+                .collect(),
+        ),
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(get, path = "/api/stages/revisions", params(StageRange), responses(
+    (status = 200, description = "Every revision to windows overlapping the range, in the order they were fetched", body = [StageRevisionResponse])
+))]
+#[get("/stages/revisions?<range..>")]
+pub async fn get_stage_revisions(
+    range: StageRange,
+    state: &State<Option<Client>>,
+) -> ApiResponse<Vec<StageRevisionResponse>> {
+    let (from, to) = match range.resolve() {
+        Ok(range) => range,
+        Err(err) => return err.into(),
+    };
+    let db = match state.inner() {
+        Some(client) => client.database("production"),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let page = PageRequest::all().sorted_by(doc! { "fetchedAt": 1, "startTime": 1 });
+    match StageRevision::repository(&db)
+        .find(StageRange::filter(from, to), page)
+        .await
+    {
+        Ok(page) => ApiResponse::Ok(page.items.into_iter().map(Into::into).collect()),
+        Err(err) => ApiError::from(err).into(),
+    }
+}
+
+/// A stage window set by hand
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StageOverride {
+    /// Unix seconds
+    pub start_time: i64,
+    /// Unix seconds
+    pub end_time: i64,
+    pub stage: i32,
+    /// Keeps the Eskom sync from changing the window, defaults to true
+    pub locked: Option<bool>,
+    /// Not needed for a preview
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl StageOverride {
+    /// The window to put in the stage log, if it makes sense
+    pub fn window(&self) -> Result<LoadSheddingStage, ApiError> {
+        if self.end_time <= self.start_time {
+            return Err(ApiError::invalid("endTime", "Must be after startTime"));
+        }
+        if self.end_time - self.start_time > MAX_RANGE {
+            return Err(ApiError::invalid(
+                "endTime",
+                "Windows can be at most 31 days long",
+            ));
+        }
+        if !(0..=MAX_STAGE).contains(&self.stage) {
+            return Err(ApiError::invalid(
+                "stage",
+                format!("Must be 0 to {MAX_STAGE}"),
+            ));
+        }
+        Ok(LoadSheddingStage {
+            id: None,
+            start_time: self.start_time,
+            end_time: self.end_time,
+            stage: self.stage,
+            update: Some(!self.locked.unwrap_or(true)),
+        })
+    }
+}
+
+/// Locks a window against the Eskom sync, or hands it back
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StageLock {
+    pub start_time: i64,
+    pub end_time: i64,
+    pub locked: bool,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct StageOverrideResponse {
+    pub revisions: Vec<StageRevisionResponse>,
+    /// Worked out before the change was made
+    pub affected: Vec<AffectedSuburb>,
+}
+
+fn validate_reason(reason: &str) -> Result<(), ApiError> {
+    let reason = reason.trim();
+    if reason.is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Err(ApiError::invalid(
+            "reason",
+            format!("Give a reason of up to {MAX_REASON_LENGTH} characters"),
+        ));
+    }
+    Ok(())
+}
+
+fn is_locked(window: &LoadSheddingStage) -> bool {
+    window.update != Some(true)
+}
+
+/// Locks or unlocks the window with the times in `lock` against the Eskom
+/// sync, recording the revision in the same `transaction`. `None` if it
+/// already was.
+pub(crate) async fn apply_lock<T: Transaction>(
+    transaction: &T,
+    lock: &StageLock,
+    provenance: &Provenance,
+) -> Result<Option<StageRevision>, ApiError> {
+    let stages = transaction.repository::<LoadSheddingStage>();
+    let window = stages
+        .find_one(
+            doc! { "startTime": lock.start_time, "endTime": lock.end_time },
+            None,
+        )
+        .await?
+        .ok_or_else(|| ApiError::not_found("No stage window has these times"))?;
+    if is_locked(&window) == lock.locked {
+        return Ok(None);
+    }
+
+    let revision = provenance
+        .locked(&window, lock.locked)
+        .record_to(&transaction.repository())
+        .await
+        .map_err(|err| {
+            log::error!("{err}");
+            ApiError::internal("Couldn't lock the stage window")
+        })?;
+    stages
+        .update_by_id(
+            window.id.unwrap(),
+            doc! { "$set": { "update": !lock.locked } },
+        )
+        .await?;
+    Ok(Some(revision))
+}
+
+/// Puts `window` in the stage log, withdrawing whatever it overlaps. A
+/// window with the same times is changed in place. It all happens in
+/// `transaction`, so the log never changes without its revisions.
+pub(crate) async fn apply_override<T: Transaction>(
+    transaction: &T,
+    mut window: LoadSheddingStage,
+    provenance: &Provenance,
+) -> Result<Vec<StageRevision>, String> {
+    let stages = transaction.repository::<LoadSheddingStage>();
+    let history = transaction.repository::<StageRevision>();
+    let filter = doc! {
+        "startTime": { "$lt": window.end_time },
+        "endTime": { "$gt": window.start_time },
+    };
+    let overlapping = stages
+        .find(filter, PageRequest::all())
+        .await
+        .map_err(|err| err.to_string())?
+        .items;
+    let mut revisions = Vec::new();
+    let mut existing = None;
+    for stage in overlapping {
+        if stage.start_time == window.start_time && stage.end_time == window.end_time {
+            existing = Some(stage);
+            continue;
+        }
+        revisions.push(provenance.withdrawn(&stage).record_to(&history).await?);
+        stages
+            .delete_many(doc! { "_id": stage.id })
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+
+    let existing = match existing {
+        Some(existing) => existing,
+        None => {
+            revisions.push(provenance.announced(&window).record_to(&history).await?);
+            stages
+                .insert(&mut window)
+                .await
+                .map_err(|err| err.to_string())?;
+            if is_locked(&window) {
+                revisions.push(provenance.locked(&window, true).record_to(&history).await?);
+            }
+            return Ok(revisions);
+        }
+    };
+    if existing.stage != window.stage {
+        revisions.push(
+            provenance
+                .changed(&existing, window.stage)
+                .record_to(&history)
+                .await?,
+        );
+    }
+    if is_locked(&existing) != is_locked(&window) {
+        revisions.push(
+            provenance
+                .locked(&window, is_locked(&window))
+                .record_to(&history)
+                .await?,
+        );
+    }
+    if !revisions.is_empty() {
+        let update = doc! { "$set": { "stage": window.stage, "update": window.update } };
+        stages
+            .update_one(doc! { "_id": existing.id }, update)
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(revisions)
+}
+
+/// Lets everything reading the stage log know it was changed by hand
+async fn stage_log_changed(
+    client: &Client,
+    cache: &ScheduleCache,
+    current: &Option<Arc<RwLock<LoadSheddingStage>>>,
+) {
+    cache.invalidate(LoadSheddingStage::COLLECTION);
+    if let Some(current) = current {
+        if let Err(err) = LoadSheddingStage::refresh(current, client).await {
+            log::warn!("Couldn't refresh the current stage: {err}");
+        }
+    }
+}
+
+#[utoipa::path(post, path = "/api/admin/stages/preview", request_body = StageOverride, security(("jwt" = [])), responses(
+    (status = 200, description = "The suburbs whose outages the window would change", body = [AffectedSuburb])
+))]
+#[post(
+    "/admin/stages/preview",
+    format = "application/json",
+    data = "<request>"
+)]
+pub async fn preview_stage_override(
+    request: Json<StageOverride>,
+    _admin: AdminToken,
+    state: &State<Option<Client>>,
+    cache: &State<ScheduleCache>,
+) -> ApiResponse<Vec<AffectedSuburb>> {
+    let window = match request.window() {
+        Ok(window) => window,
+        Err(err) => return err.into(),
+    };
+    let db = match state.inner() {
+        Some(client) => client.database("production"),
+        None => return ApiError::database_unavailable().into(),
+    };
+    match outages::affected_by(&db, cache.inner(), &window).await {
+        Ok(affected) => ApiResponse::Ok(affected),
+        Err(err) => err.into(),
+    }
+}
+
+/// Sets a stage window by hand, locked against the Eskom sync unless asked
+/// otherwise
+#[utoipa::path(put, path = "/api/admin/stages", request_body = StageOverride, security(("jwt" = [])), responses(
+    (status = 200, description = "The revisions made and the suburbs affected", body = StageOverrideResponse)
+))]
+#[put("/admin/stages", format = "application/json", data = "<request>")]
+pub async fn override_stage(
+    request: Json<StageOverride>,
+    admin: AdminToken,
+    state: &State<Option<Client>>,
+    cache: &State<ScheduleCache>,
+    current: &State<Option<Arc<RwLock<LoadSheddingStage>>>>,
+) -> ApiResponse<StageOverrideResponse> {
+    let window = match request.window() {
+        Ok(window) => window,
+        Err(err) => return err.into(),
+    };
+    if let Err(err) = validate_reason(&request.reason) {
+        return err.into();
+    }
+    let client = match state.inner() {
+        Some(client) => client,
+        None => return ApiError::database_unavailable().into(),
+    };
+    let db = client.database("production");
+    let affected = match outages::affected_by(&db, cache.inner(), &window).await {
+        Ok(affected) => affected,
+        Err(err) => {
+            log::warn!("Couldn't work out who a stage override affects: {err}");
+            Vec::new()
+        }
+    };
+
+    let provenance = Provenance::admin(&admin, &request.reason);
+    // Dropping the transaction when something fails aborts it
+    let result = match MongoStore::new(client, "production").begin().await {
+        Ok(transaction) => match apply_override(&transaction, window, &provenance).await {
+            Ok(revisions) => transaction
+                .commit()
+                .await
+                .map(|_| revisions)
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err.to_string()),
+    };
+    // A commit that failed may still have gone through
+    stage_log_changed(client, cache, current).await;
+    match result {
+        Ok(revisions) => {
+            log::info!(
+                "{} set stage {} from {} to {}: {}",
+                admin.email(),
+                request.stage,
+                request.start_time,
+                request.end_time,
+                request.reason.trim()
+            );
+            ApiResponse::Ok(StageOverrideResponse {
+                revisions: revisions.into_iter().map(Into::into).collect(),
+                affected,
+            })
+        }
+        Err(err) => {
+            log::error!("Couldn't override a stage window: {err}");
+            ApiError::internal("Couldn't override the stage window").into()
+        }
+    }
+}
+
+#[utoipa::path(put, path = "/api/admin/stages/lock", request_body = StageLock, security(("jwt" = [])), responses(
+    (status = 200, description = "The revision made, if the lock changed", body = [StageRevisionResponse])
+))]
+#[put("/admin/stages/lock", format = "application/json", data = "<request>")]
+pub async fn lock_stage(
+    request: Json<StageLock>,
+    admin: AdminToken,
+    state: &State<Option<Client>>,
+    cache: &State<ScheduleCache>,
+    current: &State<Option<Arc<RwLock<LoadSheddingStage>>>>,
+) -> ApiResponse<Vec<StageRevisionResponse>> {
+    if let Err(err) = validate_reason(&request.reason) {
+        return err.into();
+    }
+    let client = match state.inner() {
+        Some(client) => client,
+        None => return ApiError::database_unavailable().into(),
+    };
+
+    let provenance = Provenance::admin(&admin, &request.reason);
+    let result = match MongoStore::new(client, "production").begin().await {
+        Ok(transaction) => match apply_lock(&transaction, &request, &provenance).await {
+            Ok(Some(revision)) => transaction
+                .commit()
+                .await
+                .map(|_| Some(revision))
+                .map_err(ApiError::from),
+            Ok(None) => Ok(None),
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err.into()),
+    };
+    if !matches!(result, Ok(None)) {
+        // A commit that failed may still have gone through
+        stage_log_changed(client, cache, current).await;
+    }
+    let revision = match result {
+        Ok(Some(revision)) => revision,
+        Ok(None) => return ApiResponse::Ok(Vec::new()),
+        Err(err) => return err.into(),
+    };
+    log::info!(
+        "{} {} the stage window from {} to {}: {}",
+        admin.email(),
+        if request.locked { "locked" } else { "unlocked" },
+        request.start_time,
+        request.end_time,
+        request.reason.trim()
+    );
+    ApiResponse::Ok(vec![revision.into()])
+}
@@ -0,0 +1,270 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+/// Somewhere to keep uploaded files. Keys are relative paths like
+/// `reports/<id>/<photo>.jpg`.
+#[async_trait]
+pub trait FileStore: Send + Sync {
+    async fn put(&self, key: &str, content_type: &str, bytes: Vec<u8>) -> Result<(), String>;
+    /// `None` if nothing is stored under `key`
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String>;
+    /// Deleting a key that doesn't exist isn't an error
+    async fn delete(&self, key: &str) -> Result<(), String>;
+}
+
+/// Keeps files in a directory on the local filesystem
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, String> {
+        let key = Path::new(key);
+        if !key
+            .components()
+            .all(|part| matches!(part, Component::Normal(_)))
+        {
+            return Err(format!("Invalid storage key {key:?}"));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl FileStore for LocalStore {
+    async fn put(&self, key: &str, _content_type: &str, bytes: Vec<u8>) -> Result<(), String> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|err| format!("Couldn't create {parent:?}: {err}"))?;
+        }
+        tokio::fs::write(&path, bytes)
+            .await
+            .map_err(|err| format!("Couldn't write {path:?}: {err}"))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let path = self.path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(format!("Couldn't read {path:?}: {err}")),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        let path = self.path(key)?;
+        match tokio::fs::remove_file(&path).await {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(format!("Couldn't delete {path:?}: {err}"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps files in a bucket of any S3-compatible object store, addressed
+/// path style so MinIO and friends work too
+pub struct S3Store {
+    endpoint: reqwest::Url,
+    bucket: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+    client: reqwest::Client,
+}
+
+impl S3Store {
+    pub fn new(
+        endpoint: &str,
+        bucket: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+    ) -> Result<Self, String> {
+        let endpoint = reqwest::Url::parse(endpoint)
+            .map_err(|err| format!("Invalid S3 endpoint {endpoint}: {err}"))?;
+        if endpoint.host_str().is_none() {
+            return Err(format!("S3 endpoint {endpoint} has no host"));
+        }
+        Ok(Self {
+            endpoint,
+            bucket,
+            region,
+            access_key,
+            secret_key,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Builds a request signed with AWS Signature Version 4
+    fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        body: Vec<u8>,
+    ) -> reqwest::RequestBuilder {
+        let path = format!(
+            "{}/{}/{}",
+            self.endpoint.path().trim_end_matches('/'),
+            self.bucket,
+            key.split('/').map(uri_encode).collect::<Vec<_>>().join("/")
+        );
+        let mut url = self.endpoint.clone();
+        url.set_path(&path);
+        let host = match url.port() {
+            Some(port) => format!("{}:{port}", url.host_str().unwrap()),
+            None => url.host_str().unwrap().to_string(),
+        };
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}",
+            url.path()
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [self.region.as_str(), "s3", "aws4_request"].iter().fold(
+            hmac_sha256(
+                format!("AWS4{}", self.secret_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac_sha256(&key, part.as_bytes()),
+        );
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        self.client
+            .request(method, url)
+            .header("x-amz-date", amz_date)
+            .header("x-amz-content-sha256", payload_hash)
+            .header(
+                "Authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+                    self.access_key
+                ),
+            )
+            .body(body)
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes everything but unreserved characters, as SigV4 expects
+fn uri_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[async_trait]
+impl FileStore for S3Store {
+    async fn put(&self, key: &str, content_type: &str, bytes: Vec<u8>) -> Result<(), String> {
+        self.request(reqwest::Method::PUT, key, bytes)
+            .header("Content-Type", content_type)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| format!("Couldn't upload {key}: {err}"))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+        let response = self
+            .request(reqwest::Method::GET, key, Vec::new())
+            .send()
+            .await
+            .map_err(|err| format!("Couldn't fetch {key}: {err}"))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response
+            .error_for_status()
+            .map_err(|err| format!("Couldn't fetch {key}: {err}"))?;
+        response
+            .bytes()
+            .await
+            .map(|bytes| Some(bytes.to_vec()))
+            .map_err(|err| format!("Couldn't fetch {key}: {err}"))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), String> {
+        self.request(reqwest::Method::DELETE, key, Vec::new())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| format!("Couldn't delete {key}: {err}"))
+    }
+}
+
+/// The file store uploads go to, managed by Rocket
+#[derive(Clone)]
+pub struct Storage(pub Arc<dyn FileStore>);
+
+impl Storage {
+    /// `STORAGE_BACKEND=s3` stores files in `S3_BUCKET` at `S3_ENDPOINT`,
+    /// anything else stores them under `STORAGE_PATH`
+    pub fn from_env() -> Self {
+        let env = |name: &str| std::env::var(name).ok();
+        if env("STORAGE_BACKEND").as_deref() == Some("s3") {
+            match (
+                env("S3_ENDPOINT"),
+                env("S3_BUCKET"),
+                env("S3_ACCESS_KEY"),
+                env("S3_SECRET_KEY"),
+            ) {
+                (Some(endpoint), Some(bucket), Some(access_key), Some(secret_key)) => {
+                    let region = env("S3_REGION").unwrap_or_else(|| "us-east-1".to_string());
+                    match S3Store::new(&endpoint, bucket, region, access_key, secret_key) {
+                        Ok(store) => {
+                            info!("Storing uploads in S3 at {endpoint}");
+                            return Self(Arc::new(store));
+                        }
+                        Err(err) => warn!("{err}, storing uploads locally"),
+                    }
+                }
+                _ => warn!("S3 storage isn't fully configured, storing uploads locally"),
+            }
+        }
+
+        let root = env("STORAGE_PATH").unwrap_or_else(|| "uploads".to_string());
+        Self(Arc::new(LocalStore::new(root)))
+    }
+}
+
+impl std::ops::Deref for Storage {
+    type Target = dyn FileStore;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
@@ -1,191 +1,1146 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::{
     api::{ApiError, ApiResponse},
-    auth::JWTAuthToken,
-    db::Entity,
+    attachments,
+    auth::{AdminToken, AuthCookie, JWTAuthToken, Session},
+    cache::ScheduleCache,
+    db::Db,
+    loadshedding::{self, PowerStatus},
+    mail,
+    oidc::OidcClaims,
+    ratelimit::RateLimiter,
+    reporting::{UserReport, UserReportResponse},
+    repository::{MongoStore, PageRequest, RepoError, Repository, Store, Transaction},
+    storage::Storage,
+    webhooks::{DeliveryResponse, WebhookDelivery, WebhookResponse, WebhookSubscription},
     DB_NAME,
 };
 use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
-    Argon2, PasswordHasher,
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        SaltString,
+    },
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
 use bson::{oid::ObjectId, Document};
 use macros::Entity;
 use mongodb::Client;
-use rocket::{delete, get, post, put, serde::json::Json, State};
+use rocket::futures::future::join_all;
+use rocket::{delete, get, patch, post, put, serde::json::Json, State};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use utoipa::ToSchema;
 
 #[utoipa::path(post, tag = "Create User", path = "/api/user", request_body = NewUser, responses(
     (status = 200, description = "User creation result", body = ResponseString)
 ))]
 #[post("/user", format = "application/json", data = "<new_user>")]
-pub async fn create_user(
-    state: &State<Option<Client>>,
-    new_user: Json<NewUser>,
-) -> ApiResponse<&'static str> {
-    if state.is_none() {
-        return ApiError::ServerError("Database is unavailable. Please try again later!").into();
-    }
-
-    let mut query = Document::new();
-    query.insert("email", new_user.email.clone());
-    let email_collisions = match User::find(
-        bson::doc! {
-            "email": &new_user.email
-        },
-        &state.as_ref().unwrap().database(DB_NAME),
-        None,
-    )
-    .await
-    {
-        Ok(user) => user,
+pub async fn create_user(db: Db, new_user: Json<NewUser>) -> ApiResponse<&'static str> {
+    let users = MongoStore::new(&db.0, DB_NAME).repository::<User>();
+    match users.count(bson::doc! { "email": &new_user.email }).await {
+        Ok(0) => {}
+        Ok(_) => return ApiError::conflict("A user with that email already exists!").into(),
         Err(err) => {
             log::error!("Couldn't query the database! {err:?}");
-            return ApiError::ServerError("Couldn't query the database to validate user request")
+            return ApiError::internal("Couldn't query the database to validate user request")
                 .into();
         }
-    };
-
-    if email_collisions.len() > 0 {
-        return ApiError::UserCreationError("A user with that email already exists!").into();
     }
 
-    User::from(new_user.into_inner())
-        .insert(&state.as_ref().unwrap().database(DB_NAME))
-        .await
-        .expect("Couldn't insert new user!");
-
-    ApiResponse::Ok("User created")
+    match users.insert(&mut User::from(new_user.into_inner())).await {
+        Ok(_) => ApiResponse::Ok("User created"),
+        Err(RepoError::Duplicate(_)) => {
+            ApiError::conflict("A user with that email already exists!").into()
+        }
+        Err(err) => {
+            log::error!("Couldn't insert new user: {err:?}");
+            ApiError::internal("Couldn't create the user").into()
+        }
+    }
 }
 
-#[utoipa::path(put, path = "/api/user/savedPlaces", request_body = SavedPlace, security(("jwt" = [])))]
+#[utoipa::path(put, path = "/api/user/savedPlaces", request_body = SavedPlace, params(("owner" = Option<String>, Query, description = "Email of the user who shared their places, if not your own")), security(("jwt" = [])))]
 #[put(
-    "/user/savedPlaces",
+    "/user/savedPlaces?<owner>",
     format = "application/json",
     data = "<saved_place>"
 )]
 pub async fn add_saved_place(
     token: JWTAuthToken,
+    owner: Option<&str>,
     saved_place: Json<SavedPlace>,
     state: &State<Option<mongodb::Client>>,
 ) -> ApiResponse<&'static str> {
-    if token.email.is_none() {
-        return ApiError::AuthError("Authenticated user required").into();
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match places_owner(&store, &token, owner).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match add_place(&store, &user, saved_place.into_inner()).await {
+        Ok(()) => ApiResponse::Ok("New Saved Place recorded"),
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(get, path = "/api/user/savedPlaces", params(("enrich" = Option<bool>, Query, description = "Attach each place's suburb and power status")), security(("jwt" = [])))]
+#[get("/user/savedPlaces?<enrich>")]
+pub async fn get_saved_places(
+    token: JWTAuthToken,
+    enrich: Option<bool>,
+    db: Db,
+    cache: &State<ScheduleCache>,
+) -> ApiResponse<Vec<SavedPlaceStatus>> {
+    match token_user(&MongoStore::new(&db.0, DB_NAME), &token).await {
+        Ok(user) => {
+            let places = user.sorted_places().into_iter();
+            if !enrich.unwrap_or(false) {
+                return ApiResponse::Ok(
+                    places
+                        .map(|place| SavedPlaceStatus {
+                            place,
+                            status: None,
+                        })
+                        .collect(),
+                );
+            }
+
+            let connection = db.database("production");
+            let db_functions = cache.inner();
+            let places = places.map(|place| async {
+                let status = match loadshedding::power_status_at(
+                    place.longitude,
+                    place.latitude,
+                    &connection,
+                    db_functions,
+                    None,
+                )
+                .await
+                {
+                    Ok(status) => status,
+                    Err(err) => {
+                        log::warn!("Couldn't find power status for {}: {err:?}", place.name);
+                        None
+                    }
+                };
+                SavedPlaceStatus { place, status }
+            });
+            ApiResponse::Ok(join_all(places).await)
+        }
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(delete, path = "/api/user/savedPlaces/{id}", params(("id",), ("owner" = Option<String>, Query, description = "Email of the user who shared their places, if not your own")), security(("jwt" = [])))]
+#[delete("/user/savedPlaces/<id>?<owner>")]
+pub async fn delete_saved_place(
+    id: &str,
+    owner: Option<&str>,
+    token: JWTAuthToken,
+    state: &State<Option<mongodb::Client>>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match places_owner(&store, &token, owner).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match remove_place(&store, &user, id).await {
+        Ok(()) => ApiResponse::Ok("Saved Place deleted"),
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(patch, path = "/api/user/savedPlaces/{id}", request_body = UpdateSavedPlace, params(("id",), ("owner" = Option<String>, Query, description = "Email of the user who shared their places, if not your own")), security(("jwt" = [])))]
+#[patch(
+    "/user/savedPlaces/<id>?<owner>",
+    format = "application/json",
+    data = "<update>"
+)]
+pub async fn update_saved_place(
+    id: &str,
+    owner: Option<&str>,
+    update: Json<UpdateSavedPlace>,
+    token: JWTAuthToken,
+    state: &State<Option<mongodb::Client>>,
+) -> ApiResponse<SavedPlace> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match places_owner(&store, &token, owner).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match edit_place(&store, &user, id, update.into_inner()).await {
+        Ok(place) => ApiResponse::Ok(place),
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(put, path = "/api/user/savedPlaces/order", request_body = Vec<String>, params(("owner" = Option<String>, Query, description = "Email of the user who shared their places, if not your own")), security(("jwt" = [])))]
+#[put(
+    "/user/savedPlaces/order?<owner>",
+    format = "application/json",
+    data = "<order>"
+)]
+pub async fn reorder_saved_places(
+    owner: Option<&str>,
+    order: Json<Vec<String>>,
+    token: JWTAuthToken,
+    state: &State<Option<mongodb::Client>>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match places_owner(&store, &token, owner).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match reorder_places(&store, &user, &order).await {
+        Ok(()) => ApiResponse::Ok("Saved Places reordered"),
+        Err(err) => err.into(),
     }
+}
+
+#[utoipa::path(put, path = "/api/user/savedPlaces/shares", request_body = PlaceShare, security(("jwt" = [])))]
+#[put(
+    "/user/savedPlaces/shares",
+    format = "application/json",
+    data = "<share>"
+)]
+pub async fn share_saved_places(
+    share: Json<PlaceShare>,
+    token: JWTAuthToken,
+    state: &State<Option<mongodb::Client>>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
 
-    let db = state.as_ref().unwrap().database(DB_NAME);
+    match share_places(&store, &user, share.into_inner()).await {
+        Ok(()) => ApiResponse::Ok("Saved Places shared"),
+        Err(err) => err.into(),
+    }
+}
 
-    let mut user = dbg!(if let Some(user) =
-        User::find_one(bson::doc! { "email": &token.email }, &db, None).await
+#[utoipa::path(delete, path = "/api/user/savedPlaces/shares/{email}", params(("email",)), security(("jwt" = [])))]
+#[delete("/user/savedPlaces/shares/<email>")]
+pub async fn unshare_saved_places(
+    email: &str,
+    token: JWTAuthToken,
+    state: &State<Option<mongodb::Client>>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match unshare_places(&store, &user, email).await {
+        Ok(()) => ApiResponse::Ok("Stopped sharing Saved Places"),
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(get, path = "/api/user/savedPlaces/shared", security(("jwt" = [])), responses(
+    (status = 200, description = "Place lists other users have shared with you", body = [SharedPlaces])
+))]
+#[get("/user/savedPlaces/shared")]
+pub async fn get_shared_places(
+    token: JWTAuthToken,
+    state: &State<Option<mongodb::Client>>,
+) -> ApiResponse<Vec<SharedPlaces>> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match store
+        .repository::<User>()
+        .find(
+            bson::doc! { "sharedWith.email": &user.email },
+            PageRequest::all(),
+        )
+        .await
     {
-        user
+        Ok(owners) => ApiResponse::Ok(
+            owners
+                .items
+                .into_iter()
+                .map(|owner| SharedPlaces {
+                    can_edit: owner
+                        .shared_with
+                        .iter()
+                        .any(|share| share.email == user.email && share.can_edit),
+                    owner_email: owner.email.clone(),
+                    owner_name: format!("{} {}", owner.first_name, owner.last_name),
+                    places: owner.sorted_places(),
+                })
+                .collect(),
+        ),
+        Err(err) => {
+            log::error!("Couldn't fetch shared places: {err:?}");
+            ApiError::internal("Couldn't fetch shared places").into()
+        }
+    }
+}
+
+/// Finds whose saved places a request acts on: the signed in user's own, or
+/// those `owner` shared with them for editing.
+async fn places_owner<S: Store>(
+    store: &S,
+    token: &JWTAuthToken,
+    owner: Option<&str>,
+) -> Result<User, ApiError> {
+    let user = token_user(store, token).await?;
+    let owner_email = match owner {
+        Some(owner) if owner != user.email => owner,
+        _ => return Ok(user),
+    };
+
+    match store
+        .repository::<User>()
+        .find_one(bson::doc! { "email": owner_email }, None)
+        .await?
+    {
+        Some(owner)
+            if owner
+                .shared_with
+                .iter()
+                .any(|share| share.email == user.email && share.can_edit) =>
+        {
+            Ok(owner)
+        }
+        _ => Err(ApiError::forbidden(
+            "You can't edit that user's saved places",
+        )),
+    }
+}
+
+/// Saved places are keyed on their Mapbox id, which has to be usable as a
+/// field name
+fn place_path(id: &str) -> Result<String, ApiError> {
+    if id.is_empty() || id.contains('.') || id.starts_with('$') {
+        return Err(ApiError::invalid("mapboxId", "That isn't a valid place id"));
+    }
+    Ok(format!("savedPlaces.{id}"))
+}
+
+/// Adds `place` to the end of `owner`'s list, unless it's already there
+pub async fn add_place<S: Store>(
+    store: &S,
+    owner: &User,
+    mut place: SavedPlace,
+) -> Result<(), ApiError> {
+    let path = place_path(&place.mapbox_id)?;
+    place.position = owner
+        .saved_places
+        .values()
+        .map(|place| place.position + 1)
+        .max()
+        .unwrap_or(0);
+
+    match store
+        .repository::<User>()
+        .update_one(
+            bson::doc! { "_id": owner.stored_id()?, &path: { "$exists": false } },
+            bson::doc! { "$set": { &path: bson::to_bson(&place).unwrap() } },
+        )
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::conflict("Duplicate saved place")),
+        Err(err) => {
+            log::error!("Couldn't record saved place: {err:?}");
+            Err(ApiError::internal("Unable to record new saved place"))
+        }
+    }
+}
+
+pub async fn remove_place<S: Store>(store: &S, owner: &User, id: &str) -> Result<(), ApiError> {
+    let path = place_path(id)?;
+    match store
+        .repository::<User>()
+        .update_one(
+            bson::doc! { "_id": owner.stored_id()?, &path: { "$exists": true } },
+            bson::doc! { "$unset": { &path: "" } },
+        )
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::not_found("The provided id doesn't exist")),
+        Err(err) => {
+            log::error!("Couldn't delete saved place: {err:?}");
+            Err(ApiError::internal("Couldn't delete saved place"))
+        }
+    }
+}
+
+/// Changes only the fields `update` sets, returning the place as it is now
+pub async fn edit_place<S: Store>(
+    store: &S,
+    owner: &User,
+    id: &str,
+    update: UpdateSavedPlace,
+) -> Result<SavedPlace, ApiError> {
+    let path = place_path(id)?;
+    let mut changes = Document::new();
+    if let Some(name) = update.name {
+        changes.insert(format!("{path}.name"), name);
+    }
+    if let Some(category) = update.category {
+        changes.insert(format!("{path}.category"), category);
+    }
+    if let Some(address) = update.address {
+        changes.insert(format!("{path}.address"), address);
+    }
+    if let Some(latitude) = update.latitude {
+        changes.insert(format!("{path}.latitude"), latitude);
+    }
+    if let Some(longitude) = update.longitude {
+        changes.insert(format!("{path}.longitude"), longitude);
+    }
+
+    let users = store.repository::<User>();
+    let id_filter = bson::doc! { "_id": owner.stored_id()?, &path: { "$exists": true } };
+    let updated = if changes.is_empty() {
+        users.count(id_filter).await.map(|found| found > 0)
     } else {
-        return ApiError::ServerError("The requested user could not be found").into();
-    });
+        users
+            .update_one(id_filter, bson::doc! { "$set": changes })
+            .await
+    };
+    match updated {
+        Ok(true) => {}
+        Ok(false) => return Err(ApiError::not_found("The provided id doesn't exist")),
+        Err(err) => {
+            log::error!("Couldn't update saved place: {err:?}");
+            return Err(ApiError::internal("Couldn't update saved place"));
+        }
+    }
 
-    if user.saved_places.contains_key(&saved_place.mapbox_id) {
-        return ApiError::SavedPlacesError("Duplicate saved place").into();
+    match users.find_by_id(owner.stored_id()?).await {
+        Ok(Some(mut user)) => user
+            .saved_places
+            .remove(id)
+            .ok_or_else(|| ApiError::not_found("The provided id doesn't exist")),
+        Ok(None) => Err(ApiError::not_found("The provided id doesn't exist")),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// `order` has to list every one of `owner`'s places once
+pub async fn reorder_places<S: Store>(
+    store: &S,
+    owner: &User,
+    order: &[String],
+) -> Result<(), ApiError> {
+    let listed: HashSet<&String> = order.iter().collect();
+    if listed.len() != order.len()
+        || order.len() != owner.saved_places.len()
+        || order.iter().any(|id| !owner.saved_places.contains_key(id))
+    {
+        return Err(ApiError::bad_request(
+            "The order has to list every saved place once",
+        ));
     }
 
-    user.saved_places
-        .insert(saved_place.mapbox_id.clone(), saved_place.into_inner());
+    let mut filter = bson::doc! { "_id": owner.stored_id()? };
+    let mut positions = Document::new();
+    for (position, id) in order.iter().enumerate() {
+        let path = place_path(id)?;
+        positions.insert(format!("{path}.position"), position as u32);
+        filter.insert(path, bson::doc! { "$exists": true });
+    }
 
-    let places = dbg!(mongodb::bson::to_bson(&user.saved_places).unwrap());
-    let doc = mongodb::bson::doc! {
-        "$set": {
-            "savedPlaces": places
+    // Fails if a place was deleted since the order was worked out
+    match store
+        .repository::<User>()
+        .update_one(filter, bson::doc! { "$set": positions })
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::conflict(
+            "Saved places changed while reordering them",
+        )),
+        Err(err) => {
+            log::error!("Couldn't reorder saved places: {err:?}");
+            Err(ApiError::internal("Couldn't reorder saved places"))
         }
+    }
+}
+
+/// Shares `user`'s places, or changes whether an existing share can edit
+pub async fn share_places<S: Store>(
+    store: &S,
+    user: &User,
+    share: PlaceShare,
+) -> Result<(), ApiError> {
+    if share.email == user.email {
+        return Err(ApiError::bad_request(
+            "You can't share places with yourself",
+        ));
+    }
+    let users = store.repository::<User>();
+    match users.count(bson::doc! { "email": &share.email }).await {
+        Ok(0) => {
+            return Err(ApiError::bad_request(
+                "Places can only be shared with registered users",
+            ))
+        }
+        Ok(_) => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let id = user.stored_id()?;
+    let updated = users
+        .update_one(
+            bson::doc! { "_id": id, "sharedWith.email": &share.email },
+            bson::doc! { "$set": { "sharedWith.$.canEdit": share.can_edit } },
+        )
+        .await;
+    let shared = match updated {
+        Ok(true) => Ok(true),
+        Ok(false) => {
+            users
+                .update_one(
+                    bson::doc! { "_id": id, "sharedWith.email": { "$ne": &share.email } },
+                    bson::doc! { "$push": { "sharedWith": bson::to_bson(&share).unwrap() } },
+                )
+                .await
+        }
+        Err(err) => Err(err),
     };
+    match shared {
+        Ok(_) => Ok(()),
+        Err(err) => {
+            log::error!("Couldn't share saved places: {err:?}");
+            Err(ApiError::internal("Couldn't share saved places"))
+        }
+    }
+}
 
-    match user.update(doc.into(), &db).await {
-        Ok(_) => ApiResponse::Ok("New Saved Place recorded"),
+pub async fn unshare_places<S: Store>(store: &S, user: &User, email: &str) -> Result<(), ApiError> {
+    match store
+        .repository::<User>()
+        .update_one(
+            bson::doc! { "_id": user.stored_id()?, "sharedWith.email": email },
+            bson::doc! { "$pull": { "sharedWith": { "email": email } } },
+        )
+        .await
+    {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(ApiError::not_found(
+            "Your places aren't shared with that user",
+        )),
         Err(err) => {
-            log::error!("Couldn't update user: {err:?}");
-            ApiError::ServerError("Unable to record new saved place").into()
+            log::error!("Couldn't stop sharing saved places: {err:?}");
+            Err(ApiError::internal("Couldn't stop sharing saved places"))
         }
     }
 }
 
-#[utoipa::path(get, path = "/api/user/savedPlaces", security(("jwt" = [])))]
-#[get("/user/savedPlaces")]
-pub async fn get_saved_places(
+/// How long a link to confirm a new email address stays valid
+const EMAIL_CHANGE_LIFETIME_SECS: u64 = 3600 * 24;
+
+/// Looks up the signed in user a token belongs to, along with the store
+/// to carry on with
+pub(crate) async fn current_user(
+    token: &JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> Result<(User, MongoStore), ApiError> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return Err(ApiError::database_unavailable()),
+    };
+    let user = token_user(&store, token).await?;
+    Ok((user, store))
+}
+
+/// Checks `password` against the user's, counting failures towards the same
+/// lockout as logins
+async fn verify_password(
+    user: &User,
+    password: &str,
+    limiter: &RateLimiter,
+) -> Result<(), ApiError> {
+    if user.password_hash.is_empty() {
+        return Err(ApiError::unauthenticated(
+            "This account signs in with an identity provider",
+        ));
+    }
+    if limiter.login_locked_out(&user.email).await.is_some() {
+        return Err(ApiError::rate_limited(
+            "Too many failed password attempts. Please try again later",
+        ));
+    }
+
+    let hash = PasswordHash::new(&user.password_hash).map_err(|err| {
+        log::error!("Stored password hash is invalid: {err:?}");
+        ApiError::internal("Couldn't verify password")
+    })?;
+    match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(_) => {
+            limiter.login_succeeded(&user.email).await;
+            Ok(())
+        }
+        Err(_) => {
+            limiter.login_failed(&user.email).await;
+            Err(ApiError::unauthenticated("Incorrect password"))
+        }
+    }
+}
+
+pub(crate) fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Couldn't hash password")
+        .to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[utoipa::path(get, path = "/api/user", security(("jwt" = [])), responses(
+    (status = 200, description = "The signed in user's profile", body = UserProfile)
+))]
+#[get("/user")]
+pub async fn get_user(
     token: JWTAuthToken,
-    state: &State<Option<mongodb::Client>>,
-) -> ApiResponse<Vec<SavedPlace>> {
-    if token.email.is_none() {
-        return ApiError::AuthError("Only authenticated users can use this endpoint").into();
+    state: &State<Option<Client>>,
+) -> ApiResponse<UserProfile> {
+    match current_user(&token, state).await {
+        Ok((user, _)) => ApiResponse::Ok(UserProfile::from(&user)),
+        Err(err) => err.into(),
     }
+}
 
-    match User::find_one(
-        bson::doc! {
-            "email": &token.email
-        },
-        &state
-            .as_ref()
-            .expect("This rocket instance has not attached database!")
-            .database(DB_NAME),
-        None,
-    )
-    .await
-    {
-        Some(user) => ApiResponse::Ok(user.saved_places.into_iter().map(|x| x.1).collect()),
-        None => ApiError::AuthError("We couldn't find the user associated with that token").into(),
+#[utoipa::path(patch, path = "/api/user", request_body = UpdateUser, security(("jwt" = [])))]
+#[patch("/user", format = "application/json", data = "<update>")]
+pub async fn update_user(
+    token: JWTAuthToken,
+    update: Json<UpdateUser>,
+    state: &State<Option<Client>>,
+) -> ApiResponse<UserProfile> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let mut user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match update_profile(&store, &mut user, update.into_inner()).await {
+        Ok(()) => ApiResponse::Ok(UserProfile::from(&user)),
+        Err(err) => err.into(),
     }
 }
 
-#[utoipa::path(delete, path = "/api/user/savedPlaces/{id}", params(("id",)), security(("jwt" = [])))]
-#[delete("/user/savedPlaces/<id>")]
-pub async fn delete_saved_place(
-    id: &str,
+#[utoipa::path(put, path = "/api/user/password", request_body = ChangePassword, security(("jwt" = [])))]
+#[put("/user/password", format = "application/json", data = "<change>")]
+pub async fn change_password(
     token: JWTAuthToken,
-    state: &State<Option<mongodb::Client>>,
-) -> ApiResponse<'static, &'static str> {
-    if token.email.is_none() {
-        return ApiError::AuthError("This endpoint is only available to logged in users").into();
-    }
-
-    let email = token.email.unwrap();
-    let db = state.as_ref().unwrap().database(DB_NAME);
-    let mut user = if let Some(user) = User::find_one(
-        bson::doc! { "email": email },
-        &state
-            .as_ref()
-            .expect("This rocket instance has no database!")
-            .database(DB_NAME),
-        None,
+    change: Json<ChangePassword>,
+    state: &State<Option<Client>>,
+    limiter: &State<RateLimiter>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match set_password(&store, &user, &change, limiter).await {
+        Ok(()) => ApiResponse::Ok("Password changed"),
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(put, path = "/api/user/email", request_body = ChangeEmail, security(("jwt" = [])))]
+#[put("/user/email", format = "application/json", data = "<change>")]
+pub async fn change_email(
+    token: JWTAuthToken,
+    change: Json<ChangeEmail>,
+    state: &State<Option<Client>>,
+    limiter: &State<RateLimiter>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    let (new_email, code) = match start_email_change(&store, &user, &change, limiter).await {
+        Ok(pending) => pending,
+        Err(err) => return err.into(),
+    };
+
+    if let Err(err) = mail::send_mail(
+        &new_email,
+        "Confirm your new email address",
+        &mail::with_link(
+            "Use this code to confirm your new email address for Where Is The Power",
+            "verifyEmail",
+            &code,
+        ),
     )
     .await
     {
-        user
-    } else {
-        return ApiError::AuthError("Couldn't find user associated with token").into();
+        log::error!("Couldn't send email change verification: {err:?}");
+        return ApiError::internal("Couldn't send the verification email").into();
+    }
+
+    ApiResponse::Ok("Check your new inbox to confirm the change")
+}
+
+/// The signed in user behind `token`
+pub(crate) async fn token_user<S: Store>(
+    store: &S,
+    token: &JWTAuthToken,
+) -> Result<User, ApiError> {
+    let email = match &token.email {
+        Some(email) => email,
+        None => return Err(ApiError::forbidden("Authenticated user required")),
     };
 
-    if !user.saved_places.contains_key(id) {
-        ApiError::SavedPlacesError("The provided id doesn't exist").into()
-    } else {
-        user.saved_places.remove(id);
-        let places = bson::to_document(&user.saved_places).unwrap();
-        match user
-            .update(
-                bson::doc! {
-                     "$set": {
-                        "savedPlaces": places
-                    }
-                }
-                .into(),
-                &db,
-            )
-            .await
-        {
-            Ok(_) => ApiResponse::Ok("Saved Place deleted"),
-            Err(err) => {
-                log::error!("Couldn't delete saved place: {err:?}");
-                ApiError::ServerError("Couldn't delete saved place").into()
+    match store
+        .repository::<User>()
+        .find_one(bson::doc! { "email": email }, None)
+        .await
+    {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(ApiError::unauthenticated(
+            "We couldn't find the user associated with that token",
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Applies the fields `update` sets to `user`, leaving the rest alone
+pub async fn update_profile<S: Store>(
+    store: &S,
+    user: &mut User,
+    update: UpdateUser,
+) -> Result<(), ApiError> {
+    let mut changes = Document::new();
+    if let Some(first_name) = update.first_name {
+        changes.insert("firstName", &first_name);
+        user.first_name = first_name;
+    }
+    if let Some(last_name) = update.last_name {
+        changes.insert("lastName", &last_name);
+        user.last_name = last_name;
+    }
+    if let Some(location) = update.location {
+        changes.insert("location", bson::to_bson(&location).unwrap());
+        user.location = Some(location);
+    }
+    if let Some(phone_number) = update.phone_number {
+        changes.insert("phoneNumber", &phone_number);
+        user.phone_number = Some(phone_number);
+    }
+
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    if let Err(err) = store
+        .repository::<User>()
+        .update_by_id(user.stored_id()?, bson::doc! { "$set": changes })
+        .await
+    {
+        log::error!("Couldn't update user profile: {err:?}");
+        return Err(ApiError::internal("Couldn't update your profile"));
+    }
+    Ok(())
+}
+
+/// Checks the current password before replacing it, then signs out every
+/// cookie session
+pub async fn set_password<S: Store>(
+    store: &S,
+    user: &User,
+    change: &ChangePassword,
+    limiter: &RateLimiter,
+) -> Result<(), ApiError> {
+    verify_password(user, &change.current_password, limiter).await?;
+    if change.new_password.is_empty() {
+        return Err(ApiError::invalid(
+            "newPassword",
+            "The new password can't be empty",
+        ));
+    }
+
+    let id = user.stored_id()?;
+    let password_hash = hash_password(&change.new_password);
+    if let Err(err) = store
+        .repository::<User>()
+        .update_by_id(id, bson::doc! { "$set": { "passwordHash": password_hash } })
+        .await
+    {
+        log::error!("Couldn't update password: {err:?}");
+        return Err(ApiError::internal("Couldn't change your password"));
+    }
+
+    // Anyone holding a login cookie has to prove they know the new password
+    if let Err(err) = store
+        .repository::<AuthCookie>()
+        .delete_many(bson::doc! { "user": id })
+        .await
+    {
+        log::error!("Couldn't sign out sessions after password change: {err:?}");
+    }
+    Ok(())
+}
+
+/// Records a pending change to the address in `change`, returning it and
+/// the code that confirms it
+pub async fn start_email_change<S: Store>(
+    store: &S,
+    user: &User,
+    change: &ChangeEmail,
+    limiter: &RateLimiter,
+) -> Result<(String, String), ApiError> {
+    // Identity provider accounts have no password, their JWT is all we get
+    if !user.password_hash.is_empty() {
+        verify_password(
+            user,
+            change.password.as_deref().unwrap_or_default(),
+            limiter,
+        )
+        .await?;
+    }
+
+    let new_email = change.new_email.trim().to_string();
+    if !new_email.contains('@') {
+        return Err(ApiError::invalid(
+            "email",
+            "That doesn't look like an email address",
+        ));
+    }
+    let users = store.repository::<User>();
+    match users.count(bson::doc! { "email": &new_email }).await {
+        Ok(0) => {}
+        Ok(_) => return Err(ApiError::conflict("A user with that email already exists!")),
+        Err(err) => return Err(err.into()),
+    }
+
+    let mut code = [0u8; 32];
+    OsRng.fill_bytes(&mut code);
+    let code = hex::encode(code);
+    let pending = PendingEmailChange {
+        email: new_email.clone(),
+        token_hash: hex::encode(Sha256::digest(code.as_bytes())),
+        expires: now() + EMAIL_CHANGE_LIFETIME_SECS,
+    };
+
+    if let Err(err) = users
+        .update_by_id(
+            user.stored_id()?,
+            bson::doc! { "$set": { "pendingEmail": bson::to_bson(&pending).unwrap() } },
+        )
+        .await
+    {
+        log::error!("Couldn't record pending email change: {err:?}");
+        return Err(ApiError::internal("Couldn't change your email address"));
+    }
+    Ok((new_email, code))
+}
+
+#[utoipa::path(post, path = "/api/user/email/verify", request_body = VerifyEmailChange)]
+#[post(
+    "/user/email/verify",
+    format = "application/json",
+    data = "<verification>"
+)]
+pub async fn verify_email_change(
+    verification: Json<VerifyEmailChange>,
+    state: &State<Option<Client>>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    match apply_email_change(&store, &verification.token).await {
+        Ok(()) => ApiResponse::Ok("Email changed. Please sign in again"),
+        Err(err) => err.into(),
+    }
+}
+
+/// Moves the user who was sent `code` over to their new address. The user,
+/// their reports and their webhooks change together or not at all.
+pub async fn apply_email_change<S: Store>(store: &S, code: &str) -> Result<(), ApiError> {
+    let token_hash = hex::encode(Sha256::digest(code.trim().as_bytes()));
+    let users = store.repository::<User>();
+    let user = users
+        .find_one(bson::doc! { "pendingEmail.tokenHash": &token_hash }, None)
+        .await?;
+    let (user, pending) = match user {
+        Some(user) => match user.pending_email.clone() {
+            Some(pending) if pending.expires < now() => {
+                return Err(ApiError::bad_request("This verification code has expired"))
+            }
+            Some(pending) => (user, pending),
+            None => {
+                return Err(ApiError::bad_request(
+                    "Invalid or already used verification code",
+                ))
+            }
+        },
+        None => {
+            return Err(ApiError::bad_request(
+                "Invalid or already used verification code",
+            ))
+        }
+    };
+
+    // The address could have been claimed since the change was requested
+    if users.count(bson::doc! { "email": &pending.email }).await? > 0 {
+        return Err(ApiError::conflict("A user with that email already exists!"));
+    }
+
+    let transaction = store.begin().await?;
+    // Of two verifications racing each other only the first gets through
+    let changed = transaction
+        .repository::<User>()
+        .update_one(
+            bson::doc! { "_id": user.stored_id()?, "pendingEmail.tokenHash": &token_hash },
+            bson::doc! {
+                "$set": { "email": &pending.email, "isVerified": true },
+                "$unset": { "pendingEmail": "" }
+            },
+        )
+        .await?;
+    if !changed {
+        transaction.abort().await?;
+        return Err(ApiError::bad_request(
+            "Invalid or already used verification code",
+        ));
+    }
+    transaction
+        .repository::<UserReport>()
+        .update_many(
+            bson::doc! { "email": &user.email },
+            bson::doc! { "$set": { "email": &pending.email } },
+        )
+        .await?;
+    transaction
+        .repository::<UserReport>()
+        .update_elements(
+            bson::doc! { "votes.email": &user.email },
+            bson::doc! { "$set": { "votes.$[vote].email": &pending.email } },
+            vec![bson::doc! { "vote.email": &user.email }],
+        )
+        .await?;
+    transaction
+        .repository::<User>()
+        .update_elements(
+            bson::doc! { "sharedWith.email": &user.email },
+            bson::doc! { "$set": { "sharedWith.$[share].email": &pending.email } },
+            vec![bson::doc! { "share.email": &user.email }],
+        )
+        .await?;
+    transaction
+        .repository::<WebhookSubscription>()
+        .update_many(
+            bson::doc! { "owner": &user.email },
+            bson::doc! { "$set": { "owner": &pending.email } },
+        )
+        .await?;
+    transaction.commit().await?;
+    Ok(())
+}
+
+#[utoipa::path(delete, path = "/api/user", security(("jwt" = [])))]
+#[delete("/user")]
+pub async fn delete_user(
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+    storage: &State<Storage>,
+) -> ApiResponse<&'static str> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    match store
+        .repository::<UserReport>()
+        .find(
+            bson::doc! { "email": &user.email, "photos.0": { "$exists": true } },
+            PageRequest::all(),
+        )
+        .await
+    {
+        Ok(reports) => {
+            for report in reports.items {
+                attachments::delete_photos(&report, storage).await;
             }
         }
+        Err(err) => {
+            log::error!("Couldn't find user's report photos: {err:?}");
+            return ApiError::internal("Couldn't delete your account").into();
+        }
+    }
+
+    match forget_user(&store, &user).await {
+        Ok(()) => {
+            log::info!("Deleted account of {}", user.email);
+            ApiResponse::Ok("Account deleted")
+        }
+        Err(err) => err.into(),
+    }
+}
+
+/// Deletes `user` and everything tied to them, bar report photos, which
+/// live in storage. The user document goes last so a failed deletion can be
+/// retried.
+pub async fn forget_user<S: Store>(store: &S, user: &User) -> Result<(), ApiError> {
+    let id = user.stored_id()?;
+    let cookies = store
+        .repository::<AuthCookie>()
+        .delete_many(bson::doc! { "user": id })
+        .await;
+    let reports = store.repository::<UserReport>();
+    let own_reports = reports
+        .delete_many(bson::doc! { "email": &user.email })
+        .await;
+    // Their votes and shares are kept on other users' documents
+    let votes = reports
+        .update_many(
+            bson::doc! { "votes.email": &user.email },
+            bson::doc! { "$pull": { "votes": { "email": &user.email } } },
+        )
+        .await;
+    let users = store.repository::<User>();
+    let shares = users
+        .update_many(
+            bson::doc! { "sharedWith.email": &user.email },
+            bson::doc! { "$pull": { "sharedWith": { "email": &user.email } } },
+        )
+        .await;
+    let webhooks = store.repository::<WebhookSubscription>();
+    let hooks = match webhooks
+        .find(bson::doc! { "owner": &user.email }, PageRequest::all())
+        .await
+    {
+        Ok(page) => {
+            let ids = page.items.iter().filter_map(|x| x.id).collect::<Vec<_>>();
+            let deliveries = store
+                .repository::<WebhookDelivery>()
+                .delete_many(bson::doc! { "webhook": { "$in": ids } })
+                .await;
+            let hooks = webhooks
+                .delete_many(bson::doc! { "owner": &user.email })
+                .await;
+            deliveries.and(hooks)
+        }
+        Err(err) => Err(err),
+    };
+    if let Err(err) = cookies.and(own_reports).and(votes).and(shares).and(hooks) {
+        log::error!("Couldn't delete user's data: {err:?}");
+        return Err(ApiError::internal("Couldn't delete your account"));
+    }
+
+    // Saved places live on the user document, so they go along with it
+    if let Err(err) = users.delete_by_id(id).await {
+        log::error!("Couldn't delete user: {err:?}");
+        return Err(ApiError::internal("Couldn't delete your account"));
+    }
+    Ok(())
+}
+
+#[utoipa::path(get, path = "/api/user/export", security(("jwt" = [])), responses(
+    (status = 200, description = "Everything we store about the signed in user", body = UserDataExport)
+))]
+#[get("/user/export")]
+pub async fn export_user_data(
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<UserDataExport> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+    let user = match token_user(&store, &token).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+
+    log::info!("Exporting data for {}", user.email);
+    match UserDataExport::collect(user, &store).await {
+        Ok(export) => ApiResponse::Ok(export),
+        Err(err) => err.into(),
+    }
+}
+
+#[utoipa::path(get, path = "/api/admin/users/{email}/export", params(("email",)), security(("jwt" = [])), responses(
+    (status = 200, description = "Everything we store about the user", body = UserDataExport)
+))]
+#[get("/admin/users/<email>/export")]
+pub async fn admin_export_user_data(
+    email: &str,
+    admin: AdminToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<UserDataExport> {
+    let store = match state.inner() {
+        Some(client) => MongoStore::new(client, DB_NAME),
+        None => return ApiError::database_unavailable().into(),
+    };
+
+    let user = match store
+        .repository::<User>()
+        .find_one(bson::doc! { "email": email }, None)
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => return ApiError::not_found("No such user").into(),
+        Err(err) => return ApiError::from(err).into(),
+    };
+
+    log::info!("{} is exporting data for {email}", admin.email());
+    match UserDataExport::collect(user, &store).await {
+        Ok(export) => ApiResponse::Ok(export),
+        Err(err) => err.into(),
     }
 }
 
@@ -208,6 +1163,7 @@ pub struct UserLocation {
         longitude: 0.0,
         category: Some("average".to_string()),
         place_type: "unkown".to_string(),
+        position: 0,
     }
 })]
 pub struct SavedPlace {
@@ -218,11 +1174,55 @@ pub struct SavedPlace {
     pub longitude: f64,
     pub category: Option<String>,
     pub place_type: String,
+    /// Where the place appears in the user's list, assigned by the server
+    #[serde(default)]
+    pub position: u32,
+}
+
+/// Fields that are left out aren't changed
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateSavedPlace {
+    pub name: Option<String>,
+    pub category: Option<String>,
+    pub address: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+/// Another user who can see this user's saved places
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaceShare {
+    pub email: String,
+    /// Whether they can add, change, reorder and delete places too
+    #[serde(default)]
+    pub can_edit: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SharedPlaces {
+    pub owner_email: String,
+    pub owner_name: String,
+    pub can_edit: bool,
+    pub places: Vec<SavedPlace>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedPlaceStatus {
+    #[serde(flatten)]
+    pub place: SavedPlace,
+    /// Only filled in when asked for, and when we have data for the place
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<PowerStatus>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Entity)]
 #[serde(rename_all = "camelCase")]
 #[collection_name = "users"]
+#[index(fields = "sharedWith.email")]
 pub struct User {
     #[serde(rename = "_id")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -232,11 +1232,260 @@ pub struct User {
     pub location: Option<UserLocation>,
     pub is_verified: bool,
     pub phone_number: Option<String>,
+    #[index(unique)]
     pub email: String,
     pub saved_places: HashMap<String, SavedPlace>,
 
-    #[serde(skip_serializing_if = "String::is_empty")]
+    /// Empty for users who have only ever signed in through OIDC
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub password_hash: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_email: Option<PendingEmailChange>,
+    #[serde(default)]
+    pub shared_with: Vec<PlaceShare>,
+    /// Goes up when other users confirm this user's reports, and down when
+    /// they dispute them or a moderator hides them
+    #[serde(default = "default_reputation")]
+    pub reputation: f64,
+}
+
+fn default_reputation() -> f64 {
+    1.0
+}
+
+impl User {
+    /// Users read back from the database always have one
+    pub(crate) fn stored_id(&self) -> Result<ObjectId, ApiError> {
+        self.id
+            .ok_or_else(|| ApiError::internal("User hasn't been stored"))
+    }
+
+    /// How much this user's reports and votes count for
+    pub fn weight(&self) -> f64 {
+        self.reputation.clamp(0.1, 5.0)
+    }
+
+    /// Saved places in the order the user arranged them
+    pub fn sorted_places(&self) -> Vec<SavedPlace> {
+        let mut places: Vec<SavedPlace> = self.saved_places.values().cloned().collect();
+        places.sort_by(|a, b| a.position.cmp(&b.position).then(a.name.cmp(&b.name)));
+        places
+    }
+}
+
+/// Everything we keep about a user, for access requests under POPIA. Other
+/// users' emails, like those of people voting on their reports, are left out.
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserDataExport {
+    pub exported_at: u64,
+    /// The stored user document, without credentials
+    #[schema(value_type = Object)]
+    pub user: User,
+    pub saved_places: Vec<SavedPlace>,
+    pub reports: Vec<ExportedReport>,
+    /// Votes the user cast on other users' reports
+    pub votes: Vec<ExportedVote>,
+    pub webhooks: Vec<ExportedWebhook>,
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedReport {
+    #[serde(flatten)]
+    pub report: UserReportResponse,
+    pub created: u64,
+    pub hidden: bool,
+}
+
+impl From<&UserReport> for ExportedReport {
+    fn from(value: &UserReport) -> Self {
+        Self {
+            report: UserReportResponse::from(value),
+            created: value.created,
+            hidden: value.hidden,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedVote {
+    pub report: String,
+    pub confirm: bool,
+    pub weight: f64,
+    pub at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportedWebhook {
+    #[serde(flatten)]
+    pub webhook: WebhookResponse,
+    pub deliveries: Vec<DeliveryResponse>,
+}
+
+impl UserDataExport {
+    pub async fn collect<S: Store>(mut user: User, store: &S) -> Result<Self, ApiError> {
+        let reports = store
+            .repository::<UserReport>()
+            .find(bson::doc! { "email": &user.email }, PageRequest::all())
+            .await;
+        let voted = store
+            .repository::<UserReport>()
+            .find(
+                bson::doc! { "votes.email": &user.email, "email": { "$ne": &user.email } },
+                PageRequest::all(),
+            )
+            .await;
+        let cookies = store
+            .repository::<AuthCookie>()
+            .find(bson::doc! { "user": user.id }, PageRequest::all())
+            .await;
+        let webhooks = store
+            .repository::<WebhookSubscription>()
+            .find(bson::doc! { "owner": &user.email }, PageRequest::all())
+            .await;
+        let (reports, voted, cookies, webhooks) = match (reports, voted, cookies, webhooks) {
+            (Ok(reports), Ok(voted), Ok(cookies), Ok(webhooks)) => {
+                (reports.items, voted.items, cookies.items, webhooks.items)
+            }
+            (Err(err), ..) | (_, Err(err), ..) | (.., Err(err), _) | (.., Err(err)) => {
+                log::error!("Couldn't collect user data for export: {err:?}");
+                return Err(ApiError::internal("Couldn't collect your data"));
+            }
+        };
+
+        let webhook_ids: Vec<ObjectId> = webhooks.iter().filter_map(|webhook| webhook.id).collect();
+        let deliveries = match store
+            .repository::<WebhookDelivery>()
+            .find(
+                bson::doc! { "webhook": { "$in": webhook_ids } },
+                PageRequest::all().sorted_by(bson::doc! { "created": -1 }),
+            )
+            .await
+        {
+            Ok(deliveries) => deliveries.items,
+            Err(err) => {
+                log::error!("Couldn't collect webhook deliveries for export: {err:?}");
+                return Err(ApiError::internal("Couldn't collect your data"));
+            }
+        };
+
+        user.password_hash.clear();
+        if let Some(pending) = user.pending_email.as_mut() {
+            pending.token_hash.clear();
+        }
+
+        Ok(Self {
+            exported_at: now(),
+            saved_places: user.sorted_places(),
+            reports: reports.iter().map(ExportedReport::from).collect(),
+            votes: voted
+                .iter()
+                .flat_map(|report| {
+                    let id = report.id.map(|id| id.to_hex()).unwrap_or_default();
+                    report
+                        .votes
+                        .iter()
+                        .filter(|vote| vote.email == user.email)
+                        .map(move |vote| ExportedVote {
+                            report: id.clone(),
+                            confirm: vote.confirm,
+                            weight: vote.weight,
+                            at: vote.at,
+                        })
+                })
+                .collect(),
+            webhooks: webhooks
+                .iter()
+                .map(|webhook| ExportedWebhook {
+                    webhook: WebhookResponse::from(webhook),
+                    deliveries: deliveries
+                        .iter()
+                        .filter(|delivery| Some(delivery.webhook) == webhook.id)
+                        .map(DeliveryResponse::from)
+                        .collect(),
+                })
+                .collect(),
+            sessions: cookies
+                .iter()
+                .filter(|cookie| cookie.exp > now())
+                .map(Session::from)
+                .collect(),
+            user,
+        })
+    }
+}
+
+/// An email change waiting for the user to confirm they own the new address
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingEmailChange {
+    pub email: String,
+    /// SHA-256 of the code we emailed, the code itself is never stored
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub token_hash: String,
+    pub expires: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserProfile {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub is_verified: bool,
+    pub location: Option<UserLocation>,
+    pub phone_number: Option<String>,
+    /// Set while a change of email address is waiting to be confirmed
+    pub pending_email: Option<String>,
+}
+
+impl From<&User> for UserProfile {
+    fn from(value: &User) -> Self {
+        Self {
+            first_name: value.first_name.clone(),
+            last_name: value.last_name.clone(),
+            email: value.email.clone(),
+            is_verified: value.is_verified,
+            location: value.location.clone(),
+            phone_number: value.phone_number.clone(),
+            pending_email: value.pending_email.as_ref().map(|x| x.email.clone()),
+        }
+    }
+}
+
+/// Fields that are left out aren't changed
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateUser {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub location: Option<UserLocation>,
+    pub phone_number: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePassword {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeEmail {
+    pub new_email: String,
+    /// Required unless the account signs in with an identity provider
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyEmailChange {
+    pub token: String,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
@@ -260,12 +1509,7 @@ pub struct NewUser {
 
 impl From<NewUser> for User {
     fn from(value: NewUser) -> Self {
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2
-            .hash_password(value.password.as_bytes(), &salt)
-            .expect("Couldn't hash password")
-            .to_string();
+        let password_hash = hash_password(&value.password);
 
         Self {
             id: None,
@@ -277,6 +1521,28 @@ impl From<NewUser> for User {
             email: value.email,
             saved_places: HashMap::new(),
             password_hash,
+            pending_email: None,
+            shared_with: Vec::new(),
+            reputation: default_reputation(),
+        }
+    }
+}
+
+impl From<OidcClaims> for User {
+    fn from(value: OidcClaims) -> Self {
+        Self {
+            id: None,
+            first_name: value.given_name.unwrap_or_default(),
+            last_name: value.family_name.unwrap_or_default(),
+            location: None,
+            is_verified: value.email_verified.unwrap_or(false),
+            phone_number: None,
+            email: value.email.unwrap_or_default(),
+            saved_places: HashMap::new(),
+            password_hash: String::new(),
+            pending_email: None,
+            shared_with: Vec::new(),
+            reputation: default_reputation(),
         }
     }
 }
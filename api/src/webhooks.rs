@@ -0,0 +1,872 @@
+use crate::{
+    api::{ApiError, ApiResponse},
+    auth::JWTAuthToken,
+    db::Entity,
+    incidents::{Incident, IncidentResponse},
+    loadshedding::{DBFunctions, DBFunctionsTrait, LoadSheddingStage},
+    reporting::now_millis,
+    repository::{MongoStore, PageRequest, Repository, Store},
+    scheduler::{Job, Schedule, Scheduler},
+    user::current_user,
+    DB_NAME,
+};
+use bson::oid::ObjectId;
+use hmac::{Hmac, Mac};
+use macros::Entity;
+use mongodb::{
+    options::{FindOneAndUpdateOptions, InsertManyOptions},
+    Client, Database,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{delete, get, post, serde::json::Json, Orbit, Rocket, State};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use utoipa::ToSchema;
+
+const MAX_WEBHOOKS_PER_USER: u64 = 10;
+const MIN_SECRET_LENGTH: usize = 16;
+/// Deliveries are given up on after this many failed attempts
+pub const MAX_ATTEMPTS: u32 = 8;
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(6 * 3600);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DISPATCH_INTERVAL: Duration = Duration::from_secs(5);
+const SUBURB_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Delivery history is kept for 30 days
+const DELIVERY_RETENTION_MILLIS: u64 = 1000 * 60 * 60 * 24 * 30;
+const DELIVERY_HISTORY_LENGTH: i64 = 50;
+
+#[utoipa::path(post, path = "/api/webhooks", request_body = NewWebhook, security(("jwt" = [])), responses(
+    (status = 200, body = WebhookResponse)
+))]
+#[post("/webhooks", format = "application/json", data = "<webhook>")]
+pub async fn create_webhook(
+    webhook: Json<NewWebhook>,
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<WebhookResponse> {
+    let (user, store) = match current_user(&token, state).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+    let webhook = webhook.into_inner();
+    if let Err(err) = webhook.validate().await {
+        return err.into();
+    }
+
+    let webhooks = store.repository::<WebhookSubscription>();
+    match webhooks.count(bson::doc! { "owner": &user.email }).await {
+        Ok(count) if count >= MAX_WEBHOOKS_PER_USER => {
+            return ApiError::bad_request("You can have at most 10 webhooks").into()
+        }
+        Ok(_) => {}
+        Err(err) => {
+            log::error!("Couldn't count webhooks: {err}");
+            return ApiError::internal("Couldn't create the webhook").into();
+        }
+    }
+
+    let mut subscription = WebhookSubscription {
+        id: None,
+        owner: user.email.clone(),
+        url: webhook.url,
+        secret: webhook.secret,
+        events: webhook.events,
+        suburbs: webhook.suburbs,
+        created: now_millis(),
+    };
+    match webhooks.insert(&mut subscription).await {
+        Ok(_) => ApiResponse::Ok(WebhookResponse::from(&subscription)),
+        Err(err) => {
+            log::error!("Couldn't create webhook: {err}");
+            ApiError::internal("Couldn't create the webhook").into()
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/api/webhooks", security(("jwt" = [])), responses(
+    (status = 200, body = [WebhookResponse])
+))]
+#[get("/webhooks")]
+pub async fn get_webhooks(
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<Vec<WebhookResponse>> {
+    let (user, store) = match current_user(&token, state).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+    let filter = bson::doc! { "owner": &user.email };
+    match store
+        .repository::<WebhookSubscription>()
+        .find(filter, PageRequest::all())
+        .await
+    {
+        Ok(page) => ApiResponse::Ok(page.items.iter().map(WebhookResponse::from).collect()),
+        Err(err) => {
+            log::error!("Couldn't fetch webhooks: {err}");
+            ApiError::internal("Couldn't fetch your webhooks").into()
+        }
+    }
+}
+
+#[utoipa::path(delete, path = "/api/webhooks/{id}", params(("id",)), security(("jwt" = [])))]
+#[delete("/webhooks/<id>")]
+pub async fn delete_webhook(
+    id: &str,
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<&'static str> {
+    let (user, store) = match current_user(&token, state).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+    let id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return ApiError::bad_request("Invalid webhook id").into(),
+    };
+
+    match store
+        .repository::<WebhookSubscription>()
+        .delete_many(bson::doc! { "_id": id, "owner": &user.email })
+        .await
+    {
+        Ok(0) => ApiError::not_found("No such webhook").into(),
+        Ok(_) => {
+            if let Err(err) = store
+                .repository::<WebhookDelivery>()
+                .delete_many(bson::doc! { "webhook": id })
+                .await
+            {
+                log::error!("Couldn't delete deliveries of webhook {id}: {err:?}");
+            }
+            ApiResponse::Ok("Webhook deleted")
+        }
+        Err(err) => {
+            log::error!("Couldn't delete webhook: {err:?}");
+            ApiError::internal("Couldn't delete the webhook").into()
+        }
+    }
+}
+
+#[utoipa::path(get, path = "/api/webhooks/{id}/deliveries", params(("id",)), security(("jwt" = [])), responses(
+    (status = 200, description = "The 50 most recent deliveries, newest first", body = [DeliveryResponse])
+))]
+#[get("/webhooks/<id>/deliveries")]
+pub async fn get_deliveries(
+    id: &str,
+    token: JWTAuthToken,
+    state: &State<Option<Client>>,
+) -> ApiResponse<Vec<DeliveryResponse>> {
+    let (user, store) = match current_user(&token, state).await {
+        Ok(user) => user,
+        Err(err) => return err.into(),
+    };
+    let id = match ObjectId::parse_str(id) {
+        Ok(id) => id,
+        Err(_) => return ApiError::bad_request("Invalid webhook id").into(),
+    };
+    match store
+        .repository::<WebhookSubscription>()
+        .find_by_id(id)
+        .await
+    {
+        Ok(Some(webhook)) if webhook.owner == user.email => {}
+        Ok(_) => return ApiError::not_found("No such webhook").into(),
+        Err(err) => return ApiError::from(err).into(),
+    }
+
+    let page = PageRequest::new(0, DELIVERY_HISTORY_LENGTH).sorted_by(bson::doc! { "created": -1 });
+    match store
+        .repository::<WebhookDelivery>()
+        .find(bson::doc! { "webhook": id }, page)
+        .await
+    {
+        Ok(page) => ApiResponse::Ok(page.items.iter().map(DeliveryResponse::from).collect()),
+        Err(err) => {
+            log::error!("Couldn't fetch webhook deliveries: {err:?}");
+            ApiError::internal("Couldn't fetch deliveries").into()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum WebhookEvent {
+    StageChanged,
+    /// A suburb the webhook watches lost power
+    OutageStarted,
+    /// A suburb the webhook watches got power back
+    OutageEnded,
+    IncidentCreated,
+}
+
+impl WebhookEvent {
+    fn needs_suburbs(self) -> bool {
+        matches!(self, Self::OutageStarted | Self::OutageEnded)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct NewWebhook {
+    /// Where deliveries are POSTed to
+    pub url: String,
+    /// Deliveries are signed with this, at least 16 characters
+    pub secret: String,
+    pub events: Vec<WebhookEvent>,
+    /// Suburbs to send outage events for. Incident events are limited to
+    /// these suburbs too, when any are given
+    #[serde(default)]
+    pub suburbs: Vec<String>,
+}
+
+impl NewWebhook {
+    pub async fn validate(&self) -> Result<(), ApiError> {
+        match reqwest::Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => {}
+            _ => {
+                return Err(ApiError::invalid(
+                    "url",
+                    "Webhook URLs have to be http(s) URLs",
+                ))
+            }
+        }
+        if let Err(err) = resolve_public(&self.url).await {
+            return Err(ApiError::invalid("url", err));
+        }
+        if self.secret.chars().count() < MIN_SECRET_LENGTH {
+            return Err(ApiError::invalid(
+                "secret",
+                "Webhook secrets have to be at least 16 characters",
+            ));
+        }
+        if self.events.is_empty() {
+            return Err(ApiError::invalid("events", "Pick at least one event"));
+        }
+        if self.suburbs.is_empty() && self.events.iter().any(|event| event.needs_suburbs()) {
+            return Err(ApiError::invalid(
+                "suburbs",
+                "Outage events need a list of suburbs",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[serde(rename_all = "camelCase")]
+#[collection_name = "webhooks"]
+pub struct WebhookSubscription {
+    #[serde(skip_serializing_if = "Option::is_none", rename = "_id")]
+    pub id: Option<ObjectId>,
+    /// Email of the user who registered the webhook
+    #[index]
+    pub owner: String,
+    pub url: String,
+    pub secret: String,
+    #[index]
+    pub events: Vec<WebhookEvent>,
+    pub suburbs: Vec<String>,
+    pub created: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookResponse {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub suburbs: Vec<String>,
+    pub created: u64,
+}
+
+impl From<&WebhookSubscription> for WebhookResponse {
+    fn from(value: &WebhookSubscription) -> Self {
+        Self {
+            id: value.id.map(|id| id.to_hex()).unwrap_or_default(),
+            url: value.url.clone(),
+            events: value.events.clone(),
+            suburbs: value.suburbs.clone(),
+            created: value.created,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// Gave up after too many failed attempts
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryAttempt {
+    pub at: u64,
+    /// The receiver's HTTP status, if it responded at all
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl DeliveryAttempt {
+    pub fn succeeded(&self) -> bool {
+        self.status
+            .is_some_and(|status| (200..300).contains(&status))
+    }
+}
+
+/// One event on its way to one webhook
+#[derive(Debug, Clone, Serialize, Deserialize, Entity)]
+#[serde(rename_all = "camelCase")]
+#[collection_name = "webhook_deliveries"]
+#[index(fields = "webhook, eventKey", unique)]
+#[index(fields = "status, nextAttempt")]
+#[index(fields = "webhook, -created")]
+pub struct WebhookDelivery {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+    pub webhook: ObjectId,
+    pub event: WebhookEvent,
+    /// Identifies the event, so instances noticing the same change don't
+    /// deliver it twice
+    pub event_key: String,
+    /// The JSON body we POST
+    pub body: String,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+    pub next_attempt: u64,
+    pub created: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryResponse {
+    pub id: String,
+    pub event: WebhookEvent,
+    pub status: DeliveryStatus,
+    pub attempts: Vec<DeliveryAttempt>,
+    /// When the next attempt is due, if there'll be one
+    pub next_attempt: Option<u64>,
+    pub created: u64,
+}
+
+impl From<&WebhookDelivery> for DeliveryResponse {
+    fn from(value: &WebhookDelivery) -> Self {
+        Self {
+            id: value.id.map(|id| id.to_hex()).unwrap_or_default(),
+            event: value.event,
+            status: value.status,
+            attempts: value.attempts.clone(),
+            next_attempt: (value.status == DeliveryStatus::Pending).then_some(value.next_attempt),
+            created: value.created,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EventBody<'a, T: Serialize> {
+    id: String,
+    event: WebhookEvent,
+    created: u64,
+    data: &'a T,
+}
+
+/// Hex HMAC-SHA256 of `<timestamp>.<body>` keyed with the webhook's secret.
+/// Receivers compare it against the `X-WIP-Signature` header, and reject
+/// old timestamps to stop replays.
+pub fn sign(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// How long to wait after the given number of failed attempts
+pub fn retry_delay(attempts: u32) -> Duration {
+    FIRST_RETRY_DELAY
+        .saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY)
+}
+
+/// Resolves a webhook URL's host, refusing hosts that point into our own
+/// network: loopback, private, link-local (the cloud metadata service among
+/// them) and unique local addresses. Returns the host and the address to
+/// connect to.
+pub async fn resolve_public(url: &str) -> Result<(String, SocketAddr), String> {
+    let url = reqwest::Url::parse(url).map_err(|_| "Webhook URLs have to be http(s) URLs")?;
+    let port = url
+        .port_or_known_default()
+        .ok_or("Webhook URLs have to be http(s) URLs")?;
+    let host = url
+        .host_str()
+        .ok_or("Webhook URLs have to be http(s) URLs")?
+        .to_string();
+    // IPv6 hosts keep their brackets
+    let addresses = match host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+    {
+        Ok(ip) => vec![SocketAddr::new(ip, port)],
+        Err(_) => tokio::net::lookup_host((host.as_str(), port))
+            .await
+            .map_err(|err| format!("Couldn't resolve {host}: {err}"))?
+            .collect::<Vec<_>>(),
+    };
+    // Every address has to be public, or DNS could hand out a private one
+    // on the next lookup
+    match addresses.first() {
+        Some(address) if addresses.iter().all(|address| is_public(address.ip())) => {
+            Ok((host, *address))
+        }
+        Some(_) => Err(format!("{host} isn't a public address")),
+        None => Err(format!("Couldn't resolve {host}")),
+    }
+}
+
+/// Whether an address is reachable from the internet rather than only from
+/// inside our own network
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || first == 0
+                // Carrier-grade NAT, 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || first & 0xfe00 == 0xfc00
+                    // Link-local, fe80::/10
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// POSTs a delivery's body to a webhook, signed with its secret. The host
+/// is checked again before every delivery, as what it resolves to can
+/// change after the webhook was registered, and the request is pinned to
+/// the address that was checked. Redirects aren't followed.
+pub async fn deliver(
+    webhook: &WebhookSubscription,
+    delivery_id: &str,
+    event: WebhookEvent,
+    body: &str,
+) -> DeliveryAttempt {
+    let http = resolve_public(&webhook.url)
+        .await
+        .and_then(|(host, address)| {
+            reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .resolve(&host, address)
+                .build()
+                .map_err(|err| err.to_string())
+        });
+    match http {
+        Ok(http) => send(&http, webhook, delivery_id, event, body).await,
+        Err(err) => DeliveryAttempt {
+            at: now_millis(),
+            status: None,
+            error: Some(err),
+        },
+    }
+}
+
+/// `deliver` without checking where the webhook points
+pub async fn send(
+    http: &reqwest::Client,
+    webhook: &WebhookSubscription,
+    delivery_id: &str,
+    event: WebhookEvent,
+    body: &str,
+) -> DeliveryAttempt {
+    let at = now_millis();
+    let timestamp = at / 1000;
+    let result = http
+        .post(&webhook.url)
+        .timeout(DELIVERY_TIMEOUT)
+        .header("Content-Type", "application/json")
+        .header("X-WIP-Event", format!("{event:?}"))
+        .header("X-WIP-Delivery", delivery_id)
+        .header("X-WIP-Timestamp", timestamp.to_string())
+        .header("X-WIP-Signature", sign(&webhook.secret, timestamp, body))
+        .body(body.to_string())
+        .send()
+        .await;
+
+    match result {
+        Ok(response) => {
+            let status = response.status();
+            DeliveryAttempt {
+                at,
+                status: Some(status.as_u16()),
+                error: (!status.is_success()).then(|| format!("Receiver responded with {status}")),
+            }
+        }
+        Err(err) => DeliveryAttempt {
+            at,
+            status: None,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+/// Queues an event for every webhook listening for it. `suburbs` limits
+/// it to webhooks watching one of them, or watching no suburbs at all.
+pub async fn emit<T: Serialize>(
+    event: WebhookEvent,
+    event_key: &str,
+    suburbs: Option<&[String]>,
+    data: &T,
+    db: &Database,
+) {
+    let mut filter = bson::doc! { "events": bson::to_bson(&event).unwrap() };
+    if let Some(suburbs) = suburbs {
+        filter.insert(
+            "$or",
+            vec![
+                bson::doc! { "suburbs": { "$size": 0 } },
+                bson::doc! { "suburbs": { "$in": suburbs } },
+            ],
+        );
+    }
+    let webhooks = match WebhookSubscription::find(filter, db, None).await {
+        Ok(webhooks) => webhooks,
+        Err(err) => {
+            log::error!("Couldn't find webhooks for {event:?}: {err:?}");
+            return;
+        }
+    };
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let created = now_millis();
+    let deliveries: Vec<WebhookDelivery> = webhooks
+        .iter()
+        .filter_map(|webhook| {
+            let id = ObjectId::new();
+            let body = serde_json::to_string(&EventBody {
+                id: id.to_hex(),
+                event,
+                created,
+                data,
+            })
+            .ok()?;
+            Some(WebhookDelivery {
+                id: Some(id),
+                webhook: webhook.id?,
+                event,
+                event_key: event_key.to_string(),
+                body,
+                status: DeliveryStatus::Pending,
+                attempts: Vec::new(),
+                next_attempt: created,
+                created,
+            })
+        })
+        .collect();
+
+    // Unordered, so deliveries another instance already queued are skipped
+    // instead of stopping the rest
+    let options = InsertManyOptions::builder().ordered(false).build();
+    if let Err(err) = db
+        .collection::<WebhookDelivery>("webhook_deliveries")
+        .insert_many(deliveries, options)
+        .await
+    {
+        match *err.kind {
+            mongodb::error::ErrorKind::BulkWrite(_) => {}
+            _ => log::error!("Couldn't queue {event:?} deliveries: {err:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StageChange {
+    stage: i32,
+    previous_stage: i32,
+    start_time: i64,
+    end_time: i64,
+}
+
+pub async fn stage_changed(previous: i32, current: &LoadSheddingStage, client: &Client) {
+    let change = StageChange {
+        stage: current.stage,
+        previous_stage: previous,
+        start_time: current.start_time,
+        end_time: current.end_time,
+    };
+    let key = format!("stage:{}:{}", current.start_time, current.stage);
+    emit(
+        WebhookEvent::StageChanged,
+        &key,
+        None,
+        &change,
+        &client.database(DB_NAME),
+    )
+    .await;
+}
+
+pub async fn incident_created(incident: &Incident, db: &Database) {
+    let key = format!("incident:{}", incident.id.unwrap_or_default());
+    emit(
+        WebhookEvent::IncidentCreated,
+        &key,
+        Some(&incident.suburbs),
+        &IncidentResponse::from(incident),
+        db,
+    )
+    .await;
+}
+
+/// Last power status we saw for a suburb some webhook watches
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SuburbPowerState {
+    #[serde(rename = "_id")]
+    suburb: String,
+    power_on: bool,
+    since: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OutageChange<'a> {
+    suburb: &'a str,
+    power_on: bool,
+    since: u64,
+}
+
+/// Compares the schedule of every suburb a webhook watches against what
+/// it was last time, sending outage events for the ones that changed.
+pub async fn check_suburbs(client: &Client) {
+    let db = client.database(DB_NAME);
+    let production = client.database("production");
+    let events = [WebhookEvent::OutageStarted, WebhookEvent::OutageEnded]
+        .iter()
+        .map(|event| bson::to_bson(event).unwrap())
+        .collect::<Vec<_>>();
+    let suburbs = match db
+        .collection::<WebhookSubscription>("webhooks")
+        .distinct("suburbs", bson::doc! { "events": { "$in": events } }, None)
+        .await
+    {
+        Ok(suburbs) => suburbs,
+        Err(err) => {
+            log::error!("Couldn't find the suburbs webhooks watch: {err:?}");
+            return;
+        }
+    };
+
+    let states = db.collection::<SuburbPowerState>("suburb_power_states");
+    let db_functions = DBFunctions {};
+    for suburb in suburbs.iter().filter_map(|suburb| suburb.as_str()) {
+        let entity = match db_functions
+            .collect_suburbs(bson::doc! { "name": suburb }, Some(&production), None)
+            .await
+        {
+            Ok(entities) => match entities.into_iter().next() {
+                Some(entity) => entity,
+                None => continue,
+            },
+            Err(err) => {
+                log::warn!("Couldn't look up suburb {suburb}: {err:?}");
+                continue;
+            }
+        };
+        let power_on = match entity.power_status(&production, &db_functions, None).await {
+            Ok(status) => status.power_on,
+            Err(err) => {
+                log::warn!("Couldn't work out whether {suburb} has power: {err:?}");
+                continue;
+            }
+        };
+
+        let since = now_millis();
+        let previous = states
+            .find_one_and_update(
+                bson::doc! { "_id": suburb, "powerOn": !power_on },
+                bson::doc! { "$set": { "powerOn": power_on, "since": since as i64 } },
+                None,
+            )
+            .await;
+        match previous {
+            // Only the instance that flipped the state sends the event
+            Ok(Some(_)) => {
+                let event = if power_on {
+                    WebhookEvent::OutageEnded
+                } else {
+                    WebhookEvent::OutageStarted
+                };
+                let change = OutageChange {
+                    suburb,
+                    power_on,
+                    since,
+                };
+                let key = format!("outage:{suburb}:{since}");
+                emit(event, &key, Some(&[suburb.to_string()]), &change, &db).await;
+            }
+            // Either unchanged or seen for the first time
+            Ok(None) => {
+                let state = SuburbPowerState {
+                    suburb: suburb.to_string(),
+                    power_on,
+                    since,
+                };
+                // Fails harmlessly when the state is already there
+                let _ = states.insert_one(state, None).await;
+            }
+            Err(err) => log::error!("Couldn't update the power state of {suburb}: {err:?}"),
+        }
+    }
+}
+
+/// Sends deliveries that are due, rescheduling failed ones with
+/// exponential backoff.
+pub async fn dispatch(client: &Client) {
+    let db = client.database(DB_NAME);
+    let store = MongoStore::new(client, DB_NAME);
+    let deliveries = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    loop {
+        let now = now_millis();
+        // Claiming a delivery pushes its next attempt back, so other
+        // instances leave it alone while we send it
+        let claimed = deliveries
+            .find_one_and_update(
+                bson::doc! { "status": bson::to_bson(&DeliveryStatus::Pending).unwrap(), "nextAttempt": { "$lte": now as i64 } },
+                bson::doc! { "$set": { "nextAttempt": (now + 2 * DELIVERY_TIMEOUT.as_millis() as u64) as i64 } },
+                FindOneAndUpdateOptions::builder()
+                    .sort(bson::doc! { "nextAttempt": 1 })
+                    .build(),
+            )
+            .await;
+        let delivery = match claimed {
+            Ok(Some(delivery)) => delivery,
+            Ok(None) => break,
+            Err(err) => {
+                log::error!("Couldn't claim a webhook delivery: {err:?}");
+                break;
+            }
+        };
+
+        let webhook = match store
+            .repository::<WebhookSubscription>()
+            .find_by_id(delivery.webhook)
+            .await
+        {
+            Ok(webhook) => webhook,
+            Err(err) => {
+                // It stays claimed for a while, then it's retried
+                log::error!("Couldn't look up the webhook of a delivery: {err:?}");
+                continue;
+            }
+        };
+        let attempt = match &webhook {
+            Some(webhook) => {
+                let id = delivery.id.unwrap_or_default().to_hex();
+                deliver(webhook, &id, delivery.event, &delivery.body).await
+            }
+            None => DeliveryAttempt {
+                at: now,
+                status: None,
+                error: Some("The webhook was deleted".to_string()),
+            },
+        };
+
+        let attempts = delivery.attempts.len() as u32 + 1;
+        let status = if attempt.succeeded() {
+            DeliveryStatus::Delivered
+        } else if attempts >= MAX_ATTEMPTS || webhook.is_none() {
+            DeliveryStatus::Failed
+        } else {
+            DeliveryStatus::Pending
+        };
+        let next_attempt = now_millis() + retry_delay(attempts).as_millis() as u64;
+        let update = bson::doc! {
+            "$push": { "attempts": bson::to_bson(&attempt).unwrap() },
+            "$set": {
+                "status": bson::to_bson(&status).unwrap(),
+                "nextAttempt": next_attempt as i64,
+            },
+        };
+        if let Err(err) = store
+            .repository::<WebhookDelivery>()
+            .update_by_id(delivery.id.unwrap(), update)
+            .await
+        {
+            log::error!("Couldn't record webhook delivery attempt: {err:?}");
+        }
+    }
+
+    let expired = now_millis().saturating_sub(DELIVERY_RETENTION_MILLIS);
+    if let Err(err) = deliveries
+        .delete_many(bson::doc! { "created": { "$lt": expired as i64 } }, None)
+        .await
+    {
+        log::error!("Couldn't delete old webhook deliveries: {err:?}");
+    }
+}
+
+/// Sends queued webhook deliveries and watches suburbs for outage events
+pub struct WebhookDispatcher;
+
+#[rocket::async_trait]
+impl Fairing for WebhookDispatcher {
+    fn info(&self) -> Info {
+        Info {
+            name: "Webhook Dispatcher",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let client = match rocket.state::<Option<Client>>().unwrap() {
+            Some(client) => client.clone(),
+            None => return,
+        };
+
+        let scheduler = rocket.state::<Scheduler>().unwrap();
+
+        let job = Job::new("webhook dispatch", Schedule::every(DISPATCH_INTERVAL));
+        let dispatch_client = client.clone();
+        scheduler.spawn(job, rocket.shutdown(), move || {
+            let client = dispatch_client.clone();
+            async move {
+                dispatch(&client).await;
+                Ok(())
+            }
+        });
+        let job = Job::new(
+            "webhook outage check",
+            Schedule::every(SUBURB_CHECK_INTERVAL),
+        );
+        scheduler.spawn(job, rocket.shutdown(), move || {
+            let client = client.clone();
+            async move {
+                check_suburbs(&client).await;
+                Ok(())
+            }
+        });
+    }
+}